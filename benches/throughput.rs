@@ -0,0 +1,176 @@
+//! Throughput benchmarks of a client and a server connected over the loopback interface, run
+//! with:
+//!
+//! ```text
+//! cargo bench --bench throughput [NAME...]
+//! ```
+//!
+//! Both endpoints run on the same reactor, so the figures measure the overhead of the library
+//! rather than the network. Only the benchmarks whose name contains one of the given names are
+//! run.
+extern crate bytes;
+extern crate futures;
+extern crate rmp_rpc;
+extern crate rmpv;
+extern crate tokio_core;
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{future, Future};
+use rmp_rpc::{Client, ClientOnlyConnector, MethodRouter, Param, ServerBuilder};
+use rmpv::Value;
+use tokio_core::reactor::Core;
+
+/// A server and a client connected to it.
+struct Bench {
+    core: Core,
+    client: Client,
+    notifications: Arc<AtomicUsize>,
+}
+
+impl Bench {
+    fn new() -> Self {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let notifications = Arc::new(AtomicUsize::new(0));
+        let mut router = MethodRouter::new();
+        let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+        let counter = Arc::clone(&notifications);
+        let _ = router.notification("event", move |_params| {
+            let _ = counter.fetch_add(1, Ordering::Relaxed);
+            Box::new(future::ok(()))
+        });
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = ServerBuilder::new(any).spawn(router, &handle).unwrap();
+        let address = server.local_addr().unwrap();
+        let client = core.run(ClientOnlyConnector::new(&address, &handle).connect())
+            .unwrap();
+        Bench {
+            core: core,
+            client: client,
+            notifications: notifications,
+        }
+    }
+
+    fn echo(&self, param: Value) -> Box<Future<Item = (), Error = ()>> {
+        Box::new(self.client.request("echo", &[param]).then(|result| match result {
+            Ok(Ok(_)) => Ok(()),
+            _ => Err(()),
+        }))
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Latency of requests sent one after the other.
+fn round_trip() {
+    const REQUESTS: usize = 10_000;
+    let mut bench = Bench::new();
+    let start = Instant::now();
+    for i in 0..REQUESTS {
+        let request = bench.echo(Value::from(i));
+        bench.core.run(request).unwrap();
+    }
+    let elapsed = seconds(start.elapsed());
+    println!(
+        "round_trip: {} requests, {:.1}us per request",
+        REQUESTS,
+        elapsed * 1e6 / REQUESTS as f64
+    );
+}
+
+/// Notifications handled per second.
+fn notifications() {
+    const NOTIFICATIONS: usize = 100_000;
+    let mut bench = Bench::new();
+    let start = Instant::now();
+    let sent = (0..NOTIFICATIONS)
+        .map(|i| bench.client.notify("event", &[Value::from(i)]))
+        .collect::<Vec<_>>();
+    let _ = bench.core.run(future::join_all(sent)).unwrap();
+    // The messages are handled in order: once the response arrives, so have the notifications.
+    let request = bench.echo(Value::Nil);
+    bench.core.run(request).unwrap();
+    let elapsed = seconds(start.elapsed());
+    assert_eq!(bench.notifications.load(Ordering::Relaxed), NOTIFICATIONS);
+    println!(
+        "notifications: {} notifications, {:.0} notifications/s",
+        NOTIFICATIONS,
+        NOTIFICATIONS as f64 / elapsed
+    );
+}
+
+/// Throughput of requests with a large binary parameter, echoed by the server.
+fn large_payloads() {
+    const REQUESTS: usize = 200;
+    const PAYLOAD: usize = 1024 * 1024;
+    let mut bench = Bench::new();
+    let payload = Bytes::from(vec![0; PAYLOAD]);
+    let start = Instant::now();
+    let requests = (0..REQUESTS)
+        .map(|_| {
+            let params = vec![Param::Binary(payload.clone())];
+            bench.client.request_zero_copy("echo", params).then(|result| match result {
+                Ok(Ok(_)) => Ok(()),
+                _ => Err(()),
+            })
+        })
+        .collect::<Vec<_>>();
+    let _ = bench.core.run(future::join_all(requests)).unwrap();
+    let elapsed = seconds(start.elapsed());
+    // Each payload goes both ways.
+    let megabytes = (2 * REQUESTS * PAYLOAD) as f64 / 1e6;
+    println!(
+        "large_payloads: {} requests of {}KB, {:.1}MB/s",
+        REQUESTS,
+        PAYLOAD / 1024,
+        megabytes / elapsed
+    );
+}
+
+/// Cost per request as the number of requests in flight grows. It should stay about the same.
+fn in_flight() {
+    const REQUESTS: usize = 100_000;
+    for &concurrency in &[10, 100, 1_000, 10_000] {
+        let mut bench = Bench::new();
+        let start = Instant::now();
+        for _ in 0..REQUESTS / concurrency {
+            let requests = (0..concurrency)
+                .map(|i| bench.echo(Value::from(i)))
+                .collect::<Vec<_>>();
+            let _ = bench.core.run(future::join_all(requests)).unwrap();
+        }
+        let elapsed = seconds(start.elapsed());
+        println!(
+            "in_flight: {} requests in flight, {:.2}us per request",
+            concurrency,
+            elapsed * 1e6 / REQUESTS as f64
+        );
+    }
+}
+
+fn main() {
+    let benches: &[(&str, fn())] = &[
+        ("round_trip", round_trip),
+        ("notifications", notifications),
+        ("large_payloads", large_payloads),
+        ("in_flight", in_flight),
+    ];
+    // Cargo passes `--bench`, and possibly other flags.
+    let filters = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect::<Vec<_>>();
+    for &(name, bench) in benches {
+        if filters.is_empty() || filters.iter().any(|filter| name.contains(filter.as_str())) {
+            bench();
+        }
+    }
+}
@@ -50,7 +50,9 @@ impl Client {
     }
 }
 
-fn parse_response(response: Result<Result<Value, Value>, ()>) -> Result<i64, RpcError> {
+fn parse_response(
+    response: Result<Result<Value, Value>, rmp_rpc::CallError>,
+) -> Result<i64, RpcError> {
     match response? {
         Ok(result) => if let Value::Integer(int) = result {
             int.as_i64().ok_or_else(|| {
@@ -85,6 +87,8 @@ fn parse_response(response: Result<Result<Value, Value>, ()>) -> Result<i64, Rpc
 #[derive(Debug)]
 pub enum RpcError {
     Other,
+    /// The connection was closed before the response was received.
+    Connection(rmp_rpc::CallError),
     /// Error returned by the server upon a request.
     Server(String),
     /// Error while processing the server response.
@@ -95,6 +99,7 @@ impl fmt::Display for RpcError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             RpcError::Other => write!(f, "unknown error"),
+            RpcError::Connection(ref e) => write!(f, "no response: {}", e),
             RpcError::Server(ref msg) => write!(f, "the server returned an error: {}", msg),
             RpcError::Client(ref msg) => {
                 write!(f, "failed to process the server response (reason: {})", msg)
@@ -107,6 +112,7 @@ impl error::Error for RpcError {
     fn description(&self) -> &str {
         match *self {
             RpcError::Other => "unknown error",
+            RpcError::Connection(_) => "the connection was closed",
             RpcError::Server(_) => "the server returned an error",
             RpcError::Client(_) => "failed to process the server response",
         }
@@ -122,3 +128,9 @@ impl From<()> for RpcError {
         RpcError::Other
     }
 }
+
+impl From<rmp_rpc::CallError> for RpcError {
+    fn from(e: rmp_rpc::CallError) -> RpcError {
+        RpcError::Connection(e)
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::{Arc, Mutex};
 use std::io;
 
-use futures::{future, Future};
+use futures::future::{self, FutureResult};
 use rmp_rpc::{Client, Service, ServiceBuilder, Value};
 
 #[derive(Clone)]
@@ -64,12 +64,10 @@ impl Service for Calculator {
     type T = i64;
     type E = String;
     type Error = io::Error;
+    type RequestFuture = FutureResult<Result<Self::T, Self::E>, Self::Error>;
+    type NotificationFuture = FutureResult<(), Self::Error>;
 
-    fn handle_request(
-        &mut self,
-        method: &str,
-        params: &[Value],
-    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
         let res = match method {
             "add" | "+" => self.add(params).map_err(|e| e.to_string()),
             "sub" | "-" => self.sub(params).map_err(|e| e.to_string()),
@@ -77,13 +75,9 @@ impl Service for Calculator {
             "clear" => self.clear().map_err(|e| e.to_string()),
             method => Err(format!("Invalid method {}", method)),
         };
-        Box::new(future::ok(res))
+        future::ok(res)
     }
-    fn handle_notification(
-        &mut self,
-        _method: &str,
-        _params: &[Value],
-    ) -> Box<Future<Item = (), Error = Self::Error>> {
+    fn handle_notification(&mut self, _method: &str, _params: &[Value]) -> Self::NotificationFuture {
         unimplemented!();
     }
 }
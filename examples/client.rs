@@ -47,6 +47,7 @@ fn main() {
                     println!("Response: {:?}", response);
                     Ok(())
                 })
+                .map_err(|e| println!("Request failed: {}", e))
         });
 
     // Run the client
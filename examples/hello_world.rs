@@ -4,11 +4,11 @@ extern crate futures;
 extern crate rmp_rpc;
 extern crate tokio_core;
 
-use std::marker::Send;
 use std::io;
 use std::net::SocketAddr;
 
-use futures::{future, Future};
+use futures::Future;
+use futures::future::{self, FutureResult};
 use rmp_rpc::{serve, Client, ClientOnlyConnector, Service, ServiceBuilder, Value};
 use tokio_core::reactor::Core;
 
@@ -23,26 +23,20 @@ impl ServiceBuilder for HelloWorld {
     }
 }
 
-fn box_ok<T: Send + 'static, E: Send + 'static>(t: T) -> Box<Future<Item = T, Error = E>> {
-    Box::new(future::ok(t))
-}
-
 impl Service for HelloWorld {
     type Error = io::Error;
     type T = String;
     type E = String;
+    type RequestFuture = FutureResult<Result<Self::T, Self::E>, Self::Error>;
+    type NotificationFuture = FutureResult<(), Self::Error>;
 
-    fn handle_request(
-        &mut self,
-        method: &str,
-        params: &[Value],
-    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
         if method != "hello" {
-            return box_ok(Err(format!("Uknown method {}", method)));
+            return future::ok(Err(format!("Uknown method {}", method)));
         }
 
         if params.len() != 1 {
-            return box_ok(Err(format!(
+            return future::ok(Err(format!(
                 "Expected 1 argument for method \"hello\", got {}",
                 params.len()
             )));
@@ -50,19 +44,15 @@ impl Service for HelloWorld {
 
         if let Value::String(ref string) = params[0] {
             if let Some(name) = string.as_str() {
-                return box_ok(Ok(format!("hello {}", name)));
+                return future::ok(Ok(format!("hello {}", name)));
             }
         }
-        box_ok(Err("Invalid argument".into()))
+        future::ok(Err("Invalid argument".into()))
     }
 
-    fn handle_notification(
-        &mut self,
-        method: &str,
-        _params: &[Value],
-    ) -> Box<Future<Item = (), Error = Self::Error>> {
+    fn handle_notification(&mut self, method: &str, _params: &[Value]) -> Self::NotificationFuture {
         // just pring the notification's method name
-        box_ok(println!("{}", method))
+        future::ok(println!("{}", method))
     }
 }
 
@@ -86,6 +76,7 @@ fn main() {
             .and_then(|client| {
                 client
                     .request("hello", &["little-dude".into()])
+                    .map_err(|e| println!("Request failed: {}", e))
                     .and_then(|response| {
                         println!("{:?}", response);
                         client
@@ -94,10 +85,13 @@ fn main() {
                     })
             })
             .and_then(|client| {
-                client.request("dummy", &[]).and_then(|response| {
-                    println!("{:?}", response);
-                    Ok(())
-                })
+                client
+                    .request("dummy", &[])
+                    .map_err(|e| println!("Request failed: {}", e))
+                    .and_then(|response| {
+                        println!("{:?}", response);
+                        Ok(())
+                    })
             }),
     );
 }
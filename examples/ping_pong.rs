@@ -20,7 +20,7 @@ use std::sync::{Arc, Mutex};
 use futures::{future, Future};
 use tokio_core::reactor::Core;
 
-use rmp_rpc::{serve, Client, Connector, Service, ServiceBuilder, Value};
+use rmp_rpc::{serve, BoxedService, Client, Connector, ServiceBuilder, Value};
 
 // Our endpoint type
 #[derive(Clone)]
@@ -41,8 +41,9 @@ impl PingPong {
 }
 
 // Implement how the endpoint handles incoming requests and notifications.
-// In this example, the endpoint does not handle notifications.
-impl Service for PingPong {
+// In this example, the endpoint does not handle notifications. The futures returned when
+// handling "ping" and "pong" have different types, so they are boxed.
+impl BoxedService for PingPong {
     type T = String;
     type E = String;
     type Error = io::Error;
@@ -63,8 +64,8 @@ impl Service for PingPong {
                     .unwrap()
                     .request("pong", &[id.into()])
                     .and_then(|_result| Ok(Ok(String::new())))
-                    .map_err(|()| {
-                        io::Error::new(io::ErrorKind::Other, "The pong request failed")
+                    .map_err(|e| {
+                        io::Error::new(io::ErrorKind::Other, format!("The pong request failed: {}", e))
                     });
 
                 // The response is the result of the an empty string (quite silly but it's just for
@@ -132,7 +133,8 @@ fn main() {
                     requests.push(
                         client
                             .request("ping", &[i.into()])
-                            .and_then(|_response| Ok(())),
+                            .and_then(|_response| Ok(()))
+                            .map_err(|e| error!("Ping failed: {}", e)),
                     );
                 }
                 future::join_all(requests)
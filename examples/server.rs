@@ -7,10 +7,9 @@ extern crate rmp_rpc;
 extern crate tokio_core;
 
 use std::io;
-use std::marker::Send;
 use std::net::SocketAddr;
 
-use futures::{future, Future};
+use futures::future::{self, FutureResult};
 use rmp_rpc::{serve, Client, Service, ServiceBuilder, Value};
 use tokio_core::reactor::Core;
 
@@ -18,11 +17,6 @@ use tokio_core::reactor::Core;
 #[derive(Clone)]
 pub struct Echo;
 
-// A little helper to make `handle_request()` a little less verbose
-fn box_ok<T: Send + 'static, E: Send + 'static>(t: T) -> Box<Future<Item = T, Error = E>> {
-    Box::new(future::ok(t))
-}
-
 // The Service trait defines how the server handles incoming requests and notifications.
 impl Service for Echo {
     type Error = io::Error;
@@ -32,41 +26,38 @@ impl Service for Echo {
     // When a request fails, the error is a String.
     type E = String;
 
+    // The futures returned by the handlers. Here they are always ready, so there is no need for
+    // boxing them.
+    type RequestFuture = FutureResult<Result<Self::T, Self::E>, Self::Error>;
+    type NotificationFuture = FutureResult<(), Self::Error>;
+
     // Define how the server handle requests.
     //
     // This server accept requests with the method "echo".
     // It echoes back the first parameter.
     // If the method is not echo, or if the first parameter is not a string, it returns an error.
-    fn handle_request(
-        &mut self,
-        method: &str,
-        params: &[Value],
-    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
         // If the method is not "echo", return an error.
         if method != "echo" {
-            return box_ok(Err(format!("Unknown method {}", method)));
+            return future::ok(Err(format!("Unknown method {}", method)));
         }
 
         // Take the first parameter, which should be a string, and echo it back
         if let Value::String(ref string) = params[0] {
             if let Some(text) = string.as_str() {
-                return box_ok(Ok(text.into()));
+                return future::ok(Ok(text.into()));
             }
         }
 
         // If we reach this point, return an error, that means the first parameter is not a String.
-        box_ok(Err("Invalid argument".into()))
+        future::ok(Err("Invalid argument".into()))
     }
 
     // Define how the server handle notifications.
     //
     // This server just prints the method in the console.
-    fn handle_notification(
-        &mut self,
-        method: &str,
-        _: &[Value],
-    ) -> Box<Future<Item = (), Error = Self::Error>> {
-        box_ok(println!("{}", method))
+    fn handle_notification(&mut self, method: &str, _: &[Value]) -> Self::NotificationFuture {
+        future::ok(println!("{}", method))
     }
 }
 
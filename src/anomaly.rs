@@ -0,0 +1,87 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures::{Poll, Stream};
+use futures::sync::mpsc;
+
+use message::Id;
+
+/// A message from the remote endpoint that does not match any request sent by the client.
+///
+/// Anomalies are reported to the [`AnomalyHandler`](trait.AnomalyHandler.html) of the connection
+/// (see [`ProtocolOptions::anomaly_handler`](struct.ProtocolOptions.html#method.anomaly_handler))
+/// and to the streams returned by [`Client::anomalies`](struct.Client.html#method.anomalies).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    /// A response to a request whose `Response` has been dropped, because it timed out for
+    /// instance. The result is discarded. The id of a dropped request is not reused until its
+    /// response has been received, so that a late response is never taken for the response to
+    /// another request.
+    OrphanResponse(Id),
+    /// A response to an id that is not used by any request in flight. The remote endpoint mixed
+    /// up the ids, so the responses that follow cannot be trusted either, and the connection is
+    /// closed, unless
+    /// [`ProtocolOptions::unexpected_responses`](struct.ProtocolOptions.html#method.unexpected_responses)
+    /// says otherwise.
+    UnknownResponse(Id),
+    /// A chunk of a streamed result for a request that is not in flight, or whose result is not
+    /// streamed (see
+    /// [`Client::request_stream`](struct.Client.html#method.request_stream)). The chunk is
+    /// discarded. The method of the notification that carried it is given.
+    UnknownChunk(String),
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Anomaly::OrphanResponse(id) => write!(f, "response to dropped request {}", id),
+            Anomaly::UnknownResponse(id) => write!(f, "response to unknown request {}", id),
+            Anomaly::UnknownChunk(ref method) => write!(f, "chunk {} of unknown request", method),
+        }
+    }
+}
+
+/// A hook called with the anomalies detected on the connections of an endpoint, to report them
+/// to a monitoring system for instance. `on_anomaly` is called on the reactor thread, so it should
+/// not block.
+pub trait AnomalyHandler: Send + Sync {
+    /// Handle an anomaly detected on the given connection (see
+    /// [`Context::connection_id`](struct.Context.html#method.connection_id)).
+    fn on_anomaly(&self, connection: usize, anomaly: &Anomaly);
+}
+
+impl<F: Fn(usize, &Anomaly) + Send + Sync> AnomalyHandler for F {
+    fn on_anomaly(&self, connection: usize, anomaly: &Anomaly) {
+        self(connection, anomaly)
+    }
+}
+
+/// Wrapper around an `AnomalyHandler`, so that it can be part of the `ProtocolOptions`.
+#[derive(Clone)]
+pub(crate) struct AnomalyHook(pub(crate) Arc<AnomalyHandler>);
+
+impl fmt::Debug for AnomalyHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "AnomalyHook")
+    }
+}
+
+/// A stream of the anomalies detected on a connection (see
+/// [`Client::anomalies`](struct.Client.html#method.anomalies)). The stream ends when the
+/// connection is closed.
+pub struct Anomalies(mpsc::UnboundedReceiver<Anomaly>);
+
+impl Anomalies {
+    pub(crate) fn new(rx: mpsc::UnboundedReceiver<Anomaly>) -> Self {
+        Anomalies(rx)
+    }
+}
+
+impl Stream for Anomalies {
+    type Item = Anomaly;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
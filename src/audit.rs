@@ -0,0 +1,195 @@
+use std::{fmt, io};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use rmp;
+use rmpv::Value;
+use rmpv::encode;
+
+use message::{Param, Request};
+
+/// How a request ended, as reported in an [`AuditRecord`](struct.AuditRecord.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    /// The request has been handled, and the response is a success.
+    Success,
+    /// The request has been handled, and the response is an error.
+    Error,
+    /// The request has not been handled before its timeout.
+    TimedOut,
+    /// The request has been answered with an error without being handled, because the server
+    /// is draining or because a quota has been exceeded.
+    Rejected,
+    /// The connection has been closed before the request was answered.
+    Abandoned,
+}
+
+/// The record of a request received by a server, passed to the
+/// [`AuditLog`](trait.AuditLog.html) once the request has been answered. It cannot be modified.
+///
+/// The parameters are not part of the record: only a digest of their encoding is kept, so that
+/// records can be stored without leaking sensitive data, while still making it possible to check
+/// whether two requests had the same parameters.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    principal: Option<String>,
+    method: String,
+    params_digest: u64,
+    received_at: SystemTime,
+    latency: Duration,
+    outcome: AuditOutcome,
+}
+
+impl AuditRecord {
+    /// Identity of the remote endpoint when the request was received (see
+    /// [`Context::set_principal`](struct.Context.html#method.set_principal)).
+    pub fn principal(&self) -> Option<&str> {
+        self.principal.as_ref().map(|principal| principal.as_str())
+    }
+
+    /// Method of the request.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// 64 bits FNV-1a digest of the msgpack encoding of the parameters. It is stable across
+    /// versions, but it is not a cryptographic hash.
+    pub fn params_digest(&self) -> u64 {
+        self.params_digest
+    }
+
+    /// Time at which the request was received.
+    pub fn received_at(&self) -> SystemTime {
+        self.received_at
+    }
+
+    /// Time between the reception of the request and its response.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// How the request ended.
+    pub fn outcome(&self) -> AuditOutcome {
+        self.outcome
+    }
+}
+
+/// A hook called once for every request a server answers, to keep an audit trail (see
+/// [`ProtocolOptions::audit_log`](struct.ProtocolOptions.html#method.audit_log)).
+///
+/// Unlike the debug logs, records are emitted for every request, including those rejected
+/// without being handled, which makes them suitable for append-only security logging.
+/// `record` is called on the reactor thread, so it should not block.
+pub trait AuditLog: Send + Sync {
+    /// Record a request that has been answered.
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F: Fn(&AuditRecord) + Send + Sync> AuditLog for F {
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+/// Wrapper around an `AuditLog`, so that it can be part of the `ProtocolOptions`.
+#[derive(Clone)]
+pub(crate) struct AuditHook(pub(crate) Arc<AuditLog>);
+
+impl fmt::Debug for AuditHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "AuditHook")
+    }
+}
+
+/// The audit record of a request that has not been answered yet.
+pub(crate) struct PendingAudit {
+    log: Arc<AuditLog>,
+    principal: Option<String>,
+    method: String,
+    params_digest: u64,
+    received_at: SystemTime,
+    start: Instant,
+}
+
+impl PendingAudit {
+    pub(crate) fn new(log: Arc<AuditLog>, principal: Option<String>, request: &Request) -> Self {
+        PendingAudit {
+            log: log,
+            principal: principal,
+            method: request.method.clone(),
+            params_digest: digest(&request.params),
+            received_at: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Emit the record of the request.
+    pub(crate) fn finish(self, outcome: AuditOutcome) {
+        let record = AuditRecord {
+            principal: self.principal,
+            method: self.method,
+            params_digest: self.params_digest,
+            received_at: self.received_at,
+            latency: self.start.elapsed(),
+            outcome: outcome,
+        };
+        self.log.record(&record);
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// A writer that computes the FNV-1a hash of the bytes written into it.
+struct Fnv(u64);
+
+impl Write for Fnv {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Return the 64 bits FNV-1a digest of the msgpack encoding of a value.
+pub(crate) fn digest_value(value: &Value) -> u64 {
+    let mut hasher = Fnv(FNV_OFFSET_BASIS);
+    // Writing into a `Fnv` cannot fail.
+    let _ = encode::write_value(&mut hasher, value);
+    hasher.0
+}
+
+fn digest(params: &[Param]) -> u64 {
+    let mut hasher = Fnv(FNV_OFFSET_BASIS);
+    for param in params {
+        // Writing into a `Fnv` cannot fail. Binary parameters are encoded the same way whether
+        // they have been copied or not.
+        let _ = match *param {
+            Param::Value(ref value) => encode::write_value(&mut hasher, value).map_err(|_| ()),
+            Param::Binary(ref bytes) => rmp::encode::write_bin(&mut hasher, bytes).map_err(|_| ()),
+        };
+    }
+    hasher.0
+}
+
+#[test]
+fn params_digest() {
+    use bytes::Bytes;
+
+    assert_eq!(digest(&[]), FNV_OFFSET_BASIS);
+    let value = digest(&[Param::Value(Value::from(1)), Param::Value(Value::from("a"))]);
+    assert_eq!(value, digest(&[Param::Value(Value::from(1)), Param::Value(Value::from("a"))]));
+    assert_ne!(value, digest(&[Param::Value(Value::from(2)), Param::Value(Value::from("a"))]));
+    let binary = Bytes::from(&b"abc"[..]);
+    assert_eq!(
+        digest(&[Param::Binary(binary.clone())]),
+        digest(&[Param::Value(Value::Binary(binary.to_vec()))])
+    );
+}
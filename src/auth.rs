@@ -0,0 +1,132 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use rmpv::Value;
+
+use message::Request;
+
+/// What an [`Authenticator`](trait.Authenticator.html) decides about a new connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Admission {
+    /// Serve the connection. If a principal is given, it becomes the identity of the remote
+    /// endpoint (see [`Context::set_principal`](struct.Context.html#method.set_principal)). The
+    /// request that carried the credentials is answered with `nil`.
+    Accept(Option<String>),
+    /// Answer the request that carried the credentials with the given error, and close the
+    /// connection.
+    Reject(Value),
+}
+
+/// A hook that authenticates the connections of a server (see
+/// [`ProtocolOptions::authenticator`](struct.ProtocolOptions.html#method.authenticator)).
+///
+/// The first request received on a connection carries the credentials of the remote endpoint,
+/// which clients send with
+/// [`Connector::set_credentials`](struct.Connector.html#method.set_credentials). It is passed to
+/// the authenticator instead of the service. Until the connection is accepted, the service does
+/// not see any message: the notifications are dropped. `on_connect` is called on the reactor
+/// thread, so it should not block.
+pub trait Authenticator: Send + Sync {
+    /// Decide whether the connection from `peer_addr`, whose first request is `request`, is
+    /// served.
+    fn on_connect(&self, peer_addr: Option<SocketAddr>, request: &Request) -> Admission;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(Option<SocketAddr>, &Request) -> Admission + Send + Sync,
+{
+    fn on_connect(&self, peer_addr: Option<SocketAddr>, request: &Request) -> Admission {
+        self(peer_addr, request)
+    }
+}
+
+/// Wrapper around an `Authenticator`, so that it can be part of the `ProtocolOptions`.
+#[derive(Clone)]
+pub(crate) struct AuthHook(pub(crate) Arc<Authenticator>);
+
+impl fmt::Debug for AuthHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "AuthHook")
+    }
+}
+
+#[test]
+fn authentication() {
+    use std::io;
+    use futures::future;
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+    use net::ClientOnlyConnector;
+    use options::ProtocolOptions;
+    use server::ServerBuilder;
+
+    let authenticator = |_: Option<SocketAddr>, request: &Request| {
+        let secret = Value::from("secret");
+        if request.method == "login" && request.params[0].clone().into_value() == secret {
+            Admission::Accept(Some("admin".to_owned()))
+        } else {
+            Admission::Reject(Value::from("invalid credentials"))
+        }
+    };
+    let mut options = ProtocolOptions::default();
+    let _ = options.authenticator(Some(Arc::new(authenticator)));
+    let mut router = MethodRouter::new();
+    let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+        .set_protocol_options(options)
+        .spawn(router, &handle)
+        .unwrap();
+    let address = server.local_addr().unwrap();
+
+    let connection = ClientOnlyConnector::new(&address, &handle)
+        .set_credentials("login", &[Value::from("secret")])
+        .connect();
+    let client = core.run(connection).unwrap();
+    let response = client.request("echo", &[Value::from(1)]);
+    assert_eq!(core.run(response).unwrap(), Ok(Value::from(1)));
+
+    let connection = ClientOnlyConnector::new(&address, &handle)
+        .set_credentials("login", &[Value::from("guess")])
+        .connect();
+    let error = core.run(connection).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+    // Without credentials, the first request is rejected and the connection is closed.
+    let client = core.run(ClientOnlyConnector::new(&address, &handle).connect()).unwrap();
+    let first = client.request("echo", &[Value::from(2)]);
+    let second = client.request("echo", &[Value::from(3)]);
+    let first = core.run(first).unwrap();
+    assert_eq!(first, Err(Value::from("invalid credentials")));
+    assert!(core.run(second).is_err());
+}
+
+#[test]
+fn capabilities_before_authentication() {
+    use futures::future;
+    use capabilities::CAPABILITIES_METHOD;
+    use methods::MethodRouter;
+    use mock::TestClient;
+    use options::ProtocolOptions;
+
+    let authenticator = |_: Option<SocketAddr>, _: &Request| Admission::Accept(None);
+    let mut options = ProtocolOptions::default();
+    let _ = options.authenticator(Some(Arc::new(authenticator)));
+    let mut router = MethodRouter::new();
+    let _ = router.request("secret", |_| Box::new(future::ok(Ok(Value::Nil))));
+    let mut client = TestClient::with_options(router, options);
+
+    let methods = |capabilities: Value| {
+        let map = capabilities.as_map().unwrap().clone();
+        map.into_iter().find(|&(ref key, _)| key.as_str() == Some("methods")).unwrap().1
+    };
+    let capabilities = client.request(CAPABILITIES_METHOD, &[]).unwrap();
+    assert_eq!(methods(capabilities), Value::Nil);
+    assert_eq!(client.request("login", &[]), Ok(Value::Nil));
+    let capabilities = client.request(CAPABILITIES_METHOD, &[]).unwrap();
+    assert_eq!(methods(capabilities), Value::Array(vec![Value::from("secret")]));
+}
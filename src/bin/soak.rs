@@ -0,0 +1,241 @@
+//! A load generator for `MessagePack-RPC` servers, built with the `soak` feature. It opens a
+//! number of connections to a server, sends requests at a fixed rate spread over these
+//! connections, and reports the latencies and the errors once it is done:
+//!
+//! ```text
+//! cargo run --features soak --bin soak -- --connections 8 --rate 5000 127.0.0.1:54321
+//! ```
+//!
+//! Each request has a single binary parameter, of the given size. The server must answer the
+//! method that is called, whose result is ignored.
+extern crate bytes;
+extern crate futures;
+extern crate rmp_rpc;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use rmp_rpc::{ClientOnlyConnector, Param};
+use tokio_core::reactor::{Core, Interval};
+
+const USAGE: &str = "Usage: soak [OPTIONS] ADDRESS
+
+Options:
+    --connections N    number of connections to open (default: 1)
+    --rate N           requests sent per second, over all the connections (default: 1000)
+    --payload N        size of the binary parameter of the requests, in bytes (default: 64)
+    --duration N       how long requests are sent, in seconds (default: 10)
+    --method NAME      method called (default: echo)
+    --grace N          how long the requests in flight are waited for, in seconds (default: 5)";
+
+/// Requests are sent in batches, at this interval.
+const TICK: Duration = Duration::from_millis(10);
+
+struct Options {
+    address: SocketAddr,
+    connections: usize,
+    rate: u32,
+    payload: usize,
+    duration: Duration,
+    method: String,
+    grace: Duration,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut address = None;
+    let mut connections = 1;
+    let mut rate = 1000;
+    let mut payload = 64;
+    let mut duration = 10;
+    let mut method = "echo".to_owned();
+    let mut grace = 5;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        if !arg.starts_with("--") {
+            let parsed = arg.parse()
+                .map_err(|e| format!("Invalid address {}: {}", arg, e))?;
+            address = Some(parsed);
+            continue;
+        }
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("Missing value for {}", arg)),
+        };
+        let invalid = |e| format!("Invalid value for {}: {}", arg, e);
+        match arg.as_str() {
+            "--connections" => connections = value.parse().map_err(&invalid)?,
+            "--rate" => rate = value.parse().map_err(&invalid)?,
+            "--payload" => payload = value.parse().map_err(&invalid)?,
+            "--duration" => duration = value.parse().map_err(&invalid)?,
+            "--method" => method = value,
+            "--grace" => grace = value.parse().map_err(&invalid)?,
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    let address = match address {
+        Some(address) => address,
+        None => return Err("Missing address".to_owned()),
+    };
+    if connections == 0 || rate == 0 {
+        return Err("The number of connections and the rate must be positive".to_owned());
+    }
+    Ok(Options {
+        address: address,
+        connections: connections,
+        rate: rate,
+        payload: payload,
+        duration: Duration::from_secs(duration),
+        method: method,
+        grace: Duration::from_secs(grace),
+    })
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: usize,
+    // Latencies of the requests that succeeded.
+    latencies: Vec<Duration>,
+    // Requests answered with an error.
+    errors: usize,
+    // Requests that got no response, because the connection was lost for instance.
+    failures: usize,
+}
+
+impl Stats {
+    fn completed(&self) -> usize {
+        self.latencies.len() + self.errors + self.failures
+    }
+
+    fn report(&mut self, elapsed: Duration) {
+        self.latencies.sort();
+        let unanswered = self.sent - self.completed();
+        println!(
+            "requests:   {} sent, {} succeeded, {} errors, {} failed, {} unanswered",
+            self.sent,
+            self.latencies.len(),
+            self.errors,
+            self.failures,
+            unanswered
+        );
+        println!(
+            "throughput: {:.1} requests/s",
+            self.latencies.len() as f64 / seconds(elapsed)
+        );
+        if self.latencies.is_empty() {
+            return;
+        }
+        println!(
+            "latency:    p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+            millis(self.percentile(50.0)),
+            millis(self.percentile(90.0)),
+            millis(self.percentile(99.0)),
+            millis(self.percentile(99.9)),
+            millis(self.latencies[self.latencies.len() - 1])
+        );
+    }
+
+    /// Return the given percentile of the latencies, which must be sorted.
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.saturating_sub(1)]
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", seconds(duration) * 1000.0)
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let connections = (0..options.connections)
+        .map(|_| ClientOnlyConnector::new(&options.address, &handle).connect())
+        .collect::<Vec<_>>();
+    let clients = match core.run(future::join_all(connections)) {
+        Ok(clients) => clients,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}", options.address, e);
+            process::exit(1);
+        }
+    };
+
+    let stats = Rc::new(RefCell::new(Stats::default()));
+    let payload = Bytes::from(vec![0; options.payload]);
+    let start = Instant::now();
+    let (rate, method, duration) = (options.rate, options.method.clone(), options.duration);
+    let mut next = 0;
+
+    let load = {
+        let stats = Rc::clone(&stats);
+        let handle = handle.clone();
+        Interval::new(TICK, &handle)
+            .unwrap()
+            .take_while(move |_| Ok(start.elapsed() < duration))
+            .for_each(move |_| {
+                // Send the requests that are due, so that the rate does not depend on the
+                // accuracy of the timer.
+                let due = (seconds(start.elapsed()) * f64::from(rate)) as usize;
+                while stats.borrow().sent < due {
+                    let client = &clients[next % clients.len()];
+                    next += 1;
+                    stats.borrow_mut().sent += 1;
+                    let sent_at = Instant::now();
+                    let stats = Rc::clone(&stats);
+                    let params = vec![Param::Binary(payload.clone())];
+                    let request = client.request_zero_copy(&method, params);
+                    handle.spawn(request.then(move |result| {
+                        let mut stats = stats.borrow_mut();
+                        match result {
+                            Ok(Ok(_)) => stats.latencies.push(sent_at.elapsed()),
+                            Ok(Err(_)) => stats.errors += 1,
+                            Err(_) => stats.failures += 1,
+                        }
+                        Ok(())
+                    }));
+                }
+                Ok(())
+            })
+    };
+    core.run(load).unwrap();
+
+    // Wait for the requests in flight, for at most the grace period.
+    let end = Instant::now() + options.grace;
+    let drain = {
+        let stats = Rc::clone(&stats);
+        Interval::new(TICK, &handle)
+            .unwrap()
+            .take_while(move |_| {
+                let stats = stats.borrow();
+                Ok(stats.completed() < stats.sent && Instant::now() < end)
+            })
+            .for_each(|_| Ok(()))
+    };
+    core.run(drain).unwrap();
+
+    stats.borrow_mut().report(start.elapsed());
+}
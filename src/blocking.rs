@@ -0,0 +1,168 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use futures::Future;
+use futures::sync::oneshot;
+use rmpv::Value;
+use tokio_core::reactor::{Core, Handle};
+
+use config::ClientConfig;
+use endpoint::Client;
+use errors::CallError;
+use net::{ClientOnlyConnector, Connection};
+use options::ProtocolOptions;
+
+/// A blocking `MessagePack-RPC` client, for programs that do not use futures, like command line
+/// tools and test harnesses.
+///
+/// `SyncClient` runs the connection on a reactor of its own, in a background thread, and its
+/// methods block the calling thread until the operation completes. The thread is stopped, and the
+/// connection closed, when the `SyncClient` is dropped.
+///
+/// ```rust,ignore
+/// let client = SyncClient::connect(&"127.0.0.1:54321".parse().unwrap())?;
+/// let sum = client.call("add", &[Value::from(1), Value::from(2)]);
+/// ```
+pub struct SyncClient {
+    client: Client,
+    // Dropping this sender stops the reactor thread.
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SyncClient {
+    /// Connect to the server at `address`, blocking until the connection is established.
+    pub fn connect(address: &SocketAddr) -> io::Result<Self> {
+        SyncClient::connect_with_options(address, ProtocolOptions::default())
+    }
+
+    /// Like [`connect`](#method.connect), with the given options for the connection.
+    pub fn connect_with_options(
+        address: &SocketAddr,
+        options: ProtocolOptions,
+    ) -> io::Result<Self> {
+        let address = *address;
+        SyncClient::start(move |handle| {
+            ClientOnlyConnector::new(&address, handle)
+                .set_protocol_options(options)
+                .connect()
+        })
+    }
+
+    /// Connect to the server described by the given configuration, blocking until the
+    /// connection is established.
+    pub fn from_config(config: &ClientConfig) -> io::Result<Self> {
+        let config = config.clone();
+        SyncClient::start(move |handle| ClientOnlyConnector::from_config(&config, handle).connect())
+    }
+
+    fn start<F>(connect: F) -> io::Result<Self>
+    where
+        F: FnOnce(&Handle) -> Connection + Send + 'static,
+    {
+        let (client_tx, client_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("rmp-rpc-client".to_owned())
+            .spawn(move || {
+                let mut core = match Core::new() {
+                    Ok(core) => core,
+                    Err(e) => {
+                        let _ = client_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let connection = connect(&core.handle());
+                let connected = core.run(connection);
+                let failed = connected.is_err();
+                let _ = client_tx.send(connected);
+                if !failed {
+                    // Run the connection until the `SyncClient` is dropped.
+                    let _ = core.run(stop_rx);
+                }
+            })?;
+
+        let client = match client_rx.recv() {
+            Ok(result) => result?,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "the reactor thread of the client panicked",
+                ))
+            }
+        };
+        Ok(SyncClient {
+            client: client,
+            stop: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Send a request and block until its response is received. If the connection is closed
+    /// before that, the error is the description of the
+    /// [`CallError`](enum.CallError.html), as a string.
+    pub fn call(&self, method: &str, params: &[Value]) -> Result<Value, Value> {
+        match self.client.request(method, params).wait() {
+            Ok(result) => result,
+            Err(e) => Err(Value::from(e.to_string())),
+        }
+    }
+
+    /// Send a notification and block until it is flushed to the connection.
+    pub fn notify(&self, method: &str, params: &[Value]) -> Result<(), CallError> {
+        match self.client.notify(method, params).wait() {
+            Ok(()) => Ok(()),
+            Err(()) => Err(self.client
+                .context()
+                .close_error()
+                .unwrap_or(CallError::ConnectionClosed)),
+        }
+    }
+
+    /// Return the underlying client. Since the connection runs in another thread, the futures
+    /// it returns can be waited for with `Future::wait`.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl Drop for SyncClient {
+    fn drop(&mut self) {
+        let _ = self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[test]
+fn sync_client() {
+    use futures::future;
+    use methods::MethodRouter;
+    use server::ServerBuilder;
+
+    let (address_tx, address_rx) = mpsc::channel();
+    let _ = thread::spawn(move || {
+        let mut router = MethodRouter::new();
+        let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+        let _ = router.notification("ignore", |_| Box::new(future::ok(())));
+        let mut core = Core::new().unwrap();
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .spawn(router, &core.handle())
+            .unwrap();
+        address_tx.send(server.local_addr().unwrap()).unwrap();
+        core.run(future::empty::<(), ()>()).unwrap();
+    });
+    let address = address_rx.recv().unwrap();
+
+    let client = SyncClient::connect(&address).unwrap();
+    assert_eq!(client.call("echo", &[Value::from(3)]), Ok(Value::from(3)));
+    assert!(client.call("missing", &[]).is_err());
+    assert_eq!(client.notify("ignore", &[]), Ok(()));
+    drop(client);
+
+    let closed = "127.0.0.1:1".parse().unwrap();
+    assert!(SyncClient::connect(&closed).is_err());
+}
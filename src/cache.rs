@@ -0,0 +1,424 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use rmpv::{encode, Value};
+
+use endpoint::{Client, Service, ServiceBuilder};
+use errors::RouterError;
+use message::Param;
+use priority::Priority;
+
+/// Default maximum number of responses kept in a cache.
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Hits and misses of a [`ResponseCache`](struct.ResponseCache.html).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheStats {
+    /// Number of requests answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of requests of cached methods that had to be handled by the service.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Proportion of the requests of cached methods answered from the cache, between 0 and 1.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// A cached response, and when it expires.
+struct Entry {
+    value: Value,
+    expires: Instant,
+}
+
+/// The method of a request, and the msgpack encoding of its parameters.
+type Key = (String, Vec<u8>);
+
+struct Inner {
+    ttls: HashMap<String, Duration>,
+    max_entries: usize,
+    entries: HashMap<Key, Entry>,
+    stats: HashMap<String, CacheStats>,
+}
+
+impl Inner {
+    fn lookup(&mut self, key: &Key) -> Option<Value> {
+        let hit = match self.entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                let _ = self.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        let stats = self.stats
+            .entry(key.0.clone())
+            .or_insert_with(CacheStats::default);
+        if hit.is_some() {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+        hit
+    }
+
+    fn insert(&mut self, key: Key, value: Value) {
+        let ttl = match self.ttls.get(&key.0) {
+            Some(ttl) => *ttl,
+            None => return,
+        };
+        if self.entries.len() >= self.max_entries {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires > now);
+            if self.entries.len() >= self.max_entries {
+                trace!("The response cache is full");
+                return;
+            }
+        }
+        let entry = Entry {
+            value: value,
+            expires: Instant::now() + ttl,
+        };
+        let _ = self.entries.insert(key, entry);
+    }
+}
+
+/// A cache of the successful responses of read-only methods, shared by all the services it wraps
+/// (see [`wrap`](#method.wrap)). Repeated requests with the same method and the same parameters
+/// are answered from the cache, without calling the service, until the response expires.
+///
+/// Only the methods given a time to live with [`ttl`](#method.ttl) are cached, so they should be
+/// deterministic and free of side effects. Error responses are never cached.
+///
+/// `ResponseCache` is cheap to clone: all the clones share the same responses. It is meant to be
+/// used on a single reactor thread.
+#[derive(Clone)]
+pub struct ResponseCache {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new()
+    }
+}
+
+impl ResponseCache {
+    /// Create a cache that caches no method.
+    pub fn new() -> Self {
+        ResponseCache {
+            inner: Rc::new(RefCell::new(Inner {
+                ttls: HashMap::new(),
+                max_entries: DEFAULT_MAX_ENTRIES,
+                entries: HashMap::new(),
+                stats: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Cache the responses to the given method for `ttl`.
+    pub fn ttl(&mut self, method: &str, ttl: Duration) -> &mut Self {
+        let _ = self.inner
+            .borrow_mut()
+            .ttls
+            .insert(method.to_owned(), ttl);
+        self
+    }
+
+    /// Set the maximum number of responses kept in the cache. When it is full, new responses are
+    /// only cached once some responses have expired. The default is 10000.
+    pub fn max_entries(&mut self, max_entries: usize) -> &mut Self {
+        self.inner.borrow_mut().max_entries = max_entries;
+        self
+    }
+
+    /// Remove all the cached responses.
+    pub fn clear(&self) {
+        self.inner.borrow_mut().entries.clear();
+    }
+
+    /// Return the hits and misses of all the methods.
+    pub fn stats(&self) -> CacheStats {
+        self.inner
+            .borrow()
+            .stats
+            .values()
+            .fold(CacheStats::default(), |total, stats| CacheStats {
+                hits: total.hits + stats.hits,
+                misses: total.misses + stats.misses,
+            })
+    }
+
+    /// Return the hits and misses of the given method.
+    pub fn method_stats(&self, method: &str) -> CacheStats {
+        self.inner
+            .borrow()
+            .stats
+            .get(method)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Wrap a [`Service`](trait.Service.html) or a [`ServiceBuilder`](trait.ServiceBuilder.html)
+    /// so that its responses are cached.
+    pub fn wrap<T>(&self, inner: T) -> Cached<T> {
+        Cached {
+            inner: inner,
+            cache: self.clone(),
+        }
+    }
+
+    fn key(&self, method: &str, params: &[Value]) -> Option<Key> {
+        if !self.inner.borrow().ttls.contains_key(method) {
+            return None;
+        }
+        let mut encoded = Vec::new();
+        for param in params {
+            // Writing into a `Vec` cannot fail.
+            let _ = encode::write_value(&mut encoded, param);
+        }
+        Some((method.to_owned(), encoded))
+    }
+
+    fn is_cached(&self, method: &str) -> bool {
+        self.inner.borrow().ttls.contains_key(method)
+    }
+}
+
+/// A service, or a service builder, whose responses are cached by a
+/// [`ResponseCache`](struct.ResponseCache.html).
+pub struct Cached<T> {
+    inner: T,
+    cache: ResponseCache,
+}
+
+impl<B: ServiceBuilder> ServiceBuilder for Cached<B> {
+    type Service = Cached<B::Service>;
+
+    fn build(&self, client: Client) -> Self::Service {
+        self.cache.wrap(self.inner.build(client))
+    }
+
+    fn registered_methods(&self) -> Vec<String> {
+        self.inner.registered_methods()
+    }
+
+    fn validate(&self) -> Result<(), RouterError> {
+        self.inner.validate()
+    }
+
+    fn protected_prefixes(&self) -> Vec<String> {
+        self.inner.protected_prefixes()
+    }
+}
+
+impl<S: Service> Cached<S> {
+    /// Answer a request from the cache if possible. Otherwise, handle it with `handle`.
+    fn respond<F>(
+        &mut self,
+        method: &str,
+        params: &[Value],
+        handle: F,
+    ) -> CachedResponse<S::RequestFuture>
+    where
+        F: FnOnce(&mut S) -> S::RequestFuture,
+    {
+        let key = match self.cache.key(method, params) {
+            Some(key) => key,
+            None => return CachedResponse(State::Uncached(handle(&mut self.inner))),
+        };
+        if let Some(value) = self.cache.inner.borrow_mut().lookup(&key) {
+            trace!("Answering {} from the cache", method);
+            return CachedResponse(State::Hit(Some(value)));
+        }
+        CachedResponse(State::Miss {
+            future: handle(&mut self.inner),
+            key: Some(key),
+            cache: self.cache.clone(),
+        })
+    }
+}
+
+impl<S: Service> Service for Cached<S> {
+    type Error = S::Error;
+    type T = Value;
+    type E = S::E;
+    type RequestFuture = CachedResponse<S::RequestFuture>;
+    type NotificationFuture = S::NotificationFuture;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
+        self.respond(method, params, |inner| inner.handle_request(method, params))
+    }
+
+    fn handle_request_zero_copy(&mut self, method: &str, params: Vec<Param>) -> Self::RequestFuture {
+        if self.cache.is_cached(method) {
+            let params = params
+                .into_iter()
+                .map(Param::into_value)
+                .collect::<Vec<Value>>();
+            self.handle_request(method, &params)
+        } else {
+            let future = self.inner.handle_request_zero_copy(method, params);
+            CachedResponse(State::Uncached(future))
+        }
+    }
+
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        deadline: Option<Instant>,
+    ) -> Self::RequestFuture {
+        if !self.cache.is_cached(method) {
+            let future = self.inner
+                .handle_request_with_deadline(method, params, deadline);
+            return CachedResponse(State::Uncached(future));
+        }
+        let params = params
+            .into_iter()
+            .map(Param::into_value)
+            .collect::<Vec<Value>>();
+        self.respond(method, &params, |inner| {
+            let params = params.iter().cloned().map(Param::Value).collect();
+            inner.handle_request_with_deadline(method, params, deadline)
+        })
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &[Value]) -> Self::NotificationFuture {
+        self.inner.handle_notification(method, params)
+    }
+
+    fn map_error(&mut self, error: Self::Error) -> Value {
+        self.inner.map_error(error)
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        self.inner.methods()
+    }
+
+    fn priority(&self, method: &str) -> Priority {
+        self.inner.priority(method)
+    }
+}
+
+/// The future returned by a [`Cached`](struct.Cached.html) service.
+pub struct CachedResponse<F>(State<F>);
+
+enum State<F> {
+    Hit(Option<Value>),
+    Miss {
+        future: F,
+        key: Option<Key>,
+        cache: ResponseCache,
+    },
+    Uncached(F),
+}
+
+impl<F, T, E> Future for CachedResponse<F>
+where
+    F: Future<Item = Result<T, E>>,
+    T: Into<Value>,
+{
+    type Item = Result<Value, E>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0 {
+            State::Hit(ref mut value) => Ok(Async::Ready(Ok(value
+                .take()
+                .expect("polled a cached response after completion")))),
+            State::Miss {
+                ref mut future,
+                ref mut key,
+                ref cache,
+            } => match future.poll()? {
+                Async::Ready(Ok(value)) => {
+                    let value = value.into();
+                    if let Some(key) = key.take() {
+                        cache.inner.borrow_mut().insert(key, value.clone());
+                    }
+                    Ok(Async::Ready(Ok(value)))
+                }
+                Async::Ready(Err(e)) => Ok(Async::Ready(Err(e))),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+            State::Uncached(ref mut future) => match future.poll()? {
+                Async::Ready(result) => Ok(Async::Ready(result.map(Into::into))),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+        }
+    }
+}
+
+#[test]
+fn response_cache() {
+    use std::cell::Cell;
+    use std::io;
+    use futures::future::{self, FutureResult};
+    use mock::TestClient;
+
+    struct Expensive(Rc<Cell<u32>>);
+
+    impl Service for Expensive {
+        type Error = io::Error;
+        type T = u32;
+        type E = String;
+        type RequestFuture = FutureResult<Result<u32, String>, io::Error>;
+        type NotificationFuture = FutureResult<(), io::Error>;
+
+        fn handle_request(&mut self, method: &str, _params: &[Value]) -> Self::RequestFuture {
+            self.0.set(self.0.get() + 1);
+            match method {
+                "fail" => future::ok(Err("failed".to_owned())),
+                _ => future::ok(Ok(self.0.get())),
+            }
+        }
+
+        fn handle_notification(
+            &mut self,
+            _method: &str,
+            _params: &[Value],
+        ) -> Self::NotificationFuture {
+            future::ok(())
+        }
+    }
+
+    let calls = Rc::new(Cell::new(0));
+    let mut cache = ResponseCache::new();
+    let _ = cache
+        .ttl("get", Duration::from_secs(60))
+        .ttl("fail", Duration::from_secs(60));
+    let mut client = TestClient::new(cache.wrap(Expensive(Rc::clone(&calls))));
+
+    assert_eq!(client.request("get", &[Value::from(1)]), Ok(Value::from(1)));
+    assert_eq!(client.request("get", &[Value::from(1)]), Ok(Value::from(1)));
+    assert_eq!(client.request("get", &[Value::from(2)]), Ok(Value::from(2)));
+    assert_eq!(client.request("other", &[]), Ok(Value::from(3)));
+    assert_eq!(client.request("other", &[]), Ok(Value::from(4)));
+    assert!(client.request("fail", &[]).is_err());
+    assert!(client.request("fail", &[]).is_err());
+    assert_eq!(calls.get(), 6);
+
+    assert_eq!(cache.method_stats("get").hits(), 1);
+    assert_eq!(cache.method_stats("get").misses(), 2);
+    assert_eq!(cache.stats().misses(), 4);
+    assert_eq!(cache.method_stats("other"), CacheStats::default());
+}
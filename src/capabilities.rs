@@ -0,0 +1,138 @@
+use rmpv::Value;
+
+/// Method of the request that asks an endpoint for its [`Capabilities`](struct.Capabilities.html).
+pub const CAPABILITIES_METHOD: &str = "$/capabilities";
+
+/// What a remote endpoint supports, returned by
+/// [`Client::capabilities`](struct.Client.html#method.capabilities).
+///
+/// It is requested with a `$/capabilities` request, answered by the endpoint itself with a map
+/// of the features it advertises in its [`Hello`](struct.Hello.html), and of the methods its
+/// service handles (see [`Service::methods`](trait.Service.html#method.methods)). The methods are
+/// unknown until the connection is authenticated, if the server
+/// [authenticates](struct.ProtocolOptions.html#method.authenticator) its connections. Peers that
+/// do not know this request answer it with an error: their capabilities are then the features of
+/// their hello, if they sent one, and their methods are unknown.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    features: Vec<String>,
+    methods: Option<Vec<String>>,
+}
+
+impl Capabilities {
+    pub(crate) fn new(features: Vec<String>, methods: Option<Vec<String>>) -> Self {
+        Capabilities {
+            features: features,
+            methods: methods,
+        }
+    }
+
+    /// Return the features the endpoint supports.
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// Return `true` if the endpoint supports the given feature.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Return the methods the endpoint handles requests for, if it told them.
+    pub fn methods(&self) -> Option<&[String]> {
+        self.methods.as_ref().map(|methods| &methods[..])
+    }
+
+    /// Return `true` if the endpoint handles requests for the given method. This is `false` if
+    /// its methods are unknown.
+    pub fn has_method(&self, method: &str) -> bool {
+        match self.methods {
+            Some(ref methods) => methods.iter().any(|m| m == method),
+            None => false,
+        }
+    }
+
+    pub(crate) fn to_value(&self) -> Value {
+        let strings = |strings: &[String]| {
+            Value::Array(strings.iter().map(|s| Value::from(s.as_str())).collect())
+        };
+        let methods = match self.methods {
+            Some(ref methods) => strings(methods),
+            None => Value::Nil,
+        };
+        Value::Map(vec![
+            (Value::from("features"), strings(&self.features)),
+            (Value::from("methods"), methods),
+        ])
+    }
+
+    /// Decode the result of a `$/capabilities` request. Return `None` if it is invalid.
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        let strings = |value: &Value| match value.as_array() {
+            Some(strings) => strings
+                .iter()
+                .map(|s| s.as_str().map(|s| s.to_owned()))
+                .collect::<Option<Vec<String>>>(),
+            None => None,
+        };
+        let map = match value.as_map() {
+            Some(map) => map,
+            None => return None,
+        };
+        let mut features = Some(Vec::new());
+        let mut methods = Some(None);
+        for &(ref key, ref value) in map {
+            match key.as_str() {
+                Some("features") => features = strings(value),
+                Some("methods") if !value.is_nil() => methods = strings(value).map(Some),
+                _ => {}
+            }
+        }
+        match (features, methods) {
+            (Some(features), Some(methods)) => Some(Capabilities::new(features, methods)),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn capabilities_round_trip() {
+    let capabilities = Capabilities::new(vec!["lz4".into()], Some(vec!["add".into()]));
+    assert_eq!(Capabilities::from_value(&capabilities.to_value()), Some(capabilities.clone()));
+    assert!(capabilities.has_feature("lz4"));
+    assert!(capabilities.has_method("add"));
+    assert!(!capabilities.has_method("sub"));
+
+    let capabilities = Capabilities::new(Vec::new(), None);
+    assert_eq!(Capabilities::from_value(&capabilities.to_value()), Some(capabilities));
+    assert_eq!(Capabilities::from_value(&Value::from("invalid")), None);
+}
+
+#[test]
+fn client_capabilities() {
+    use futures::{future, Async, Future};
+    use hello::Hello;
+    use methods::MethodRouter;
+    use mock::TestClient;
+    use options::ProtocolOptions;
+    use rpc_error::RpcError;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("add", |_| Box::new(future::ok(Ok(Value::Nil))));
+    let mut hello = Hello::default();
+    let _ = hello.add_feature("streaming");
+    let mut options = ProtocolOptions::new();
+    let _ = options.hello(Some(hello));
+    let mut client = TestClient::with_options(router, options);
+
+    let capabilities = client.client().capabilities();
+    let capabilities = client.run(capabilities).unwrap();
+    assert!(capabilities.has_feature("streaming"));
+    assert_eq!(capabilities.methods(), Some(&["add".to_owned()][..]));
+    // The second time, the cached capabilities are returned without sending a request.
+    let cached = client.client().capabilities().poll();
+    assert_eq!(cached.unwrap(), Async::Ready(capabilities));
+
+    // Builtin methods the endpoint does not know are not passed to the service.
+    let error = client.request("$/cancel", &[Value::from(1)]).unwrap_err();
+    assert_eq!(RpcError::from_value(&error).unwrap().code, RpcError::UNSUPPORTED_FEATURE);
+}
@@ -0,0 +1,394 @@
+//! Logical connections multiplexed over one connection.
+//!
+//! The bytes of a channel are carried by `$/channel` notifications whose parameters are the name
+//! of the channel and:
+//!
+//! - a binary value, for the bytes written to the channel;
+//! - an integer, to allow the remote endpoint to send that many more bytes;
+//! - `nil`, once the channel is closed.
+//!
+//! Each channel runs a complete endpoint over these bytes, so it has its own request ids, options
+//! and limits. A channel may have at most `CHANNEL_WINDOW` bytes that the remote endpoint has not
+//! read yet, so that a channel whose reader is slow does not hold up the others.
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use rmpv::Value;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use context::Context;
+use endpoint::{Client, Endpoint, Service, ServiceBuilder};
+use message::Notification;
+use options::ProtocolOptions;
+
+/// Method of the notifications that carry the channels.
+pub const CHANNEL_METHOD: &str = "$/channel";
+
+/// Number of bytes that can be sent on a channel before the remote endpoint reads them.
+const CHANNEL_WINDOW: usize = 64 * 1024;
+
+struct ChannelState {
+    // Whether a `Channel` uses this state. Bytes received for a channel that is not open yet are
+    // kept until it is.
+    open: bool,
+    incoming: VecDeque<u8>,
+    outgoing: Vec<u8>,
+    // Bytes that can still be written before the remote endpoint grants more.
+    credit: usize,
+    // Bytes read since the last grant.
+    consumed: usize,
+    // Set when the remote endpoint closed the channel, or when the connection is closed.
+    closed: bool,
+    // Set when the channel is closed locally, until the remote endpoint is told.
+    shutdown: bool,
+    reader: Option<Task>,
+    writer: Option<Task>,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState {
+            open: false,
+            incoming: VecDeque::new(),
+            outgoing: Vec::new(),
+            credit: CHANNEL_WINDOW,
+            consumed: 0,
+            closed: false,
+            shutdown: false,
+            reader: None,
+            writer: None,
+        }
+    }
+}
+
+impl ChannelState {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+    }
+}
+
+/// The channels of a connection, shared by its endpoint and the channels.
+#[derive(Default)]
+pub(crate) struct Channels {
+    channels: HashMap<String, Arc<Mutex<ChannelState>>>,
+    // The endpoint of the connection, to notify when a channel has bytes to send.
+    endpoint: Option<Task>,
+    // Set once the connection is closed.
+    closed: bool,
+}
+
+impl Channels {
+    fn notify_endpoint(&mut self) {
+        if let Some(task) = self.endpoint.take() {
+            task.notify();
+        }
+    }
+
+    /// Process a `$/channel` notification.
+    pub(crate) fn receive(&mut self, params: &[Value]) {
+        let name = match params.get(0).and_then(Value::as_str) {
+            Some(name) => name,
+            None => {
+                warn!("Invalid channel message: {:?}", params);
+                return;
+            }
+        };
+        let state = Arc::clone(self.channels
+            .entry(name.to_owned())
+            .or_insert_with(Arc::default));
+        let mut state = state.lock().unwrap();
+        match params.get(1) {
+            Some(&Value::Binary(ref bytes)) => {
+                if state.incoming.len() + bytes.len() > CHANNEL_WINDOW {
+                    warn!("The remote endpoint overflowed channel {}", name);
+                }
+                state.incoming.extend(bytes);
+                if let Some(task) = state.reader.take() {
+                    task.notify();
+                }
+            }
+            Some(&Value::Nil) => state.close(),
+            Some(credit) => match credit.as_u64() {
+                Some(credit) => {
+                    state.credit = cmp::min(state.credit + credit as usize, CHANNEL_WINDOW);
+                    if let Some(task) = state.writer.take() {
+                        task.notify();
+                    }
+                }
+                None => warn!("Invalid channel message: {:?}", params),
+            },
+            None => warn!("Invalid channel message: {:?}", params),
+        }
+    }
+
+    /// Return the messages the channels have to send. The current task is notified when there
+    /// are more.
+    pub(crate) fn poll_messages(&mut self) -> Vec<Notification> {
+        self.endpoint = Some(task::current());
+        let mut messages = Vec::new();
+        let mut finished = Vec::new();
+        for (name, state) in &self.channels {
+            let mut state = state.lock().unwrap();
+            if !state.outgoing.is_empty() {
+                let bytes = state.outgoing.drain(..).collect::<Vec<u8>>();
+                messages.push(channel_message(name, Value::Binary(bytes)));
+            }
+            if state.consumed >= CHANNEL_WINDOW / 2 {
+                messages.push(channel_message(name, Value::from(state.consumed)));
+                state.consumed = 0;
+            }
+            if state.shutdown {
+                messages.push(channel_message(name, Value::Nil));
+                state.shutdown = false;
+            }
+            if !state.open && state.closed {
+                finished.push(name.clone());
+            }
+        }
+        for name in finished {
+            let _ = self.channels.remove(&name);
+        }
+        messages
+    }
+
+    /// Close all the channels, when the connection is closed.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        for state in self.channels.values() {
+            state.lock().unwrap().close();
+        }
+    }
+}
+
+fn channel_message(name: &str, payload: Value) -> Notification {
+    Notification {
+        method: CHANNEL_METHOD.to_owned(),
+        params: vec![Value::from(name), payload],
+    }
+}
+
+/// The bytes of a channel, that its endpoint reads and writes.
+pub(crate) struct ChannelStream {
+    state: Arc<Mutex<ChannelState>>,
+    channels: Arc<Mutex<Channels>>,
+}
+
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.incoming.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+            state.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = cmp::min(buf.len(), state.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(state.incoming.drain(..n)) {
+            *dst = src;
+        }
+        state.consumed += n;
+        let grant = state.consumed >= CHANNEL_WINDOW / 2;
+        drop(state);
+        if grant {
+            self.channels.lock().unwrap().notify_endpoint();
+        }
+        Ok(n)
+    }
+}
+
+impl AsyncRead for ChannelStream {}
+
+impl Write for ChannelStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if state.credit == 0 {
+            state.writer = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = cmp::min(buf.len(), state.credit);
+        state.outgoing.extend_from_slice(&buf[..n]);
+        state.credit -= n;
+        drop(state);
+        self.channels.lock().unwrap().notify_endpoint();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.shutdown = true;
+        }
+        state.open = false;
+        drop(state);
+        self.channels.lock().unwrap().notify_endpoint();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for ChannelStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// A logical connection carried by another connection, with its own endpoint: its requests have
+/// their own ids, and its options and limits only apply to it. Both endpoints of the connection
+/// open the channel with the same name.
+///
+/// `Channel` is a future that must be polled for the messages of the channel to be sent and
+/// handled, typically by spawning it on the reactor. It completes when the channel or the
+/// connection is closed. The endpoint of a channel does not run timers: request timeouts and
+/// keepalives do not apply to it.
+///
+/// ```rust,ignore
+/// let channel = Channel::open(&client.context(), "logs", &NoService, ProtocolOptions::new())?;
+/// let logs = channel.client();
+/// handle.spawn(channel.map_err(|_| ()));
+/// ```
+pub struct Channel<S: Service> {
+    endpoint: Endpoint<S, ChannelStream>,
+    client: Client,
+}
+
+impl<S: Service> Channel<S> {
+    /// Open the channel `name` on the connection of `context`. The requests received on the
+    /// channel are handled by a service built by `service_builder`.
+    ///
+    /// Return an error if the channel is already open, or if the connection is closed.
+    pub fn open<B>(
+        context: &Context,
+        name: &str,
+        service_builder: &B,
+        options: ProtocolOptions,
+    ) -> io::Result<Self>
+    where
+        B: ServiceBuilder<Service = S>,
+    {
+        let channels = context.channels();
+        let state = {
+            let mut channels_guard = channels.lock().unwrap();
+            if channels_guard.closed {
+                return Err(io::ErrorKind::NotConnected.into());
+            }
+            let state = Arc::clone(channels_guard
+                .channels
+                .entry(name.to_owned())
+                .or_insert_with(Arc::default));
+            {
+                let mut state = state.lock().unwrap();
+                if state.open {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("channel {} is already open", name),
+                    ));
+                }
+                state.open = true;
+            }
+            state
+        };
+        let stream = ChannelStream {
+            state: state,
+            channels: Arc::clone(channels),
+        };
+        let mut endpoint = Endpoint::new(stream, options);
+        let client = endpoint.set_client();
+        let service = service_builder.build(client.clone());
+        endpoint.set_server(service);
+        Ok(Channel {
+            endpoint: endpoint,
+            client: client,
+        })
+    }
+
+    /// Return a client that sends requests and notifications on the channel.
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+}
+
+impl<S: Service> Future for Channel<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.endpoint.poll()
+    }
+}
+
+#[test]
+fn channels() {
+    use futures::future;
+    use methods::MethodRouter;
+    use mock::duplex;
+    use net::NoService;
+
+    let router = |name: &'static str| {
+        let mut router = MethodRouter::new();
+        let _ = router.request("name", move |_| Box::new(future::ok(Ok(Value::from(name)))));
+        let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+        router
+    };
+    let (server_stream, client_stream) = duplex();
+    let mut server = Endpoint::new(server_stream, ProtocolOptions::default());
+    let server_context = server.set_client().context();
+    server.set_server(router("main"));
+    let mut endpoint = Endpoint::<NoService, _>::new(client_stream, ProtocolOptions::default());
+    let client = endpoint.set_client();
+    let context = client.context();
+
+    let options = ProtocolOptions::default();
+    let mut server_a = Channel::open(&server_context, "a", &router("a"), options.clone()).unwrap();
+    let mut server_b = Channel::open(&server_context, "b", &router("b"), options.clone()).unwrap();
+    let mut a = Channel::open(&context, "a", &NoService, options.clone()).unwrap();
+    let mut b = Channel::open(&context, "b", &NoService, options.clone()).unwrap();
+    assert!(Channel::open(&context, "a", &NoService, options.clone()).is_err());
+
+    // More than a window, so that the channel waits for the remote endpoint to read.
+    let large = Value::Binary(vec![7; 3 * CHANNEL_WINDOW]);
+    let requests = future::join_all(vec![
+        client.request("name", &[]),
+        a.client().request("name", &[]),
+        b.client().request("name", &[]),
+        b.client().request("echo", &[large.clone()]),
+    ]);
+    let mut requests = requests;
+    let responses = future::poll_fn(|| {
+        let _ = endpoint.poll();
+        let _ = a.poll();
+        let _ = b.poll();
+        let _ = server.poll();
+        let _ = server_a.poll();
+        let _ = server_b.poll();
+        let _ = endpoint.poll();
+        match requests.poll() {
+            Ok(Async::NotReady) => {
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+            result => result,
+        }
+    }).wait()
+        .unwrap();
+    let expected = vec![Ok("main".into()), Ok("a".into()), Ok("b".into()), Ok(large)];
+    assert_eq!(responses, expected);
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// The time of a manual clock, and the timers waiting for it.
+pub(crate) struct ManualTime {
+    now: Instant,
+    next_id: usize,
+    // The deadline of the timers that have been polled, and the task to notify once it is
+    // reached.
+    timers: HashMap<usize, (Instant, Task)>,
+}
+
+impl ManualTime {
+    /// Remove the timers that expired, and return the tasks to notify.
+    fn expire(&mut self) -> Vec<Task> {
+        let now = self.now;
+        let expired = self.timers
+            .iter()
+            .filter(|&(_, &(deadline, _))| deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<usize>>();
+        expired
+            .into_iter()
+            .filter_map(|id| self.timers.remove(&id))
+            .map(|(_, task)| task)
+            .collect()
+    }
+}
+
+/// The clock the endpoints read the time from, to time out requests and idle connections, to send
+/// pings, and to give up on the requests in flight when a connection is closed (see
+/// [`ProtocolOptions::clock`](struct.ProtocolOptions.html#method.clock)).
+///
+/// By default, this is the system clock, whose timers run on the reactor of the endpoint. A
+/// [`manual`](#method.manual) clock only moves when it is advanced, and its timers do not need a
+/// reactor, so that tests can reproduce timeouts exactly, without waiting for them (see
+/// [`mock::test_runtime`](mock/fn.test_runtime.html)). The deadlines given to
+/// [`Service::handle_request_with_deadline`](trait.Service.html#method.handle_request_with_deadline)
+/// are read from the clock as well.
+#[derive(Clone, Default)]
+pub struct Clock(Option<Arc<Mutex<ManualTime>>>);
+
+impl Clock {
+    /// Return the system clock.
+    pub fn system() -> Self {
+        Clock(None)
+    }
+
+    /// Return a clock that starts at the current time, and only moves when it is
+    /// [advanced](#method.advance).
+    pub fn manual() -> Self {
+        Clock(Some(Arc::new(Mutex::new(ManualTime {
+            now: Instant::now(),
+            next_id: 0,
+            timers: HashMap::new(),
+        }))))
+    }
+
+    /// Return `true` if this is a manual clock.
+    pub fn is_manual(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Return the current time.
+    pub fn now(&self) -> Instant {
+        match self.0 {
+            Some(ref time) => time.lock().unwrap().now,
+            None => Instant::now(),
+        }
+    }
+
+    /// Move a manual clock forward by `duration`, and wake up the endpoints whose timers expire.
+    /// The system clock cannot be advanced, so this does nothing on it.
+    pub fn advance(&self, duration: Duration) {
+        let expired = match self.0 {
+            Some(ref time) => {
+                let mut time = time.lock().unwrap();
+                time.now += duration;
+                time.expire()
+            }
+            None => return,
+        };
+        for task in expired {
+            task.notify();
+        }
+    }
+
+    /// Move a manual clock forward to the earliest deadline of its timers. Return `false` if no
+    /// timer is waiting.
+    pub(crate) fn advance_to_next_timer(&self) -> bool {
+        let next = match self.0 {
+            Some(ref time) => {
+                let time = time.lock().unwrap();
+                time.timers.values().map(|&(deadline, _)| deadline).min()
+            }
+            None => None,
+        };
+        match next {
+            Some(deadline) => {
+                let now = self.now();
+                if deadline > now {
+                    self.advance(deadline - now);
+                } else {
+                    self.advance(Duration::from_secs(0));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Return a timer that fires at `deadline`. The timers of the system clock run on `reactor`,
+    /// so `None` is returned if there is no reactor.
+    pub(crate) fn timer_at(
+        &self,
+        deadline: Instant,
+        reactor: Option<&Handle>,
+    ) -> io::Result<Option<Timer>> {
+        match (&self.0, reactor) {
+            (&Some(ref time), _) => {
+                let id = {
+                    let mut time = time.lock().unwrap();
+                    time.next_id = time.next_id.wrapping_add(1);
+                    time.next_id
+                };
+                Ok(Some(Timer::Manual {
+                    time: Arc::clone(time),
+                    id: id,
+                    deadline: deadline,
+                }))
+            }
+            (&None, Some(reactor)) => Ok(Some(Timer::System(Timeout::new_at(deadline, reactor)?))),
+            (&None, None) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(ref time) => write!(f, "Clock::Manual({:?})", time.lock().unwrap().now),
+            None => write!(f, "Clock::System"),
+        }
+    }
+}
+
+/// A timer of a [`Clock`](struct.Clock.html).
+pub(crate) enum Timer {
+    System(Timeout),
+    Manual {
+        time: Arc<Mutex<ManualTime>>,
+        id: usize,
+        deadline: Instant,
+    },
+}
+
+impl Timer {
+    /// Fire at `deadline` instead.
+    pub(crate) fn reset(&mut self, deadline: Instant) {
+        match *self {
+            Timer::System(ref mut timeout) => timeout.reset(deadline),
+            Timer::Manual {
+                deadline: ref mut current,
+                ..
+            } => *current = deadline,
+        }
+    }
+}
+
+impl Future for Timer {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Timer::System(ref mut timeout) => timeout.poll(),
+            Timer::Manual {
+                ref time,
+                id,
+                deadline,
+            } => {
+                let mut time = time.lock().unwrap();
+                if time.now >= deadline {
+                    let _ = time.timers.remove(&id);
+                    return Ok(Async::Ready(()));
+                }
+                let _ = time.timers.insert(id, (deadline, task::current()));
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Timer::Manual { ref time, id, .. } = *self {
+            let _ = time.lock().unwrap().timers.remove(&id);
+        }
+    }
+}
+
+#[test]
+fn manual_clock() {
+    use futures::future;
+
+    let clock = Clock::manual();
+    let start = clock.now();
+    let mut timer = clock
+        .timer_at(start + Duration::from_secs(10), None)
+        .unwrap()
+        .unwrap();
+    let mut poll_timer = || {
+        let poll = future::poll_fn(|| Ok::<_, ()>(Async::Ready(timer.poll().unwrap())));
+        poll.wait().unwrap()
+    };
+    assert!(!clock.advance_to_next_timer());
+    assert_eq!(poll_timer(), Async::NotReady);
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(poll_timer(), Async::NotReady);
+    assert!(clock.advance_to_next_timer());
+    assert_eq!(clock.now() - start, Duration::from_secs(10));
+    assert_eq!(poll_timer(), Async::Ready(()));
+    assert!(!clock.advance_to_next_timer());
+}
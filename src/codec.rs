@@ -1,58 +1,281 @@
-use std::io;
+use std::fmt;
+use std::io::{self, Write};
 use bytes::BytesMut;
+use rmp::decode as rmp_decode;
+use rmpv::Value;
 use tokio_io::codec::{Decoder, Encoder};
+#[cfg(feature = "compression")]
+use compression;
 use errors::DecodeError;
-use message::Message;
+use message::{read_value, Budget, DecodeLimits, EmptyParams, Message, MessageWriter, Param};
+use options::{ProtocolOptions, ProtocolPolicy};
+use scan::Scan;
 
-pub struct Codec;
+#[derive(Default)]
+pub struct Codec {
+    // Binary parameters of requests that are at least this large are not copied out of the
+    // receive buffer.
+    zero_copy_binary: Option<usize>,
+    // Accept some common deviations from the specification (see `Message::decode_with`).
+    lenient: bool,
+    // Mirror the quirks of the reference implementations (see
+    // `ProtocolOptions::legacy_compatibility`).
+    legacy: bool,
+    // Accept, and send, the parameters of requests and notifications as maps of named arguments
+    // (see `ProtocolOptions::named_params`).
+    named: bool,
+    // How the requests and notifications without parameters are encoded.
+    empty_params: EmptyParams,
+    // Messages larger than this are rejected before being decoded.
+    max_message_size: Option<usize>,
+    // Messages nested too deeply, or with too many values, are rejected while being decoded.
+    limits: DecodeLimits,
+    // What happens to the messages that cannot be decoded.
+    invalid_messages: ProtocolPolicy,
+    // Scan of the message at the start of the receive buffer.
+    scan: Scan,
+    // Messages at least this large are compressed, once the remote endpoint accepts them.
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+    // Whether the remote endpoint accepts compressed messages.
+    #[cfg(feature = "compression")]
+    compress: bool,
+}
+
+impl Codec {
+    pub fn new(options: &ProtocolOptions) -> Self {
+        Codec {
+            zero_copy_binary: options.get_zero_copy_binary(),
+            lenient: options.has_lenient_decoding() || options.has_legacy_compatibility(),
+            legacy: options.has_legacy_compatibility(),
+            named: options.has_named_params(),
+            empty_params: options.get_empty_params(),
+            max_message_size: options.get_max_message_size(),
+            limits: DecodeLimits {
+                max_depth: options.get_max_nesting_depth(),
+                max_elements: options.get_max_elements(),
+            },
+            invalid_messages: options.get_invalid_messages(),
+            scan: Scan::default(),
+            #[cfg(feature = "compression")]
+            compression: options.get_compression(),
+            #[cfg(feature = "compression")]
+            compress: false,
+        }
+    }
+
+    /// Encode `message` the way the remote endpoint expects it.
+    pub fn encode_message<W>(&self, message: &Message, wr: &mut W) -> io::Result<()>
+    where
+        W: MessageWriter,
+    {
+        message.encode_with(wr, self.empty_params, self.named)
+    }
+
+    /// Return the size of the message at the start of the receive buffer, as far as it is known
+    /// from the bytes received so far. It is a lower bound, and 1 when nothing is known.
+    pub fn pending_size(&self) -> usize {
+        self.scan.pos.saturating_add(self.scan.pending)
+    }
+
+    /// Start compressing the outgoing messages, if compression is enabled.
+    #[cfg(feature = "compression")]
+    pub fn enable_compression(&mut self) {
+        self.compress = self.compression.is_some();
+    }
+
+    /// Return the size above which outgoing messages are compressed, once the remote endpoint
+    /// accepts compressed messages.
+    #[cfg(feature = "compression")]
+    pub fn compression_threshold(&self) -> Option<usize> {
+        if self.compress {
+            self.compression
+        } else {
+            None
+        }
+    }
+}
+
+/// Position of a binary parameter that has been left in the receive buffer: index of the
+/// parameter, and start and end of its content.
+type BinaryRange = (usize, usize, usize);
+
+fn read_param<T: AsRef<[u8]>>(
+    rd: &mut io::Cursor<T>,
+    index: usize,
+    budget: &mut Budget,
+    threshold: Option<usize>,
+    ranges: &mut Vec<BinaryRange>,
+) -> Result<Param, DecodeError> {
+    if let Some(threshold) = threshold {
+        let start = rd.position();
+        if let Ok(len) = rmp_decode::read_bin_len(rd) {
+            let len = len as usize;
+            if len >= threshold {
+                let data_start = rd.position() as usize;
+                if data_start + len > rd.get_ref().as_ref().len() {
+                    return Err(DecodeError::Truncated);
+                }
+                rd.set_position((data_start + len) as u64);
+                ranges.push((index, data_start, data_start + len));
+                // This is replaced by the actual parameter once the message has been decoded.
+                return Ok(Param::Value(Value::Nil));
+            }
+        }
+        rd.set_position(start);
+    }
+    Ok(Param::Value(read_value(rd, budget)?))
+}
 
 impl Decoder for Codec {
     type Item = Message;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
-        let res: Result<Option<Self::Item>, Self::Error>;
-        let position = {
+        let threshold = self.zero_copy_binary;
+        let lenient = self.lenient;
+        let legacy = self.legacy;
+        let named = self.named;
+        #[cfg(feature = "compression")]
+        let empty_params = self.empty_params;
+        let limits = self.limits;
+        let invalid_messages = self.invalid_messages;
+        let max = self.max_message_size.unwrap_or_else(usize::max_value);
+        #[cfg(feature = "compression")]
+        let decompress = self.compression.is_some();
+        let scan = &mut self.scan;
+        let mut ranges = Vec::new();
+        let (position, mut res) = {
             let mut buf = io::Cursor::new(&src);
             loop {
-                match Message::decode(&mut buf) {
-                    Ok(message) => {
-                        res = Ok(Some(message));
-                        break;
+                let start = buf.position() as usize;
+                // Only decode the message once it has been completely received, so that it is
+                // decoded once. A message that claims to be huge is rejected before anything is
+                // allocated for it.
+                let size = match scan.advance(&src[start..], max)? {
+                    Some(size) => size,
+                    // The invalid messages skipped so far are dropped, so that the scan resumes
+                    // at the start of the buffer.
+                    None => break (start, Ok(None)),
+                };
+                *scan = Scan::default();
+                ranges.clear();
+                #[cfg(feature = "compression")]
+                {
+                    let frame = &src[start..start + size];
+                    if let (true, Some(data)) = (decompress, compression::compressed_data(frame)) {
+                        // The decompressed message is decoded on its own, and its binary
+                        // parameters are slices of the decompressed buffer.
+                        let mut inner = Codec {
+                            zero_copy_binary: threshold,
+                            lenient: lenient,
+                            legacy: legacy,
+                            named: named,
+                            empty_params: empty_params,
+                            max_message_size: Some(max),
+                            limits: limits,
+                            invalid_messages: invalid_messages,
+                            ..Codec::default()
+                        };
+                        let message = match compression::decompress(data, max)? {
+                            Some(mut message) => inner.decode(&mut message)?,
+                            None => None,
+                        };
+                        match message {
+                            Some(message) => break (start + size, Ok(Some(message))),
+                            None => {
+                                skip_invalid(invalid_messages, &"invalid compressed message")?;
+                                buf.set_position((start + size) as u64);
+                                continue;
+                            }
+                        }
                     }
-                    Err(err) => match err {
-                        DecodeError::Truncated => return Ok(None),
-                        DecodeError::Invalid => continue,
-                        DecodeError::UnknownIo(io_err) => {
-                            res = Err(io_err);
-                            break;
+                }
+                let decoded = Message::decode_with(
+                    &mut buf,
+                    lenient,
+                    legacy,
+                    named,
+                    limits,
+                    &mut |rd, index, budget| read_param(rd, index, budget, threshold, &mut ranges),
+                );
+                match decoded {
+                    Ok(message) => break (buf.position() as usize, Ok(Some(message))),
+                    Err(DecodeError::Truncated) => break (start, Ok(None)),
+                    Err(DecodeError::UnknownIo(io_err)) => {
+                        break (buf.position() as usize, Err(io_err))
+                    }
+                    Err(e) => {
+                        if let Err(e) = skip_invalid(invalid_messages, &e) {
+                            break (start, Err(e));
                         }
-                    },
+                        // The message is decoded as it is read, so we may have stopped in the
+                        // middle of the invalid value. Skip it entirely.
+                        buf.set_position((start + size) as u64);
+                    }
                 }
             }
-            buf.position() as usize
         };
-        let _ = src.split_to(position);
+        let frame = src.split_to(position);
+        if ranges.is_empty() {
+            return res;
+        }
+        // Binary parameters become slices of the frame, which is not copied.
+        let frame = frame.freeze();
+        if let Ok(Some(Message::Request(ref mut request))) = res {
+            for (index, start, end) in ranges.drain(..) {
+                request.params[index] = Param::Binary(frame.slice(start, end));
+            }
+        }
         res
     }
 }
 
+/// Apply the policy to an invalid message: return an error if the connection must be closed.
+fn skip_invalid(policy: ProtocolPolicy, error: &fmt::Display) -> io::Result<()> {
+    match policy {
+        ProtocolPolicy::Ignore => Ok(()),
+        ProtocolPolicy::LogAndIgnore => {
+            warn!("Skipping invalid message: {}", error);
+            Ok(())
+        }
+        ProtocolPolicy::CloseConnection => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid message: {}", error),
+        )),
+    }
+}
+
 impl Encoder for Codec {
     type Item = Message;
     type Error = io::Error;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        let bytes = msg.pack()?;
-        buf.extend_from_slice(&bytes);
+        self.encode_message(&msg, &mut BytesWriter(buf))
+    }
+}
+
+/// Writes directly at the end of a `BytesMut`, growing it as needed.
+struct BytesWriter<'a>(&'a mut BytesMut);
+
+impl<'a> Write for BytesWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
 }
 
+impl<'a> MessageWriter for BytesWriter<'a> {}
+
 #[test]
 fn decode() {
-    use message::{Message, Request};
+    use message::{Id, Message, Request};
     fn try_decode(input: &[u8], rest: &[u8]) -> io::Result<Option<Message>> {
-        let mut codec = Codec {};
+        let mut codec = Codec::default();
         let mut buf = BytesMut::from(input);
         let result = codec.decode(&mut buf);
         assert_eq!(rest, &buf);
@@ -60,7 +283,7 @@ fn decode() {
     }
 
     let msg = Message::Request(Request {
-        id: 1234,
+        id: Id::from(1234_u32),
         method: "dummy".to_string(),
         params: Vec::new(),
     });
@@ -87,3 +310,189 @@ fn decode() {
     bytes = [&vec![0, 1, 2], &msg.pack().unwrap()[..]].concat();
     assert_eq!(try_decode(&bytes, b"").unwrap(), Some(msg.clone()));
 }
+
+#[test]
+fn invalid_messages() {
+    use message::Notification;
+
+    let msg = Message::Notification(Notification {
+        method: "dummy".to_string(),
+        params: Vec::new(),
+    });
+    let bytes = [&vec![0, 1, 2], &msg.pack().unwrap()[..]].concat();
+    for &policy in &[ProtocolPolicy::Ignore, ProtocolPolicy::LogAndIgnore] {
+        let mut options = ProtocolOptions::default();
+        let _ = options.invalid_messages(policy);
+        let mut codec = Codec::new(&options);
+        let decoded = codec.decode(&mut BytesMut::from(&bytes[..])).unwrap();
+        assert_eq!(decoded, Some(msg.clone()));
+    }
+
+    let mut options = ProtocolOptions::default();
+    let _ = options.invalid_messages(ProtocolPolicy::CloseConnection);
+    let mut codec = Codec::new(&options);
+    let error = codec.decode(&mut BytesMut::from(&bytes[..])).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn decode_incrementally() {
+    use message::{Id, Message, Request};
+
+    let msg = Message::Request(Request {
+        id: Id::from(1234_u32),
+        method: "dummy".to_string(),
+        params: vec![Param::Value(Value::from(vec![Value::from(1000); 1000]))],
+    });
+    let bytes = msg.pack().unwrap();
+
+    // The message arrives in small chunks: the scan resumes where it stopped each time.
+    let mut codec = Codec::default();
+    let mut buf = BytesMut::new();
+    for chunk in bytes[..bytes.len() - 1].chunks(100) {
+        buf.extend_from_slice(chunk);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(codec.scan.pos > buf.len() - 3);
+    }
+    buf.extend_from_slice(&bytes[bytes.len() - 1..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg.clone()));
+    assert!(buf.is_empty());
+
+    // Skipped invalid messages do not confuse the scan of the next message.
+    let mut buf = BytesMut::from(&[0xc1, 0x01][..]);
+    buf.extend_from_slice(&bytes[..10]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    assert_eq!(&buf[..], &bytes[..10]);
+    buf.extend_from_slice(&bytes[10..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+}
+
+#[test]
+fn decode_zero_copy_binary() {
+    use bytes::Bytes;
+    use message::{Id, Message, Param, Request};
+
+    let msg = Message::Request(Request {
+        id: Id::from(1234_u32),
+        method: "dummy".to_string(),
+        params: vec![
+            Param::Value(Value::from(1)),
+            Param::Binary(Bytes::from(vec![42; 16])),
+            Param::Value(Value::Binary(vec![1, 2, 3])),
+        ],
+    });
+
+    // Only the large binary parameter is left in the buffer, the small one is copied as usual.
+    let mut codec = Codec::new(ProtocolOptions::new().zero_copy_binary(Some(8)));
+    let mut buf = BytesMut::from(msg.pack().unwrap());
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn max_message_size() {
+    use message::{Id, Message, Request};
+
+    let msg = Message::Request(Request {
+        id: Id::from(1234_u32),
+        method: "dummy".to_string(),
+        params: vec![Param::Value(Value::from(vec![Value::from("a"); 16]))],
+    });
+    let bytes = msg.pack().unwrap();
+    let size = |bytes: &[u8], max| Scan::default().advance(bytes, max);
+    assert_eq!(size(&bytes, bytes.len()).unwrap(), Some(bytes.len()));
+    assert_eq!(size(&bytes[..bytes.len() - 1], bytes.len()).unwrap(), None);
+    assert!(size(&bytes, bytes.len() - 1).is_err());
+
+    let values = [
+        Value::from(-200),
+        Value::from(1.5),
+        Value::from(u64::max_value()),
+        Value::Binary(vec![0; 300]),
+        Value::Ext(-1, vec![0; 8]),
+        Value::Ext(3, vec![0; 100]),
+        Value::Map(vec![(Value::from("key"), Value::from(vec![Value::Nil; 20]))]),
+    ];
+    for value in &values {
+        let mut bytes = Vec::new();
+        ::rmpv::encode::write_value(&mut bytes, value).unwrap();
+        assert_eq!(size(&bytes, 1024).unwrap(), Some(bytes.len()));
+    }
+
+    let mut codec = Codec::new(ProtocolOptions::new().max_message_size(Some(64)));
+    let mut buf = BytesMut::from(bytes);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+
+    // Requests whose parameter claims to be a 4GB binary, or whose parameters claim to be 4
+    // billion values, are rejected from their header.
+    let header = [0x94, 0x00, 0x01, 0xa1, b'a'];
+    let mut buf = BytesMut::from([&header[..], &[0x91, 0xc6, 0xff, 0xff, 0xff, 0xff]].concat());
+    assert!(codec.decode(&mut buf).is_err());
+    let mut buf = BytesMut::from([&header[..], &[0xdd, 0xff, 0xff, 0xff, 0xff]].concat());
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[test]
+fn legacy_compatibility() {
+    use message::{Id, Message, Notification, Response};
+    use rpc_error::RpcError;
+
+    let mut codec = Codec::new(ProtocolOptions::new().legacy_compatibility(true));
+    let notification = Message::Notification(Notification {
+        method: "a".to_string(),
+        params: Vec::new(),
+    });
+    let mut buf = BytesMut::new();
+    codec.encode(notification.clone(), &mut buf).unwrap();
+    assert_eq!(&buf[..], &[0x93, 0x02, 0xa1, b'a', 0xc0]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(notification));
+
+    // Error code 2, with "bad" as message.
+    let response = [0x94, 0x01, 0x07, 0x02, 0xa3, b'b', b'a', b'd'];
+    let expected = Message::Response(Response {
+        id: Id::from(7_u32),
+        result: Err(Value::from(RpcError::new(2, "bad"))),
+    });
+    let mut buf = BytesMut::from(&response[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(expected));
+    let mut buf = BytesMut::from(&response[..]);
+    let error = match Codec::default().decode(&mut buf).unwrap() {
+        Some(Message::Response(response)) => response.result.unwrap_err(),
+        _ => panic!("expected a response"),
+    };
+    assert_eq!(error, Value::from(2));
+}
+
+#[test]
+fn empty_params() {
+    use message::Notification;
+
+    let notification = Message::Notification(Notification {
+        method: "a".to_string(),
+        params: Vec::new(),
+    });
+    let array = [0x93, 0x02, 0xa1, b'a', 0x90];
+    let nil = [0x93, 0x02, 0xa1, b'a', 0xc0];
+
+    let mut codec = Codec::default();
+    let mut buf = BytesMut::new();
+    codec.encode(notification.clone(), &mut buf).unwrap();
+    assert_eq!(&buf[..], &array[..]);
+    let mut codec = Codec::new(ProtocolOptions::new().empty_params(EmptyParams::Nil));
+    let mut buf = BytesMut::new();
+    codec.encode(notification.clone(), &mut buf).unwrap();
+    assert_eq!(&buf[..], &nil[..]);
+    let options = ProtocolOptions::new()
+        .legacy_compatibility(true)
+        .empty_params(EmptyParams::Array)
+        .clone();
+    let mut buf = BytesMut::new();
+    Codec::new(&options).encode(notification.clone(), &mut buf).unwrap();
+    assert_eq!(&buf[..], &array[..]);
+
+    // Both forms are decoded, even without lenient decoding.
+    for bytes in &[&array[..], &nil[..]] {
+        let mut buf = BytesMut::from(*bytes);
+        assert_eq!(Codec::default().decode(&mut buf).unwrap(), Some(notification.clone()));
+    }
+}
@@ -0,0 +1,171 @@
+//! LZ4 compression of the messages, negotiated per connection (see
+//! [`ProtocolOptions::compression`](struct.ProtocolOptions.html#method.compression)).
+//!
+//! A compressed message is sent as a msgpack ext value of type `COMPRESSED_EXT`, whose data is
+//! the size of the uncompressed message as a little endian `u32`, followed by the message
+//! compressed as an LZ4 block. It is only sent to peers that advertised the `lz4` feature in
+//! their [`Hello`](struct.Hello.html).
+use std::io;
+
+use bytes::BytesMut;
+use lz4::block;
+
+/// Feature advertised in the `Hello` by the endpoints that accept compressed messages.
+pub const COMPRESSION_FEATURE: &str = "lz4";
+
+/// Type of the msgpack ext value that carries a compressed message.
+const COMPRESSED_EXT: i8 = 0x4c;
+
+/// Return the data of the ext value that carries a compressed message, if `frame` is such a
+/// value.
+pub(crate) fn compressed_data(frame: &[u8]) -> Option<&[u8]> {
+    let (len_bytes, start) = match frame.first() {
+        Some(&0xc7) => (1, 3),
+        Some(&0xc8) => (2, 4),
+        Some(&0xc9) => (4, 6),
+        _ => return None,
+    };
+    if frame.len() < start || frame[start - 1] as i8 != COMPRESSED_EXT {
+        return None;
+    }
+    let len = frame[1..1 + len_bytes]
+        .iter()
+        .fold(0, |len, byte| len << 8 | *byte as usize);
+    frame.get(start..start + len)
+}
+
+/// Decompress the data of a compressed message. Return `None` if it is corrupted, and an error if
+/// the message is larger than `max` bytes, before decompressing it.
+pub(crate) fn decompress(data: &[u8], max: usize) -> io::Result<Option<BytesMut>> {
+    if data.len() < 4 {
+        return Ok(None);
+    }
+    let size = data[..4]
+        .iter()
+        .rev()
+        .fold(0, |size, byte| size << 8 | *byte as usize);
+    if size > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message larger than {} bytes", max),
+        ));
+    }
+    if size > i32::max_value() as usize {
+        return Ok(None);
+    }
+    match block::decompress(&data[4..], Some(size as i32)) {
+        Ok(ref message) if message.len() == size => Ok(Some(BytesMut::from(&message[..]))),
+        _ => Ok(None),
+    }
+}
+
+/// Replace the message at the end of `buf`, from `start`, by its compressed form, unless
+/// compressing it does not make it smaller.
+pub(crate) fn compress(buf: &mut BytesMut, start: usize) -> io::Result<()> {
+    let compressed = block::compress(&buf[start..], None, true)?;
+    // Size of the ext header.
+    let header = if compressed.len() <= 0xff {
+        3
+    } else if compressed.len() <= 0xffff {
+        4
+    } else {
+        6
+    };
+    if header + compressed.len() >= buf.len() - start {
+        return Ok(());
+    }
+    buf.truncate(start);
+    let len = compressed.len();
+    match header {
+        3 => buf.extend_from_slice(&[0xc7, len as u8]),
+        4 => buf.extend_from_slice(&[0xc8, (len >> 8) as u8, len as u8]),
+        _ => buf.extend_from_slice(&[
+            0xc9,
+            (len >> 24) as u8,
+            (len >> 16) as u8,
+            (len >> 8) as u8,
+            len as u8,
+        ]),
+    }
+    buf.extend_from_slice(&[COMPRESSED_EXT as u8]);
+    buf.extend_from_slice(&compressed);
+    Ok(())
+}
+
+#[test]
+fn compression_round_trip() {
+    let message = vec![b'a'; 1000];
+    let mut buf = BytesMut::from(&b"prefix"[..]);
+    buf.extend_from_slice(&message);
+    compress(&mut buf, 6).unwrap();
+    assert!(buf.len() < 100);
+    assert_eq!(&buf[..6], b"prefix");
+
+    let data = compressed_data(&buf[6..]).unwrap();
+    assert_eq!(decompress(data, 1000).unwrap().unwrap(), BytesMut::from(message));
+    assert!(decompress(data, 999).is_err());
+    assert_eq!(decompress(&data[..data.len() - 1], 1000).unwrap(), None);
+
+    // Incompressible messages are left as they are.
+    let mut buf = BytesMut::from(&[1, 2, 3][..]);
+    compress(&mut buf, 0).unwrap();
+    assert_eq!(&buf[..], &[1, 2, 3]);
+}
+
+#[test]
+fn compressed_connection() {
+    use std::sync::Arc;
+    use futures::future;
+    use rmpv::Value;
+    use metrics::BasicMetrics;
+    use methods::MethodRouter;
+    use mock::TestClient;
+    use options::ProtocolOptions;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+    let param = Value::from(vec![Value::from("compressible"); 1000]);
+
+    let mut bytes = Vec::new();
+    for threshold in &[None, Some(1024)] {
+        let metrics = Arc::new(BasicMetrics::new());
+        let mut options = ProtocolOptions::new();
+        let _ = options
+            .compression(*threshold)
+            .metrics(Some(Arc::clone(&metrics) as Arc<_>));
+        let mut client = TestClient::with_options(router.clone(), options);
+        // The first request is sent before the client receives the hello of the server, and is
+        // not compressed.
+        for _ in 0..4 {
+            assert_eq!(client.request("echo", &[param.clone()]), Ok(param.clone()));
+        }
+        bytes.push(metrics.bytes_written());
+    }
+    assert!(bytes[1] * 4 < bytes[0]);
+}
+
+#[test]
+fn compressed_named_params() {
+    use futures::future;
+    use rmpv::Value;
+    use methods::MethodRouter;
+    use mock::TestClient;
+    use options::ProtocolOptions;
+
+    let mut router = MethodRouter::new();
+    let _ = router
+        .request("count", |params| {
+            let count = params[0].as_array().map(|items| items.len()).unwrap_or(0);
+            Box::new(future::ok(Ok(Value::from(count))))
+        })
+        .named_params("count", &["items"]);
+    let mut options = ProtocolOptions::new();
+    let _ = options.compression(Some(64)).named_params(true);
+    let mut client = TestClient::with_options(router, options);
+    let items = Value::from(vec![Value::from("compressible"); 100]);
+    // The first request is not compressed, the next ones are.
+    for _ in 0..3 {
+        let response = client.client().request_named("count", &[("items", items.clone())]);
+        assert_eq!(client.run(response).unwrap(), Ok(Value::from(100)));
+    }
+}
@@ -0,0 +1,427 @@
+//! Configuration of servers and clients, that can be loaded from a file.
+//!
+//! With the `config` feature enabled, all the structures of this module implement
+//! `serde::Deserialize`, so that they can be read from any format supported by serde (TOML, YAML,
+//! JSON, etc.). Durations are expressed in milliseconds. All the fields but the addresses are
+//! optional: missing fields keep their default value.
+//!
+//! For instance, in TOML:
+//!
+//! ```toml
+//! [server]
+//! address = "0.0.0.0:5000"
+//!
+//! nodelay = true
+//! max_connections = 10000
+//!
+//! [server.protocol]
+//! ordered_responses = true
+//! poll_budget = 64
+//!
+//! [client]
+//! address = "10.0.0.1:5000"
+//! fallback_addresses = ["10.0.0.2:5000", "10.0.0.3:5000"]
+//! tls = { domain = "rpc.example.com" }
+//! backoff = { initial_delay_ms = 50, max_delay_ms = 10000, max_attempts = 10 }
+//! ```
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rmpv::Value;
+
+use hello::Hello;
+use message::EmptyParams;
+use options::ProtocolOptions;
+use reconnect::{Backoff, OverflowPolicy, ReplayPolicy};
+
+/// Configuration of a server and/or a client.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+#[cfg_attr(feature = "config", serde(default))]
+pub struct Config {
+    /// Configuration of the server (see
+    /// [`ServerBuilder::from_config`](../struct.ServerBuilder.html#method.from_config)).
+    pub server: Option<ServerConfig>,
+    /// Configuration of the client (see
+    /// [`Connector::from_config`](../struct.Connector.html#method.from_config) and
+    /// [`ReconnectingClient::from_config`](../struct.ReconnectingClient.html#method.from_config)).
+    pub client: Option<ClientConfig>,
+}
+
+/// Configuration of a server.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+pub struct ServerConfig {
+    /// Address the server listens on.
+    pub address: SocketAddr,
+    /// Options used for the connections the server accepts.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub protocol: ProtocolConfig,
+    /// See [`ServerBuilder::set_nodelay`](../struct.ServerBuilder.html#method.set_nodelay).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub nodelay: Option<bool>,
+    /// See [`ServerBuilder::set_keepalive`](../struct.ServerBuilder.html#method.set_keepalive), in
+    /// milliseconds.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub keepalive_ms: Option<u64>,
+    /// See [`ServerBuilder::set_backlog`](../struct.ServerBuilder.html#method.set_backlog).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub backlog: Option<i32>,
+    /// See
+    /// [`ServerBuilder::set_max_connections`](../struct.ServerBuilder.html#method.set_max_connections).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub max_connections: Option<usize>,
+    /// See
+    /// [`ServerBuilder::set_recv_buffer_size`](../struct.ServerBuilder.html#method.set_recv_buffer_size).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub recv_buffer_size: Option<usize>,
+    /// See
+    /// [`ServerBuilder::set_send_buffer_size`](../struct.ServerBuilder.html#method.set_send_buffer_size).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub send_buffer_size: Option<usize>,
+    /// See
+    /// [`ServerBuilder::set_handshake_timeout`](../struct.ServerBuilder.html#method.set_handshake_timeout),
+    /// in milliseconds.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub handshake_timeout_ms: Option<u64>,
+    /// See
+    /// [`ServerBuilder::set_close_idle_on_exhaustion`](../struct.ServerBuilder.html#method.set_close_idle_on_exhaustion).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub close_idle_on_exhaustion: Option<bool>,
+    /// See [`ServerBuilder::reserve_prefix`](../struct.ServerBuilder.html#method.reserve_prefix).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub reserved_prefixes: Vec<String>,
+    /// See
+    /// [`ServerBuilder::set_worker_threads`](../struct.ServerBuilder.html#method.set_worker_threads).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub worker_threads: Option<usize>,
+}
+
+/// Configuration of a client.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+pub struct ClientConfig {
+    /// Address of the server.
+    pub address: SocketAddr,
+    /// Addresses of the replicas of the server, tried in order when the connection to `address`
+    /// fails (see
+    /// [`Connector::set_fallback_addrs`](../struct.Connector.html#method.set_fallback_addrs)).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub fallback_addresses: Vec<SocketAddr>,
+    /// If set, TLS is used.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub tls: Option<TlsConfig>,
+    /// Options used for the connection.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub protocol: ProtocolConfig,
+    /// Reconnection policy, for reconnecting clients.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub backoff: Option<BackoffConfig>,
+    /// Maximum number of times a request is sent again when the connection is lost, for
+    /// reconnecting clients. By default, requests are not sent again.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub max_replays: Option<u32>,
+    /// Maximum number of calls waiting for the connection to be established, for reconnecting
+    /// clients. By default, there is no limit.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub queue_capacity: Option<usize>,
+    /// If set, the oldest waiting call fails when the queue is full, instead of the new one.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub queue_drop_oldest: bool,
+    /// See [`Connector::set_connect_timeout`](../struct.Connector.html#method.set_connect_timeout),
+    /// in milliseconds.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub connect_timeout_ms: Option<u64>,
+    /// See [`Connector::set_nodelay`](../struct.Connector.html#method.set_nodelay).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub nodelay: Option<bool>,
+    /// See [`Connector::set_keepalive`](../struct.Connector.html#method.set_keepalive), in
+    /// milliseconds.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub keepalive_ms: Option<u64>,
+    /// See [`Connector::set_local_addr`](../struct.Connector.html#method.set_local_addr).
+    #[cfg_attr(feature = "config", serde(default))]
+    pub local_address: Option<SocketAddr>,
+}
+
+/// TLS configuration of a client.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+#[cfg_attr(feature = "config", serde(default))]
+pub struct TlsConfig {
+    /// Hostname of the server, used to verify its certificate. If it is not set, the hostname is
+    /// not verified, which is dangerous.
+    pub domain: Option<String>,
+}
+
+/// Configuration of the protocol options (see [`ProtocolOptions`](../struct.ProtocolOptions.html)).
+/// The options that are not set keep their default value.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+#[cfg_attr(feature = "config", serde(default))]
+pub struct ProtocolConfig {
+    /// See [`ProtocolOptions::ordered_responses`](../struct.ProtocolOptions.html#method.ordered_responses).
+    pub ordered_responses: Option<bool>,
+    /// See [`Limits::poll_budget`](../struct.Limits.html#method.poll_budget).
+    pub poll_budget: Option<usize>,
+    /// See [`Limits::max_in_flight`](../struct.Limits.html#method.max_in_flight).
+    pub max_in_flight: Option<usize>,
+    /// See [`Limits::request_timeout`](../struct.Limits.html#method.request_timeout), in
+    /// milliseconds.
+    pub request_timeout_ms: Option<u64>,
+    /// See [`Limits::principal_max_in_flight`](../struct.Limits.html#method.principal_max_in_flight).
+    pub principal_max_in_flight: Option<usize>,
+    /// See [`Limits::principal_rate`](../struct.Limits.html#method.principal_rate).
+    pub principal_rate: Option<u32>,
+    /// See [`Limits::principal_bandwidth`](../struct.Limits.html#method.principal_bandwidth).
+    pub principal_bandwidth: Option<u32>,
+    /// See [`Limits::connection_rate`](../struct.Limits.html#method.connection_rate).
+    pub connection_rate: Option<u32>,
+    /// See [`Limits::peer_rate`](../struct.Limits.html#method.peer_rate).
+    pub peer_rate: Option<u32>,
+    /// See [`ProtocolOptions::hello`](../struct.ProtocolOptions.html#method.hello).
+    pub hello: Option<HelloConfig>,
+    /// See [`ProtocolOptions::shutdown_error`](../struct.ProtocolOptions.html#method.shutdown_error).
+    pub shutdown_error: Option<String>,
+    /// See [`ProtocolOptions::zero_copy_binary`](../struct.ProtocolOptions.html#method.zero_copy_binary).
+    pub zero_copy_binary: Option<usize>,
+    /// See [`ProtocolOptions::lenient_decoding`](../struct.ProtocolOptions.html#method.lenient_decoding).
+    pub lenient_decoding: Option<bool>,
+    /// See [`ProtocolOptions::legacy_compatibility`](../struct.ProtocolOptions.html#method.legacy_compatibility).
+    pub legacy_compatibility: Option<bool>,
+    /// See [`ProtocolOptions::named_params`](../struct.ProtocolOptions.html#method.named_params).
+    pub named_params: Option<bool>,
+    /// If set, requests and notifications without parameters are sent with `nil` parameters if
+    /// `true`, and an empty array if `false` (see
+    /// [`ProtocolOptions::empty_params`](../struct.ProtocolOptions.html#method.empty_params)).
+    pub nil_empty_params: Option<bool>,
+    /// See [`ProtocolOptions::max_message_size`](../struct.ProtocolOptions.html#method.max_message_size).
+    pub max_message_size: Option<usize>,
+    /// See [`ProtocolOptions::max_nesting_depth`](../struct.ProtocolOptions.html#method.max_nesting_depth).
+    pub max_nesting_depth: Option<usize>,
+    /// See [`ProtocolOptions::max_elements`](../struct.ProtocolOptions.html#method.max_elements).
+    pub max_elements: Option<usize>,
+    /// See [`ProtocolOptions::encode_buffer`](../struct.ProtocolOptions.html#method.encode_buffer).
+    pub encode_buffer: Option<usize>,
+    /// See
+    /// [`ProtocolOptions::adaptive_read_buffer`](../struct.ProtocolOptions.html#method.adaptive_read_buffer).
+    pub adaptive_read_buffer: Option<usize>,
+    /// See [`ProtocolOptions::idle_timeout`](../struct.ProtocolOptions.html#method.idle_timeout),
+    /// in milliseconds.
+    pub idle_timeout_ms: Option<u64>,
+    /// See
+    /// [`ProtocolOptions::keepalive_interval`](../struct.ProtocolOptions.html#method.keepalive_interval),
+    /// in milliseconds.
+    pub keepalive_interval_ms: Option<u64>,
+    /// See [`ProtocolOptions::health_check`](../struct.ProtocolOptions.html#method.health_check).
+    pub health_check: Option<bool>,
+    /// Timeouts of specific methods, in milliseconds (see
+    /// [`ProtocolOptions::method_timeout`](../struct.ProtocolOptions.html#method.method_timeout)).
+    pub method_timeouts_ms: HashMap<String, u64>,
+    /// See [`ProtocolOptions::read_rate`](../struct.ProtocolOptions.html#method.read_rate), in
+    /// bytes per second.
+    pub read_rate: Option<u32>,
+    /// See [`ProtocolOptions::write_rate`](../struct.ProtocolOptions.html#method.write_rate), in
+    /// bytes per second.
+    pub write_rate: Option<u32>,
+    /// See [`ProtocolOptions::max_request_id`](../struct.ProtocolOptions.html#method.max_request_id).
+    pub max_request_id: Option<u32>,
+    /// See [`ProtocolOptions::spawn_handlers`](../struct.ProtocolOptions.html#method.spawn_handlers).
+    pub spawn_handlers: Option<bool>,
+}
+
+/// Configuration of the [`Hello`](../struct.Hello.html) sent when a connection is established.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+pub struct HelloConfig {
+    /// Name of the implementation.
+    pub name: String,
+    /// Version of the implementation.
+    pub version: String,
+    /// Features supported by the implementation.
+    #[cfg_attr(feature = "config", serde(default))]
+    pub features: Vec<String>,
+}
+
+/// Configuration of a [`Backoff`](../struct.Backoff.html) policy.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "config", derive(Deserialize))]
+#[cfg_attr(feature = "config", serde(default))]
+pub struct BackoffConfig {
+    /// Delay before the first retry, in milliseconds.
+    pub initial_delay_ms: Option<u64>,
+    /// Maximum delay between two attempts, in milliseconds.
+    pub max_delay_ms: Option<u64>,
+    /// Maximum number of consecutive failed attempts. By default, the client retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl<'a> From<&'a ProtocolConfig> for ProtocolOptions {
+    fn from(config: &'a ProtocolConfig) -> Self {
+        let mut options = ProtocolOptions::default();
+        if let Some(enabled) = config.ordered_responses {
+            let _ = options.ordered_responses(enabled);
+        }
+        let mut limits = options.get_limits();
+        if let Some(budget) = config.poll_budget {
+            let _ = limits.poll_budget(budget);
+        }
+        if config.max_in_flight.is_some() {
+            let _ = limits.max_in_flight(config.max_in_flight);
+        }
+        if let Some(timeout) = config.request_timeout_ms {
+            let _ = limits.request_timeout(Some(Duration::from_millis(timeout)));
+        }
+        if config.principal_max_in_flight.is_some() {
+            let _ = limits.principal_max_in_flight(config.principal_max_in_flight);
+        }
+        if config.principal_rate.is_some() {
+            let _ = limits.principal_rate(config.principal_rate);
+        }
+        if config.principal_bandwidth.is_some() {
+            let _ = limits.principal_bandwidth(config.principal_bandwidth);
+        }
+        if config.connection_rate.is_some() {
+            let _ = limits.connection_rate(config.connection_rate);
+        }
+        if config.peer_rate.is_some() {
+            let _ = limits.peer_rate(config.peer_rate);
+        }
+        let _ = options.limits(limits);
+        if let Some(ref hello) = config.hello {
+            let _ = options.hello(Some(Hello::from(hello)));
+        }
+        if let Some(ref error) = config.shutdown_error {
+            let _ = options.shutdown_error(Some(Value::from(error.as_str())));
+        }
+        if config.zero_copy_binary.is_some() {
+            let _ = options.zero_copy_binary(config.zero_copy_binary);
+        }
+        if let Some(enabled) = config.lenient_decoding {
+            let _ = options.lenient_decoding(enabled);
+        }
+        if let Some(enabled) = config.legacy_compatibility {
+            let _ = options.legacy_compatibility(enabled);
+        }
+        if let Some(enabled) = config.named_params {
+            let _ = options.named_params(enabled);
+        }
+        match config.nil_empty_params {
+            Some(true) => {
+                let _ = options.empty_params(EmptyParams::Nil);
+            }
+            Some(false) => {
+                let _ = options.empty_params(EmptyParams::Array);
+            }
+            None => {}
+        }
+        if config.max_message_size.is_some() {
+            let _ = options.max_message_size(config.max_message_size);
+        }
+        if let Some(depth) = config.max_nesting_depth {
+            let _ = options.max_nesting_depth(depth);
+        }
+        if config.max_elements.is_some() {
+            let _ = options.max_elements(config.max_elements);
+        }
+        if config.encode_buffer.is_some() {
+            let _ = options.encode_buffer(config.encode_buffer);
+        }
+        if config.adaptive_read_buffer.is_some() {
+            let _ = options.adaptive_read_buffer(config.adaptive_read_buffer);
+        }
+        if let Some(timeout) = config.idle_timeout_ms {
+            let _ = options.idle_timeout(Some(Duration::from_millis(timeout)));
+        }
+        if let Some(interval) = config.keepalive_interval_ms {
+            let _ = options.keepalive_interval(Some(Duration::from_millis(interval)));
+        }
+        if let Some(enabled) = config.health_check {
+            let _ = options.health_check(enabled);
+        }
+        for (method, timeout) in &config.method_timeouts_ms {
+            let _ = options.method_timeout(method, Some(Duration::from_millis(*timeout)));
+        }
+        if config.read_rate.is_some() {
+            let _ = options.read_rate(config.read_rate);
+        }
+        if config.write_rate.is_some() {
+            let _ = options.write_rate(config.write_rate);
+        }
+        if let Some(max) = config.max_request_id {
+            let _ = options.max_request_id(max);
+        }
+        if let Some(enabled) = config.spawn_handlers {
+            let _ = options.spawn_handlers(enabled);
+        }
+        options
+    }
+}
+
+impl<'a> From<&'a HelloConfig> for Hello {
+    fn from(config: &'a HelloConfig) -> Self {
+        let mut hello = Hello::new(&config.name, &config.version);
+        for feature in &config.features {
+            let _ = hello.add_feature(feature);
+        }
+        hello
+    }
+}
+
+impl<'a> From<&'a BackoffConfig> for Backoff {
+    fn from(config: &'a BackoffConfig) -> Self {
+        let default = Backoff::default();
+        let initial_delay = match config.initial_delay_ms {
+            Some(delay) => Duration::from_millis(delay),
+            None => default.initial_delay(),
+        };
+        let max_delay = match config.max_delay_ms {
+            Some(delay) => Duration::from_millis(delay),
+            None => default.max_delay(),
+        };
+        let mut backoff = Backoff::new(initial_delay, max_delay);
+        let _ = backoff.set_max_attempts(config.max_attempts);
+        backoff
+    }
+}
+
+impl ClientConfig {
+    /// Return the replay policy described by this configuration.
+    pub fn replay_policy(&self) -> ReplayPolicy {
+        match self.max_replays {
+            Some(max) => ReplayPolicy::Retry(max),
+            None => ReplayPolicy::Fail,
+        }
+    }
+
+    /// Return the policy applied when the queue of the calls waiting for a connection is full.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        if self.queue_drop_oldest {
+            OverflowPolicy::DropOldest
+        } else {
+            OverflowPolicy::RejectNew
+        }
+    }
+}
+
+#[test]
+fn protocol_config() {
+    let config = ProtocolConfig {
+        poll_budget: Some(16),
+        hello: Some(HelloConfig {
+            name: "test".to_string(),
+            version: "1.2.3".to_string(),
+            features: vec!["streaming".to_string()],
+        }),
+        ..Default::default()
+    };
+    let options = ProtocolOptions::from(&config);
+    assert_eq!(options.get_poll_budget(), 16);
+    assert!(!options.has_ordered_responses());
+    assert!(options.get_hello().unwrap().has_feature("streaming"));
+    assert_eq!(
+        options.get_shutdown_error(),
+        ProtocolOptions::default().get_shutdown_error()
+    );
+}
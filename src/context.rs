@@ -0,0 +1,176 @@
+use std::any::Any;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use capabilities::Capabilities;
+use channel::Channels;
+use errors::CallError;
+use extensions::Extensions;
+use hello::Hello;
+use message::Id;
+use redact::Redactions;
+
+static NEXT_CONNECTION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Default)]
+struct Inner {
+    peer_hello: Option<Hello>,
+    principal: Option<String>,
+    peer_addr: Option<SocketAddr>,
+    close_error: Option<CallError>,
+    capabilities: Option<Capabilities>,
+    // The request being passed to the service, while its handler is called.
+    current_request: Option<Id>,
+}
+
+/// Information about a connection, shared by everything that handles this connection. It can be
+/// retrieved with [`Client::context`](struct.Client.html#method.context).
+///
+/// `Context` is cheap to clone: all the clones refer to the same connection.
+#[derive(Clone)]
+pub struct Context {
+    id: usize,
+    inner: Arc<Mutex<Inner>>,
+    // Separate from `inner`, so that the other methods can be called while the extensions are
+    // borrowed.
+    extensions: Arc<Mutex<Extensions>>,
+    channels: Arc<Mutex<Channels>>,
+    redactions: Redactions,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context::new(Redactions::default())
+    }
+}
+
+impl Context {
+    pub(crate) fn new(redactions: Redactions) -> Self {
+        Context {
+            id: NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed),
+            inner: Arc::default(),
+            extensions: Arc::default(),
+            channels: Arc::default(),
+            redactions: redactions,
+        }
+    }
+
+    /// Return the number identifying the connection, unique within the process. It is the number
+    /// reported to the [`Metrics`](trait.Metrics.html), and the one to pass to
+    /// [`ServerHandle::disconnect`](struct.ServerHandle.html#method.disconnect).
+    pub fn connection_id(&self) -> usize {
+        self.id
+    }
+
+    /// Return the [`Hello`](struct.Hello.html) the remote endpoint sent, if any.
+    pub fn peer_hello(&self) -> Option<Hello> {
+        self.inner.lock().unwrap().peer_hello.clone()
+    }
+
+    /// Return the features the remote endpoint advertised in its
+    /// [`Hello`](struct.Hello.html). If it did not send any `Hello`, this is empty: the peer may
+    /// be an older implementation, and should be assumed to only support the base protocol.
+    pub fn peer_features(&self) -> Vec<String> {
+        match self.inner.lock().unwrap().peer_hello {
+            Some(ref hello) => hello.features().to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Return `true` if the remote endpoint advertised support for the given feature.
+    pub fn peer_supports(&self, feature: &str) -> bool {
+        match self.inner.lock().unwrap().peer_hello {
+            Some(ref hello) => hello.has_feature(feature),
+            None => false,
+        }
+    }
+
+    /// Return the identity of the remote endpoint, if it has been set.
+    pub fn principal(&self) -> Option<String> {
+        self.inner.lock().unwrap().principal.clone()
+    }
+
+    /// Set the identity of the remote endpoint, once it has been authenticated. On a server, the
+    /// per-principal quotas (see [`Limits`](struct.Limits.html)) are then shared by all the
+    /// connections with the same principal, so that opening more connections does not give more
+    /// allowance.
+    pub fn set_principal(&self, principal: &str) {
+        self.inner.lock().unwrap().principal = Some(principal.to_owned());
+    }
+
+    /// Return the address of the remote endpoint, if the connection is a TCP connection.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.lock().unwrap().peer_addr
+    }
+
+    /// Return why the connection was closed, once it is.
+    pub fn close_error(&self) -> Option<CallError> {
+        self.inner.lock().unwrap().close_error
+    }
+
+    /// Call `f` with the [`Extensions`](struct.Extensions.html) of the connection: the values
+    /// that middlewares and handlers attach to it. The extensions are locked while `f` runs, so
+    /// `f` must not call `with_extensions` again.
+    pub fn with_extensions<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Extensions) -> R,
+    {
+        f(&mut self.extensions.lock().unwrap())
+    }
+
+    /// Return a copy of the extension of the given type, if the connection has one.
+    pub fn extension<T: Any + Send + Clone>(&self) -> Option<T> {
+        self.with_extensions(|extensions| extensions.get::<T>().cloned())
+    }
+
+    /// Attach a value to the connection, replacing the previous value of the same type, which is
+    /// returned.
+    pub fn insert_extension<T: Any + Send>(&self, value: T) -> Option<T> {
+        self.with_extensions(|extensions| extensions.insert(value))
+    }
+
+    pub(crate) fn set_peer_addr(&self, addr: SocketAddr) {
+        self.inner.lock().unwrap().peer_addr = Some(addr);
+    }
+
+    /// Record why the connection is closed. Only the first reason is kept.
+    pub(crate) fn set_close_error(&self, error: CallError) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.close_error.is_none() {
+            inner.close_error = Some(error);
+        }
+    }
+
+    /// Return the capabilities of the remote endpoint, if they have been requested already.
+    pub(crate) fn capabilities(&self) -> Option<Capabilities> {
+        self.inner.lock().unwrap().capabilities.clone()
+    }
+
+    pub(crate) fn set_capabilities(&self, capabilities: Capabilities) {
+        self.inner.lock().unwrap().capabilities = Some(capabilities);
+    }
+
+    pub(crate) fn set_peer_hello(&self, hello: Hello) {
+        self.inner.lock().unwrap().peer_hello = Some(hello);
+    }
+
+    /// Return the id of the request whose handler is being called, if any.
+    pub(crate) fn current_request(&self) -> Option<Id> {
+        self.inner.lock().unwrap().current_request
+    }
+
+    pub(crate) fn set_current_request(&self, id: Option<Id>) {
+        self.inner.lock().unwrap().current_request = id;
+    }
+
+    /// Return the [`Channel`](struct.Channel.html)s multiplexed over the connection.
+    pub(crate) fn channels(&self) -> &Arc<Mutex<Channels>> {
+        &self.channels
+    }
+
+    /// Return the functions that hide sensitive parameters from the logs of the connection.
+    pub(crate) fn redactions(&self) -> &Redactions {
+        &self.redactions
+    }
+}
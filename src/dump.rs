@@ -0,0 +1,317 @@
+//! Protocol dumps.
+//!
+//! A [`ProtocolDump`](struct.ProtocolDump.html) reports every message a transport encodes or
+//! decodes, with its direction, the time at which it was sent or received, and its size on the
+//! wire. It is meant for debugging interoperability issues with other implementations, without
+//! capturing and decoding the traffic by hand:
+//!
+//! ```rust,ignore
+//! let dump = ProtocolDump::to_writer(io::stderr());
+//! let mut options = ProtocolOptions::default();
+//! let _ = options.dump(Some(dump.clone()));
+//! // ...
+//! dump.set_enabled(false);
+//! ```
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rmpv::Value;
+
+use message::{Id, Message, Notification, Request, Response};
+use redact::Redactions;
+
+/// Number of spaces each nesting level is indented by in a pretty-printed message.
+const INDENT: usize = 2;
+
+/// Arrays and maps that do not fit on a line of this width are written with one element per
+/// line.
+const WIDTH: usize = 80;
+
+/// Whether a message has been sent or received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The message has been received from the remote endpoint.
+    Incoming,
+    /// The message has been sent to the remote endpoint.
+    Outgoing,
+}
+
+/// A message sent or received by a transport, passed to the [`DumpSink`](trait.DumpSink.html) of
+/// a [`ProtocolDump`](struct.ProtocolDump.html).
+///
+/// Its `Display` representation is a header line with the timestamp, direction and size,
+/// followed by the pretty-printed message.
+#[derive(Clone, Debug)]
+pub struct DumpRecord {
+    direction: Direction,
+    timestamp: SystemTime,
+    size: usize,
+    message: Value,
+}
+
+impl DumpRecord {
+    /// Whether the message has been sent or received.
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Time at which the message has been queued for sending, or decoded.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Size of the message on the wire, in bytes. It is the size of the compressed message if
+    /// the message has been compressed.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The message, as the msgpack array that is sent on the wire. The parameters hidden by the
+    /// [redactions](struct.ProtocolOptions.html#method.redactions) are redacted.
+    pub fn message(&self) -> &Value {
+        &self.message
+    }
+}
+
+impl fmt::Display for DumpRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let timestamp = self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let direction = match self.direction {
+            Direction::Incoming => "<-",
+            Direction::Outgoing => "->",
+        };
+        write!(
+            f,
+            "{}.{:06} {} {} bytes\n",
+            timestamp.as_secs(),
+            timestamp.subsec_nanos() / 1000,
+            direction,
+            self.size
+        )?;
+        write_pretty(f, &self.message, INDENT)
+    }
+}
+
+/// Write `value` at the given indentation, breaking the arrays and maps that are too wide.
+fn write_pretty(f: &mut fmt::Formatter, value: &Value, indent: usize) -> fmt::Result {
+    write!(f, "{:1$}", "", indent)?;
+    write_nested(f, value, indent)
+}
+
+fn write_nested(f: &mut fmt::Formatter, value: &Value, indent: usize) -> fmt::Result {
+    let inline = value.to_string();
+    if indent + inline.len() <= WIDTH {
+        return f.write_str(&inline);
+    }
+    match *value {
+        Value::Array(ref items) if !items.is_empty() => {
+            f.write_str("[\n")?;
+            for item in items {
+                write_pretty(f, item, indent + INDENT)?;
+                f.write_str(",\n")?;
+            }
+            write!(f, "{:1$}]", "", indent)
+        }
+        Value::Map(ref entries) if !entries.is_empty() => {
+            f.write_str("{\n")?;
+            for &(ref key, ref value) in entries {
+                write!(f, "{:1$}{2}: ", "", indent + INDENT, key)?;
+                write_nested(f, value, indent + INDENT)?;
+                f.write_str(",\n")?;
+            }
+            write!(f, "{:1$}}}", "", indent)
+        }
+        _ => f.write_str(&inline),
+    }
+}
+
+/// A destination for the records of a [`ProtocolDump`](struct.ProtocolDump.html). `dump` is
+/// called on the reactor thread, so it should not block.
+pub trait DumpSink: Send + Sync {
+    /// Report a message that has been sent or received.
+    fn dump(&self, record: &DumpRecord);
+}
+
+impl<F: Fn(&DumpRecord) + Send + Sync> DumpSink for F {
+    fn dump(&self, record: &DumpRecord) {
+        self(record)
+    }
+}
+
+/// Reports the messages sent and received by the transports it is given to (see
+/// [`ProtocolOptions::dump`](struct.ProtocolOptions.html#method.dump)) to a
+/// [`DumpSink`](trait.DumpSink.html).
+///
+/// The dump can be enabled and disabled at any time, including while connections are running.
+/// Its clones share the same sink and the same switch. When it is disabled, the messages are not
+/// converted, so it costs almost nothing.
+#[derive(Clone)]
+pub struct ProtocolDump {
+    sink: Arc<DumpSink>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl ProtocolDump {
+    /// Create an enabled dump that reports to `sink`.
+    pub fn new(sink: Arc<DumpSink>) -> Self {
+        ProtocolDump {
+            sink: sink,
+            enabled: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Create an enabled dump that writes the records to `writer`, followed by an empty line.
+    /// Write errors are ignored.
+    pub fn to_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        let writer = Mutex::new(writer);
+        ProtocolDump::new(Arc::new(move |record: &DumpRecord| {
+            if let Ok(mut writer) = writer.lock() {
+                let _ = write!(writer, "{}\n\n", record);
+            }
+        }))
+    }
+
+    /// Enable or disable the dump.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Return `true` if the messages are reported.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Report a message of the given size, if the dump is enabled.
+    pub(crate) fn record(
+        &self,
+        direction: Direction,
+        size: usize,
+        message: &Message,
+        redactions: &Redactions,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.sink.dump(&DumpRecord {
+            direction: direction,
+            timestamp: SystemTime::now(),
+            size: size,
+            message: to_value(message, redactions),
+        });
+    }
+}
+
+impl fmt::Debug for ProtocolDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ProtocolDump")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+/// Convert a message into the array it is encoded as.
+fn to_value(message: &Message, redactions: &Redactions) -> Value {
+    match *message {
+        Message::Request(Request {
+            id,
+            ref method,
+            ref params,
+        }) => {
+            let params = params.iter().cloned().map(|param| param.into_value());
+            let params = redactions.redact(method, &params.collect::<Vec<_>>());
+            Value::Array(vec![
+                Value::from(0),
+                id_value(id),
+                Value::from(method.as_str()),
+                Value::Array(params),
+            ])
+        }
+        Message::Response(Response { id, ref result }) => {
+            let (error, result) = match *result {
+                Ok(ref result) => (Value::Nil, result.clone()),
+                Err(ref error) => (error.clone(), Value::Nil),
+            };
+            Value::Array(vec![Value::from(1), id_value(id), error, result])
+        }
+        Message::Notification(Notification {
+            ref method,
+            ref params,
+        }) => Value::Array(vec![
+            Value::from(2),
+            Value::from(method.as_str()),
+            Value::Array(redactions.redact(method, params)),
+        ]),
+    }
+}
+
+fn id_value(id: Id) -> Value {
+    match id {
+        Id::Unsigned(id) => Value::from(id),
+        Id::Negative(id) => Value::from(id),
+    }
+}
+
+/// Collect the records of a dump, for the tests.
+#[cfg(test)]
+fn collector() -> (ProtocolDump, Arc<Mutex<Vec<DumpRecord>>>) {
+    let records = Arc::new(Mutex::new(vec![]));
+    let sink = Arc::clone(&records);
+    let dump = ProtocolDump::new(Arc::new(move |record: &DumpRecord| {
+        sink.lock().unwrap().push(record.clone());
+    }));
+    (dump, records)
+}
+
+#[test]
+fn protocol_dump() {
+    use futures::{Future, Sink, Stream};
+    use mock::duplex;
+    use options::ProtocolOptions;
+    use transport::Transport;
+
+    let (dump, records) = collector();
+    let mut redactions = Redactions::new();
+    let _ = redactions.hide("login", &[0]);
+    let mut options = ProtocolOptions::default();
+    let _ = options.dump(Some(dump.clone())).redactions(redactions);
+
+    let request = Message::Request(Request {
+        id: Id::from(7_u32),
+        method: "login".to_owned(),
+        params: vec![Value::from("hunter2").into()],
+    });
+    let notification = Message::Notification(Notification {
+        method: "ping".to_owned(),
+        params: vec![],
+    });
+    let (left, right) = duplex();
+    let left = Transport::with_options(left, &options);
+    let left = left.send(request.clone()).wait().unwrap();
+    dump.set_enabled(false);
+    let _ = left.send(notification).wait().unwrap();
+    dump.set_enabled(true);
+    let received = Transport::with_options(right, &options).take(2).collect().wait();
+    assert_eq!(received.unwrap().len(), 2);
+
+    let records = records.lock().unwrap();
+    let directions = records.iter().map(|r| r.direction()).collect::<Vec<_>>();
+    let expected = vec![Direction::Outgoing, Direction::Incoming, Direction::Incoming];
+    assert_eq!(directions, expected);
+    assert_eq!(records[0].size(), request.pack().unwrap().len());
+    assert_eq!(records[1].size(), records[0].size());
+    let expected = Value::Array(vec![
+        Value::from(0),
+        Value::from(7),
+        Value::from("login"),
+        Value::Array(vec![Value::from("<redacted>")]),
+    ]);
+    assert_eq!(records[1].message(), &expected);
+    let text = records[0].to_string();
+    assert!(text.contains(&format!("-> {} bytes\n", records[0].size())));
+    assert!(text.ends_with("  [0, 7, \"login\", [\"<redacted>\"]]"));
+}
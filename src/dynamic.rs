@@ -0,0 +1,267 @@
+//! Services and service builders chosen at runtime.
+//!
+//! `Service` has associated types, so it cannot be made into a trait object, and the server and
+//! connector types are generic over the service builder. [`DynService`](type.DynService.html)
+//! and [`DynServiceBuilder`](type.DynServiceBuilder.html) erase these types, so that an
+//! application can pick its service at runtime, from its command line for instance, and still
+//! use a single server type:
+//!
+//! ```rust,ignore
+//! let builder = if args.calculator {
+//!     into_dyn_builder(Calculator)
+//! } else {
+//!     into_dyn_builder(router)
+//! };
+//! let server: Server<DynServiceBuilder> = ServerBuilder::new(address).build(builder, &handle)?;
+//! ```
+use std::io;
+use std::time::Instant;
+
+use futures::Future;
+use rmpv::Value;
+
+use endpoint::{BoxedService, Client, Service, ServiceBuilder};
+use errors::RouterError;
+use message::Param;
+use priority::Priority;
+
+/// A service trait object. The results and errors of the requests are converted into `Value`s,
+/// and the futures fail with `io::Error`s, like those of a
+/// [`MethodRouter`](struct.MethodRouter.html). Any `Service` with these errors can be converted
+/// with [`into_dyn_service`](fn.into_dyn_service.html).
+pub type DynService = Box<BoxedService<Error = io::Error, T = Value, E = Value>>;
+
+/// A service builder trait object, that builds [`DynService`](type.DynService.html)s. Any
+/// `ServiceBuilder` whose services fail with `io::Error`s can be converted with
+/// [`into_dyn_builder`](fn.into_dyn_builder.html).
+pub type DynServiceBuilder = Box<ServiceBuilder<Service = DynService> + Send + Sync>;
+
+impl<S: BoxedService + ?Sized> BoxedService for Box<S> {
+    type Error = S::Error;
+    type T = S::T;
+    type E = S::E;
+
+    fn handle_request(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+        (**self).handle_request(method, params)
+    }
+
+    fn handle_request_zero_copy(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+        (**self).handle_request_zero_copy(method, params)
+    }
+
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        deadline: Option<Instant>,
+    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+        (**self).handle_request_with_deadline(method, params, deadline)
+    }
+
+    fn handle_notification(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = (), Error = Self::Error>> {
+        (**self).handle_notification(method, params)
+    }
+
+    fn map_error(&mut self, error: Self::Error) -> Value {
+        (**self).map_error(error)
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        (**self).methods()
+    }
+
+    fn priority(&self, method: &str) -> Priority {
+        (**self).priority(method)
+    }
+}
+
+impl<B: ServiceBuilder + ?Sized> ServiceBuilder for Box<B> {
+    type Service = B::Service;
+
+    fn build(&self, client: Client) -> Self::Service {
+        (**self).build(client)
+    }
+
+    fn registered_methods(&self) -> Vec<String> {
+        (**self).registered_methods()
+    }
+
+    fn validate(&self) -> Result<(), RouterError> {
+        (**self).validate()
+    }
+
+    fn protected_prefixes(&self) -> Vec<String> {
+        (**self).protected_prefixes()
+    }
+}
+
+/// Boxes the futures of a service, and converts their results into `Value`s.
+struct Erased<S>(S);
+
+impl<S: Service<Error = io::Error>> BoxedService for Erased<S> {
+    type Error = io::Error;
+    type T = Value;
+    type E = Value;
+
+    fn handle_request(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = io::Error>> {
+        erase(self.0.handle_request(method, params))
+    }
+
+    fn handle_request_zero_copy(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+    ) -> Box<Future<Item = Result<Value, Value>, Error = io::Error>> {
+        erase(self.0.handle_request_zero_copy(method, params))
+    }
+
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        deadline: Option<Instant>,
+    ) -> Box<Future<Item = Result<Value, Value>, Error = io::Error>> {
+        erase(self.0.handle_request_with_deadline(method, params, deadline))
+    }
+
+    fn handle_notification(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        Box::new(self.0.handle_notification(method, params))
+    }
+
+    fn map_error(&mut self, error: io::Error) -> Value {
+        self.0.map_error(error)
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        self.0.methods()
+    }
+
+    fn priority(&self, method: &str) -> Priority {
+        self.0.priority(method)
+    }
+}
+
+fn erase<F, T, E>(future: F) -> Box<Future<Item = Result<Value, Value>, Error = io::Error>>
+where
+    F: Future<Item = Result<T, E>, Error = io::Error> + 'static,
+    T: Into<Value>,
+    E: Into<Value>,
+{
+    Box::new(future.map(|result| result.map(Into::into).map_err(Into::into)))
+}
+
+/// Builds the services of another builder, and converts them into `DynService`s.
+struct ErasedBuilder<B>(B);
+
+impl<B> ServiceBuilder for ErasedBuilder<B>
+where
+    B: ServiceBuilder,
+    B::Service: Service<Error = io::Error>,
+{
+    type Service = DynService;
+
+    fn build(&self, client: Client) -> DynService {
+        into_dyn_service(self.0.build(client))
+    }
+
+    fn registered_methods(&self) -> Vec<String> {
+        self.0.registered_methods()
+    }
+
+    fn validate(&self) -> Result<(), RouterError> {
+        self.0.validate()
+    }
+
+    fn protected_prefixes(&self) -> Vec<String> {
+        self.0.protected_prefixes()
+    }
+}
+
+/// Convert a service into a [`DynService`](type.DynService.html).
+pub fn into_dyn_service<S>(service: S) -> DynService
+where
+    S: Service<Error = io::Error> + 'static,
+{
+    Box::new(Erased(service))
+}
+
+/// Convert a service builder into a [`DynServiceBuilder`](type.DynServiceBuilder.html).
+pub fn into_dyn_builder<B>(builder: B) -> DynServiceBuilder
+where
+    B: ServiceBuilder + Send + Sync + 'static,
+    B::Service: Service<Error = io::Error>,
+{
+    Box::new(ErasedBuilder(builder))
+}
+
+#[test]
+fn dyn_services() {
+    use futures::future::{self, FutureResult};
+    use methods::MethodRouter;
+    use mock::test_runtime;
+    use options::ProtocolOptions;
+
+    struct Answer;
+
+    impl Service for Answer {
+        type Error = io::Error;
+        type T = u32;
+        type E = String;
+        type RequestFuture = FutureResult<Result<u32, String>, io::Error>;
+        type NotificationFuture = FutureResult<(), io::Error>;
+
+        fn handle_request(&mut self, method: &str, _: &[Value]) -> Self::RequestFuture {
+            match method {
+                "answer" => future::ok(Ok(42)),
+                _ => future::ok(Err(format!("unknown method {}", method))),
+            }
+        }
+
+        fn handle_notification(&mut self, _: &str, _: &[Value]) -> Self::NotificationFuture {
+            future::ok(())
+        }
+    }
+
+    impl ServiceBuilder for Answer {
+        type Service = Answer;
+
+        fn build(&self, _client: Client) -> Answer {
+            Answer
+        }
+    }
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("answer", |_| Box::new(future::ok(Ok(Value::from(0)))));
+    // The builders have different types, but are used through the same type.
+    let builders = vec![into_dyn_builder(router), into_dyn_builder(Answer)];
+    assert_eq!(builders[0].registered_methods(), vec!["answer".to_owned()]);
+
+    let expected = [Value::from(0), Value::from(42)];
+    for (builder, expected) in builders.iter().zip(expected.iter()) {
+        let mut runtime = test_runtime(builder, 1, &ProtocolOptions::default(), 1);
+        let response = runtime.client(0).request("answer", &[]);
+        assert_eq!(runtime.run(response).unwrap(), Ok(expected.clone()));
+        let response = runtime.client(0).request("question", &[]);
+        assert!(runtime.run(response).unwrap().is_err());
+    }
+}
@@ -1,23 +1,132 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::{self, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use futures::future::JoinAll;
+use futures::stream::FuturesUnordered;
 use futures::sync::{mpsc, oneshot};
-use tokio_io::codec::Framed;
+use futures::task;
+use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite};
 use rmpv::Value;
+#[cfg(feature = "serde-params")]
+use rmpv::ext;
+#[cfg(feature = "serde-params")]
+use serde::Serialize;
+#[cfg(feature = "serde-params")]
+use serde::de::DeserializeOwned;
 
-use message::{Message, Notification, Request};
+use anomaly::{Anomalies, Anomaly, AnomalyHandler};
+use audit::{AuditOutcome, PendingAudit};
+use auth::{Admission, Authenticator};
+use capabilities::{Capabilities, CAPABILITIES_METHOD};
+use channel::CHANNEL_METHOD;
+use clock::Timer;
+#[cfg(feature = "compression")]
+use compression::COMPRESSION_FEATURE;
+use context::Context;
+use errors::{CallError, ClientError, RouterError};
+use hello::{Hello, HELLO_METHOD};
+use keepalive::{Liveness, PING_METHOD};
+use message::{Id, Message, Notification, Param, Request};
 use message::Response as MsgPackResponse;
-use codec::Codec;
+use metrics::{ConnectionMetrics, PendingMetrics};
+use options::{DuplicateIdPolicy, Limits, ProtocolOptions, ProtocolPolicy};
+use priority::{PrioritizedTasks, Priority};
+use rpc_error::RpcError;
+use server::{Disconnect, QuotaPermit, Registration, ServerHandle};
+use streaming::{chunk_method, ResponseStream, STREAM_PREFIX};
+use throttle::Bucket;
+use transport::Transport;
 
 /// The `Service` trait defines how a `MessagePack-RPC` server handles requests and notifications.
+///
+/// The futures returned by the handlers are associated types, so that services that return
+/// concrete futures do not allocate for each request. Services that return boxed futures can
+/// implement [`BoxedService`](trait.BoxedService.html) instead, which is simpler.
 pub trait Service {
     type Error: Error;
     type T: Into<Value>;
     type E: Into<Value>;
+    /// Future returned by `handle_request`.
+    type RequestFuture: Future<Item = Result<Self::T, Self::E>, Error = Self::Error> + 'static;
+    /// Future returned by `handle_notification`.
+    type NotificationFuture: Future<Item = (), Error = Self::Error> + 'static;
+
+    /// Handle a `MessagePack-RPC` request.
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture;
+
+    /// Handle a `MessagePack-RPC` request whose large binary parameters may not have been copied
+    /// out of the receive buffer (see
+    /// [`ProtocolOptions::zero_copy_binary`](struct.ProtocolOptions.html#method.zero_copy_binary)).
+    /// By default, the parameters are converted into `Value`s, which copies the binary
+    /// parameters, and the request is handled by `handle_request`.
+    fn handle_request_zero_copy(&mut self, method: &str, params: Vec<Param>) -> Self::RequestFuture {
+        let params = params
+            .into_iter()
+            .map(Param::into_value)
+            .collect::<Vec<Value>>();
+        self.handle_request(method, &params)
+    }
+
+    /// Handle a `MessagePack-RPC` request that times out at `deadline` (see
+    /// [`ProtocolOptions::method_timeout`](struct.ProtocolOptions.html#method.method_timeout) and
+    /// [`Limits::request_timeout`](struct.Limits.html#method.request_timeout)). This is the
+    /// method the endpoint calls for each request. Once the deadline is reached, the request is
+    /// answered with a timeout error and the returned future is dropped, so handlers can use the
+    /// deadline to skip work that would be wasted. By default, the deadline is ignored, and the
+    /// request is handled by `handle_request_zero_copy`.
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        _deadline: Option<Instant>,
+    ) -> Self::RequestFuture {
+        self.handle_request_zero_copy(method, params)
+    }
+
+    /// Handle a `MessagePack-RPC` notification.
+    fn handle_notification(&mut self, method: &str, params: &[Value]) -> Self::NotificationFuture;
+
+    /// Convert an error returned by a future handling a request into the error sent in the
+    /// response. The connection is not affected by such errors. By default, the error is sent as
+    /// an [`RpcError`](struct.RpcError.html) with the `INTERNAL_ERROR` code, and its `Display`
+    /// representation as message.
+    fn map_error(&mut self, error: Self::Error) -> Value {
+        Value::from(RpcError::internal_error(&error.to_string()))
+    }
+
+    /// Return the methods the service handles requests for, if it knows them. They are sent to
+    /// the clients that ask for the capabilities of the endpoint (see
+    /// [`Client::capabilities`](struct.Client.html#method.capabilities)). By default, they are
+    /// unknown.
+    fn methods(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Return the [`Priority`](enum.Priority.html) of the requests for `method`, which decides
+    /// in which order the requests in flight on a connection are answered. By default, all the
+    /// requests have a `Normal` priority.
+    fn priority(&self, _method: &str) -> Priority {
+        Priority::Normal
+    }
+}
+
+/// A [`Service`](trait.Service.html) whose handlers return boxed futures. Every type that
+/// implements `BoxedService` implements `Service`.
+///
+/// This is how services were written before `Service` had associated future types: such services
+/// only need to implement `BoxedService` instead of `Service`.
+pub trait BoxedService {
+    type Error: Error + 'static;
+    type T: Into<Value> + 'static;
+    type E: Into<Value> + 'static;
 
     /// Handle a `MessagePack-RPC` request.
     fn handle_request(
@@ -26,83 +135,572 @@ pub trait Service {
         params: &[Value],
     ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>>;
 
+    /// See [`Service::handle_request_zero_copy`](trait.Service.html#method.handle_request_zero_copy).
+    fn handle_request_zero_copy(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+        let params = params
+            .into_iter()
+            .map(Param::into_value)
+            .collect::<Vec<Value>>();
+        self.handle_request(method, &params)
+    }
+
+    /// See
+    /// [`Service::handle_request_with_deadline`](trait.Service.html#method.handle_request_with_deadline).
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        _deadline: Option<Instant>,
+    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+        self.handle_request_zero_copy(method, params)
+    }
+
     /// Handle a `MessagePack-RPC` notification.
     fn handle_notification(
         &mut self,
         method: &str,
         params: &[Value],
     ) -> Box<Future<Item = (), Error = Self::Error>>;
+
+    /// See [`Service::map_error`](trait.Service.html#method.map_error).
+    fn map_error(&mut self, error: Self::Error) -> Value {
+        Value::from(RpcError::internal_error(&error.to_string()))
+    }
+
+    /// See [`Service::methods`](trait.Service.html#method.methods).
+    fn methods(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// See [`Service::priority`](trait.Service.html#method.priority).
+    fn priority(&self, _method: &str) -> Priority {
+        Priority::Normal
+    }
+}
+
+impl<S: BoxedService> Service for S {
+    type Error = S::Error;
+    type T = S::T;
+    type E = S::E;
+    type RequestFuture = Box<Future<Item = Result<S::T, S::E>, Error = S::Error>>;
+    type NotificationFuture = Box<Future<Item = (), Error = S::Error>>;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
+        BoxedService::handle_request(self, method, params)
+    }
+
+    fn handle_request_zero_copy(&mut self, method: &str, params: Vec<Param>) -> Self::RequestFuture {
+        BoxedService::handle_request_zero_copy(self, method, params)
+    }
+
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        deadline: Option<Instant>,
+    ) -> Self::RequestFuture {
+        BoxedService::handle_request_with_deadline(self, method, params, deadline)
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &[Value]) -> Self::NotificationFuture {
+        BoxedService::handle_notification(self, method, params)
+    }
+
+    fn map_error(&mut self, error: Self::Error) -> Value {
+        BoxedService::map_error(self, error)
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        BoxedService::methods(self)
+    }
+
+    fn priority(&self, method: &str) -> Priority {
+        BoxedService::priority(self, method)
+    }
+}
+
+/// Prefix of the builtin methods, handled by the endpoints themselves instead of their services.
+/// Requests for the builtin methods an endpoint does not know are answered with an
+/// [`RpcError::unsupported_feature`](struct.RpcError.html#method.unsupported_feature) error, and
+/// the notifications are ignored.
+pub(crate) const BUILTIN_PREFIX: &str = "$/";
+
+/// Result of a `$/ping` request, when the endpoint is healthy.
+const HEALTHY: &str = "ok";
+
+/// Error sent in response to a `$/ping` request while the server is draining.
+const DRAINING_ERROR: &str = "server is draining";
+
+/// Error sent in response to a request that has not been handled before its timeout (see
+/// `Limits::request_timeout`).
+const REQUEST_TIMEOUT_ERROR: &str = "request timed out";
+
+/// Error sent in response to a request that exceeds the quotas of the principal of the connection
+/// (see `Limits::principal_max_in_flight` and `Limits::principal_rate`).
+const QUOTA_EXCEEDED_ERROR: &str = "quota exceeded";
+
+/// Error returned to the client for a request that cannot be sent because all the request ids
+/// are used by requests in flight (see `ProtocolOptions::max_request_id`).
+const REQUEST_IDS_EXHAUSTED_ERROR: &str = "too many requests in flight";
+
+/// Error sent in response to a request whose spawned handler was dropped by the reactor before
+/// completing (see `ProtocolOptions::spawn_handlers`).
+const REQUEST_ABANDONED_ERROR: &str = "request abandoned";
+
+/// What is attached to a request while it is in flight. Everything is released when the request
+/// is answered or abandoned.
+struct InFlight {
+    deadline: Option<Instant>,
+    timeout: Option<Timer>,
+    // Released when the request is not in flight anymore.
+    _permit: Option<QuotaPermit>,
+    audit: Option<PendingAudit>,
+    metrics: Option<PendingMetrics>,
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+}
+
+impl InFlight {
+    /// Report how the request ended to the audit log and to the metrics.
+    fn finish(&mut self, outcome: AuditOutcome) {
+        if let Some(audit) = self.audit.take() {
+            audit.finish(outcome);
+        }
+        if let Some(metrics) = self.metrics.take() {
+            metrics.finish(outcome);
+        }
+    }
 }
 
-struct Server<S: Service> {
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        // Requests are dropped before completion when the connection is closed.
+        self.finish(AuditOutcome::Abandoned);
+    }
+}
+
+/// A future handling a request, tagged with the id of the request it answers. When it completes,
+/// it yields this id along with the result of the request, or along with the error if it fails.
+struct RequestTask<F: Future> {
+    id: Id,
+    inner: Handler<F>,
+    in_flight: InFlight,
+}
+
+impl<F, T, E> Future for RequestTask<F>
+where
+    F: Future<Item = Result<T, E>>,
+    T: Into<Value>,
+    E: Into<Value>,
+{
+    type Item = (Id, Result<Value, Value>);
+    type Error = (Id, F::Error);
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        #[cfg(feature = "tracing")]
+        let span = self.in_flight.span.clone();
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        match self.inner.poll() {
+            Ok(Async::Ready(None)) => {
+                warn!("The handler of request {} was dropped", self.id);
+                self.in_flight.finish(AuditOutcome::Error);
+                return Ok(Async::Ready((self.id, Err(Value::from(REQUEST_ABANDONED_ERROR)))));
+            }
+            Ok(Async::Ready(Some(result))) => {
+                let result = result.map(|v| v.into()).map_err(|e| e.into());
+                self.in_flight.finish(if result.is_ok() {
+                    AuditOutcome::Success
+                } else {
+                    AuditOutcome::Error
+                });
+                return Ok(Async::Ready((self.id, result)));
+            }
+            Ok(Async::NotReady) => {}
+            Err(e) => {
+                self.in_flight.finish(AuditOutcome::Error);
+                return Err((self.id, e));
+            }
+        }
+        let timed_out = match self.in_flight.timeout {
+            Some(ref mut timeout) => match timeout.poll() {
+                Ok(Async::Ready(())) => true,
+                Ok(Async::NotReady) => false,
+                Err(e) => {
+                    warn!("Timer error for request {}: {}", self.id, e);
+                    false
+                }
+            },
+            None => false,
+        };
+        if timed_out {
+            warn!("Request {} timed out", self.id);
+            self.in_flight.finish(AuditOutcome::TimedOut);
+            return Ok(Async::Ready((self.id, Err(Value::from(REQUEST_TIMEOUT_ERROR)))));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A future returned by a service. It is either polled by the connection along with the other
+/// handlers, or spawned on the reactor, in which case the connection only waits for its outcome.
+/// It yields `None` if a spawned future was dropped before completing.
+enum Handler<F: Future> {
+    Inline(F),
+    Spawned(oneshot::Receiver<Result<F::Item, F::Error>>),
+}
+
+impl<F: Future + 'static> Handler<F> {
+    /// Spawn `future` on `reactor` if there is one, or keep it to poll it inline otherwise.
+    fn new(future: F, reactor: Option<&Handle>) -> Self {
+        match reactor {
+            Some(reactor) => {
+                let (tx, rx) = oneshot::channel();
+                reactor.spawn(Spawned {
+                    inner: future,
+                    tx: Some(tx),
+                    #[cfg(feature = "tracing")]
+                    span: ::tracing::Span::current(),
+                });
+                Handler::Spawned(rx)
+            }
+            None => Handler::Inline(future),
+        }
+    }
+}
+
+impl<F: Future> Future for Handler<F> {
+    type Item = Option<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match *self {
+            Handler::Inline(ref mut inner) => match inner.poll()? {
+                Async::Ready(item) => Ok(Async::Ready(Some(item))),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+            Handler::Spawned(ref mut rx) => match rx.poll() {
+                Ok(Async::Ready(Ok(item))) => Ok(Async::Ready(Some(item))),
+                Ok(Async::Ready(Err(e))) => Err(e),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(oneshot::Canceled) => Ok(Async::Ready(None)),
+            },
+        }
+    }
+}
+
+/// A handler spawned on the reactor. It sends its outcome back to the connection, and is dropped
+/// early if the connection does not wait for it anymore, because the request timed out or the
+/// connection was closed.
+struct Spawned<F: Future> {
+    inner: F,
+    tx: Option<oneshot::Sender<Result<F::Item, F::Error>>>,
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+}
+
+impl<F: Future> Future for Spawned<F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
+        let result = match self.inner.poll() {
+            Ok(Async::Ready(item)) => Ok(item),
+            Ok(Async::NotReady) => {
+                let canceled = match self.tx {
+                    Some(ref mut tx) => tx.poll_cancel() != Ok(Async::NotReady),
+                    None => true,
+                };
+                if canceled {
+                    trace!("Nobody waits for the handler anymore, dropping it");
+                    return Ok(Async::Ready(()));
+                }
+                return Ok(Async::NotReady);
+            }
+            Err(e) => Err(e),
+        };
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(result);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+struct InnerServer<S: Service> {
     service: S,
-    request_tasks: HashMap<u32, Box<Future<Item = Result<S::T, S::E>, Error = S::Error>>>,
-    notification_tasks: Vec<Box<Future<Item = (), Error = S::Error>>>,
+    // Only the tasks that have been notified are polled, so the cost of polling these sets does
+    // not grow with the number of requests and notifications in flight.
+    request_tasks: PrioritizedTasks<RequestTask<S::RequestFuture>>,
+    notification_tasks: FuturesUnordered<Handler<S::NotificationFuture>>,
+    ordered_responses: bool,
+    // Each request gets a slot in the order of the responses when it is received. Requests may
+    // share an id, so the slots are numbered in the order the requests arrive.
+    next_slot: u64,
+    // Slots of the requests that have not been answered yet, in the order they were received.
+    // This is only used if responses must be sent in order.
+    response_order: VecDeque<u64>,
+    // Responses that are ready but wait for the responses to earlier requests to be sent first.
+    buffered_responses: HashMap<u64, MsgPackResponse>,
+    // Ids of the requests being handled, and their slot.
+    in_flight_ids: HashMap<Id, u64>,
+    // Requests waiting for the request with the same id to be answered, oldest first, along with
+    // whether they exceeded the rate limits when they were received, and their slot.
+    deferred_requests: HashMap<Id, VecDeque<(Request, bool, u64)>>,
+    // Deferred requests whose turn has come, to be handled by the endpoint.
+    ready_requests: VecDeque<(Request, bool, u64)>,
 }
 
-impl<S: Service> Server<S> {
-    fn new(service: S) -> Self {
-        Server {
+impl<S: Service> InnerServer<S> {
+    fn new(service: S, options: &ProtocolOptions) -> Self {
+        InnerServer {
             service: service,
-            request_tasks: HashMap::new(),
-            notification_tasks: Vec::new(),
+            request_tasks: PrioritizedTasks::new(),
+            notification_tasks: FuturesUnordered::new(),
+            ordered_responses: options.has_ordered_responses(),
+            next_slot: 0,
+            response_order: VecDeque::new(),
+            buffered_responses: HashMap::new(),
+            in_flight_ids: HashMap::new(),
+            deferred_requests: HashMap::new(),
+            ready_requests: VecDeque::new(),
+        }
+    }
+
+    /// Return `true` if a request with the given id is being handled.
+    fn is_in_flight(&self, id: Id) -> bool {
+        self.in_flight_ids.contains_key(&id)
+    }
+
+    /// Return the slot of a request that was just received, in the order of the responses.
+    fn reserve_slot(&mut self) -> u64 {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        if self.ordered_responses {
+            self.response_order.push_back(slot);
         }
+        slot
+    }
+
+    /// Handle a request once the request with the same id has been answered.
+    fn defer_request(&mut self, request: Request, rate_limited: bool, slot: u64) {
+        self.deferred_requests
+            .entry(request.id)
+            .or_insert_with(VecDeque::new)
+            .push_back((request, rate_limited, slot));
+    }
+
+    /// Forget about a request that has been answered, and let the next request with the same id
+    /// be handled, if any. Return the slot of the request.
+    fn request_done(&mut self, id: Id) -> Option<u64> {
+        let slot = self.in_flight_ids.remove(&id);
+        let next = match self.deferred_requests.get_mut(&id) {
+            Some(deferred) => deferred.pop_front(),
+            None => return slot,
+        };
+        if let Some(request) = next {
+            self.ready_requests.push_back(request);
+        }
+        if self.deferred_requests[&id].is_empty() {
+            let _ = self.deferred_requests.remove(&id);
+        }
+        slot
+    }
+
+    /// Return the deferred requests that can now be handled.
+    fn take_ready_requests(&mut self) -> VecDeque<(Request, bool, u64)> {
+        mem::replace(&mut self.ready_requests, VecDeque::new())
+    }
+
+    /// Return `true` if no request or notification is being handled.
+    fn is_idle(&self) -> bool {
+        self.request_tasks.is_empty() && self.notification_tasks.is_empty()
     }
 
     fn poll_notification_tasks(&mut self) {
         trace!("Polling pending notification tasks");
-        let mut done = vec![];
-        for (idx, task) in self.notification_tasks.iter_mut().enumerate() {
-            match task.poll().unwrap() {
-                Async::Ready(_) => done.push(idx),
-                Async::NotReady => continue,
+        // When the set is empty, `poll` returns `Ready(None)`. A failed task is removed from the
+        // set, which can be polled again.
+        loop {
+            match self.notification_tasks.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(e) => warn!("Failed to handle a notification: {}", e),
             }
         }
-        for idx in done.iter().rev() {
-            self.notification_tasks.remove(*idx);
-        }
     }
 
-    fn poll_request_tasks<T: AsyncRead + AsyncWrite>(&mut self, stream: &mut Transport<T>) {
+    /// Send the responses of the requests that completed, by decreasing priority. Return `false`
+    /// if some may be left in their tasks because the stream is congested, or because only a few
+    /// low priority requests are answered at a time.
+    ///
+    /// The notifications queued by the `client` of the endpoint are sent before each response, so
+    /// that the notifications a handler sends while it works, to report its progress for
+    /// instance, reach the remote endpoint before its response.
+    fn poll_request_tasks<T: AsyncRead + AsyncWrite>(
+        &mut self,
+        stream: &mut Transport<T>,
+        mut client: Option<&mut InnerClient>,
+    ) -> bool {
         trace!("Polling pending requests");
-        let mut done = vec![];
-        for (id, task) in &mut self.request_tasks {
-            match task.poll().unwrap() {
-                Async::Ready(response) => {
-                    let msg = Message::Response(MsgPackResponse {
-                        id: *id,
-                        result: response.map(|v| v.into()).map_err(|e| e.into()),
-                    });
-                    done.push(*id);
-                    stream.send(msg);
+        // When the set is empty, `poll` returns `Ready(None)`. A failed task is removed from the
+        // set, which can be polled again.
+        let mut done = true;
+        self.request_tasks.start_round();
+        loop {
+            if stream.is_congested() {
+                // Leave the responses in their tasks until the remote endpoint reads the queued
+                // bytes, so that a slow reader does not make them pile up in memory.
+                trace!("Too many bytes waiting to be written, not sending responses anymore");
+                done = false;
+                break;
+            }
+            let (id, result) = match self.request_tasks.poll() {
+                Ok(Async::Ready(Some((id, result)))) => (id, result),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err((id, e)) => {
+                    warn!("Failed to handle request {}: {}", id, e);
+                    (id, Err(self.service.map_error(e)))
+                }
+            };
+            let slot = self.request_done(id);
+            if let Some(ref mut client) = client {
+                client.process_notifications(stream);
+            }
+            let response = MsgPackResponse {
+                id: id,
+                result: result,
+            };
+            if let (true, Some(slot)) = (self.ordered_responses, slot) {
+                let _ = self.buffered_responses.insert(slot, response);
+            } else {
+                stream.send(Message::Response(response));
+            }
+        }
+
+        if self.request_tasks.is_throttled() {
+            done = false;
+        }
+        if self.ordered_responses {
+            self.send_ordered_responses(stream);
+        }
+        done
+    }
+
+    fn send_ordered_responses<T: AsyncRead + AsyncWrite>(&mut self, stream: &mut Transport<T>) {
+        while let Some(slot) = self.response_order.front().cloned() {
+            match self.buffered_responses.remove(&slot) {
+                Some(response) => {
+                    let _ = self.response_order.pop_front();
+                    stream.send(Message::Response(response));
+                }
+                None => {
+                    trace!("Response number {} is not ready yet", slot);
+                    break;
                 }
-                Async::NotReady => continue,
             }
         }
+    }
+
+    /// Handle a request. Its handler is spawned on `reactor` if one is given.
+    fn process_request(
+        &mut self,
+        request: Request,
+        slot: u64,
+        in_flight: InFlight,
+        reactor: Option<&Handle>,
+    ) {
+        let priority = self.service.priority(&request.method);
+        let response = {
+            #[cfg(feature = "tracing")]
+            let _enter = in_flight.span.enter();
+            let method = request.method.as_str();
+            let response = self.service
+                .handle_request_with_deadline(method, request.params, in_flight.deadline);
+            Handler::new(response, reactor)
+        };
+        let _ = self.in_flight_ids.insert(request.id, slot);
+        let task = RequestTask {
+            id: request.id,
+            inner: response,
+            in_flight: in_flight,
+        };
+        self.request_tasks.push(task, priority);
+    }
 
-        for idx in done.iter_mut().rev() {
-            let _ = self.request_tasks.remove(idx);
+    /// Return `true` if there are `max_in_flight` requests or more in flight. The deferred
+    /// requests count as in flight.
+    fn is_saturated(&self, limits: &Limits) -> bool {
+        match limits.get_max_in_flight() {
+            Some(max) => {
+                let deferred = self.deferred_requests
+                    .values()
+                    .map(VecDeque::len)
+                    .sum::<usize>();
+                self.request_tasks.len() + deferred + self.ready_requests.len() >= max
+            }
+            None => false,
         }
     }
 
-    fn process_request(&mut self, request: Request) {
-        let method = request.method.as_str();
-        let params = request.params;
-        let response = self.service.handle_request(method, &params);
-        self.request_tasks.insert(request.id, response);
+    /// Answer a request with an error, without handling it.
+    fn reject_request<T: AsyncRead + AsyncWrite>(
+        &mut self,
+        request: Request,
+        slot: u64,
+        error: Value,
+        stream: &mut Transport<T>,
+    ) {
+        let response = MsgPackResponse {
+            id: request.id,
+            result: Err(error),
+        };
+        if self.ordered_responses {
+            let _ = self.buffered_responses.insert(slot, response);
+            self.send_ordered_responses(stream);
+        } else {
+            stream.send(Message::Response(response));
+        }
     }
 
-    fn process_notification(&mut self, notification: Notification) {
+    /// Handle a notification. Its handler is spawned on `reactor` if one is given.
+    fn process_notification(&mut self, notification: Notification, reactor: Option<&Handle>) {
         let method = notification.method.as_str();
         let params = notification.params;
         let task = self.service.handle_notification(method, &params);
-        self.notification_tasks.push(task);
+        self.notification_tasks.push(Handler::new(task, reactor));
     }
 }
 
 type ResponseTx = oneshot::Sender<Result<Value, Value>>;
-/// Future response to a request. It resolved once the response is available.
-pub struct Response(oneshot::Receiver<Result<Value, Value>>);
+/// Future response to a request. It resolved once the response is available. It fails with a
+/// [`CallError`](enum.CallError.html) telling why if the connection is closed before.
+pub struct Response {
+    rx: oneshot::Receiver<Result<Value, Value>>,
+    // Tells why the connection was closed if the response never comes.
+    context: Context,
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+}
+
+impl Response {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn new(rx: oneshot::Receiver<Result<Value, Value>>, method: &str, context: &Context) -> Self {
+        Response {
+            rx: rx,
+            context: context.clone(),
+            #[cfg(feature = "tracing")]
+            span: info_span!("call", method = %method, peer = ?context.peer_addr()),
+        }
+    }
+}
 
 type AckTx = oneshot::Sender<()>;
 
@@ -110,18 +708,73 @@ type AckTx = oneshot::Sender<()>;
 /// guarantees that the server receives it, just that it has been sent.
 pub struct Ack(oneshot::Receiver<()>);
 
-type RequestTx = mpsc::UnboundedSender<(Request, ResponseTx)>;
-type RequestRx = mpsc::UnboundedReceiver<(Request, ResponseTx)>;
+/// Requests sent by a `Client` to the endpoint.
+enum OutgoingRequests {
+    Single(Request, ResponseTx),
+    // Requests that must be sent together.
+    Batch(Vec<(Request, ResponseTx)>),
+    // A request whose result may be received in chunks.
+    Streamed(Request, ResponseTx, ChunkTx),
+}
+
+type ChunkTx = mpsc::UnboundedSender<Value>;
+
+type RequestTx = mpsc::UnboundedSender<OutgoingRequests>;
+type RequestRx = mpsc::UnboundedReceiver<OutgoingRequests>;
+
+type QueueTx = mpsc::UnboundedSender<RequestRx>;
+type QueueRx = mpsc::UnboundedReceiver<RequestRx>;
+
+/// Maximum number of requests taken from the queue of a `Client` handle before moving on to the
+/// queue of the next handle.
+const REQUEST_BURST: usize = 8;
+
+/// What happened when requests were taken from the queue of a `Client` handle.
+enum QueueState {
+    // All the requests of the queue have been taken.
+    Idle,
+    // The queue may still have requests.
+    Busy,
+    // The handle has been dropped, and all its requests have been taken.
+    Closed,
+}
 
 type NotificationTx = mpsc::UnboundedSender<(Notification, AckTx)>;
 type NotificationRx = mpsc::UnboundedReceiver<(Notification, AckTx)>;
 
+type SubscriberTx = mpsc::UnboundedSender<Notification>;
+type SubscriptionTx = mpsc::UnboundedSender<SubscriberTx>;
+type SubscriptionRx = mpsc::UnboundedReceiver<SubscriberTx>;
+
+type AnomalyTx = mpsc::UnboundedSender<Anomaly>;
+type AnomalySubscriptionTx = mpsc::UnboundedSender<AnomalyTx>;
+type AnomalySubscriptionRx = mpsc::UnboundedReceiver<AnomalyTx>;
+
+/// A stream of the notifications sent by the remote endpoint (see
+/// [`Client::notifications`](struct.Client.html#method.notifications)). The stream ends when the
+/// connection is closed.
+pub struct Notifications(mpsc::UnboundedReceiver<Notification>);
+
+impl Stream for Notifications {
+    type Item = Notification;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
 impl Future for Response {
     type Item = Result<Value, Value>;
-    type Error = ();
+    type Error = CallError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll().map_err(|_| ())
+        #[cfg(feature = "tracing")]
+        let _enter = self.span.enter();
+        let context = &self.context;
+        self.rx
+            .poll()
+            .map_err(|_| context.close_error().unwrap_or(CallError::ConnectionClosed))
     }
 }
 
@@ -136,97 +789,318 @@ impl Future for Ack {
 
 struct InnerClient {
     shutting_down: bool,
+    // Last id generated for a request. Ids wrap around after `max_request_id`, skipping those
+    // still in use.
     request_id: u32,
-    requests_rx: RequestRx,
+    max_request_id: u32,
+    // Receives the request queues of the new `Client` handles.
+    queues_rx: QueueRx,
+    queues_closed: bool,
+    // The request queues of the `Client` handles, in the order they are served.
+    queues: VecDeque<RequestRx>,
     notifications_rx: NotificationRx,
-    pending_requests: HashMap<u32, ResponseTx>,
+    subscriptions_rx: SubscriptionRx,
+    pending_requests: HashMap<Id, ResponseTx>,
+    // The requests in flight whose result may be received in chunks.
+    streams: HashMap<Id, ChunkTx>,
     pending_notifications: Vec<AckTx>,
+    subscribers: Vec<SubscriberTx>,
+    connection_id: usize,
+    anomaly_handler: Option<Arc<AnomalyHandler>>,
+    anomaly_subscriptions_rx: AnomalySubscriptionRx,
+    anomaly_subscribers: Vec<AnomalyTx>,
+    // What happens to the responses to unknown requests.
+    unexpected_responses: ProtocolPolicy,
 }
 
 impl InnerClient {
-    fn new() -> (Self, Client) {
+    fn new(
+        context: Context,
+        max_request_id: u32,
+        anomaly_handler: Option<Arc<AnomalyHandler>>,
+    ) -> (Self, Client) {
         let (requests_tx, requests_rx) = mpsc::unbounded();
+        let (queues_tx, queues_rx) = mpsc::unbounded();
         let (notifications_tx, notifications_rx) = mpsc::unbounded();
+        let (subscriptions_tx, subscriptions_rx) = mpsc::unbounded();
+        let (anomaly_subscriptions_tx, anomaly_subscriptions_rx) = mpsc::unbounded();
+        let connection_id = context.connection_id();
 
-        let client_proxy = Client::new(requests_tx, notifications_tx);
+        let client_proxy = Client {
+            requests_tx: requests_tx,
+            queues_tx: queues_tx,
+            notifications_tx: notifications_tx,
+            subscriptions_tx: subscriptions_tx,
+            anomaly_subscriptions_tx: anomaly_subscriptions_tx,
+            context: context,
+        };
 
+        let mut queues = VecDeque::new();
+        queues.push_back(requests_rx);
         let client = InnerClient {
             shutting_down: false,
             request_id: 0,
-            requests_rx: requests_rx,
+            max_request_id: max_request_id,
+            queues_rx: queues_rx,
+            queues_closed: false,
+            queues: queues,
             notifications_rx: notifications_rx,
+            subscriptions_rx: subscriptions_rx,
             pending_requests: HashMap::new(),
+            streams: HashMap::new(),
             pending_notifications: Vec::new(),
+            subscribers: Vec::new(),
+            connection_id: connection_id,
+            anomaly_handler: anomaly_handler,
+            anomaly_subscriptions_rx: anomaly_subscriptions_rx,
+            anomaly_subscribers: Vec::new(),
+            unexpected_responses: ProtocolPolicy::CloseConnection,
         };
 
         (client, client_proxy)
     }
 
+    fn has_pending_requests(&self) -> bool {
+        !self.pending_requests.is_empty()
+    }
+
     fn shutdown(&mut self) {
         trace!("Shutting down inner client");
         self.shutting_down = true;
     }
 
-    fn is_shutting_down(&self) -> bool {
-        self.shutting_down
+    /// Return `true` once all the `Client` handles have been dropped, and the requests and
+    /// notifications they sent have been answered or acknowledged.
+    fn is_done(&self) -> bool {
+        self.shutting_down && self.pending_requests.is_empty()
+            && self.pending_notifications.is_empty()
     }
 
     fn process_notifications<T: AsyncRead + AsyncWrite>(&mut self, stream: &mut Transport<T>) {
         trace!("Polling client notifications channel");
-        match self.notifications_rx.poll() {
-            Ok(Async::Ready(Some((notification, ack_sender)))) => {
-                trace!("Got notification from client.");
-                stream.send(Message::Notification(notification));
-                self.pending_notifications.push(ack_sender);
+        // Drain the channel: it only notifies the current task again once it returned NotReady.
+        loop {
+            match self.notifications_rx.poll() {
+                Ok(Async::Ready(Some((notification, ack_sender)))) => {
+                    trace!("Got notification from client.");
+                    stream.send(Message::Notification(notification));
+                    self.pending_notifications.push(ack_sender);
+                }
+                Ok(Async::NotReady) => {
+                    trace!("No new notification from client");
+                    break;
+                }
+                Ok(Async::Ready(None)) => {
+                    trace!("Client closed the notifications channel.");
+                    self.shutdown();
+                    break;
+                }
+                Err(()) => {
+                    // I have no idea how this should be handled.
+                    // The documentation does not tell what may trigger an error.
+                    panic!("An error occured while polling the notifications channel.")
+                }
             }
-            Ok(Async::NotReady) => trace!("No new notification from client"),
-            Ok(Async::Ready(None)) => {
-                trace!("Client closed the notifications channel.");
-                self.shutdown();
+        }
+    }
+
+    fn process_queues(&mut self) {
+        trace!("Polling client queues channel");
+        // Drain the channel: it only notifies the current task again once it returned NotReady.
+        while !self.queues_closed {
+            match self.queues_rx.poll() {
+                Ok(Async::Ready(Some(queue))) => self.queues.push_back(queue),
+                Ok(Async::Ready(None)) => {
+                    trace!("All the client handles have been dropped.");
+                    self.queues_closed = true;
+                }
+                Ok(Async::NotReady) => break,
+                Err(()) => panic!("An error occured while polling the queues channel"),
+            }
+        }
+    }
+
+    /// Send the requests of the `Client` handles. The queues of the handles are served in turn, a
+    /// few requests at a time, so that a handle that sends many requests does not delay the
+    /// requests of the others. Return `false` if requests are left in the queues because the
+    /// stream is congested.
+    fn process_requests<T: AsyncRead + AsyncWrite>(&mut self, stream: &mut Transport<T>) -> bool {
+        trace!("Polling client requests queues");
+        self.process_queues();
+        // Serve the queues until they are all drained: each queue only notifies the current task
+        // again once it returned NotReady.
+        let mut done = true;
+        loop {
+            let mut busy = false;
+            for _ in 0..self.queues.len() {
+                if stream.is_congested() {
+                    trace!("Too many bytes waiting to be written, not sending requests anymore");
+                    done = false;
+                    break;
+                }
+                let mut queue = self.queues.pop_front().unwrap();
+                match self.process_queue(&mut queue, stream) {
+                    QueueState::Idle => self.queues.push_back(queue),
+                    QueueState::Busy => {
+                        busy = true;
+                        self.queues.push_back(queue);
+                    }
+                    QueueState::Closed => {}
+                }
             }
-            Err(()) => {
-                // I have no idea how this should be handled.
-                // The documentation does not tell what may trigger an error.
-                panic!("An error occured while polling the notifications channel.")
+            if !busy || !done {
+                break;
             }
         }
+        if self.queues_closed && self.queues.is_empty() {
+            trace!("Client closed the requests channels.");
+            self.shutdown();
+        }
+        done
     }
 
-    fn process_requests<T: AsyncRead + AsyncWrite>(&mut self, stream: &mut Transport<T>) {
-        trace!("Polling client requests channel");
-        match self.requests_rx.poll() {
-            Ok(Async::Ready(Some((mut request, response_sender)))) => {
-                self.request_id += 1;
-                trace!("Got request from client: {:?}", request);
-                request.id = self.request_id;
-                stream.send(Message::Request(request));
-                self.pending_requests
-                    .insert(self.request_id, response_sender);
+    fn process_queue<T: AsyncRead + AsyncWrite>(
+        &mut self,
+        queue: &mut RequestRx,
+        stream: &mut Transport<T>,
+    ) -> QueueState {
+        for _ in 0..REQUEST_BURST {
+            match queue.poll() {
+                Ok(Async::Ready(Some(OutgoingRequests::Single(request, response_sender)))) => {
+                    self.send_request(stream, request, response_sender, None);
+                }
+                Ok(Async::Ready(Some(OutgoingRequests::Batch(requests)))) => {
+                    trace!("Got a batch of {} requests from client", requests.len());
+                    for (request, response_sender) in requests {
+                        self.send_request(stream, request, response_sender, None);
+                    }
+                }
+                Ok(Async::Ready(Some(OutgoingRequests::Streamed(request, sender, chunks)))) => {
+                    self.send_request(stream, request, sender, Some(chunks));
+                }
+                Ok(Async::Ready(None)) => return QueueState::Closed,
+                Ok(Async::NotReady) => return QueueState::Idle,
+                Err(()) => {
+                    // I have no idea how this should be handled.
+                    // The documentation does not tell what may trigger an error.
+                    panic!("An error occured while polling the requests channel");
+                }
             }
-            Ok(Async::Ready(None)) => {
-                trace!("Client closed the requests channel.");
-                self.shutdown();
+        }
+        QueueState::Busy
+    }
+
+    fn send_request<T: AsyncRead + AsyncWrite>(
+        &mut self,
+        stream: &mut Transport<T>,
+        mut request: Request,
+        response_sender: ResponseTx,
+        chunks: Option<ChunkTx>,
+    ) {
+        request.id = match self.next_request_id() {
+            Some(id) => id,
+            None => {
+                warn!("All the request ids are in use. Failing request to {}.", request.method);
+                let _ = response_sender.send(Err(Value::from(REQUEST_IDS_EXHAUSTED_ERROR)));
+                return;
             }
-            Ok(Async::NotReady) => trace!("No new request from client"),
-            Err(()) => {
-                // I have no idea how this should be handled.
-                // The documentation does not tell what may trigger an error.
-                panic!("An error occured while polling the requests channel");
+        };
+        trace!("Got request from client: {:?}", stream.redactions().request(&request));
+        self.pending_requests.insert(request.id, response_sender);
+        if let Some(chunks) = chunks {
+            let _ = self.streams.insert(request.id, chunks);
+        }
+        stream.send(Message::Request(request));
+    }
+
+    /// Return an id that is not used by a request in flight, or `None` if they all are.
+    fn next_request_id(&mut self) -> Option<Id> {
+        if self.pending_requests.len() as u64 > u64::from(self.max_request_id) {
+            return None;
+        }
+        loop {
+            self.request_id = if self.request_id >= self.max_request_id {
+                0
+            } else {
+                self.request_id + 1
+            };
+            let id = Id::from(self.request_id);
+            if !self.pending_requests.contains_key(&id) {
+                return Some(id);
             }
         }
     }
 
-    fn process_response(&mut self, response: MsgPackResponse) {
-        if self.is_shutting_down() {
+    fn process_subscriptions(&mut self) {
+        trace!("Polling client subscriptions channel");
+        while let Ok(Async::Ready(Some(subscriber))) = self.subscriptions_rx.poll() {
+            trace!("New subscriber for incoming notifications.");
+            self.subscribers.push(subscriber);
+        }
+        while let Ok(Async::Ready(Some(subscriber))) = self.anomaly_subscriptions_rx.poll() {
+            trace!("New subscriber for anomalies.");
+            self.anomaly_subscribers.push(subscriber);
+        }
+    }
+
+    /// Report an anomaly to the handler and to the subscribers, and forget about the subscribers
+    /// who are gone.
+    fn report_anomaly(&mut self, anomaly: Anomaly) {
+        warn!("Protocol anomaly on connection {}: {}", self.connection_id, anomaly);
+        if let Some(ref handler) = self.anomaly_handler {
+            handler.on_anomaly(self.connection_id, &anomaly);
+        }
+        self.anomaly_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(anomaly.clone()).is_ok());
+    }
+
+    fn process_notification(&mut self, notification: Notification) {
+        if self.subscribers.is_empty() {
+            trace!("No subscriber for incoming notifications. Ignoring it.");
             return;
         }
-        if let Some(response_tx) = self.pending_requests.remove(&response.id) {
-            trace!("Forwarding response to the client.");
-            if let Err(e) = response_tx.send(response.result) {
-                warn!("Failed to send response to client: {:?}", e);
+        // Forward the notification to all the subscribers, and forget about those who are gone.
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(notification.clone()).is_ok());
+    }
+
+    /// Forward a chunk of a streamed result to the request it belongs to.
+    fn process_chunk(&mut self, notification: Notification) {
+        let id = notification.method[STREAM_PREFIX.len()..]
+            .trim_right_matches("/chunk")
+            .parse::<u64>();
+        let chunks = match id {
+            Ok(id) => self.streams.get(&Id::Unsigned(id)),
+            Err(_) => None,
+        };
+        match chunks {
+            Some(chunks) => {
+                let chunk = notification.params.into_iter().next().unwrap_or(Value::Nil);
+                let _ = chunks.unbounded_send(chunk);
+            }
+            None => self.report_anomaly(Anomaly::UnknownChunk(notification.method)),
+        }
+    }
+
+    /// Forward a response to the request it answers. A response to a request that is not in
+    /// flight means that the remote endpoint mixed up the ids, so the responses that follow
+    /// cannot be trusted either: an error is returned, and the connection is closed. Both this and
+    /// the responses to dropped requests are reported as anomalies.
+    fn process_response(&mut self, response: MsgPackResponse) -> io::Result<()> {
+        // The chunks of a streamed result are all received before its response.
+        let _ = self.streams.remove(&response.id);
+        match self.pending_requests.remove(&response.id) {
+            Some(response_tx) => {
+                trace!("Forwarding response to the client.");
+                if response_tx.send(response.result).is_err() {
+                    self.report_anomaly(Anomaly::OrphanResponse(response.id));
+                }
+                Ok(())
+            }
+            None => {
+                self.report_anomaly(Anomaly::UnknownResponse(response.id));
+                unexpected_response(self.unexpected_responses, response.id)
             }
-        } else {
-            warn!("no pending request found for response {}", &response.id);
         }
     }
 
@@ -240,55 +1114,75 @@ impl InnerClient {
     }
 }
 
-pub struct Endpoint<S: Service, T: AsyncRead + AsyncWrite> {
-    stream: RefCell<Transport<T>>,
-    client: Option<RefCell<InnerClient>>,
-    server: Option<RefCell<Server<S>>>,
+/// Return the map of the given named arguments.
+fn named_args(args: &[(&str, Value)]) -> Value {
+    let args = args.iter()
+        .map(|&(name, ref value)| (Value::from(name), value.clone()))
+        .collect();
+    Value::Map(args)
 }
 
-struct Transport<T: AsyncRead + AsyncWrite>(Framed<T, Codec>);
-
-impl<T> Transport<T>
-where
-    T: AsyncRead + AsyncWrite,
-{
-    fn send(&mut self, message: Message) {
-        trace!("Sending {:?}", message);
-        match self.start_send(message) {
-            Ok(AsyncSink::Ready) => return,
-            // FIXME: there should probably be a retry mechanism.
-            Ok(AsyncSink::NotReady(_message)) => panic!("The sink is full."),
-            Err(e) => panic!("An error occured while trying to send message: {:?}", e),
+/// Apply the policy to a response that matches no request: return an error if the connection
+/// must be closed.
+fn unexpected_response(policy: ProtocolPolicy, id: Id) -> io::Result<()> {
+    match policy {
+        ProtocolPolicy::Ignore => Ok(()),
+        ProtocolPolicy::LogAndIgnore => {
+            warn!("Ignoring a response to unknown request {}", id);
+            Ok(())
         }
+        ProtocolPolicy::CloseConnection => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("received a response to unknown request {}", id),
+        )),
     }
 }
 
-impl<T> Stream for Transport<T>
-where
-    T: AsyncRead + AsyncWrite,
-{
-    type Item = Message;
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.0.poll()
-    }
+/// A connection being closed (see `ServerHandle::disconnect`).
+struct Closing {
+    // When the requests in flight are abandoned.
+    deadline: Option<Timer>,
 }
 
-impl<T> Sink for Transport<T>
-where
-    T: AsyncRead + AsyncWrite,
-{
-    type SinkItem = Message;
-    type SinkError = io::Error;
-
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        self.0.start_send(item)
+impl Closing {
+    fn is_expired(&mut self) -> bool {
+        match self.deadline {
+            Some(ref mut deadline) => match deadline.poll() {
+                Ok(Async::Ready(())) => true,
+                Ok(Async::NotReady) => false,
+                Err(e) => {
+                    warn!("Failed to poll the disconnection deadline: {}", e);
+                    true
+                }
+            },
+            None => false,
+        }
     }
+}
 
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.0.poll_complete()
-    }
+pub struct Endpoint<S: Service, T: AsyncRead + AsyncWrite> {
+    stream: RefCell<Transport<T>>,
+    client: Option<RefCell<InnerClient>>,
+    server: Option<RefCell<InnerServer<S>>>,
+    server_handle: Option<ServerHandle>,
+    // Only set if the endpoint belongs to a server.
+    registration: Option<Registration>,
+    // Set once the server asked to close the connection.
+    closing: Option<Closing>,
+    liveness: Option<Liveness>,
+    reactor: Option<Handle>,
+    options: ProtocolOptions,
+    // The limits currently applied. If the endpoint belongs to a server, they are refreshed from
+    // the server each time the endpoint is polled, since they can be changed at runtime.
+    limits: Limits,
+    context: Context,
+    metrics: Option<ConnectionMetrics>,
+    // The features advertised in the hello, reported in the capabilities of the endpoint.
+    features: Vec<String>,
+    // Set until the connection is authenticated.
+    authenticator: Option<Arc<Authenticator>>,
+    // The requests accepted on the connection, if their rate is limited.
+    rate_limit: Option<Bucket>,
 }
 
 impl<S, T> Endpoint<S, T>
@@ -296,69 +1190,552 @@ where
     S: Service,
     T: AsyncRead + AsyncWrite,
 {
-    pub fn new(stream: T) -> Self {
+    #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+    pub fn new(stream: T, options: ProtocolOptions) -> Self {
+        let redactions = options.get_redactions().clone();
+        let context = Context::new(redactions);
+        let metrics = options
+            .get_metrics()
+            .map(|metrics| ConnectionMetrics::new(metrics, context.connection_id()));
+        let mut transport = Transport::with_options(stream, &options);
+        transport.set_metrics(metrics.clone());
+        let mut hello = options.get_hello().cloned();
+        #[cfg(feature = "compression")]
+        {
+            // Compression is negotiated through the hellos.
+            if options.get_compression().is_some() {
+                let _ = hello
+                    .get_or_insert_with(Hello::default)
+                    .add_feature(COMPRESSION_FEATURE);
+            }
+        }
+        let mut features = Vec::new();
+        if let Some(hello) = hello {
+            transport.send_control(Message::Notification(hello.to_notification()));
+            features = hello.features().to_vec();
+        }
         Endpoint {
-            stream: RefCell::new(Transport(stream.framed(Codec))),
+            stream: RefCell::new(transport),
             client: None,
             server: None,
+            server_handle: None,
+            registration: None,
+            closing: None,
+            liveness: Liveness::new(&options),
+            reactor: None,
+            limits: options.get_limits(),
+            authenticator: options.get_authenticator(),
+            rate_limit: None,
+            options: options,
+            context: context,
+            metrics: metrics,
+            features: features,
         }
     }
 
     pub fn set_server(&mut self, service: S) {
-        self.server = Some(RefCell::new(Server::new(service)));
+        self.server = Some(RefCell::new(InnerServer::new(service, &self.options)));
+    }
+
+    /// Attach the endpoint to the server that accepted its connection. The address of the remote
+    /// endpoint, if any, must be set first.
+    pub(crate) fn set_server_handle(&mut self, server_handle: ServerHandle) {
+        let connection = self.context.connection_id();
+        let registration = server_handle.register(connection, self.context.peer_addr());
+        self.registration = Some(registration);
+        self.server_handle = Some(server_handle);
+    }
+
+    /// Set the reactor the endpoint runs on. It is needed to time out requests, and to limit the
+    /// bytes transferred.
+    pub(crate) fn set_reactor(&mut self, reactor: Handle) {
+        self.stream
+            .get_mut()
+            .set_throttles(&self.options, &reactor);
+        self.reactor = Some(reactor);
+    }
+
+    /// Queue a notification, before the messages sent by the client.
+    pub(crate) fn send_notification(&mut self, notification: Notification) {
+        self.stream
+            .get_mut()
+            .send(Message::Notification(notification));
+    }
+
+    /// Record the address of the remote endpoint in the context of the connection.
+    pub(crate) fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.context.set_peer_addr(addr);
+    }
+
+    /// Make each write to the stream carry exactly one message (see
+    /// `Transport::set_message_aligned_writes`).
+    #[cfg(feature = "websocket")]
+    pub(crate) fn set_message_aligned_writes(&mut self) {
+        self.stream.borrow_mut().set_message_aligned_writes();
     }
 
     pub fn set_client(&mut self) -> Client {
-        let (client, client_proxy) = InnerClient::new();
+        let (mut client, client_proxy) = InnerClient::new(
+            self.context.clone(),
+            self.options.get_max_request_id(),
+            self.options.get_anomaly_handler(),
+        );
+        client.unexpected_responses = self.options.get_unexpected_responses();
         self.client = Some(RefCell::new(client));
         client_proxy
     }
 
-    fn handle_message(&mut self, msg: Message) {
-        trace!("Received {:?}", msg);
+    fn handle_message(&mut self, msg: Message) -> io::Result<()> {
+        trace!("Received {:?}", self.context.redactions().message(&msg));
+        if let Some(ref mut liveness) = self.liveness {
+            liveness.received();
+        }
+        #[cfg(feature = "serde-params")]
+        let msg = match self.options.get_ext_registry() {
+            Some(registry) => registry.decode_message(msg),
+            None => msg,
+        };
         match msg {
-            Message::Request(request) => if let Some(ref mut server) = self.server {
-                server.get_mut().process_request(request);
-            } else {
-                trace!("This endpoint does not handle requests. Ignoring it.");
-            },
+            Message::Request(ref request) if request.method == CAPABILITIES_METHOD => {
+                self.send_capabilities(request.id)
+            }
+            Message::Request(ref request)
+                if request.method == PING_METHOD && self.options.has_health_check() =>
+            {
+                self.send_health(request.id)
+            }
+            Message::Request(ref request) if request.method.starts_with(BUILTIN_PREFIX) => {
+                self.send_unsupported(request.id, &request.method)
+            }
+            Message::Request(request) if self.is_authenticating() => self.authenticate(request),
+            Message::Request(request) => self.handle_request(request)?,
+            Message::Notification(ref notification) if notification.method == HELLO_METHOD => {
+                self.process_hello(&notification.params)
+            }
+            Message::Notification(ref notification) if notification.method == PING_METHOD => {
+                trace!("Received a ping from the remote endpoint");
+            }
+            Message::Notification(ref notification) if notification.method == CHANNEL_METHOD => {
+                let channels = self.context.channels();
+                channels.lock().unwrap().receive(&notification.params);
+            }
+            Message::Notification(notification)
+                if notification.method.starts_with(STREAM_PREFIX) =>
+            {
+                if let Some(ref mut client) = self.client {
+                    client.get_mut().process_chunk(notification);
+                }
+            }
+            Message::Notification(ref notification)
+                if notification.method.starts_with(BUILTIN_PREFIX) =>
+            {
+                debug!("Ignoring unsupported notification {}", notification.method);
+            }
+            Message::Notification(ref notification) if self.is_authenticating() => {
+                debug!("Dropping notification {}: not authenticated", notification.method);
+            }
             Message::Notification(notification) => if let Some(ref mut server) = self.server {
-                server.get_mut().process_notification(notification);
+                if let Some(ref metrics) = self.metrics {
+                    metrics.notification_received(&notification.method);
+                }
+                let reactor = if self.options.has_spawned_handlers() {
+                    self.reactor.as_ref()
+                } else {
+                    None
+                };
+                server.get_mut().process_notification(notification, reactor);
+            } else if let Some(ref mut client) = self.client {
+                client.get_mut().process_notification(notification);
             } else {
                 trace!("This endpoint does not handle notifications. Ignoring it.");
             },
             Message::Response(response) => if let Some(ref mut client) = self.client {
-                client.get_mut().process_response(response);
+                client.get_mut().process_response(response)?;
             } else {
-                trace!("This endpoint does not handle responses. Ignoring it.");
+                trace!("This endpoint does not send requests.");
+                let policy = self.options.get_unexpected_responses();
+                unexpected_response(policy, response.id)?;
             },
         }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, request: Request) -> io::Result<()> {
+        // Requests are accounted for in the rate limits when they are received, and not again if
+        // they are deferred.
+        let rate_limited = self.is_rate_limited();
+        self.serve_request(request, rate_limited, None)
+    }
+
+    /// Handle or reject a request. Deferred requests keep the slot in the order of the responses
+    /// they got when they were received.
+    fn serve_request(
+        &mut self,
+        request: Request,
+        rate_limited: bool,
+        slot: Option<u64>,
+    ) -> io::Result<()> {
+        let server = match self.server {
+            Some(ref mut server) => server.get_mut(),
+            None => {
+                trace!("This endpoint does not handle requests. Sending an error back.");
+                let response = MsgPackResponse {
+                    id: request.id,
+                    result: Err(Value::from("This endpoint does not handle requests")),
+                };
+                self.stream
+                    .get_mut()
+                    .send_control(Message::Response(response));
+                return Ok(());
+            }
+        };
+        let slot = match slot {
+            Some(slot) => slot,
+            None => server.reserve_slot(),
+        };
+
+        if server.is_in_flight(request.id) {
+            match self.options.get_duplicate_ids() {
+                DuplicateIdPolicy::Reject => {
+                    warn!("Request {} is already in flight. Rejecting the duplicate.", request.id);
+                    let error = RpcError::invalid_request("duplicate request id");
+                    server.reject_request(request, slot, Value::from(error), self.stream.get_mut());
+                }
+                DuplicateIdPolicy::Queue => {
+                    debug!("Request {} is already in flight. Deferring the duplicate.", request.id);
+                    server.defer_request(request, rate_limited, slot);
+                }
+                DuplicateIdPolicy::Close => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("request {} is already in flight", request.id),
+                    ));
+                }
+            }
+            return Ok(());
+        }
+
+        let principal = self.context.principal();
+        let mut in_flight = InFlight {
+            deadline: None,
+            timeout: None,
+            _permit: None,
+            audit: self.options
+                .get_audit_log()
+                .map(|log| PendingAudit::new(log, principal.clone(), &request)),
+            metrics: self.metrics
+                .as_ref()
+                .map(|metrics| metrics.request(&request.method)),
+            #[cfg(feature = "tracing")]
+            span: info_span!(
+                "request",
+                method = %request.method,
+                id = %request.id,
+                peer = ?self.context.peer_addr()
+            ),
+        };
+
+        let shutdown_error = match self.server_handle {
+            Some(ref handle) if handle.is_draining() => self.options.get_shutdown_error(),
+            _ => None,
+        };
+        if let Some(error) = shutdown_error {
+            trace!("The server is draining. Rejecting request {}.", request.id);
+            server.reject_request(request, slot, error.clone(), self.stream.get_mut());
+            in_flight.finish(AuditOutcome::Rejected);
+            return Ok(());
+        }
+
+        if rate_limited {
+            warn!("Rate limit exceeded. Rejecting request {}.", request.id);
+            let error = Value::from(RpcError::rate_limited());
+            server.reject_request(request, slot, error, self.stream.get_mut());
+            in_flight.finish(AuditOutcome::Rejected);
+            return Ok(());
+        }
+
+        if let (&Some(ref handle), Some(principal)) = (&self.server_handle, principal) {
+            match handle.acquire_quota(&principal, &request) {
+                Some(permit) => in_flight._permit = Some(permit),
+                None => {
+                    warn!("Quota of {} exceeded. Rejecting request {}.", principal, request.id);
+                    let error = Value::from(QUOTA_EXCEEDED_ERROR);
+                    server.reject_request(request, slot, error, self.stream.get_mut());
+                    in_flight.finish(AuditOutcome::Rejected);
+                    return Ok(());
+                }
+            }
+        }
+
+        let timeout = self.options
+            .get_method_timeout(&request.method)
+            .or(self.limits.get_request_timeout());
+        if let Some(timeout) = timeout {
+            let clock = self.options.get_clock();
+            let deadline = clock.now() + timeout;
+            match clock.timer_at(deadline, self.reactor.as_ref()) {
+                Ok(Some(timer)) => {
+                    in_flight.deadline = Some(deadline);
+                    in_flight.timeout = Some(timer);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to create a timer for request {}: {}", request.id, e),
+            }
+        }
+        let reactor = if self.options.has_spawned_handlers() {
+            self.reactor.as_ref()
+        } else {
+            None
+        };
+        // Tell the handlers which request they handle, to stream its result.
+        self.context.set_current_request(Some(request.id));
+        server.process_request(request, slot, in_flight, reactor);
+        self.context.set_current_request(None);
+        Ok(())
+    }
+
+    /// Account for a new request in the rate limits of the connection and of the address it comes
+    /// from. Return `true` if it exceeds one of them.
+    fn is_rate_limited(&mut self) -> bool {
+        match self.limits.get_connection_rate() {
+            Some(rate) => {
+                let bucket = self.rate_limit.get_or_insert_with(|| Bucket::new(rate));
+                bucket.set_rate(rate);
+                if !bucket.try_take() {
+                    return true;
+                }
+            }
+            None => self.rate_limit = None,
+        }
+        match (&self.server_handle, self.context.peer_addr()) {
+            (&Some(ref handle), Some(address)) => !handle.acquire_peer_rate(address.ip()),
+            _ => false,
+        }
     }
 
-    fn flush(&mut self) {
+    /// Return `true` if the requests and notifications are not passed to the service yet,
+    /// because the connection has not been authenticated.
+    fn is_authenticating(&self) -> bool {
+        self.authenticator.is_some() && self.server.is_some()
+    }
+
+    /// Pass the first request of the connection to the authenticator, and close the connection if
+    /// it is rejected.
+    fn authenticate(&mut self, request: Request) {
+        let admission = match self.authenticator {
+            Some(ref authenticator) => authenticator.on_connect(self.context.peer_addr(), &request),
+            None => return,
+        };
+        let id = self.context.connection_id();
+        let (result, outcome) = match admission {
+            Admission::Accept(principal) => {
+                debug!("Connection {} authenticated", id);
+                if let Some(principal) = principal {
+                    self.context.set_principal(&principal);
+                }
+                self.authenticator = None;
+                (Ok(Value::Nil), AuditOutcome::Success)
+            }
+            Admission::Reject(error) => {
+                warn!("Connection {} rejected by the authenticator", id);
+                self.closing = Some(Closing { deadline: None });
+                (Err(error), AuditOutcome::Rejected)
+            }
+        };
+        if let Some(log) = self.options.get_audit_log() {
+            PendingAudit::new(log, self.context.principal(), &request).finish(outcome);
+        }
+        let response = MsgPackResponse {
+            id: request.id,
+            result: result,
+        };
+        self.stream
+            .get_mut()
+            .send_control(Message::Response(response));
+    }
+
+    /// Answer a request for a builtin method this endpoint does not know, which the remote
+    /// endpoint may use to fall back to plain requests.
+    fn send_unsupported(&mut self, id: Id, method: &str) {
+        debug!("Request {} is for unsupported method {}", id, method);
+        let response = MsgPackResponse {
+            id: id,
+            result: Err(Value::from(RpcError::unsupported_feature(method))),
+        };
+        self.stream
+            .get_mut()
+            .send_control(Message::Response(response));
+    }
+
+    /// Answer a `$/capabilities` request.
+    fn send_capabilities(&mut self, id: Id) {
+        let methods = match self.server {
+            // The methods are only told to authenticated peers.
+            Some(_) if self.is_authenticating() => None,
+            Some(ref server) => server.borrow().service.methods(),
+            // This endpoint does not handle any request.
+            None => Some(Vec::new()),
+        };
+        let capabilities = Capabilities::new(self.features.clone(), methods);
+        let response = MsgPackResponse {
+            id: id,
+            result: Ok(capabilities.to_value()),
+        };
+        self.stream
+            .get_mut()
+            .send_control(Message::Response(response));
+    }
+
+    /// Answer a `$/ping` request.
+    fn send_health(&mut self, id: Id) {
+        let result = match self.server_handle {
+            Some(ref handle) if handle.is_draining() => Err(Value::from(DRAINING_ERROR)),
+            _ => Ok(Value::from(HEALTHY)),
+        };
+        let response = MsgPackResponse {
+            id: id,
+            result: result,
+        };
+        self.stream
+            .get_mut()
+            .send_control(Message::Response(response));
+    }
+
+    fn process_hello(&mut self, params: &[Value]) {
+        if self.context.peer_hello().is_some() {
+            warn!("The remote endpoint already sent a hello message. Ignoring it.");
+            return;
+        }
+        match Hello::from_params(params) {
+            Some(hello) => {
+                trace!("Received hello from the remote endpoint: {:?}", hello);
+                #[cfg(feature = "compression")]
+                {
+                    if hello.has_feature(COMPRESSION_FEATURE) {
+                        self.stream.get_mut().enable_compression();
+                    }
+                }
+                self.context.set_peer_hello(hello);
+            }
+            None => warn!("Invalid hello message: {:?}", params),
+        }
+    }
+
+    /// Return `true` if the connection has been idle for too long (see
+    /// `ProtocolOptions::idle_timeout`). Send a ping if one is due.
+    fn is_idle(&mut self) -> io::Result<bool> {
+        // The connection is not idle while requests are in flight.
+        let busy = self.is_busy();
+        let reactor = self.reactor.as_ref();
+        let liveness = match self.liveness.as_mut() {
+            // Without a reactor, only the timers of a manual clock wake the endpoint up.
+            Some(liveness) if reactor.is_some() || self.options.get_clock().is_manual() => liveness,
+            _ => return Ok(false),
+        };
+        if busy {
+            liveness.received();
+        }
+        let (idle, ping) = liveness.poll(reactor)?;
+        if let Some(ping) = ping {
+            self.stream.get_mut().send_control(ping);
+        }
+        Ok(idle)
+    }
+
+    /// Return `true` if requests are in flight, in either direction.
+    fn is_busy(&mut self) -> bool {
+        let serving = match self.server {
+            Some(ref mut server) => !server.get_mut().is_idle(),
+            None => false,
+        };
+        let waiting = match self.client {
+            Some(ref mut client) => client.get_mut().has_pending_requests(),
+            None => false,
+        };
+        serving || waiting
+    }
+
+    /// Stop reading from the connection, and close it once the requests in flight are answered.
+    fn start_closing(&mut self, disconnect: Disconnect) {
+        trace!("Closing connection {}", self.context.connection_id());
+        if let Some(notification) = disconnect.notification {
+            self.stream
+                .get_mut()
+                .send_control(Message::Notification(notification));
+        }
+        let clock = self.options.get_clock();
+        let deadline = clock
+            .timer_at(clock.now() + disconnect.grace, self.reactor.as_ref())
+            .unwrap_or(None);
+        self.closing = Some(Closing { deadline: deadline });
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         trace!("Flushing stream");
-        match self.stream.get_mut().poll_complete() {
-            Ok(Async::Ready(())) => if let Some(ref mut client) = self.client {
+        if let Async::Ready(()) = self.stream.get_mut().poll_complete()? {
+            if let Some(ref mut client) = self.client {
                 client.get_mut().acknowledge_notifications();
-            },
-            Ok(Async::NotReady) => return,
-            Err(e) => panic!("Failed to flush the sink: {:?}", e),
+            }
         }
+        Ok(())
     }
 }
 
-impl<S, T: AsyncRead + AsyncWrite> Future for Endpoint<S, T>
+impl<S, T: AsyncRead + AsyncWrite> Endpoint<S, T>
 where
     S: Service,
 {
-    type Item = ();
-    type Error = io::Error;
+    fn poll_connection(&mut self) -> Poll<(), io::Error> {
+        // Register the new subscribers before reading incoming messages, so that they do not miss
+        // notifications that are already waiting in the stream.
+        if let Some(ref mut client) = self.client {
+            client.get_mut().process_subscriptions();
+        }
+
+        if let Some(ref server_handle) = self.server_handle {
+            self.limits = server_handle.limits();
+        }
+        let (broadcasts, disconnect) = match self.registration {
+            Some(ref registration) if self.closing.is_none() => (
+                registration.take_broadcasts(),
+                registration.poll_disconnect(),
+            ),
+            _ => (Vec::new(), None),
+        };
+        for notification in broadcasts {
+            self.stream
+                .get_mut()
+                .send(Message::Notification(notification));
+        }
+        if let Some(disconnect) = disconnect {
+            self.start_closing(disconnect);
+        }
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         trace!("Polling stream.");
+        let mut budget = self.limits.get_poll_budget();
+        let mut saturated = false;
         loop {
-            match self.stream.get_mut().poll().unwrap() {
-                Async::Ready(Some(msg)) => self.handle_message(msg),
+            if self.closing.is_some() {
+                break;
+            }
+            if let Some(ref server) = self.server {
+                if server.borrow().is_saturated(&self.limits) {
+                    // Stop reading until some of the requests in flight complete.
+                    trace!("Too many requests in flight, not reading anymore.");
+                    saturated = true;
+                    break;
+                }
+            }
+            if budget == 0 {
+                // Do not monopolize the reactor when the remote endpoint sends messages faster
+                // than we can process them: ask to be polled again, and let other tasks run.
+                trace!("Poll budget exhausted, yielding.");
+                task::current().notify();
+                break;
+            }
+            budget -= 1;
+            match self.stream.get_mut().poll()? {
+                Async::Ready(Some(msg)) => self.handle_message(msg)?,
                 Async::Ready(None) => {
                     trace!("Stream closed by remote peer.");
                     // FIXME: not sure if we should still continue sending responses here. Is it
@@ -373,19 +1750,82 @@ where
             }
         }
 
+        let mut congested = false;
         if let Some(ref mut server) = self.server {
             let server = server.get_mut();
-            server.poll_request_tasks(self.stream.get_mut());
+            let client = self.client.as_mut().map(|client| client.get_mut());
+            congested = !server.poll_request_tasks(self.stream.get_mut(), client);
             server.poll_notification_tasks();
+            if saturated && !server.is_saturated(&self.limits) {
+                // Some requests completed: we can read again.
+                task::current().notify();
+            }
+        }
+
+        // The requests deferred because their id was in use can now be handled.
+        let ready = match self.server {
+            Some(ref mut server) => server.get_mut().take_ready_requests(),
+            None => VecDeque::new(),
+        };
+        if !ready.is_empty() {
+            for (request, rate_limited, slot) in ready {
+                self.serve_request(request, rate_limited, Some(slot))?;
+            }
+            task::current().notify();
         }
 
-        let mut client_shutdown: bool = false;
         if let Some(ref mut client) = self.client {
             let client = client.get_mut();
             let stream = self.stream.get_mut();
-            client.process_requests(stream);
+            congested = !client.process_requests(stream) || congested;
             client.process_notifications(stream);
-            if client.is_shutting_down() {
+        }
+
+        if self.stream.get_mut().is_congested() {
+            // The channels are notified once the stream is writable again.
+            congested = true;
+        } else {
+            let messages = self.context.channels().lock().unwrap().poll_messages();
+            for notification in messages {
+                self.stream
+                    .get_mut()
+                    .send(Message::Notification(notification));
+            }
+        }
+
+        if self.is_idle()? {
+            trace!("Connection {} is idle, closing it", self.context.connection_id());
+            self.context.set_close_error(CallError::TimedOut);
+            return Ok(Async::Ready(()));
+        }
+
+        self.flush()?;
+
+        if congested && !self.stream.get_mut().is_congested() {
+            // Enough bytes have been written: the remaining responses and requests can be sent.
+            // Otherwise, the task is notified once the stream is writable again.
+            task::current().notify();
+        }
+
+        if let Some(ref mut closing) = self.closing {
+            let id = self.context.connection_id();
+            if closing.is_expired() {
+                warn!("Closing connection {} with requests in flight", id);
+                return Ok(Async::Ready(()));
+            }
+            let idle = match self.server {
+                Some(ref mut server) => server.get_mut().is_idle(),
+                None => true,
+            };
+            if idle && self.stream.get_mut().poll_complete()?.is_ready() {
+                trace!("Connection {} closed", id);
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        let mut client_shutdown: bool = false;
+        if let Some(ref mut client) = self.client {
+            if client.get_mut().is_done() {
                 trace!("Client shut down, exiting");
                 client_shutdown = true;
             }
@@ -394,54 +1834,278 @@ where
             self.client = None;
         }
 
-        self.flush();
+        // Let the server know whether the connection can be closed if it runs out of file
+        // descriptors.
+        let busy = self.is_busy();
+        if let Some(ref registration) = self.registration {
+            registration.set_idle(!busy);
+        }
 
         trace!("notifying the reactor that we're not done yet");
         Ok(Async::NotReady)
     }
 }
 
-/// A `Service` builder. This trait must be implemented for servers.
+impl<S, T: AsyncRead + AsyncWrite> Future for Endpoint<S, T>
+where
+    S: Service,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_connection();
+        // Tell the requests in flight why they will not get a response.
+        match result {
+            Ok(Async::Ready(())) => self.context.set_close_error(CallError::ConnectionClosed),
+            Err(ref e) => self.context.set_close_error(CallError::from(e)),
+            Ok(Async::NotReady) => return result,
+        }
+        if let (Some(registration), Some(reason)) =
+            (self.registration.as_mut(), self.context.close_error())
+        {
+            registration.set_close_reason(reason);
+        }
+        self.context.channels().lock().unwrap().close();
+        result
+    }
+}
+
+/// A `Service` builder. This trait must be implemented for servers, unless their services are
+/// built by a [`SessionBuilder`](struct.SessionBuilder.html).
 pub trait ServiceBuilder {
     type Service: Service + 'static;
 
+    /// Build the service for a new connection. The `client` can be kept by the service to send
+    /// requests and notifications to the remote endpoint. Its
+    /// [`context`](struct.Client.html#method.context) tells which features the remote endpoint
+    /// supports (see [`Context::peer_features`](struct.Context.html#method.peer_features)).
     fn build(&self, client: Client) -> Self::Service;
+
+    /// Return the methods the services have handlers for, requests and notifications alike. A
+    /// server refuses to start if one of them is in a reserved namespace (see
+    /// [`ServerBuilder::reserve_prefix`](struct.ServerBuilder.html#method.reserve_prefix)). By
+    /// default, they are unknown and not checked.
+    fn registered_methods(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Check that the services are configured consistently. A server refuses to start if this
+    /// fails, so that mistakes are caught at startup rather than by the first requests. By
+    /// default, nothing is checked.
+    fn validate(&self) -> Result<(), RouterError> {
+        Ok(())
+    }
+
+    /// Return the prefixes of the methods that must only be served to authenticated connections.
+    /// A server refuses to start if there are some and its options have no
+    /// [`authenticator`](struct.ProtocolOptions.html#method.authenticator). By default, there are
+    /// none.
+    fn protected_prefixes(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 /// A client that sends requests and notifications to a remote MessagePack-RPC server.
-#[derive(Clone)]
+///
+/// `Client` can be cloned to send requests from several places. Each clone has its own queue of
+/// requests, and the endpoint serves the queues in turn, so that a clone that sends many requests
+/// does not delay the requests of the others.
 pub struct Client {
     requests_tx: RequestTx,
+    queues_tx: QueueTx,
     notifications_tx: NotificationTx,
+    subscriptions_tx: SubscriptionTx,
+    anomaly_subscriptions_tx: AnomalySubscriptionTx,
+    context: Context,
 }
 
-impl Client {
-    fn new(requests_tx: RequestTx, notifications_tx: NotificationTx) -> Self {
+impl Clone for Client {
+    fn clone(&self) -> Self {
+        let (requests_tx, requests_rx) = mpsc::unbounded();
+        // If the endpoint is gone, `requests_rx` is dropped, and the requests sent by the clone
+        // fail immediately.
+        let _ = mpsc::UnboundedSender::unbounded_send(&self.queues_tx, requests_rx);
         Client {
             requests_tx: requests_tx,
-            notifications_tx: notifications_tx,
+            queues_tx: self.queues_tx.clone(),
+            notifications_tx: self.notifications_tx.clone(),
+            subscriptions_tx: self.subscriptions_tx.clone(),
+            anomaly_subscriptions_tx: self.anomaly_subscriptions_tx.clone(),
+            context: self.context.clone(),
+        }
+    }
+}
+
+impl Client {
+    /// Return the context of the connection this client sends messages on.
+    pub fn context(&self) -> Context {
+        self.context.clone()
+    }
+
+    /// Return the [`Capabilities`](struct.Capabilities.html) of the remote endpoint. They are
+    /// requested the first time, and then cached in the context of the connection.
+    pub fn capabilities(&self) -> Box<Future<Item = Capabilities, Error = CallError>> {
+        if let Some(capabilities) = self.context.capabilities() {
+            return Box::new(future::ok(capabilities));
         }
+        let context = self.context.clone();
+        let capabilities = self.request(CAPABILITIES_METHOD, &[])
+            .and_then(move |response| {
+                let capabilities = match response {
+                    Ok(capabilities) => match Capabilities::from_value(&capabilities) {
+                        Some(capabilities) => capabilities,
+                        None => return Err(CallError::Protocol),
+                    },
+                    // The remote endpoint does not know the request. Its hello, if it sent one,
+                    // has been received before the response.
+                    Err(_) => Capabilities::new(context.peer_features(), None),
+                };
+                context.set_capabilities(capabilities.clone());
+                Ok(capabilities)
+            });
+        Box::new(capabilities)
     }
+
+    /// Check that the remote endpoint is alive with a `"$/ping"` request, and return the round
+    /// trip time. The result is the error of the remote endpoint if it answered with one: it
+    /// is draining, or it does not answer these requests (see
+    /// [`ProtocolOptions::health_check`](struct.ProtocolOptions.html#method.health_check)).
+    pub fn ping(&self) -> Box<Future<Item = Result<Duration, Value>, Error = CallError>> {
+        let start = Instant::now();
+        let ping = self.request(PING_METHOD, &[])
+            .map(move |response| response.map(|_| start.elapsed()));
+        Box::new(ping)
+    }
+
     /// Send a `MessagePack-RPC` request
     pub fn request(&self, method: &str, params: &[Value]) -> Response {
-        trace!("New request (method={}, params={:?})", method, params);
+        let params = params.iter().cloned().map(Param::Value).collect();
+        self.request_zero_copy(method, params)
+    }
+
+    /// Like [`request`](#method.request), but fail with a
+    /// [`ClientError`](enum.ClientError.html) if the request is answered with an error, so that
+    /// the transport failures and the errors of the server can be handled together, or
+    /// propagated with `?`.
+    pub fn call(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Value, Error = ClientError>> {
+        let call = self.request(method, params)
+            .map_err(ClientError::from)
+            .and_then(|response| response.map_err(ClientError::Remote));
+        Box::new(call)
+    }
+
+    /// Send a request whose parameters are named arguments. They are sent as a map, either on
+    /// its own if the connection has
+    /// [`ProtocolOptions::named_params`](struct.ProtocolOptions.html#method.named_params)
+    /// enabled, or as the only item of the array of parameters otherwise.
+    pub fn request_named(&self, method: &str, args: &[(&str, Value)]) -> Response {
+        self.request(method, &[named_args(args)])
+    }
+
+    /// Like [`call`](#method.call), with named arguments (see
+    /// [`request_named`](#method.request_named)) taken from the fields of `params`, which must
+    /// serialize into a map, like a struct. The result is deserialized into a `T`.
+    #[cfg(feature = "serde-params")]
+    pub fn call_named<P, T>(
+        &self,
+        method: &str,
+        params: &P,
+    ) -> Box<Future<Item = T, Error = ClientError>>
+    where
+        P: Serialize,
+        T: DeserializeOwned + 'static,
+    {
+        let params = match ext::to_value(params) {
+            Ok(map @ Value::Map(_)) => Ok(map),
+            Ok(_) => Err("the named arguments do not serialize into a map".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let params = match params {
+            Ok(params) => params,
+            Err(e) => return Box::new(future::err(ClientError::Encode(e))),
+        };
+        Box::new(self.call(method, &[params]).and_then(|result| {
+            ext::from_value(result).map_err(|e| ClientError::Decode(e.to_string()))
+        }))
+    }
+
+    /// Send a `MessagePack-RPC` request. The `Param::Binary` parameters are written to the
+    /// transport as they are, without being copied.
+    pub fn request_zero_copy(&self, method: &str, params: Vec<Param>) -> Response {
+        trace!(
+            "New request (method={}, params={:?})",
+            method,
+            self.context.redactions().zero_copy_params(method, &params)
+        );
         let request = Request {
-            id: 0,
+            id: Id::Unsigned(0),
             method: method.to_owned(),
-            params: Vec::from(params),
+            params: params,
         };
         let (tx, rx) = oneshot::channel();
+        let response = Response::new(rx, method, &self.context);
         // If send returns an Err, its because the other side has been dropped. By ignoring it,
         // we are just dropping the `tx`, which will mean the rx will return Canceled when
         // polled. In turn, that is translated into a BrokenPipe, which conveys the proper
         // error.
-        let _ = mpsc::UnboundedSender::unbounded_send(&self.requests_tx, (request, tx));
-        Response(rx)
+        let _ = mpsc::UnboundedSender::unbounded_send(
+            &self.requests_tx,
+            OutgoingRequests::Single(request, tx),
+        );
+        response
+    }
+
+    /// Send a `MessagePack-RPC` request whose result may be streamed in chunks by the server
+    /// (see [`Pusher::send_chunk`](struct.Pusher.html#method.send_chunk)). The returned stream
+    /// yields the chunks as they are received, and then the result of the response unless it is
+    /// `nil`.
+    pub fn request_stream(&self, method: &str, params: &[Value]) -> ResponseStream {
+        trace!(
+            "New streamed request (method={}, params={:?})",
+            method,
+            self.context.redactions().params(method, params)
+        );
+        let request = Request {
+            id: Id::Unsigned(0),
+            method: method.to_owned(),
+            params: params.iter().cloned().map(Param::Value).collect(),
+        };
+        let (tx, rx) = oneshot::channel();
+        let (chunks_tx, chunks_rx) = mpsc::unbounded();
+        let response = Response::new(rx, method, &self.context);
+        let _ = mpsc::UnboundedSender::unbounded_send(
+            &self.requests_tx,
+            OutgoingRequests::Streamed(request, tx, chunks_tx),
+        );
+        ResponseStream::new(chunks_rx, response)
+    }
+
+    /// Start a batch of `MessagePack-RPC` requests. The requests added to the batch are sent
+    /// together when [`Batch::send`](struct.Batch.html#method.send) is called, and are written to
+    /// the transport in a single flush.
+    pub fn batch(&self) -> Batch {
+        Batch {
+            requests_tx: self.requests_tx.clone(),
+            context: self.context.clone(),
+            requests: Vec::new(),
+        }
     }
 
-    /// Send a `MessagePack-RPC` notification
+    /// Send a `MessagePack-RPC` notification. Notifications are "fire-and-forget": the remote
+    /// endpoint does not answer them. The returned `Ack` resolves once the notification has been
+    /// flushed to the underlying transport, and fails if the connection is closed before that
+    /// happens.
     pub fn notify(&self, method: &str, params: &[Value]) -> Ack {
-        trace!("New notification (method={}, params={:?})", method, params);
+        trace!(
+            "New notification (method={}, params={:?})",
+            method,
+            self.context.redactions().params(method, params)
+        );
         let notification = Notification {
             method: method.to_owned(),
             params: Vec::from(params),
@@ -450,6 +2114,156 @@ impl Client {
         let _ = mpsc::UnboundedSender::unbounded_send(&self.notifications_tx, (notification, tx));
         Ack(rx)
     }
+
+    /// Send a notification whose parameters are named arguments (see
+    /// [`request_named`](#method.request_named)).
+    pub fn notify_named(&self, method: &str, args: &[(&str, Value)]) -> Ack {
+        self.notify(method, &[named_args(args)])
+    }
+
+    /// Return a stream of the notifications sent by the remote endpoint, starting from now. Each
+    /// call returns a new stream that receives all the notifications.
+    ///
+    /// Note that if the endpoint has been given a `Service` (see
+    /// [`Connector::set_service_builder`](struct.Connector.html#method.set_service_builder)),
+    /// incoming notifications are handled by the service, and none of them are sent to these
+    /// streams.
+    pub fn notifications(&self) -> Notifications {
+        let (tx, rx) = mpsc::unbounded();
+        // If the endpoint is gone, `tx` is dropped and the stream ends immediately.
+        let _ = mpsc::UnboundedSender::unbounded_send(&self.subscriptions_tx, tx);
+        Notifications(rx)
+    }
+
+    /// Return a stream of the [anomalies](enum.Anomaly.html) detected on the connection,
+    /// starting from now: responses and chunks that do not match any request in flight. Each
+    /// call returns a new stream that receives all the anomalies.
+    pub fn anomalies(&self) -> Anomalies {
+        let (tx, rx) = mpsc::unbounded();
+        // If the endpoint is gone, `tx` is dropped and the stream ends immediately.
+        let _ = mpsc::UnboundedSender::unbounded_send(&self.anomaly_subscriptions_tx, tx);
+        Anomalies::new(rx)
+    }
+}
+
+/// A handle that request handlers use to send notifications to the remote endpoint while they
+/// work, to report their progress for instance, before their response. It is cheap to clone.
+///
+/// The notifications sent before the future of a handler completes are written to the connection
+/// before its response.
+///
+/// The default pusher is not attached to a connection: the notifications sent with it are
+/// dropped.
+#[derive(Clone, Default)]
+pub struct Pusher {
+    client: Option<Client>,
+    // The request whose result is streamed by `send_chunk`.
+    request: Option<Id>,
+}
+
+impl Pusher {
+    /// Create a pusher that sends notifications with the client of a connection, as given to
+    /// [`ServiceBuilder::build`](trait.ServiceBuilder.html#tymethod.build). If it is created in
+    /// [`Service::handle_request`](trait.Service.html#tymethod.handle_request), it can also
+    /// stream the result of the request being handled.
+    pub fn new(client: Client) -> Self {
+        let request = client.context.current_request();
+        Pusher {
+            client: Some(client),
+            request: request,
+        }
+    }
+
+    /// Return a pusher on the same connection that streams the result of the request being
+    /// handled.
+    pub(crate) fn for_current_request(&self) -> Self {
+        Pusher {
+            client: self.client.clone(),
+            request: match self.client {
+                Some(ref client) => client.context.current_request(),
+                None => None,
+            },
+        }
+    }
+
+    /// Send a notification to the remote endpoint. See
+    /// [`Client::notify`](struct.Client.html#method.notify).
+    pub fn notify(&self, method: &str, params: &[Value]) -> Ack {
+        match self.client {
+            Some(ref client) => client.notify(method, params),
+            None => {
+                trace!("Not attached to a connection, dropping notification {}", method);
+                Ack(oneshot::channel().1)
+            }
+        }
+    }
+
+    /// Send a chunk of the result of the request being handled, to a client that uses
+    /// [`Client::request_stream`](struct.Client.html#method.request_stream). The chunks are
+    /// dropped if the pusher was not created for a request.
+    pub fn send_chunk(&self, chunk: Value) -> Ack {
+        match self.request {
+            Some(id) => self.notify(&chunk_method(id), &[chunk]),
+            None => {
+                trace!("Not handling a request, dropping chunk");
+                Ack(oneshot::channel().1)
+            }
+        }
+    }
+}
+
+/// A batch of requests, created with [`Client::batch`](struct.Client.html#method.batch).
+pub struct Batch {
+    // The requests are sent on the queue of the client that created the batch.
+    requests_tx: RequestTx,
+    context: Context,
+    requests: Vec<Request>,
+}
+
+impl Batch {
+    /// Add a request to the batch.
+    pub fn request(&mut self, method: &str, params: &[Value]) -> &mut Self {
+        trace!(
+            "New batched request (method={}, params={:?})",
+            method,
+            self.context.redactions().params(method, params)
+        );
+        self.requests.push(Request {
+            id: Id::Unsigned(0),
+            method: method.to_owned(),
+            params: params.iter().cloned().map(Param::Value).collect(),
+        });
+        self
+    }
+
+    /// Send all the requests of the batch. The returned future resolves once all the responses
+    /// have been received. Responses are in the same order as the requests.
+    pub fn send(&mut self) -> BatchResponse {
+        let mut requests = Vec::with_capacity(self.requests.len());
+        let mut responses = Vec::with_capacity(self.requests.len());
+        for request in self.requests.drain(..) {
+            let (tx, rx) = oneshot::channel();
+            responses.push(Response::new(rx, &request.method, &self.context));
+            requests.push((request, tx));
+        }
+        let _ = mpsc::UnboundedSender::unbounded_send(
+            &self.requests_tx,
+            OutgoingRequests::Batch(requests),
+        );
+        BatchResponse(future::join_all(responses))
+    }
+}
+
+/// Future responses to a batch of requests. It resolves once all the responses are available.
+pub struct BatchResponse(JoinAll<Vec<Response>>);
+
+impl Future for BatchResponse {
+    type Item = Vec<Result<Value, Value>>;
+    type Error = CallError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
 }
 
 impl Future for Client {
@@ -460,3 +2274,359 @@ impl Future for Client {
         Ok(Async::Ready(()))
     }
 }
+
+#[test]
+fn client_fairness() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use futures::future::FutureResult;
+    use mock::TestClient;
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Service for Recorder {
+        type Error = io::Error;
+        type T = Value;
+        type E = Value;
+        type RequestFuture = FutureResult<Result<Value, Value>, io::Error>;
+        type NotificationFuture = FutureResult<(), io::Error>;
+
+        fn handle_request(&mut self, method: &str, _params: &[Value]) -> Self::RequestFuture {
+            self.0.borrow_mut().push(method.to_owned());
+            future::ok(Ok(Value::Nil))
+        }
+
+        fn handle_notification(&mut self, _: &str, _: &[Value]) -> Self::NotificationFuture {
+            future::ok(())
+        }
+    }
+
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let mut client = TestClient::new(Recorder(Rc::clone(&calls)));
+    let busy = client.client().clone();
+    let other = client.client().clone();
+    let mut responses = (0..100)
+        .map(|_| busy.request("busy", &[]))
+        .collect::<Vec<_>>();
+    responses.push(other.request("other", &[]));
+    let _ = client.run(future::join_all(responses)).unwrap();
+
+    let calls = calls.borrow();
+    assert_eq!(calls.len(), 101);
+    let position = calls.iter().position(|method| method == "other").unwrap();
+    assert!(position <= REQUEST_BURST);
+}
+
+#[test]
+fn request_ids() {
+    let (mut client, _proxy) = InnerClient::new(Context::default(), 2, None);
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        let id = client.next_request_id().unwrap();
+        let _ = client.pending_requests.insert(id, oneshot::channel().0);
+        ids.push(id);
+    }
+    assert_eq!(ids, vec![Id::from(1_u32), Id::from(2_u32), Id::from(0_u32)]);
+    assert!(client.next_request_id().is_none());
+
+    // Ids wrap around, skipping those still in flight.
+    let _ = client.pending_requests.remove(&Id::from(2_u32));
+    assert_eq!(client.next_request_id(), Some(Id::from(2_u32)));
+
+    let response = MsgPackResponse {
+        id: Id::from(7_u32),
+        result: Ok(Value::Nil),
+    };
+    assert!(client.process_response(response).is_err());
+}
+
+#[test]
+fn anomalies() {
+    use std::sync::Mutex;
+
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler = {
+        let handled = Arc::clone(&handled);
+        move |_: usize, anomaly: &Anomaly| handled.lock().unwrap().push(anomaly.clone())
+    };
+    let (mut client, proxy) = InnerClient::new(Context::default(), 8, Some(Arc::new(handler)));
+    let anomalies = proxy.anomalies();
+    // The subscriptions channel can only be polled from a task.
+    let _ = future::lazy(|| {
+        client.process_subscriptions();
+        future::ok::<(), ()>(())
+    }).wait();
+
+    // The response to a request whose future has been dropped is an orphan.
+    let _ = client.pending_requests.insert(Id::from(1_u32), oneshot::channel().0);
+    let response = MsgPackResponse {
+        id: Id::from(1_u32),
+        result: Ok(Value::Nil),
+    };
+    assert!(client.process_response(response).is_ok());
+    let response = MsgPackResponse {
+        id: Id::from(2_u32),
+        result: Ok(Value::Nil),
+    };
+    assert!(client.process_response(response.clone()).is_err());
+    // Unless the connection is not closed for these.
+    client.unexpected_responses = ProtocolPolicy::Ignore;
+    assert!(client.process_response(response).is_ok());
+    client.process_chunk(Notification {
+        method: chunk_method(Id::from(3_u32)),
+        params: vec![Value::from(0)],
+    });
+
+    let expected = vec![
+        Anomaly::OrphanResponse(Id::from(1_u32)),
+        Anomaly::UnknownResponse(Id::from(2_u32)),
+        Anomaly::UnknownResponse(Id::from(2_u32)),
+        Anomaly::UnknownChunk("$stream/3/chunk".to_owned()),
+    ];
+    assert_eq!(anomalies.take(4).collect().wait().unwrap(), expected);
+    assert_eq!(*handled.lock().unwrap(), expected);
+}
+
+#[test]
+fn spawned_handlers() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    struct Pending(Rc<Cell<bool>>);
+
+    impl Future for Pending {
+        type Item = ();
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl Drop for Pending {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let handler = Handler::new(future::ok::<_, io::Error>(7), Some(&handle));
+    assert_eq!(core.run(handler).unwrap(), Some(7));
+
+    // A spawned handler is dropped once nobody waits for its outcome.
+    let dropped = Rc::new(Cell::new(false));
+    let handler = Handler::new(Pending(Rc::clone(&dropped)), Some(&handle));
+    core.turn(Some(Duration::from_millis(0)));
+    assert!(!dropped.get());
+    drop(handler);
+    core.turn(Some(Duration::from_millis(0)));
+    assert!(dropped.get());
+}
+
+#[test]
+fn duplicate_ids() {
+    use methods::MethodRouter;
+    use mock::duplex;
+    use tokio_core::reactor::Core;
+
+    // Completes after being polled a few times, so that the requests after it complete first.
+    struct Slow(usize, Value);
+
+    impl Future for Slow {
+        type Item = Result<Value, Value>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+            if self.0 == 0 {
+                return Ok(Async::Ready(Ok(self.1.clone())));
+            }
+            self.0 -= 1;
+            task::current().notify();
+            Ok(Async::NotReady)
+        }
+    }
+
+    // Send the given requests, and return the responses.
+    let run_with = |policy: DuplicateIdPolicy, options: &ProtocolOptions, calls: &[(u32, &str)]| {
+        let mut core = Core::new().unwrap();
+        let mut router = MethodRouter::new();
+        let _ = router
+            .request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))))
+            .request("slow", |params| Box::new(Slow(10, params[0].clone())));
+        let (server_stream, client_stream) = duplex();
+        let mut options = options.clone();
+        let _ = options.duplicate_ids(policy);
+        let mut endpoint = Endpoint::new(server_stream, options);
+        let client = endpoint.set_client();
+        endpoint.set_server(router.build(client));
+        let closed = endpoint.map_err(|e| e.kind());
+
+        let requests = calls.iter().map(|&(id, param)| {
+            let method = if param.starts_with("slow") { "slow" } else { "echo" };
+            Message::Request(Request {
+                id: Id::from(id),
+                method: method.to_owned(),
+                params: vec![Value::from(param).into()],
+            })
+        });
+        let requests = requests.collect::<Vec<_>>();
+        let requests = ::futures::stream::iter_ok::<_, io::Error>(requests);
+        let (transport, _) = core.run(Transport::new(client_stream).send_all(requests)).unwrap();
+        let responses = transport
+            .take(calls.len() as u64)
+            .collect()
+            .map_err(|e| e.kind());
+        match core.run(closed.select2(responses)) {
+            Ok(future::Either::B((responses, _))) => Ok(responses),
+            Ok(future::Either::A(_)) => panic!("the connection was closed cleanly"),
+            Err(future::Either::A((e, _))) | Err(future::Either::B((e, _))) => Err(e),
+        }
+    };
+    let duplicates = [(1, "first"), (1, "second")];
+    let run = |policy| run_with(policy, &ProtocolOptions::default(), &duplicates);
+    let response = |id: u32, result: Result<&str, RpcError>| {
+        Message::Response(MsgPackResponse {
+            id: Id::from(id),
+            result: result.map(Value::from).map_err(Value::from),
+        })
+    };
+
+    let duplicate = RpcError::invalid_request("duplicate request id");
+    assert_eq!(
+        run(DuplicateIdPolicy::Reject).unwrap(),
+        vec![response(1, Err(duplicate.clone())), response(1, Ok("first"))]
+    );
+    assert_eq!(
+        run(DuplicateIdPolicy::Queue).unwrap(),
+        vec![response(1, Ok("first")), response(1, Ok("second"))]
+    );
+    assert_eq!(run(DuplicateIdPolicy::Close), Err(io::ErrorKind::InvalidData));
+
+    // With ordered responses, requests that share an id are answered in the order they were
+    // received, behind a slow request.
+    let mut options = ProtocolOptions::default();
+    let _ = options.ordered_responses(true);
+    let calls = [(0, "slow"), (1, "first"), (1, "second")];
+    assert_eq!(
+        run_with(DuplicateIdPolicy::Reject, &options, &calls).unwrap(),
+        vec![
+            response(0, Ok("slow")),
+            response(1, Ok("first")),
+            response(1, Err(duplicate)),
+        ]
+    );
+    assert_eq!(
+        run_with(DuplicateIdPolicy::Queue, &options, &calls).unwrap(),
+        vec![
+            response(0, Ok("slow")),
+            response(1, Ok("first")),
+            response(1, Ok("second")),
+        ]
+    );
+    // A deferred request only counts once in the rate limit.
+    let mut limits = Limits::default();
+    let _ = limits.connection_rate(Some(2));
+    let _ = options.ordered_responses(false).limits(limits);
+    assert_eq!(
+        run_with(DuplicateIdPolicy::Queue, &options, &duplicates).unwrap(),
+        vec![response(1, Ok("first")), response(1, Ok("second"))]
+    );
+}
+
+#[test]
+fn ordered_responses() {
+    use methods::MethodRouter;
+    use mock::duplex;
+    use tokio_core::reactor::Core;
+
+    // Completes after being polled a few times, so that the requests after it complete first.
+    struct Slow(usize);
+
+    impl Future for Slow {
+        type Item = Result<Value, Value>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+            if self.0 == 0 {
+                return Ok(Async::Ready(Ok(Value::from("slow"))));
+            }
+            self.0 -= 1;
+            task::current().notify();
+            Ok(Async::NotReady)
+        }
+    }
+
+    // Send a slow request then a fast one, and return the ids of the responses, in the order
+    // they are received.
+    let run = |ordered: bool| {
+        let mut core = Core::new().unwrap();
+        let mut router = MethodRouter::new();
+        let _ = router
+            .request("slow", |_| Box::new(Slow(10)))
+            .request("fast", |_| Box::new(future::ok(Ok(Value::from("fast")))));
+        let (server_stream, client_stream) = duplex();
+        let mut options = ProtocolOptions::default();
+        let _ = options.ordered_responses(ordered);
+        let mut endpoint = Endpoint::new(server_stream, options);
+        let client = endpoint.set_client();
+        endpoint.set_server(router.build(client));
+        core.handle().spawn(endpoint.map_err(|e| panic!("{}", e)));
+
+        let requests = ["slow", "fast"].iter().enumerate().map(|(id, method)| {
+            Message::Request(Request {
+                id: Id::from(id as u32),
+                method: (*method).to_owned(),
+                params: vec![],
+            })
+        });
+        let requests = ::futures::stream::iter_ok::<_, io::Error>(requests.collect::<Vec<_>>());
+        let (transport, _) = core.run(Transport::new(client_stream).send_all(requests)).unwrap();
+        let responses = core.run(transport.take(2).collect()).unwrap();
+        responses
+            .into_iter()
+            .map(|msg| match msg {
+                Message::Response(response) => (response.id, response.result),
+                msg => panic!("expected a response, got {:?}", msg),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let slow = (Id::from(0u32), Ok(Value::from("slow")));
+    let fast = (Id::from(1u32), Ok(Value::from("fast")));
+    // By default, the fast request is answered first.
+    assert_eq!(run(false), vec![fast.clone(), slow.clone()]);
+    // With ordered responses, the fast response waits for the slow one.
+    assert_eq!(run(true), vec![slow, fast]);
+}
+
+#[cfg(feature = "serde-params")]
+#[test]
+fn typed_calls() {
+    use std::collections::HashMap;
+    use methods::MethodRouter;
+    use mock::TestClient;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("greet", |_| Box::new(future::ok(Ok(Value::from("hello")))));
+    let mut client = TestClient::new(router);
+    let mut args = HashMap::new();
+    let _ = args.insert("name", "world");
+
+    let call = client.client().call_named::<_, String>("greet", &args);
+    assert_eq!(client.run(call).unwrap(), "hello");
+    // The result does not deserialize into the expected type.
+    let call = client.client().call_named::<_, u32>("greet", &args);
+    match client.run(call) {
+        Err(error @ ClientError::Decode(_)) => assert!(!error.is_transport()),
+        _ => panic!("the result was not rejected"),
+    }
+    // The arguments do not serialize into a map.
+    let call = client.client().call_named::<_, String>("greet", &5);
+    match client.run(call) {
+        Err(ClientError::Encode(_)) => {}
+        _ => panic!("the arguments were not rejected"),
+    }
+}
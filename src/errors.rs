@@ -1,5 +1,7 @@
 use std::{error, fmt, io};
-use rmpv::decode;
+use std::net::SocketAddr;
+use rmp::decode::{MarkerReadError, NumValueReadError, ValueReadError};
+use rmpv::{decode, Value};
 
 /// Error while decoding a sequence of bytes into a `MessagePack-RPC` message
 #[derive(Debug)]
@@ -7,8 +9,22 @@ pub enum DecodeError {
     /// Some bytes are missing to decode a full msgpack value
     Truncated,
     /// A byte sequence could not be decoded as a msgpack value, or this value is not a valid
-    /// msgpack-rpc message.
+    /// msgpack-rpc message for a reason not covered by the other variants.
     Invalid,
+    /// The type of the message, its first item, is not one of the known message types.
+    InvalidMessageType,
+    /// The id of a request or a response is not an integer that fits in 64 bits.
+    InvalidId,
+    /// The method of a request or a notification is not a UTF-8 string.
+    InvalidMethod,
+    /// The parameters of a request or a notification are not an array.
+    InvalidParams,
+    /// A value of the message is nested in more arrays and maps than allowed (see
+    /// [`ProtocolOptions::max_nesting_depth`](struct.ProtocolOptions.html#method.max_nesting_depth)).
+    DepthLimitExceeded,
+    /// The message holds more values than allowed (see
+    /// [`ProtocolOptions::max_elements`](struct.ProtocolOptions.html#method.max_elements)).
+    TooManyElements,
     /// An unknown IO error while reading a byte sequence
     UnknownIo(io::Error),
 }
@@ -25,6 +41,12 @@ impl error::Error for DecodeError {
             DecodeError::Truncated => "could not read enough bytes to decode a complete message",
             DecodeError::UnknownIo(_) => "Unknown IO error while decoding a message",
             DecodeError::Invalid => "the byte sequence is not a valid msgpack-rpc message",
+            DecodeError::InvalidMessageType => "unknown message type",
+            DecodeError::InvalidId => "the id of the message is not a 64 bits integer",
+            DecodeError::InvalidMethod => "the method of the message is not a UTF-8 string",
+            DecodeError::InvalidParams => "the parameters of the message are not an array",
+            DecodeError::DepthLimitExceeded => "the message is nested too deeply",
+            DecodeError::TooManyElements => "the message holds too many values",
         }
     }
 
@@ -36,6 +58,382 @@ impl error::Error for DecodeError {
     }
 }
 
+/// Why a request sent with a [`Client`](struct.Client.html) did not get a response: the
+/// connection was closed before the response was received. Retry policies can tell a connection
+/// lost in flight, after which sending the request again is usually safe if it is idempotent,
+/// from a misbehaving peer, which will likely misbehave again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallError {
+    /// The remote endpoint reset the connection (`ECONNRESET`).
+    ConnectionReset,
+    /// The connection was closed while messages were being written to it (`EPIPE`).
+    BrokenPipe,
+    /// The connection was closed cleanly, by the remote endpoint or by this one.
+    ConnectionClosed,
+    /// The connection timed out.
+    TimedOut,
+    /// The remote endpoint sent data that breaks the protocol, for instance a response to a
+    /// request that is not in flight.
+    Protocol,
+    /// The connection failed with another I/O error.
+    Io(io::ErrorKind),
+}
+
+impl CallError {
+    /// Return `true` if the connection was lost, rather than closed because of the remote
+    /// endpoint misbehaving.
+    pub fn is_connection_lost(&self) -> bool {
+        match *self {
+            CallError::ConnectionReset | CallError::BrokenPipe | CallError::ConnectionClosed => true,
+            CallError::TimedOut | CallError::Protocol | CallError::Io(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CallError::Io(kind) => write!(f, "the connection failed: {:?}", kind),
+            _ => error::Error::description(self).fmt(f),
+        }
+    }
+}
+
+impl error::Error for CallError {
+    fn description(&self) -> &str {
+        match *self {
+            CallError::ConnectionReset => "the connection was reset by the remote endpoint",
+            CallError::BrokenPipe => "the connection was closed while writing to it",
+            CallError::ConnectionClosed => "the connection was closed",
+            CallError::TimedOut => "the connection timed out",
+            CallError::Protocol => "the remote endpoint broke the protocol",
+            CallError::Io(_) => "the connection failed",
+        }
+    }
+}
+
+/// Why a [`ResponseStream`](struct.ResponseStream.html) failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamError {
+    /// The connection was closed before the response was received.
+    Call(CallError),
+    /// The request was answered with an error, after the chunks that were already received.
+    Response(Value),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            StreamError::Call(ref e) => e.fmt(f),
+            StreamError::Response(ref value) => write!(f, "the request failed: {}", value),
+        }
+    }
+}
+
+impl error::Error for StreamError {
+    fn description(&self) -> &str {
+        match *self {
+            StreamError::Call(ref e) => e.description(),
+            StreamError::Response(_) => "the request failed",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            StreamError::Call(ref e) => Some(e),
+            StreamError::Response(_) => None,
+        }
+    }
+}
+
+/// Why a call made with a [`Client`](struct.Client.html) failed, with
+/// [`Client::call`](struct.Client.html#method.call) for instance. It tells the transport
+/// failures from the errors sent back by the server, and converts from and to `io::Error`, so
+/// that it can be propagated with `?`.
+///
+/// The [`Connection`](struct.Connection.html) futures fail with an `io::Error`, which can be
+/// turned into a `ClientError` with `map_err(ClientError::Connect)`.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The connection to the server could not be established.
+    Connect(io::Error),
+    /// The connection was lost before the response was received.
+    Call(CallError),
+    /// Another I/O error.
+    Io(io::Error),
+    /// The request or the connection timed out.
+    TimedOut,
+    /// The server answered the request with an error.
+    Remote(Value),
+    /// The parameters of the request could not be serialized.
+    Encode(String),
+    /// The result of the request could not be deserialized into the expected type.
+    Decode(String),
+}
+
+impl ClientError {
+    /// Return the error sent by the server, if the request was answered with an error.
+    pub fn remote_error(&self) -> Option<&Value> {
+        match *self {
+            ClientError::Remote(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Return `true` if the request failed because of the transport rather than the server
+    /// answering it with an error.
+    pub fn is_transport(&self) -> bool {
+        match *self {
+            ClientError::Remote(_) | ClientError::Encode(_) | ClientError::Decode(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ClientError::Connect(ref e) => write!(f, "failed to connect: {}", e),
+            ClientError::Call(ref e) => e.fmt(f),
+            ClientError::Io(ref e) => e.fmt(f),
+            ClientError::TimedOut => "the request timed out".fmt(f),
+            ClientError::Remote(ref value) => write!(f, "the request failed: {}", value),
+            ClientError::Encode(ref e) => write!(f, "invalid parameters: {}", e),
+            ClientError::Decode(ref e) => write!(f, "invalid result: {}", e),
+        }
+    }
+}
+
+impl error::Error for ClientError {
+    fn description(&self) -> &str {
+        match *self {
+            ClientError::Connect(_) => "failed to connect",
+            ClientError::Call(ref e) => e.description(),
+            ClientError::Io(ref e) => e.description(),
+            ClientError::TimedOut => "the request timed out",
+            ClientError::Remote(_) => "the request failed",
+            ClientError::Encode(_) => "invalid parameters",
+            ClientError::Decode(_) => "invalid result",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ClientError::Connect(ref e) | ClientError::Io(ref e) => Some(e),
+            ClientError::Call(ref e) => Some(e),
+            ClientError::TimedOut
+            | ClientError::Remote(_)
+            | ClientError::Encode(_)
+            | ClientError::Decode(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+impl From<CallError> for ClientError {
+    fn from(err: CallError) -> ClientError {
+        match err {
+            CallError::TimedOut => ClientError::TimedOut,
+            err => ClientError::Call(err),
+        }
+    }
+}
+
+impl From<StreamError> for ClientError {
+    fn from(err: StreamError) -> ClientError {
+        match err {
+            StreamError::Call(err) => ClientError::from(err),
+            StreamError::Response(value) => ClientError::Remote(value),
+        }
+    }
+}
+
+impl From<ClientError> for io::Error {
+    fn from(err: ClientError) -> io::Error {
+        let kind = match err {
+            ClientError::Connect(err) | ClientError::Io(err) => return err,
+            ClientError::Call(CallError::ConnectionReset) => io::ErrorKind::ConnectionReset,
+            ClientError::Call(CallError::BrokenPipe) => io::ErrorKind::BrokenPipe,
+            ClientError::Call(CallError::ConnectionClosed) => io::ErrorKind::UnexpectedEof,
+            ClientError::Call(CallError::Protocol) => io::ErrorKind::InvalidData,
+            ClientError::Call(CallError::Io(kind)) => kind,
+            ClientError::Call(CallError::TimedOut) | ClientError::TimedOut => {
+                io::ErrorKind::TimedOut
+            }
+            ClientError::Remote(_) => io::ErrorKind::Other,
+            ClientError::Encode(_) => io::ErrorKind::InvalidInput,
+            ClientError::Decode(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+/// Why a [`Server`](struct.Server.html) could not be built, or stopped accepting connections.
+/// It converts from and to `io::Error`, so that it can be propagated with `?`.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The server could not listen on the given address.
+    Bind(SocketAddr, io::Error),
+    /// The service has a handler for a method in a reserved namespace (see
+    /// [`ServerBuilder::reserve_prefix`](struct.ServerBuilder.html#method.reserve_prefix)).
+    ReservedMethod {
+        /// The method of the handler.
+        method: String,
+        /// The reserved prefix the method starts with.
+        prefix: String,
+    },
+    /// The service builder is misconfigured (see
+    /// [`ServiceBuilder::validate`](trait.ServiceBuilder.html#method.validate)).
+    InvalidRouter(RouterError),
+    /// Another I/O error, for instance while accepting connections.
+    Io(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ServerError::Bind(ref address, ref e) => {
+                write!(f, "failed to listen on {}: {}", address, e)
+            }
+            ServerError::ReservedMethod {
+                ref method,
+                ref prefix,
+            } => write!(
+                f,
+                "cannot handle method {}: the methods starting with {:?} are reserved",
+                method, prefix
+            ),
+            ServerError::InvalidRouter(ref e) => write!(f, "invalid router: {}", e),
+            ServerError::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn description(&self) -> &str {
+        match *self {
+            ServerError::Bind(..) => "failed to listen",
+            ServerError::ReservedMethod { .. } => "cannot handle a reserved method",
+            ServerError::InvalidRouter(_) => "invalid router",
+            ServerError::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ServerError::Bind(_, ref e) | ServerError::Io(ref e) => Some(e),
+            ServerError::InvalidRouter(ref e) => Some(e),
+            ServerError::ReservedMethod { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
+
+impl From<ServerError> for io::Error {
+    fn from(err: ServerError) -> io::Error {
+        match err {
+            ServerError::Bind(_, e) | ServerError::Io(e) => e,
+            ServerError::ReservedMethod { .. } | ServerError::InvalidRouter(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+            }
+        }
+    }
+}
+
+/// Why a [`MethodRouter`](struct.MethodRouter.html) is rejected when a server is built (see
+/// [`MethodRouter::validate`](struct.MethodRouter.html#method.validate)).
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouterError {
+    /// The router has no handler, not even a default one, so it would answer every request with
+    /// a "method not found" error (see
+    /// [`MethodRouter::require_handlers`](struct.MethodRouter.html#method.require_handlers)).
+    Empty,
+    /// A setting was configured for a method that no handler takes, which is usually a typo in
+    /// the name of the method or of a handler.
+    Unhandled {
+        /// The method.
+        method: String,
+        /// The setting, like `"priority"` or `"named_params"`.
+        setting: &'static str,
+    },
+    /// The named arguments of a method have the same name twice, so the second one can never be
+    /// given.
+    DuplicateName {
+        /// The method.
+        method: String,
+        /// The name given twice.
+        name: String,
+    },
+    /// A method of a mounted router already had a handler, which it did not replace (see
+    /// [`MethodRouter::mount`](struct.MethodRouter.html#method.mount)).
+    ConflictingMount {
+        /// The method, with the prefix of the mount.
+        method: String,
+    },
+    /// The methods in a protected namespace would be served without authentication, since the
+    /// server has no authenticator (see
+    /// [`MethodRouter::protect`](struct.MethodRouter.html#method.protect)).
+    Unauthenticated {
+        /// The prefix of the protected namespace.
+        prefix: String,
+    },
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RouterError::Empty => write!(f, "the router has no handler"),
+            RouterError::Unhandled {
+                ref method,
+                setting,
+            } => write!(f, "{} is set for {}, which has no handler", setting, method),
+            RouterError::DuplicateName {
+                ref method,
+                ref name,
+            } => write!(f, "the argument {:?} of {} is named twice", name, method),
+            RouterError::ConflictingMount { ref method } => {
+                write!(f, "{} is mounted over another handler", method)
+            }
+            RouterError::Unauthenticated { ref prefix } => write!(
+                f,
+                "the methods under {:?} are protected, but the server has no authenticator",
+                prefix
+            ),
+        }
+    }
+}
+
+impl error::Error for RouterError {
+    fn description(&self) -> &str {
+        "invalid router"
+    }
+}
+
+impl<'a> From<&'a io::Error> for CallError {
+    fn from(err: &'a io::Error) -> CallError {
+        match err.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+                CallError::ConnectionReset
+            }
+            io::ErrorKind::BrokenPipe => CallError::BrokenPipe,
+            io::ErrorKind::UnexpectedEof => CallError::ConnectionClosed,
+            io::ErrorKind::TimedOut => CallError::TimedOut,
+            io::ErrorKind::InvalidData => CallError::Protocol,
+            kind => CallError::Io(kind),
+        }
+    }
+}
+
 impl From<io::Error> for DecodeError {
     fn from(err: io::Error) -> DecodeError {
         match err.kind() {
@@ -62,3 +460,71 @@ impl From<decode::Error> for DecodeError {
         }
     }
 }
+
+impl From<ValueReadError> for DecodeError {
+    fn from(err: ValueReadError) -> DecodeError {
+        match err {
+            ValueReadError::InvalidMarkerRead(io_err) | ValueReadError::InvalidDataRead(io_err) => {
+                From::from(io_err)
+            }
+            ValueReadError::TypeMismatch(_) => DecodeError::Invalid,
+        }
+    }
+}
+
+impl From<NumValueReadError> for DecodeError {
+    fn from(err: NumValueReadError) -> DecodeError {
+        match err {
+            NumValueReadError::InvalidMarkerRead(io_err)
+            | NumValueReadError::InvalidDataRead(io_err) => From::from(io_err),
+            NumValueReadError::TypeMismatch(_) | NumValueReadError::OutOfRange => {
+                DecodeError::Invalid
+            }
+        }
+    }
+}
+
+impl From<MarkerReadError> for DecodeError {
+    fn from(err: MarkerReadError) -> DecodeError {
+        From::from(err.0)
+    }
+}
+
+#[test]
+fn call_error_classification() {
+    let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+    assert_eq!(CallError::from(&reset), CallError::ConnectionReset);
+    assert!(CallError::from(&reset).is_connection_lost());
+    let pipe = io::Error::new(io::ErrorKind::BrokenPipe, "pipe");
+    assert_eq!(CallError::from(&pipe), CallError::BrokenPipe);
+    let invalid = io::Error::new(io::ErrorKind::InvalidData, "unknown id");
+    assert_eq!(CallError::from(&invalid), CallError::Protocol);
+    assert!(!CallError::from(&invalid).is_connection_lost());
+    let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+    assert_eq!(CallError::from(&denied), CallError::Io(io::ErrorKind::PermissionDenied));
+}
+
+#[test]
+fn client_and_server_errors() {
+    match ClientError::from(CallError::TimedOut) {
+        ClientError::TimedOut => {}
+        e => panic!("unexpected error: {}", e),
+    }
+    let lost = io::Error::from(ClientError::from(CallError::ConnectionReset));
+    assert_eq!(CallError::from(&lost), CallError::ConnectionReset);
+    let remote = ClientError::from(StreamError::Response(Value::from("boom")));
+    assert_eq!(remote.remote_error(), Some(&Value::from("boom")));
+    assert!(!remote.is_transport());
+    assert_eq!(remote.to_string(), "the request failed: \"boom\"");
+
+    let reserved = ServerError::ReservedMethod {
+        method: "$/stats".to_string(),
+        prefix: "$/".to_string(),
+    };
+    assert_eq!(io::Error::from(reserved).kind(), io::ErrorKind::InvalidInput);
+    let address = "127.0.0.1:80".parse().unwrap();
+    let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+    let bind = ServerError::Bind(address, denied);
+    assert_eq!(bind.to_string(), "failed to listen on 127.0.0.1:80: denied");
+    assert_eq!(io::Error::from(bind).kind(), io::ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,305 @@
+#[cfg(feature = "serde-params")]
+use std::collections::HashMap;
+
+use rmpv::Value;
+#[cfg(feature = "serde-params")]
+use rmpv::{decode, encode, ext};
+#[cfg(feature = "serde-params")]
+use serde::Serialize;
+#[cfg(feature = "serde-params")]
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "serde-params")]
+use message::{Message, Param};
+
+/// A Rust type sent as a msgpack extension value: an application-defined type code and opaque
+/// bytes. Extension values can be used anywhere in the parameters and results, and are passed to
+/// the services and clients as `Value::Ext`; implementing `ExtType` registers how a type is
+/// converted from and to such values, with [`to_ext`](fn.to_ext.html) and
+/// [`from_ext`](fn.from_ext.html).
+///
+/// ```rust,ignore
+/// struct Point(u8, u8);
+///
+/// impl ExtType for Point {
+///     const EXT_TYPE: i8 = 7;
+///
+///     fn encode_ext(&self) -> Vec<u8> {
+///         vec![self.0, self.1]
+///     }
+///
+///     fn decode_ext(data: &[u8]) -> Option<Self> {
+///         if data.len() == 2 {
+///             Some(Point(data[0], data[1]))
+///         } else {
+///             None
+///         }
+///     }
+/// }
+/// ```
+pub trait ExtType: Sized {
+    /// The type code of the extension. The negative codes are reserved by the MessagePack
+    /// specification, applications use 0 to 127.
+    const EXT_TYPE: i8;
+
+    /// Encode the value as the data of an extension value.
+    fn encode_ext(&self) -> Vec<u8>;
+
+    /// Decode the data of an extension value. Return `None` if it is invalid.
+    fn decode_ext(data: &[u8]) -> Option<Self>;
+}
+
+/// Encode `value` as an extension value.
+pub fn to_ext<T: ExtType>(value: &T) -> Value {
+    Value::Ext(T::EXT_TYPE, value.encode_ext())
+}
+
+/// Decode an extension value. Return `None` if `value` is not an extension value of the type of
+/// `T`, or if its data is invalid.
+pub fn from_ext<T: ExtType>(value: &Value) -> Option<T> {
+    match *value {
+        Value::Ext(ext_type, ref data) if ext_type == T::EXT_TYPE => T::decode_ext(data),
+        _ => None,
+    }
+}
+
+/// An [`ExtType`](trait.ExtType.html) whose data is the msgpack encoding of its serde
+/// representation: implementing `SerdeExt` only requires picking a type code. A value that cannot
+/// be serialized is encoded with empty data, which does not decode. This trait is only available
+/// with the `serde-params` feature.
+///
+/// ```rust,ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct Buffer(u64);
+///
+/// impl SerdeExt for Buffer {
+///     const EXT_TYPE: i8 = 0;
+/// }
+/// ```
+#[cfg(feature = "serde-params")]
+pub trait SerdeExt: Serialize + DeserializeOwned {
+    /// The type code of the extension (see [`ExtType::EXT_TYPE`](trait.ExtType.html)).
+    const EXT_TYPE: i8;
+}
+
+#[cfg(feature = "serde-params")]
+impl<T: SerdeExt> ExtType for T {
+    const EXT_TYPE: i8 = <T as SerdeExt>::EXT_TYPE;
+
+    fn encode_ext(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Ok(value) = ext::to_value(self) {
+            let _ = encode::write_value(&mut data, &value);
+        }
+        data
+    }
+
+    fn decode_ext(mut data: &[u8]) -> Option<Self> {
+        let value = decode::read_value(&mut data).ok()?;
+        if !data.is_empty() {
+            return None;
+        }
+        ext::from_value(value).ok()
+    }
+}
+
+#[cfg(feature = "serde-params")]
+type ExtDecoder = fn(&[u8]) -> Option<Value>;
+
+/// The extension types known to an endpoint, consulted when it receives the parameters of the
+/// requests and notifications, and the results and errors of the responses (see
+/// [`ProtocolOptions::ext_registry`](struct.ProtocolOptions.html#method.ext_registry)).
+///
+/// The extension values of a registered type are decoded into their Rust type, then replaced by
+/// its serde representation, so that the services and the typed calls can deserialize them
+/// directly, with [`from_params`](fn.from_params.html) or
+/// [`Client::call_named`](struct.Client.html#method.call_named). The extension values of other
+/// types, and those whose data is invalid, are passed as they are. This type is only available
+/// with the `serde-params` feature.
+#[cfg(feature = "serde-params")]
+#[derive(Clone, Debug, Default)]
+pub struct ExtRegistry {
+    decoders: HashMap<i8, ExtDecoder>,
+}
+
+#[cfg(feature = "serde-params")]
+impl ExtRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        ExtRegistry::default()
+    }
+
+    /// Register the extension type of `T`, replacing the type registered with the same type code
+    /// if any.
+    pub fn register<T: ExtType + Serialize>(&mut self) -> &mut Self {
+        let _ = self.decoders.insert(T::EXT_TYPE, decode_as::<T>);
+        self
+    }
+
+    /// Return `true` if a type is registered for the type code `ext_type`.
+    pub fn is_registered(&self, ext_type: i8) -> bool {
+        self.decoders.contains_key(&ext_type)
+    }
+
+    /// Decode the registered extension values in `value`, at any depth.
+    pub fn decode(&self, value: Value) -> Value {
+        match value {
+            Value::Ext(ext_type, data) => match self.decoders.get(&ext_type) {
+                Some(decoder) => decoder(&data).unwrap_or(Value::Ext(ext_type, data)),
+                None => Value::Ext(ext_type, data),
+            },
+            Value::Array(values) => {
+                Value::Array(values.into_iter().map(|v| self.decode(v)).collect())
+            }
+            Value::Map(entries) => Value::Map(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (self.decode(k), self.decode(v)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    pub(crate) fn decode_message(&self, msg: Message) -> Message {
+        if self.decoders.is_empty() {
+            return msg;
+        }
+        match msg {
+            Message::Request(mut request) => {
+                request.params = request
+                    .params
+                    .into_iter()
+                    .map(|param| match param {
+                        Param::Value(value) => Param::Value(self.decode(value)),
+                        binary => binary,
+                    })
+                    .collect();
+                Message::Request(request)
+            }
+            Message::Notification(mut notification) => {
+                notification.params =
+                    notification.params.into_iter().map(|v| self.decode(v)).collect();
+                Message::Notification(notification)
+            }
+            Message::Response(mut response) => {
+                response.result = match response.result {
+                    Ok(value) => Ok(self.decode(value)),
+                    Err(value) => Err(self.decode(value)),
+                };
+                Message::Response(response)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde-params")]
+fn decode_as<T: ExtType + Serialize>(data: &[u8]) -> Option<Value> {
+    T::decode_ext(data).and_then(|value| ext::to_value(&value).ok())
+}
+
+#[cfg(feature = "runtime")]
+#[test]
+fn ext_round_trip() {
+    use futures::future;
+    use methods::MethodRouter;
+    use mock::TestClient;
+
+    #[derive(Debug, PartialEq)]
+    struct Point(u8, u8);
+
+    impl ExtType for Point {
+        const EXT_TYPE: i8 = 7;
+
+        fn encode_ext(&self) -> Vec<u8> {
+            vec![self.0, self.1]
+        }
+
+        fn decode_ext(data: &[u8]) -> Option<Self> {
+            if data.len() == 2 {
+                Some(Point(data[0], data[1]))
+            } else {
+                None
+            }
+        }
+    }
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("mirror", |params| {
+        let result = match from_ext::<Point>(&params[0]) {
+            Some(point) => Ok(to_ext(&Point(point.1, point.0))),
+            None => Err(Value::from("expected a point")),
+        };
+        Box::new(future::ok(result))
+    });
+    let mut client = TestClient::new(router);
+    let result = client.request("mirror", &[to_ext(&Point(1, 2))]).unwrap();
+    assert_eq!(from_ext::<Point>(&result), Some(Point(2, 1)));
+    assert!(client.request("mirror", &[Value::Ext(8, vec![1, 2])]).is_err());
+    assert!(client.request("mirror", &[Value::Ext(7, vec![1])]).is_err());
+}
+
+#[cfg(feature = "serde-params")]
+#[test]
+fn ext_registry() {
+    use futures::future;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use methods::MethodRouter;
+    use mock::TestClient;
+    use options::ProtocolOptions;
+
+    #[derive(Debug, PartialEq)]
+    struct Buffer(u64);
+
+    impl Serialize for Buffer {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_u64(self.0)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Buffer {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            u64::deserialize(deserializer).map(Buffer)
+        }
+    }
+
+    impl SerdeExt for Buffer {
+        const EXT_TYPE: i8 = 0;
+    }
+
+    assert_eq!(to_ext(&Buffer(300)), Value::Ext(0, vec![0xcd, 0x01, 0x2c]));
+    assert_eq!(from_ext::<Buffer>(&Value::Ext(0, vec![0x07])), Some(Buffer(7)));
+    assert_eq!(from_ext::<Buffer>(&Value::Ext(0, vec![0x07, 0x07])), None);
+    assert_eq!(from_ext::<Buffer>(&Value::Ext(0, vec![0xa1, 0x61])), None);
+
+    let mut registry = ExtRegistry::new();
+    let _ = registry.register::<Buffer>();
+    assert!(registry.is_registered(0) && !registry.is_registered(1));
+    let value = Value::Array(vec![
+        Value::Map(vec![(Value::from("buffer"), to_ext(&Buffer(1)))]),
+        Value::Ext(1, vec![1]),
+        Value::Ext(0, vec![0xc1]),
+    ]);
+    let expected = Value::Array(vec![
+        Value::Map(vec![(Value::from("buffer"), Value::from(1))]),
+        Value::Ext(1, vec![1]),
+        Value::Ext(0, vec![0xc1]),
+    ]);
+    assert_eq!(registry.decode(value), expected);
+
+    // The registry is consulted for the parameters and the results.
+    let mut router = MethodRouter::new();
+    let _ = router.request("next", |params| {
+        let result = match params[0].as_u64() {
+            Some(n) => Ok(to_ext(&Buffer(n + 1))),
+            None => Err(params[0].clone()),
+        };
+        Box::new(future::ok(result))
+    });
+    let mut options = ProtocolOptions::default();
+    let _ = options.ext_registry(Some(registry));
+    let mut client = TestClient::with_options(router, options);
+    assert_eq!(client.request("next", &[to_ext(&Buffer(7))]), Ok(Value::from(8)));
+    let result = client.request("next", &[Value::Ext(1, vec![7])]);
+    assert_eq!(result, Err(Value::Ext(1, vec![7])));
+}
@@ -0,0 +1,84 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A map that holds at most one value of each type, like `http::Extensions`. Each connection has
+/// one (see [`Context::with_extensions`](struct.Context.html#method.with_extensions)), so that
+/// independent middlewares and handlers can share data about the connection, such as the
+/// authenticated identity or rate limiting state, without global state.
+///
+/// To avoid collisions between independent users, the values should be of types private to their
+/// users, for instance a newtype.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<Any + Send>>,
+}
+
+impl Extensions {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    /// Insert a value, and return the previous value of the same type, if any.
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        match self.map.insert(TypeId::of::<T>(), Box::new(value)) {
+            Some(previous) => previous.downcast().ok().map(|previous| *previous),
+            None => None,
+        }
+    }
+
+    /// Return a reference to the value of the given type, if any.
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        match self.map.get(&TypeId::of::<T>()) {
+            Some(value) => value.downcast_ref(),
+            None => None,
+        }
+    }
+
+    /// Return a mutable reference to the value of the given type, if any.
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        match self.map.get_mut(&TypeId::of::<T>()) {
+            Some(value) => value.downcast_mut(),
+            None => None,
+        }
+    }
+
+    /// Remove the value of the given type, and return it.
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        match self.map.remove(&TypeId::of::<T>()) {
+            Some(value) => value.downcast().ok().map(|value| *value),
+            None => None,
+        }
+    }
+
+    /// Return `true` if the map holds no value.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Extensions {{ {} values }}", self.map.len())
+    }
+}
+
+#[test]
+fn extensions() {
+    #[derive(Debug, PartialEq)]
+    struct Identity(String);
+    #[derive(Debug, PartialEq)]
+    struct Budget(u32);
+
+    let mut extensions = Extensions::new();
+    assert!(extensions.insert(Identity("alice".to_owned())).is_none());
+    assert!(extensions.insert(Budget(10)).is_none());
+    assert_eq!(extensions.get::<Identity>(), Some(&Identity("alice".to_owned())));
+
+    extensions.get_mut::<Budget>().unwrap().0 -= 1;
+    assert_eq!(extensions.insert(Budget(5)), Some(Budget(9)));
+    assert_eq!(extensions.remove::<Budget>(), Some(Budget(5)));
+    assert!(extensions.get::<Budget>().is_none());
+    assert!(!extensions.is_empty());
+}
@@ -0,0 +1,114 @@
+use rmpv::Value;
+
+use message::Notification;
+
+/// Method of the notification that carries a [`Hello`](struct.Hello.html).
+pub const HELLO_METHOD: &str = "$/hello";
+
+/// A message describing an endpoint, that can be sent to the remote endpoint when the connection
+/// is established (see [`ProtocolOptions::hello`](struct.ProtocolOptions.html#method.hello)).
+///
+/// It is sent as a `$/hello` notification whose parameters are the implementation name, its
+/// version, and the list of features it supports. Peers that do not know about it just see an
+/// unknown notification.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    name: String,
+    version: String,
+    features: Vec<String>,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Hello::new("rmp-rpc", env!("CARGO_PKG_VERSION"))
+    }
+}
+
+impl Hello {
+    /// Create a new `Hello` for the given implementation, that does not advertise any feature.
+    pub fn new(name: &str, version: &str) -> Self {
+        Hello {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            features: Vec::new(),
+        }
+    }
+
+    /// Advertise support for the given feature.
+    pub fn add_feature(&mut self, feature: &str) -> &mut Self {
+        if !self.has_feature(feature) {
+            self.features.push(feature.to_owned());
+        }
+        self
+    }
+
+    /// Name of the implementation.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Version of the implementation.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Features supported by the endpoint.
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// Return `true` if the endpoint supports the given feature.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Build the `$/hello` notification that carries this `Hello`.
+    pub fn to_notification(&self) -> Notification {
+        let features = self.features
+            .iter()
+            .map(|feature| Value::from(feature.as_str()))
+            .collect::<Vec<Value>>();
+        Notification {
+            method: HELLO_METHOD.to_owned(),
+            params: vec![
+                Value::from(self.name.as_str()),
+                Value::from(self.version.as_str()),
+                Value::Array(features),
+            ],
+        }
+    }
+
+    /// Build a `Hello` from the parameters of a `$/hello` notification. Return `None` if they
+    /// are invalid.
+    pub fn from_params(params: &[Value]) -> Option<Self> {
+        if params.len() < 3 {
+            return None;
+        }
+        let (name, version) = match (params[0].as_str(), params[1].as_str()) {
+            (Some(name), Some(version)) => (name, version),
+            _ => return None,
+        };
+        let features = match params[2].as_array() {
+            Some(features) => features
+                .iter()
+                .map(|feature| feature.as_str().map(|s| s.to_owned()))
+                .collect::<Option<Vec<String>>>(),
+            None => return None,
+        };
+        features.map(|features| Hello {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            features: features,
+        })
+    }
+}
+
+#[test]
+fn hello_round_trip() {
+    let mut hello = Hello::new("test", "1.2.3");
+    let _ = hello.add_feature("streaming").add_feature("compression");
+    let notification = hello.to_notification();
+    assert_eq!(notification.method, HELLO_METHOD);
+    assert_eq!(Hello::from_params(&notification.params), Some(hello));
+    assert_eq!(Hello::from_params(&[Value::from("test")]), None);
+}
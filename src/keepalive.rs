@@ -0,0 +1,113 @@
+use std::cmp;
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future};
+use futures::task;
+use tokio_core::reactor::Handle;
+
+use clock::{Clock, Timer};
+use message::{Message, Notification};
+use options::ProtocolOptions;
+
+/// Method of the notification sent to keep a connection alive (see
+/// [`ProtocolOptions::keepalive_interval`](struct.ProtocolOptions.html#method.keepalive_interval)),
+/// and of the request that checks that an endpoint is alive (see
+/// [`ProtocolOptions::health_check`](struct.ProtocolOptions.html#method.health_check)).
+pub const PING_METHOD: &str = "$/ping";
+
+/// Closes the connection once it has been idle for too long, and sends pings at regular
+/// intervals.
+pub(crate) struct Liveness {
+    idle_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    last_received: Instant,
+    last_ping: Instant,
+    clock: Clock,
+    // Fires at the next deadline. With the system clock, it is only created once the endpoint
+    // runs on a reactor.
+    timer: Option<Timer>,
+}
+
+impl Liveness {
+    /// Return `None` if the options disable both the idle timeout and the pings.
+    pub(crate) fn new(options: &ProtocolOptions) -> Option<Self> {
+        if options.get_idle_timeout().is_none() && options.get_keepalive_interval().is_none() {
+            return None;
+        }
+        let clock = options.get_clock().clone();
+        let now = clock.now();
+        Some(Liveness {
+            idle_timeout: options.get_idle_timeout(),
+            keepalive_interval: options.get_keepalive_interval(),
+            last_received: now,
+            last_ping: now,
+            clock: clock,
+            timer: None,
+        })
+    }
+
+    /// Record that a message has been received, or that work is in progress on the connection.
+    pub(crate) fn received(&mut self) {
+        self.last_received = self.clock.now();
+    }
+
+    /// Return `true` if the connection has been idle for too long. Otherwise, return the ping to
+    /// send, if one is due, and arrange for the current task to be notified at the next deadline.
+    pub(crate) fn poll(&mut self, reactor: Option<&Handle>) -> io::Result<(bool, Option<Message>)> {
+        let now = self.clock.now();
+        let idle_deadline = self.idle_timeout.map(|timeout| self.last_received + timeout);
+        if let Some(deadline) = idle_deadline {
+            if now >= deadline {
+                return Ok((true, None));
+            }
+        }
+        let mut ping = None;
+        if let Some(interval) = self.keepalive_interval {
+            if now >= self.last_ping + interval {
+                ping = Some(Message::Notification(Notification {
+                    method: PING_METHOD.to_owned(),
+                    params: Vec::new(),
+                }));
+                self.last_ping = now;
+            }
+        }
+        let ping_deadline = self.keepalive_interval
+            .map(|interval| self.last_ping + interval);
+        let deadline = match (idle_deadline, ping_deadline) {
+            (Some(idle), Some(ping)) => cmp::min(idle, ping),
+            (Some(deadline), None) | (None, Some(deadline)) => deadline,
+            (None, None) => return Ok((false, ping)),
+        };
+        match self.timer {
+            Some(ref mut timer) => timer.reset(deadline),
+            None => self.timer = self.clock.timer_at(deadline, reactor)?,
+        }
+        if let Some(ref mut timer) = self.timer {
+            if let Async::Ready(()) = timer.poll()? {
+                task::current().notify();
+            }
+        }
+        Ok((false, ping))
+    }
+}
+
+#[test]
+fn health_check() {
+    use rmpv::Value;
+    use methods::MethodRouter;
+    use mock::TestClient;
+    use rpc_error::RpcError;
+
+    let mut client = TestClient::new(MethodRouter::new());
+    let ping = client.client().ping();
+    assert!(client.run(ping).unwrap().is_ok());
+    assert_eq!(client.request(PING_METHOD, &[]), Ok(Value::from("ok")));
+
+    let mut options = ProtocolOptions::new();
+    let _ = options.health_check(false);
+    let mut client = TestClient::with_options(MethodRouter::new(), options);
+    let ping = client.client().ping();
+    let error = client.run(ping).unwrap().unwrap_err();
+    assert!(RpcError::is_unsupported_feature(&error));
+}
@@ -1,28 +1,228 @@
 //! This crate provides facilities to use the `MessagePack` remote procedure call system
 //! (`MessagePack-RPC`) in Rust.
+//!
+//! The clients and servers run on tokio, and are part of the default `runtime` feature. Without
+//! it (`default-features = false`), the crate only provides the [`message`](message/index.html)
+//! module and the value helpers, which do not depend on tokio: this is enough for tools that
+//! parse, build or re-encode messages, from captured traffic for instance.
 #![cfg_attr(feature = "clippy", feature(plugin))]
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 #![cfg_attr(feature = "clippy", deny(clippy))]
 #![cfg_attr(feature = "clippy", allow(missing_docs_in_private_items))]
 #![cfg_attr(feature = "clippy", allow(type_complexity))]
 
+#[cfg(feature = "websocket")]
+extern crate base64;
 extern crate bytes;
+#[cfg(feature = "runtime")]
 extern crate futures;
-#[macro_use]
+#[cfg(feature = "websocket")]
+extern crate httparse;
+#[cfg(feature = "runtime")]
+extern crate iovec;
+#[cfg_attr(any(feature = "runtime", feature = "tokio1"), macro_use)]
 extern crate log;
+#[cfg(feature = "runtime")]
 extern crate native_tls;
+#[cfg(feature = "runtime")]
+extern crate net2;
+#[cfg(feature = "runtime")]
+extern crate num_cpus;
+extern crate rmp;
 extern crate rmpv;
+#[cfg(any(feature = "config", feature = "serde-params"))]
+extern crate serde;
+#[cfg(feature = "config")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "compression")]
+extern crate lz4;
+#[cfg(feature = "websocket")]
+extern crate sha1;
+#[cfg(feature = "tokio1")]
+extern crate tokio_1;
+#[cfg(feature = "runtime")]
 extern crate tokio_core;
+#[cfg(feature = "runtime")]
 extern crate tokio_io;
+#[cfg(feature = "runtime")]
 extern crate tokio_tls;
+#[cfg(all(unix, feature = "runtime"))]
+extern crate tokio_uds;
+#[cfg(feature = "tracing")]
+#[macro_use(info_span)]
+extern crate tracing;
 
+#[cfg(feature = "runtime")]
+pub mod config;
+#[cfg(feature = "runtime")]
+mod anomaly;
+#[cfg(feature = "runtime")]
+mod audit;
+#[cfg(feature = "runtime")]
+mod auth;
+#[cfg(feature = "runtime")]
+mod blocking;
+#[cfg(feature = "runtime")]
+mod cache;
+#[cfg(feature = "runtime")]
+mod capabilities;
+#[cfg(feature = "runtime")]
+mod channel;
+#[cfg(feature = "runtime")]
+mod clock;
+#[cfg(feature = "runtime")]
+mod dump;
 mod errors;
+mod ext;
+#[cfg(feature = "runtime")]
+mod extensions;
+#[cfg(feature = "runtime")]
 mod codec;
-mod message;
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "runtime")]
+mod context;
+#[cfg(feature = "runtime")]
+mod dynamic;
+#[cfg(feature = "runtime")]
+mod hello;
+#[cfg(feature = "runtime")]
+mod keepalive;
+pub mod message;
+#[cfg(feature = "runtime")]
+mod lifecycle;
+#[cfg(feature = "runtime")]
+mod methods;
+#[cfg(feature = "runtime")]
+mod metrics;
+#[cfg(feature = "runtime")]
+pub mod mock;
+#[cfg(feature = "runtime")]
 mod net;
+#[cfg(feature = "nvim")]
+pub mod nvim;
+mod params;
+#[cfg(feature = "runtime")]
 mod endpoint;
+#[cfg(feature = "runtime")]
+mod options;
+#[cfg(feature = "runtime")]
+mod pool;
+#[cfg(feature = "presets")]
+pub mod presets;
+#[cfg(feature = "runtime")]
+mod priority;
+#[cfg(feature = "runtime")]
+mod proxy;
+#[cfg(feature = "runtime")]
+mod reconnect;
+#[cfg(feature = "runtime")]
+pub mod recording;
+#[cfg(feature = "runtime")]
+mod redact;
+#[cfg(feature = "runtime")]
+mod resolver;
+#[cfg(feature = "runtime")]
+mod rewrite;
+mod rpc_error;
+#[cfg(any(feature = "runtime", feature = "tokio1"))]
+mod scan;
+#[cfg(feature = "runtime")]
+mod server;
+#[cfg(feature = "runtime")]
+mod session;
+#[cfg(feature = "runtime")]
+mod streaming;
+#[cfg(feature = "runtime")]
+mod throttle;
+mod time;
+#[cfg(feature = "tokio1")]
+pub mod tokio1;
+#[cfg(feature = "runtime")]
+mod transform;
+#[cfg(feature = "runtime")]
+mod transport;
+#[cfg(feature = "runtime")]
+mod udp;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
-pub use endpoint::{Ack, Client, Response, Service, ServiceBuilder};
-pub use net::{serve, ClientOnlyConnector, Connection, Connector};
+#[cfg(feature = "runtime")]
+pub use anomaly::{Anomalies, Anomaly, AnomalyHandler};
+#[cfg(feature = "runtime")]
+pub use audit::{AuditLog, AuditOutcome, AuditRecord};
+#[cfg(feature = "runtime")]
+pub use auth::{Admission, Authenticator};
+#[cfg(feature = "runtime")]
+pub use blocking::SyncClient;
+#[cfg(feature = "runtime")]
+pub use channel::Channel;
+#[cfg(feature = "runtime")]
+pub use capabilities::Capabilities;
+#[cfg(feature = "runtime")]
+pub use clock::Clock;
+#[cfg(feature = "runtime")]
+pub use cache::{CacheStats, Cached, CachedResponse, ResponseCache};
+#[cfg(feature = "runtime")]
+pub use context::Context;
+#[cfg(feature = "runtime")]
+pub use dynamic::{into_dyn_builder, into_dyn_service, DynService, DynServiceBuilder};
+#[cfg(feature = "runtime")]
+pub use dump::{Direction, DumpRecord, DumpSink, ProtocolDump};
+pub use errors::{CallError, ClientError, DecodeError, RouterError, ServerError, StreamError};
+#[cfg(feature = "runtime")]
+pub use endpoint::{Ack, Batch, BatchResponse, BoxedService, Client, Notifications, Pusher,
+                   Response, Service, ServiceBuilder};
+pub use ext::{from_ext, to_ext, ExtType};
+#[cfg(feature = "serde-params")]
+pub use ext::{ExtRegistry, SerdeExt};
+#[cfg(feature = "runtime")]
+pub use extensions::Extensions;
+#[cfg(feature = "runtime")]
+pub use hello::Hello;
+pub use message::{EmptyParams, Notification, Param};
+#[cfg(feature = "runtime")]
+pub use lifecycle::{ConnectionEvent, ConnectionEvents};
+#[cfg(feature = "runtime")]
+pub use methods::{MethodFuture, MethodNotificationFuture, MethodRouter};
+#[cfg(feature = "runtime")]
+pub use metrics::{BasicMetrics, Metrics};
+#[cfg(feature = "runtime")]
+pub use net::{connect_to, serve, serve_with_options, ClientOnlyConnector, Connection,
+              Connector};
+#[cfg(feature = "runtime")]
+pub use options::{DuplicateIdPolicy, Limits, ProtocolOptions, ProtocolPolicy};
+pub use params::{ParamType, ParamsSpec};
+#[cfg(feature = "serde-params")]
+pub use params::from_params;
+#[cfg(feature = "runtime")]
+pub use pool::{Balancing, ClientPool};
+#[cfg(feature = "runtime")]
+pub use priority::Priority;
+#[cfg(feature = "runtime")]
+pub use proxy::{ProxyService, Router, Upstream};
+#[cfg(feature = "runtime")]
+pub use reconnect::{Backoff, OverflowPolicy, ReconnectingClient, ReplayPolicy};
+#[cfg(feature = "runtime")]
+pub use redact::Redactions;
+#[cfg(feature = "runtime")]
+pub use resolver::{HostResolver, Resolver};
+#[cfg(feature = "runtime")]
+pub use rewrite::MethodRewrites;
+pub use rpc_error::RpcError;
+#[cfg(feature = "runtime")]
+pub use server::{Server, ServerBuilder, ServerHandle, ServerReady, ServerShutdown};
+#[cfg(feature = "runtime")]
+pub use session::SessionBuilder;
+#[cfg(feature = "runtime")]
+pub use streaming::ResponseStream;
+pub use time::{RpcDuration, RpcTimestamp};
+#[cfg(feature = "runtime")]
+pub use transform::{ParamTransforms, Transformed};
+#[cfg(feature = "runtime")]
+pub use transport::Transport;
+#[cfg(feature = "runtime")]
+pub use udp::UdpTransport;
 
 pub use rmpv::{Integer, Utf8String, Value};
@@ -0,0 +1,53 @@
+use std::net::SocketAddr;
+
+use futures::{Poll, Stream};
+use futures::sync::mpsc;
+
+use errors::CallError;
+
+/// Something that happened to a connection of a server (see
+/// [`ServerHandle::events`](struct.ServerHandle.html#method.events)).
+///
+/// The peer address is `None` for the connections accepted on a Unix socket. The connection ids
+/// are those of the [`Context`](struct.Context.html#method.connection_id) of the connections,
+/// and of [`ServerHandle::connections`](struct.ServerHandle.html#method.connections).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// A connection completed its handshakes, and is now served.
+    Connected {
+        connection: usize,
+        peer_addr: Option<SocketAddr>,
+    },
+    /// A connection has been closed. The reason is `CallError::ConnectionClosed` if it was closed
+    /// cleanly, by either side.
+    Disconnected {
+        connection: usize,
+        peer_addr: Option<SocketAddr>,
+        reason: CallError,
+    },
+    /// A connection failed before it was served, during its TLS or WebSocket handshake for
+    /// instance. It has no connection id.
+    Failed {
+        peer_addr: Option<SocketAddr>,
+        error: CallError,
+    },
+}
+
+/// A stream of the [`ConnectionEvent`](enum.ConnectionEvent.html)s of a server. It ends once the
+/// server and all its handles have been dropped.
+pub struct ConnectionEvents(mpsc::UnboundedReceiver<ConnectionEvent>);
+
+impl ConnectionEvents {
+    pub(crate) fn new(rx: mpsc::UnboundedReceiver<ConnectionEvent>) -> Self {
+        ConnectionEvents(rx)
+    }
+}
+
+impl Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
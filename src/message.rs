@@ -1,14 +1,35 @@
+//! The `MessagePack-RPC` messages, as they are sent and received by a
+//! [`Transport`](../struct.Transport.html).
+//!
+//! Messages can be decoded with [`Message::decode`](enum.Message.html#method.decode) and encoded
+//! with [`Message::pack`](enum.Message.html#method.pack) without a connection, and without the
+//! `runtime` feature:
+//!
+//! ```rust,ignore
+//! let message = Message::decode(&mut io::Cursor::new(&payload))?;
+//! println!("{:?}", message);
+//! let bytes = message.pack()?;
+//! ```
 use errors::*;
-use std::io::{self, Read};
-use rmpv::{decode, encode, Integer, Utf8String, Value};
-use std::convert::From;
+use std::{cmp, fmt};
+use std::io::{self, Read, Write};
+use bytes::Bytes;
+use rmp::Marker;
+use rmp::decode as rmp_decode;
+use rmp::encode as rmp_encode;
+use rmpv::{decode, encode, Value};
+
+use rpc_error::RpcError;
 
 /// Represents a `MessagePack-RPC` message as described in the
 /// [specifications](https://github.com/msgpack-rpc/msgpack-rpc/blob/master/spec.md#messagepack-rpc-protocol-specification)
 #[derive(PartialEq, Clone, Debug)]
 pub enum Message {
+    /// A request, that expects a response with the same id.
     Request(Request),
+    /// The response to a request.
     Response(Response),
+    /// A notification, that expects no response.
     Notification(Notification),
 }
 
@@ -16,16 +37,132 @@ pub enum Message {
 /// [specifications](https://github.com/msgpack-rpc/msgpack-rpc/blob/master/spec.md#messagepack-rpc-protocol-specification)
 #[derive(PartialEq, Clone, Debug)]
 pub struct Request {
-    pub id: u32,
+    /// Id of the request, used to match it with its response
+    pub id: Id,
+    /// Name of the method called
     pub method: String,
-    pub params: Vec<Value>,
+    /// Parameters of the method
+    pub params: Vec<Param>,
+}
+
+/// Id of a request. The specification says ids are 32 bits unsigned integers, but some peers use
+/// any msgpack integer, so ids can also be negative or larger than `u32::MAX`. The ids we
+/// generate always fit in a `u32`.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Id {
+    /// A positive (or zero) id.
+    Unsigned(u64),
+    /// A negative id.
+    Negative(i64),
+}
+
+impl Id {
+    fn decode<R: Read>(rd: &mut R, budget: &mut Budget) -> Result<Self, DecodeError> {
+        match read_value(rd, budget)? {
+            Value::Integer(id) => match (id.as_u64(), id.as_i64()) {
+                (Some(id), _) => Ok(Id::Unsigned(id)),
+                (None, Some(id)) => Ok(Id::Negative(id)),
+                (None, None) => Err(DecodeError::InvalidId),
+            },
+            _ => Err(DecodeError::InvalidId),
+        }
+    }
+
+    fn encode<W: Write>(&self, wr: &mut W) -> io::Result<()> {
+        match *self {
+            Id::Unsigned(id) => {
+                let _ = rmp_encode::write_uint(wr, id)?;
+            }
+            Id::Negative(id) => {
+                let _ = rmp_encode::write_sint(wr, id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<u32> for Id {
+    fn from(id: u32) -> Self {
+        Id::Unsigned(u64::from(id))
+    }
+}
+
+impl From<u64> for Id {
+    fn from(id: u64) -> Self {
+        Id::Unsigned(id)
+    }
+}
+
+impl From<i64> for Id {
+    fn from(id: i64) -> Self {
+        if id < 0 {
+            Id::Negative(id)
+        } else {
+            Id::Unsigned(id as u64)
+        }
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Id::Unsigned(id) => id.fmt(f),
+            Id::Negative(id) => id.fmt(f),
+        }
+    }
+}
+
+/// A parameter of a request. Binary parameters can be kept in a `Bytes` buffer instead of being
+/// copied into a `Value::Binary`: such parameters are written to the transport without being
+/// copied (see [`Client::request_zero_copy`](struct.Client.html#method.request_zero_copy)), and
+/// large binary parameters can be received without being copied out of the receive buffer (see
+/// [`ProtocolOptions::zero_copy_binary`](struct.ProtocolOptions.html#method.zero_copy_binary)).
+#[derive(PartialEq, Clone, Debug)]
+pub enum Param {
+    /// A parameter of any type.
+    Value(Value),
+    /// A binary parameter.
+    Binary(Bytes),
+}
+
+impl Param {
+    /// Convert the parameter into a `Value`. Binary parameters are copied into a `Value::Binary`.
+    pub fn into_value(self) -> Value {
+        match self {
+            Param::Value(value) => value,
+            Param::Binary(bytes) => Value::Binary(bytes.to_vec()),
+        }
+    }
+
+    /// Return the content of a binary parameter, whether it is a `Param::Binary` or a
+    /// `Value::Binary`.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match *self {
+            Param::Value(ref value) => value.as_slice(),
+            Param::Binary(ref bytes) => Some(bytes),
+        }
+    }
+}
+
+impl From<Value> for Param {
+    fn from(value: Value) -> Self {
+        Param::Value(value)
+    }
+}
+
+impl From<Bytes> for Param {
+    fn from(bytes: Bytes) -> Self {
+        Param::Binary(bytes)
+    }
 }
 
 /// Represents a `MessagePack-RPC` response as described in the
 /// [specifications](https://github.com/msgpack-rpc/msgpack-rpc/blob/master/spec.md#messagepack-rpc-protocol-specification)
 #[derive(PartialEq, Clone, Debug)]
 pub struct Response {
-    pub id: u32,
+    /// Id of the request this response answers
+    pub id: Id,
+    /// Result of the request: the result if it succeeded, the error otherwise
     pub result: Result<Value, Value>,
 }
 
@@ -33,111 +170,394 @@ pub struct Response {
 /// [specifications](https://github.com/msgpack-rpc/msgpack-rpc/blob/master/spec.md#messagepack-rpc-protocol-specification)
 #[derive(PartialEq, Clone, Debug)]
 pub struct Notification {
+    /// Name of the method the notification is about
     pub method: String,
+    /// Parameters of the notification
     pub params: Vec<Value>,
 }
 
+/// How the requests and notifications without parameters are encoded. Decoding always accepts
+/// both forms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmptyParams {
+    /// An empty array, as the specification requires.
+    Array,
+    /// `nil`, as some implementations expect.
+    Nil,
+}
+
+impl Default for EmptyParams {
+    fn default() -> Self {
+        EmptyParams::Array
+    }
+}
+
 const REQUEST_MESSAGE: u64 = 0;
 const RESPONSE_MESSAGE: u64 = 1;
 const NOTIFICATION_MESSAGE: u64 = 2;
 
+// Upper bound for the capacity we pre-allocate when decoding the parameters of a message, so that
+// a bogus array length does not make us allocate a huge buffer.
+const MAX_PREALLOCATED_PARAMS: usize = 64;
+
+/// Default maximum number of arrays and maps a value can be nested in.
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Limits applied while decoding a message, so that a hostile peer cannot exhaust the stack with
+/// deeply nested values, or the memory with a huge number of small values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DecodeLimits {
+    pub(crate) max_depth: usize,
+    pub(crate) max_elements: Option<usize>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_elements: None,
+        }
+    }
+}
+
+/// What is left of the `DecodeLimits` while a message is decoded.
+pub(crate) struct Budget {
+    // How many more arrays and maps can be entered.
+    depth: usize,
+    // How many more values can be read.
+    elements: Option<usize>,
+}
+
+impl Budget {
+    fn new(limits: DecodeLimits) -> Self {
+        Budget {
+            depth: limits.max_depth,
+            elements: limits.max_elements,
+        }
+    }
+
+    /// Account for a new value.
+    fn count(&mut self) -> Result<(), DecodeError> {
+        match self.elements {
+            Some(0) => Err(DecodeError::TooManyElements),
+            Some(ref mut elements) => {
+                *elements -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Read a value, like `rmpv::decode::value::read_value`, but within the limits of `budget`.
+/// Arrays and maps are read without recursing into rmpv, whose recursion is not bounded.
+pub(crate) fn read_value<R: Read>(rd: &mut R, budget: &mut Budget) -> Result<Value, DecodeError> {
+    budget.count()?;
+    let (len, is_map) = match rmp_decode::read_marker(rd)? {
+        Marker::FixArray(len) => (u32::from(len), false),
+        Marker::Array16 => (read_len(rd, 2)?, false),
+        Marker::Array32 => (read_len(rd, 4)?, false),
+        Marker::FixMap(len) => (u32::from(len), true),
+        Marker::Map16 => (read_len(rd, 2)?, true),
+        Marker::Map32 => (read_len(rd, 4)?, true),
+        // The other values do not nest: rmpv reads them, starting with the marker again.
+        marker => {
+            let marker = [marker.to_u8()];
+            return Ok(decode::value::read_value(&mut (&marker[..]).chain(rd))?);
+        }
+    };
+    if budget.depth == 0 {
+        return Err(DecodeError::DepthLimitExceeded);
+    }
+    budget.depth -= 1;
+    let value = if is_map {
+        read_map(rd, len as usize, budget)?
+    } else {
+        let mut array = Vec::with_capacity(cmp::min(len as usize, MAX_PREALLOCATED_PARAMS));
+        for _ in 0..len {
+            array.push(read_value(rd, budget)?);
+        }
+        Value::Array(array)
+    };
+    budget.depth += 1;
+    Ok(value)
+}
+
 impl Message {
+    /// Decode a message. The message is read directly from `rd`, without building an intermediate
+    /// `Value` for the whole message. Values nested in more than 64 arrays and maps are rejected.
     pub fn decode<R>(rd: &mut R) -> Result<Message, DecodeError>
     where
         R: Read,
     {
-        let msg = decode::value::read_value(rd)?;
-        if let Value::Array(ref array) = msg {
-            if array.len() < 3 {
-                // notification are the shortest message and have 3 items
-                return Err(DecodeError::Invalid);
+        let limits = DecodeLimits::default();
+        Message::decode_with(rd, false, false, false, limits, &mut |rd, _, budget| {
+            Ok(Param::Value(read_value(rd, budget)?))
+        })
+    }
+
+    /// Decode a message, using `read_param` to read each parameter of a request. It is given the
+    /// index of the parameter, and what is left of the `limits`.
+    ///
+    /// If `lenient` is `true`, some common deviations from the specification are accepted and
+    /// normalized: method names encoded as `bin` instead of `str`, parameters sent as a map
+    /// instead of an array, which become a single parameter holding this map, and responses with
+    /// 3 items, whose error is omitted. Parameters sent as `nil` instead of an empty array are
+    /// always accepted.
+    ///
+    /// If `legacy` is `true`, the errors of the responses are decoded as the reference
+    /// implementations send them (see `Response::decode`).
+    ///
+    /// If `named` is `true`, parameters sent as a map of named arguments are accepted, and
+    /// become a single parameter holding this map, as in lenient mode.
+    pub(crate) fn decode_with<R, F>(
+        rd: &mut R,
+        lenient: bool,
+        legacy: bool,
+        named: bool,
+        limits: DecodeLimits,
+        read_param: &mut F,
+    ) -> Result<Message, DecodeError>
+    where
+        R: Read,
+        F: FnMut(&mut R, usize, &mut Budget) -> Result<Param, DecodeError>,
+    {
+        let len = rmp_decode::read_array_len(rd)?;
+        if len < 3 {
+            // notification are the shortest message and have 3 items
+            return Err(DecodeError::Invalid);
+        }
+        let message_type = match rmp_decode::read_int(rd) {
+            Ok(message_type) => message_type,
+            Err(rmp_decode::NumValueReadError::TypeMismatch(_))
+            | Err(rmp_decode::NumValueReadError::OutOfRange) => {
+                return Err(DecodeError::InvalidMessageType)
             }
-            if let Value::Integer(msg_type) = array[0] {
-                match msg_type.as_u64() {
-                    Some(REQUEST_MESSAGE) => {
-                        return Ok(Message::Request(Request::decode(array)?));
-                    }
-                    Some(RESPONSE_MESSAGE) => {
-                        return Ok(Message::Response(Response::decode(array)?));
-                    }
-                    Some(NOTIFICATION_MESSAGE) => {
-                        return Ok(Message::Notification(Notification::decode(array)?));
-                    }
-                    _ => {
-                        return Err(DecodeError::Invalid);
-                    }
-                }
-            } else {
-                return Err(DecodeError::Invalid);
+            Err(e) => return Err(DecodeError::from(e)),
+        };
+        let mut budget = Budget::new(limits);
+        // Whether the parameters can be a map.
+        let maps = lenient || named;
+        let (message, decoded) = match message_type {
+            REQUEST_MESSAGE => {
+                let request = Request::decode(rd, len, lenient, maps, &mut budget, read_param)?;
+                (Message::Request(request), 4)
             }
-        } else {
-            return Err(DecodeError::Invalid);
+            RESPONSE_MESSAGE => {
+                let response = Response::decode(rd, len, lenient, legacy, &mut budget)?;
+                (Message::Response(response), cmp::min(len, 4))
+            }
+            NOTIFICATION_MESSAGE => {
+                let notification = Notification::decode(rd, lenient, maps, &mut budget)?;
+                (Message::Notification(notification), 3)
+            }
+            _ => return Err(DecodeError::InvalidMessageType),
+        };
+        // Skip the extra items, if any
+        for _ in decoded..len {
+            let _ = read_value(rd, &mut budget)?;
         }
+        Ok(message)
+    }
+
+    /// Encode the message and write it to `wr`. The message is written directly, without building
+    /// an intermediate `Value` for the whole message.
+    pub fn encode<W>(&self, wr: &mut W) -> io::Result<()>
+    where
+        W: MessageWriter,
+    {
+        self.encode_with(wr, EmptyParams::Array, false)
     }
 
-    pub fn as_value(&self) -> Value {
+    /// Encode the message and write it to `wr`, writing empty parameters as `empty_params`
+    /// requires. If `named` is `true`, the parameters of the requests and notifications that are
+    /// a single map are written as this map of named arguments, instead of an array.
+    pub(crate) fn encode_with<W>(
+        &self,
+        wr: &mut W,
+        empty_params: EmptyParams,
+        named: bool,
+    ) -> io::Result<()>
+    where
+        W: MessageWriter,
+    {
         match *self {
             Message::Request(Request {
                 id,
                 ref method,
                 ref params,
-            }) => Value::Array(vec![
-                Value::Integer(Integer::from(REQUEST_MESSAGE)),
-                Value::Integer(Integer::from(id)),
-                Value::String(Utf8String::from(method.as_str())),
-                Value::Array(params.clone()),
-            ]),
+            }) => {
+                rmp_encode::write_array_len(wr, 4)?;
+                rmp_encode::write_uint(wr, REQUEST_MESSAGE)?;
+                id.encode(wr)?;
+                rmp_encode::write_str(wr, method)?;
+                if named && params.len() == 1 {
+                    if let Param::Value(ref map @ Value::Map(_)) = params[0] {
+                        encode::write_value(wr, map)?;
+                        return Ok(());
+                    }
+                }
+                write_params_len(wr, params.len(), empty_params)?;
+                for param in params {
+                    match *param {
+                        Param::Value(ref value) => encode::write_value(wr, value)?,
+                        Param::Binary(ref bytes) => {
+                            rmp_encode::write_bin_len(wr, bytes.len() as u32)?;
+                            wr.write_binary(bytes)?;
+                        }
+                    }
+                }
+            }
             Message::Response(Response { id, ref result }) => {
-                let (error, result) = match *result {
-                    Ok(ref result) => (Value::Nil, result.to_owned()),
-                    Err(ref err) => (err.to_owned(), Value::Nil),
-                };
-                Value::Array(vec![
-                    Value::Integer(Integer::from(RESPONSE_MESSAGE)),
-                    Value::Integer(Integer::from(id)),
-                    error,
-                    result,
-                ])
+                rmp_encode::write_array_len(wr, 4)?;
+                rmp_encode::write_uint(wr, RESPONSE_MESSAGE)?;
+                id.encode(wr)?;
+                match *result {
+                    Ok(ref result) => {
+                        rmp_encode::write_nil(wr)?;
+                        encode::write_value(wr, result)?;
+                    }
+                    Err(ref err) => {
+                        encode::write_value(wr, err)?;
+                        rmp_encode::write_nil(wr)?;
+                    }
+                }
             }
             Message::Notification(Notification {
                 ref method,
                 ref params,
-            }) => Value::Array(vec![
-                Value::Integer(Integer::from(NOTIFICATION_MESSAGE)),
-                Value::String(Utf8String::from(method.as_str())),
-                Value::Array(params.to_owned()),
-            ]),
+            }) => {
+                rmp_encode::write_array_len(wr, 3)?;
+                rmp_encode::write_uint(wr, NOTIFICATION_MESSAGE)?;
+                rmp_encode::write_str(wr, method)?;
+                if named && params.len() == 1 {
+                    if let Value::Map(_) = params[0] {
+                        encode::write_value(wr, &params[0])?;
+                        return Ok(());
+                    }
+                }
+                write_params_len(wr, params.len(), empty_params)?;
+                for param in params {
+                    encode::write_value(wr, param)?;
+                }
+            }
         }
+        Ok(())
     }
 
+    /// Encode the message into a new buffer.
     pub fn pack(&self) -> io::Result<Vec<u8>> {
         let mut bytes = vec![];
-        encode::write_value(&mut bytes, &self.as_value())?;
+        self.encode(&mut bytes)?;
         Ok(bytes)
     }
 }
 
-impl Notification {
-    fn decode(array: &[Value]) -> Result<Self, DecodeError> {
-        if array.len() < 3 {
-            return Err(DecodeError::Invalid);
-        }
+/// A destination for encoded messages.
+pub trait MessageWriter: Write {
+    /// Write the content of a binary parameter, whose header has already been written. By default,
+    /// it is copied like the rest of the message.
+    fn write_binary(&mut self, bytes: &Bytes) -> io::Result<()> {
+        self.write_all(bytes)
+    }
+}
 
-        let method = if let Value::String(ref method) = array[1] {
-            method
-                .as_str()
-                .and_then(|s| Some(s.to_string()))
-                .ok_or(DecodeError::Invalid)?
-        } else {
-            return Err(DecodeError::Invalid);
-        };
+impl MessageWriter for Vec<u8> {}
 
-        let params = if let Value::Array(ref params) = array[2] {
-            params.clone()
-        } else {
-            return Err(DecodeError::Invalid);
-        };
+fn write_params_len<W: Write>(wr: &mut W, len: usize, empty: EmptyParams) -> io::Result<()> {
+    if len == 0 && empty == EmptyParams::Nil {
+        rmp_encode::write_nil(wr)?;
+    } else {
+        rmp_encode::write_array_len(wr, len as u32)?;
+    }
+    Ok(())
+}
+
+/// Read the length stored in the `size` bytes that follow a marker.
+fn read_len<R: Read>(rd: &mut R, size: usize) -> Result<u32, DecodeError> {
+    let mut buf = [0; 4];
+    rd.read_exact(&mut buf[4 - size..])?;
+    Ok(buf.iter().fold(0, |len, byte| (len << 8) | u32::from(*byte)))
+}
+
+fn read_method<R: Read>(rd: &mut R, lenient: bool) -> Result<String, DecodeError> {
+    let len = match rmp_decode::read_marker(rd)? {
+        Marker::FixStr(len) => u32::from(len),
+        Marker::Str8 => read_len(rd, 1)?,
+        Marker::Str16 => read_len(rd, 2)?,
+        Marker::Str32 => read_len(rd, 4)?,
+        Marker::Bin8 if lenient => read_len(rd, 1)?,
+        Marker::Bin16 if lenient => read_len(rd, 2)?,
+        Marker::Bin32 if lenient => read_len(rd, 4)?,
+        _ => return Err(DecodeError::InvalidMethod),
+    };
+    let mut bytes = Vec::new();
+    let read = rd.by_ref().take(u64::from(len)).read_to_end(&mut bytes)?;
+    if read < len as usize {
+        return Err(DecodeError::Truncated);
+    }
+    String::from_utf8(bytes).map_err(|_| DecodeError::InvalidMethod)
+}
 
+/// How the parameters of a message are encoded.
+enum ParamsLayout {
+    /// An array of the given length, as required by the specification.
+    Array(usize),
+    /// A map of the given length. This is only accepted in lenient mode, or when named arguments
+    /// are.
+    Map(usize),
+}
+
+fn read_params_layout<R: Read>(rd: &mut R, maps: bool) -> Result<ParamsLayout, DecodeError> {
+    let layout = match rmp_decode::read_marker(rd)? {
+        Marker::FixArray(len) => ParamsLayout::Array(len as usize),
+        Marker::Array16 => ParamsLayout::Array(read_len(rd, 2)? as usize),
+        Marker::Array32 => ParamsLayout::Array(read_len(rd, 4)? as usize),
+        Marker::FixMap(len) if maps => ParamsLayout::Map(len as usize),
+        Marker::Map16 if maps => ParamsLayout::Map(read_len(rd, 2)? as usize),
+        Marker::Map32 if maps => ParamsLayout::Map(read_len(rd, 4)? as usize),
+        Marker::Null => ParamsLayout::Array(0),
+        _ => return Err(DecodeError::InvalidParams),
+    };
+    Ok(layout)
+}
+
+fn read_map<R: Read>(rd: &mut R, len: usize, budget: &mut Budget) -> Result<Value, DecodeError> {
+    let mut map = Vec::with_capacity(cmp::min(len, MAX_PREALLOCATED_PARAMS));
+    for _ in 0..len {
+        let key = read_value(rd, budget)?;
+        let value = read_value(rd, budget)?;
+        map.push((key, value));
+    }
+    Ok(Value::Map(map))
+}
+
+fn read_params<R: Read>(
+    rd: &mut R,
+    maps: bool,
+    budget: &mut Budget,
+) -> Result<Vec<Value>, DecodeError> {
+    match read_params_layout(rd, maps)? {
+        ParamsLayout::Array(len) => {
+            let mut params = Vec::with_capacity(cmp::min(len, MAX_PREALLOCATED_PARAMS));
+            for _ in 0..len {
+                params.push(read_value(rd, budget)?);
+            }
+            Ok(params)
+        }
+        ParamsLayout::Map(len) => Ok(vec![read_map(rd, len, budget)?]),
+    }
+}
+
+impl Notification {
+    fn decode<R: Read>(
+        rd: &mut R,
+        lenient: bool,
+        maps: bool,
+        budget: &mut Budget,
+    ) -> Result<Self, DecodeError> {
+        let method = read_method(rd, lenient)?;
+        let params = read_params(rd, maps, budget)?;
         Ok(Notification {
             method: method,
             params: params,
@@ -146,34 +566,33 @@ impl Notification {
 }
 
 impl Request {
-    fn decode(array: &[Value]) -> Result<Self, DecodeError> {
-        if array.len() < 4 {
+    fn decode<R, F>(
+        rd: &mut R,
+        len: u32,
+        lenient: bool,
+        maps: bool,
+        budget: &mut Budget,
+        read_param: &mut F,
+    ) -> Result<Self, DecodeError>
+    where
+        R: Read,
+        F: FnMut(&mut R, usize, &mut Budget) -> Result<Param, DecodeError>,
+    {
+        if len < 4 {
             return Err(DecodeError::Invalid);
         }
-
-        let id = if let Value::Integer(id) = array[1] {
-            id.as_u64()
-                .and_then(|id| Some(id as u32))
-                .ok_or(DecodeError::Invalid)?
-        } else {
-            return Err(DecodeError::Invalid);
-        };
-
-        let method = if let Value::String(ref method) = array[2] {
-            method
-                .as_str()
-                .and_then(|s| Some(s.to_string()))
-                .ok_or(DecodeError::Invalid)?
-        } else {
-            return Err(DecodeError::Invalid);
-        };
-
-        let params = if let Value::Array(ref params) = array[3] {
-            params.clone()
-        } else {
-            return Err(DecodeError::Invalid);
+        let id = Id::decode(rd, budget)?;
+        let method = read_method(rd, lenient)?;
+        let params = match read_params_layout(rd, maps)? {
+            ParamsLayout::Array(len) => {
+                let mut params = Vec::with_capacity(cmp::min(len, MAX_PREALLOCATED_PARAMS));
+                for index in 0..len {
+                    params.push(read_param(rd, index, budget)?);
+                }
+                params
+            }
+            ParamsLayout::Map(len) => vec![Param::Value(read_map(rd, len, budget)?)],
         };
-
         Ok(Request {
             id: id,
             method: method,
@@ -183,27 +602,46 @@ impl Request {
 }
 
 impl Response {
-    fn decode(array: &[Value]) -> Result<Self, DecodeError> {
-        if array.len() < 2 {
+    /// Decode a response of `len` items. The specification requires 4 items: the type, the id,
+    /// the error and the result. If `lenient` is `true`, responses with 3 items, that some
+    /// implementations send for successful calls, are accepted: their last item is the result.
+    ///
+    /// If `legacy` is `true`, an error that is an integer code, along with a result that is a
+    /// string, is decoded as an [`RpcError`](struct.RpcError.html) with this code and this
+    /// message: this is how the reference C++ and Ruby implementations report errors.
+    fn decode<R: Read>(
+        rd: &mut R,
+        len: u32,
+        lenient: bool,
+        legacy: bool,
+        budget: &mut Budget,
+    ) -> Result<Self, DecodeError> {
+        if len < 3 || (len == 3 && !lenient) {
             return Err(DecodeError::Invalid);
         }
-
-        let id = if let Value::Integer(id) = array[1] {
-            id.as_u64()
-                .and_then(|id| Some(id as u32))
-                .ok_or(DecodeError::Invalid)?
-        } else {
-            return Err(DecodeError::Invalid);
-        };
-
-        match array[2] {
+        let id = Id::decode(rd, budget)?;
+        if len == 3 {
+            return Ok(Response {
+                id: id,
+                result: Ok(read_value(rd, budget)?),
+            });
+        }
+        let error = read_value(rd, budget)?;
+        let result = read_value(rd, budget)?;
+        if let (true, Some(code), Some(message)) = (legacy, error.as_i64(), result.as_str()) {
+            return Ok(Response {
+                id: id,
+                result: Err(Value::from(RpcError::new(code, message))),
+            });
+        }
+        match error {
             Value::Nil => Ok(Response {
                 id: id,
-                result: Ok(array[3].clone()),
+                result: Ok(result),
             }),
-            ref error => Ok(Response {
+            error => Ok(Response {
                 id: id,
-                result: Err(error.clone()),
+                result: Err(error),
             }),
         }
     }
@@ -212,7 +650,7 @@ impl Response {
 #[test]
 fn test_decode_request() {
     let valid = Message::Request(Request {
-        id: 1234,
+        id: Id::from(1234_u32),
         method: "dummy".to_string(),
         params: Vec::new(),
     });
@@ -240,8 +678,144 @@ fn test_decode_request() {
         bytes[1] = 5;
         let mut buf = io::Cursor::new(&bytes);
         assert!(match Message::decode(&mut buf) {
-            Err(DecodeError::Invalid) => true,
+            Err(DecodeError::InvalidMessageType) => true,
             _ => false,
         });
     }
 }
+
+#[test]
+fn test_request_ids() {
+    let ids = [
+        Id::from(0_u32),
+        Id::from(u64::max_value()),
+        Id::from(-1_i64),
+        Id::from(i64::min_value()),
+    ];
+    for id in &ids {
+        let request = Message::Request(Request {
+            id: *id,
+            method: "dummy".to_string(),
+            params: Vec::new(),
+        });
+        let response = Message::Response(Response {
+            id: *id,
+            result: Ok(Value::Nil),
+        });
+        for message in vec![request, response] {
+            let bytes = message.pack().unwrap();
+            assert_eq!(message, Message::decode(&mut io::Cursor::new(&bytes)).unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_decode_short_response() {
+    // A successful response without the error item, followed by a notification.
+    let bytes = [0x93, 0x01, 0x05, 0xa2, b'o', b'k', 0x93, 0x02, 0xa1, b'a', 0x90];
+    assert!(match Message::decode(&mut io::Cursor::new(&bytes[..])) {
+        Err(DecodeError::Invalid) => true,
+        _ => false,
+    });
+
+    let mut rd = io::Cursor::new(&bytes[..]);
+    let mut decode = |rd: &mut io::Cursor<&[u8]>| {
+        let limits = DecodeLimits::default();
+        Message::decode_with(rd, true, false, false, limits, &mut |rd, _, budget| {
+            Ok(Param::Value(read_value(rd, budget)?))
+        })
+    };
+    let expected = Message::Response(Response {
+        id: Id::from(5_u32),
+        result: Ok(Value::from("ok")),
+    });
+    assert_eq!(decode(&mut rd).unwrap(), expected);
+    let expected = Message::Notification(Notification {
+        method: "a".to_string(),
+        params: Vec::new(),
+    });
+    assert_eq!(decode(&mut rd).unwrap(), expected);
+}
+
+#[test]
+fn test_lenient_decoding() {
+    // A request whose method is encoded as bin, and whose params are a map.
+    let bytes = [
+        0x94, 0x00, 0x01, 0xc4, 0x03, b'f', b'o', b'o', 0x81, 0xa1, b'a', 0x02
+    ];
+    assert!(match Message::decode(&mut io::Cursor::new(&bytes[..])) {
+        Err(DecodeError::InvalidMethod) => true,
+        _ => false,
+    });
+
+    let expected = Message::Request(Request {
+        id: Id::from(1_u32),
+        method: "foo".to_string(),
+        params: vec![Param::Value(Value::Map(vec![(Value::from("a"), Value::from(2))]))],
+    });
+    let mut rd = io::Cursor::new(&bytes[..]);
+    let limits = DecodeLimits::default();
+    let decoded =
+        Message::decode_with(&mut rd, true, false, false, limits, &mut |rd, _, budget| {
+            Ok(Param::Value(read_value(rd, budget)?))
+        });
+    assert_eq!(decoded.unwrap(), expected);
+}
+
+#[test]
+fn test_named_params() {
+    let request = Message::Request(Request {
+        id: Id::from(1_u32),
+        method: "foo".to_string(),
+        params: vec![Param::Value(Value::Map(vec![(Value::from("a"), Value::from(2))]))],
+    });
+    let mut bytes = Vec::new();
+    request.encode_with(&mut bytes, EmptyParams::Array, true).unwrap();
+    assert_eq!(bytes, [0x94, 0x00, 0x01, 0xa3, b'f', b'o', b'o', 0x81, 0xa1, b'a', 0x02]);
+    assert!(match Message::decode(&mut io::Cursor::new(&bytes[..])) {
+        Err(DecodeError::InvalidParams) => true,
+        _ => false,
+    });
+
+    let mut rd = io::Cursor::new(&bytes[..]);
+    let limits = DecodeLimits::default();
+    let decoded =
+        Message::decode_with(&mut rd, false, false, true, limits, &mut |rd, _, budget| {
+            Ok(Param::Value(read_value(rd, budget)?))
+        });
+    assert_eq!(decoded.unwrap(), request);
+}
+
+#[test]
+fn test_decode_limits() {
+    // A notification whose only parameter is nested in 1000 arrays.
+    let mut bytes = vec![0x93, 0x02, 0xa1, b'a', 0x91];
+    bytes.extend(vec![0x91; 999]);
+    bytes.push(0xc0);
+    assert!(match Message::decode(&mut io::Cursor::new(&bytes)) {
+        Err(DecodeError::DepthLimitExceeded) => true,
+        _ => false,
+    });
+
+    let bytes = [0x93, 0x02, 0xa1, b'a', 0x93, 0x01, 0x02, 0x03];
+    let decode = |max_elements| {
+        let limits = DecodeLimits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_elements: Some(max_elements),
+        };
+        let mut rd = io::Cursor::new(&bytes[..]);
+        Message::decode_with(&mut rd, false, false, false, limits, &mut |_, _, _| unreachable!())
+    };
+    assert!(decode(3).is_ok());
+    assert!(match decode(2) {
+        Err(DecodeError::TooManyElements) => true,
+        _ => false,
+    });
+
+    // A request whose id is a string.
+    let bytes = [0x94, 0x00, 0xa1, b'1', 0xa1, b'a', 0x90];
+    assert!(match Message::decode(&mut io::Cursor::new(&bytes[..])) {
+        Err(DecodeError::InvalidId) => true,
+        _ => false,
+    });
+}
@@ -0,0 +1,885 @@
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use rmpv::Value;
+
+use endpoint::{BoxedService, Client, Pusher, ServiceBuilder};
+use errors::RouterError;
+use params::{to_positional, ParamsSpec};
+use priority::Priority;
+use rpc_error::RpcError;
+
+/// Future returned by the request handlers of a [`MethodRouter`](struct.MethodRouter.html).
+pub type MethodFuture = Box<Future<Item = Result<Value, Value>, Error = io::Error>>;
+
+/// Future returned by the notification handlers of a
+/// [`MethodRouter`](struct.MethodRouter.html).
+pub type MethodNotificationFuture = Box<Future<Item = (), Error = io::Error>>;
+
+/// Error sent in response to the requests over the concurrency limit of their method.
+const BUSY_ERROR: &str = "busy";
+
+type RequestHandler = Fn(&[Value], Pusher) -> MethodFuture + Send + Sync;
+type NotificationHandler = Fn(&[Value]) -> MethodNotificationFuture + Send + Sync;
+type DefaultRequestHandler = Fn(&str, &[Value]) -> MethodFuture + Send + Sync;
+type DefaultNotificationHandler = Fn(&str, &[Value]) -> MethodNotificationFuture + Send + Sync;
+
+/// The handlers of a [`MethodRouter`](struct.MethodRouter.html).
+#[derive(Default)]
+struct Handlers {
+    requests: HashMap<String, Arc<RequestHandler>>,
+    notifications: HashMap<String, Arc<NotificationHandler>>,
+    default_request: Option<Arc<DefaultRequestHandler>>,
+    default_notification: Option<Arc<DefaultNotificationHandler>>,
+    limits: HashMap<String, Arc<Mutex<Limit>>>,
+    priorities: HashMap<String, Priority>,
+    params: HashMap<String, ParamsSpec>,
+    // The names of the parameters of the methods that accept named arguments.
+    names: HashMap<String, Vec<String>>,
+    // The methods of mounted routers that already had a handler (see `MethodRouter::mount`).
+    conflicts: Vec<String>,
+    // The namespaces only served to authenticated connections (see `MethodRouter::protect`).
+    protected: Vec<String>,
+    // Whether a router without any handler is invalid.
+    require_handlers: bool,
+}
+
+impl Handlers {
+    /// Return the positional parameters of a call whose parameters are named arguments, if its
+    /// method accepts them.
+    fn positional(&self, method: &str, params: &[Value]) -> Option<Result<Vec<Value>, RpcError>> {
+        let names = self.names.get(method)?;
+        match params.first() {
+            Some(&Value::Map(ref args)) if params.len() == 1 => Some(to_positional(names, args)),
+            _ => None,
+        }
+    }
+}
+
+/// The concurrency limit of a method, shared by all the connections.
+struct Limit {
+    max: usize,
+    queue: Option<usize>,
+    running: usize,
+    // The requests waiting for one of the running requests to complete, oldest first.
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+enum Acquire {
+    Ready(Permit),
+    Queued(oneshot::Receiver<Permit>),
+    Rejected,
+}
+
+impl Limit {
+    fn acquire(limit: &Arc<Mutex<Limit>>) -> Acquire {
+        let mut state = limit.lock().unwrap();
+        // Requests that were dropped while waiting don't take any room.
+        state.waiting.retain(|tx| !tx.is_canceled());
+        if state.running < state.max {
+            state.running += 1;
+            return Acquire::Ready(Permit {
+                limit: Some(Arc::clone(limit)),
+            });
+        }
+        if let Some(queue) = state.queue {
+            if state.waiting.len() >= queue {
+                return Acquire::Rejected;
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        state.waiting.push_back(tx);
+        Acquire::Queued(rx)
+    }
+}
+
+/// The right to handle a request for a method that has a concurrency limit. It is handed over to
+/// the next waiting request when it is dropped.
+struct Permit {
+    limit: Option<Arc<Mutex<Limit>>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let limit = match self.limit.take() {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut state = limit.lock().unwrap();
+        while let Some(tx) = state.waiting.pop_front() {
+            let permit = Permit {
+                limit: Some(Arc::clone(&limit)),
+            };
+            match tx.send(permit) {
+                Ok(()) => return,
+                // The request is not waiting anymore: the permit must not be released again.
+                Err(mut permit) => permit.limit = None,
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+/// Call `call` once `limit` allows it. The permit is held until the returned future completes or
+/// is dropped.
+fn limited<F>(limit: &Arc<Mutex<Limit>>, method: &str, call: F) -> MethodFuture
+where
+    F: FnOnce() -> MethodFuture + 'static,
+{
+    let run = |permit: Permit| -> MethodFuture {
+        Box::new(call().then(move |result| {
+            drop(permit);
+            result
+        }))
+    };
+    match Limit::acquire(limit) {
+        Acquire::Ready(permit) => run(permit),
+        Acquire::Queued(rx) => {
+            trace!("Too many requests for {} in flight, queuing the request", method);
+            Box::new(rx.then(move |permit| match permit {
+                Ok(permit) => run(permit),
+                Err(_) => Box::new(future::ok(Err(Value::from(BUSY_ERROR)))),
+            }))
+        }
+        Acquire::Rejected => {
+            debug!("Too many requests for {} in flight, rejecting the request", method);
+            Box::new(future::ok(Err(Value::from(BUSY_ERROR))))
+        }
+    }
+}
+
+/// A service that dispatches requests and notifications to one handler per method, instead of
+/// matching the method in [`Service::handle_request`](trait.Service.html#tymethod.handle_request).
+///
+/// The requests for a method that has no handler are passed to the
+/// [`default_handler`](#method.default_handler) if there is one, and are otherwise answered with
+/// a "method not found" [`RpcError`](struct.RpcError.html). The default handler makes it possible
+/// to forward the unknown methods elsewhere, for instance to another server with a `Client`.
+/// Likewise, the notifications for a method that has no handler are passed to the
+/// [`default_notification_handler`](#method.default_notification_handler), or ignored.
+///
+/// `MethodRouter` is also a [`ServiceBuilder`](trait.ServiceBuilder.html), and is cheap to clone:
+/// all the clones, and so all the connections, share the same handlers. Handlers can be
+/// [registered](#method.register) and [unregistered](#method.unregister) while the server runs,
+/// through any clone: the change applies to the next requests of every connection, without
+/// closing them.
+#[derive(Clone, Default)]
+pub struct MethodRouter {
+    handlers: Arc<RwLock<Handlers>>,
+    // Attached to the connection the router was built for.
+    pusher: Pusher,
+}
+
+impl MethodRouter {
+    /// Create a router without handlers, that answers all the requests with a "method not found"
+    /// error.
+    pub fn new() -> Self {
+        MethodRouter::default()
+    }
+
+    /// Set the handler of the requests for the given method, replacing the previous one.
+    pub fn request<F>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> MethodFuture + Send + Sync + 'static,
+    {
+        let _ = self.register(method, handler);
+        self
+    }
+
+    /// Like [`request`](#method.request), for a handler that also gets a
+    /// [`Pusher`](struct.Pusher.html), to send notifications to the client of the request while
+    /// it works, like progress reports. The notifications sent before the handler completes are
+    /// sent before its response. The pusher is only attached to a connection when the router is
+    /// used as a [`ServiceBuilder`](trait.ServiceBuilder.html): otherwise, the notifications are
+    /// dropped.
+    pub fn request_with_pusher<F>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&[Value], Pusher) -> MethodFuture + Send + Sync + 'static,
+    {
+        let _ = self.register_with_pusher(method, handler);
+        self
+    }
+
+    /// Set the handler of the notifications for the given method, replacing the previous one.
+    pub fn notification<F>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> MethodNotificationFuture + Send + Sync + 'static,
+    {
+        let _ = self.register_notification(method, handler);
+        self
+    }
+
+    /// Set the handler of the requests for the methods that have no handler. It receives the
+    /// method along with the parameters.
+    pub fn default_handler<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&str, &[Value]) -> MethodFuture + Send + Sync + 'static,
+    {
+        self.handlers.write().unwrap().default_request = Some(Arc::new(handler));
+        self
+    }
+
+    /// Set the handler of the notifications for the methods that have no handler. It receives
+    /// the method along with the parameters.
+    pub fn default_notification_handler<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&str, &[Value]) -> MethodNotificationFuture + Send + Sync + 'static,
+    {
+        self.handlers.write().unwrap().default_notification = Some(Arc::new(handler));
+        self
+    }
+
+    /// Allow at most `max` requests for the given method to be handled at the same time, across
+    /// all the connections that share the router, so that an expensive method cannot starve the
+    /// others. The requests over this limit wait for the running ones to complete, in the order
+    /// in which they were received. If `queue` is not `None`, at most `queue` requests wait, and
+    /// the others are answered with a `"busy"` error right away: with a queue of `Some(0)`, the
+    /// requests over the limit are always rejected.
+    ///
+    /// The limit applies to the requests for this method whether it has a handler or is handled
+    /// by the [`default_handler`](#method.default_handler). It replaces the previous limit of the
+    /// method, if any.
+    pub fn limit_concurrency(
+        &mut self,
+        method: &str,
+        max: usize,
+        queue: Option<usize>,
+    ) -> &mut Self {
+        let limit = Limit {
+            max: max,
+            queue: queue,
+            running: 0,
+            waiting: VecDeque::new(),
+        };
+        let _ = self.handlers
+            .write()
+            .unwrap()
+            .limits
+            .insert(method.to_owned(), Arc::new(Mutex::new(limit)));
+        self
+    }
+
+    /// Set the [`Priority`](enum.Priority.html) of the requests for the given method, whether it
+    /// has a handler or is handled by the [`default_handler`](#method.default_handler). By
+    /// default, requests have a `Normal` priority.
+    pub fn priority(&mut self, method: &str, priority: Priority) -> &mut Self {
+        let _ = self.handlers
+            .write()
+            .unwrap()
+            .priorities
+            .insert(method.to_owned(), priority);
+        self
+    }
+
+    /// Check the parameters of the requests and notifications for the given method before they
+    /// are handled, whether it has a handler or is handled by a default handler. The requests
+    /// whose parameters do not match `spec` are answered with an "invalid params"
+    /// [`RpcError`](struct.RpcError.html) that tells what is wrong, and the notifications are
+    /// ignored. It replaces the previous specification of the method, if any.
+    pub fn params(&mut self, method: &str, spec: ParamsSpec) -> &mut Self {
+        let _ = self.handlers
+            .write()
+            .unwrap()
+            .params
+            .insert(method.to_owned(), spec);
+        self
+    }
+
+    /// Accept named arguments for the given method, whether it has a handler or is handled by a
+    /// default handler: the calls whose only parameter is a map are turned into positional
+    /// parameters, in the order of `names`, before their parameters are
+    /// [checked](#method.params) and they are handled. The arguments that are left out become
+    /// `nil`, unless they come after the last one given, and the requests with an unknown
+    /// argument are answered with an "invalid params" [`RpcError`](struct.RpcError.html). The
+    /// calls with positional parameters are handled as usual.
+    ///
+    /// This makes it possible to serve the peers that send named arguments, with the same
+    /// handlers (see also
+    /// [`ProtocolOptions::named_params`](struct.ProtocolOptions.html#method.named_params)).
+    pub fn named_params(&mut self, method: &str, names: &[&str]) -> &mut Self {
+        let names = names.iter().map(|name| name.to_string()).collect();
+        let _ = self.handlers
+            .write()
+            .unwrap()
+            .names
+            .insert(method.to_owned(), names);
+        self
+    }
+
+    /// Register the request and notification handlers of `router` under `prefix`, with their
+    /// settings: its handler for `"add"` handles the calls to `prefix` followed by `"add"` here.
+    /// Its default handlers are not mounted. A method that already has a handler here keeps it,
+    /// and the conflict makes [`validate`](#method.validate) fail, so that a router mounted over
+    /// another one, or twice, is caught when the server is built.
+    pub fn mount(&mut self, prefix: &str, router: &MethodRouter) -> &mut Self {
+        let mounted = {
+            let other = router.handlers.read().unwrap();
+            let name = |method: &String| format!("{}{}", prefix, method);
+            Handlers {
+                requests: other.requests.iter().map(|(m, h)| (name(m), h.clone())).collect(),
+                notifications: other
+                    .notifications
+                    .iter()
+                    .map(|(m, h)| (name(m), h.clone()))
+                    .collect(),
+                limits: other.limits.iter().map(|(m, l)| (name(m), l.clone())).collect(),
+                priorities: other.priorities.iter().map(|(m, p)| (name(m), *p)).collect(),
+                params: other.params.iter().map(|(m, p)| (name(m), p.clone())).collect(),
+                names: other.names.iter().map(|(m, n)| (name(m), n.clone())).collect(),
+                ..Handlers::default()
+            }
+        };
+        {
+            let mut guard = self.handlers.write().unwrap();
+            let handlers = &mut *guard;
+            for (method, handler) in mounted.requests {
+                match handlers.requests.entry(method) {
+                    Entry::Occupied(entry) => handlers.conflicts.push(entry.key().clone()),
+                    Entry::Vacant(entry) => {
+                        let _ = entry.insert(handler);
+                    }
+                }
+            }
+            for (method, handler) in mounted.notifications {
+                match handlers.notifications.entry(method) {
+                    Entry::Occupied(entry) => handlers.conflicts.push(entry.key().clone()),
+                    Entry::Vacant(entry) => {
+                        let _ = entry.insert(handler);
+                    }
+                }
+            }
+            handlers.limits.extend(mounted.limits);
+            handlers.priorities.extend(mounted.priorities);
+            handlers.params.extend(mounted.params);
+            handlers.names.extend(mounted.names);
+        }
+        self
+    }
+
+    /// Declare that the methods that start with `prefix` must only be served to authenticated
+    /// connections. A [`ServerBuilder`](struct.ServerBuilder.html) refuses to build a server with
+    /// this router unless its options have an
+    /// [`authenticator`](struct.ProtocolOptions.html#method.authenticator), which then
+    /// authenticates every connection before any of its requests is handled.
+    pub fn protect(&mut self, prefix: &str) -> &mut Self {
+        self.handlers
+            .write()
+            .unwrap()
+            .protected
+            .push(prefix.to_owned());
+        self
+    }
+
+    /// If `required` is `true`, [`validate`](#method.validate) fails if the router has no
+    /// handler, not even a default one. It is disabled by default, since the handlers can also be
+    /// [registered](#method.register) once the server runs.
+    pub fn require_handlers(&mut self, required: bool) -> &mut Self {
+        self.handlers.write().unwrap().require_handlers = required;
+        self
+    }
+
+    /// Set the handler of the requests for the given method, possibly while the server runs.
+    /// Return `true` if it replaces a previous handler. The requests already being handled are
+    /// not affected.
+    pub fn register<F>(&self, method: &str, handler: F) -> bool
+    where
+        F: Fn(&[Value]) -> MethodFuture + Send + Sync + 'static,
+    {
+        self.register_with_pusher(method, move |params, _| handler(params))
+    }
+
+    /// Like [`register`](#method.register), for a handler that gets a
+    /// [`Pusher`](struct.Pusher.html) (see [`request_with_pusher`](#method.request_with_pusher)).
+    pub fn register_with_pusher<F>(&self, method: &str, handler: F) -> bool
+    where
+        F: Fn(&[Value], Pusher) -> MethodFuture + Send + Sync + 'static,
+    {
+        trace!("Registering a handler for the requests for {}", method);
+        let mut handlers = self.handlers.write().unwrap();
+        handlers
+            .requests
+            .insert(method.to_owned(), Arc::new(handler))
+            .is_some()
+    }
+
+    /// Remove the handler of the requests for the given method, possibly while the server runs.
+    /// Return `false` if the method had no handler.
+    pub fn unregister(&self, method: &str) -> bool {
+        trace!("Unregistering the handler of the requests for {}", method);
+        self.handlers
+            .write()
+            .unwrap()
+            .requests
+            .remove(method)
+            .is_some()
+    }
+
+    /// Like [`register`](#method.register), for notifications.
+    pub fn register_notification<F>(&self, method: &str, handler: F) -> bool
+    where
+        F: Fn(&[Value]) -> MethodNotificationFuture + Send + Sync + 'static,
+    {
+        trace!("Registering a handler for the notifications for {}", method);
+        let mut handlers = self.handlers.write().unwrap();
+        handlers
+            .notifications
+            .insert(method.to_owned(), Arc::new(handler))
+            .is_some()
+    }
+
+    /// Like [`unregister`](#method.unregister), for notifications.
+    pub fn unregister_notification(&self, method: &str) -> bool {
+        trace!("Unregistering the handler of the notifications for {}", method);
+        self.handlers
+            .write()
+            .unwrap()
+            .notifications
+            .remove(method)
+            .is_some()
+    }
+
+    /// Return `true` if the given method has a request handler.
+    pub fn has_request(&self, method: &str) -> bool {
+        self.handlers.read().unwrap().requests.contains_key(method)
+    }
+
+    /// Return `true` if the given method has a notification handler.
+    pub fn has_notification(&self, method: &str) -> bool {
+        self.handlers
+            .read()
+            .unwrap()
+            .notifications
+            .contains_key(method)
+    }
+
+    /// Check that the router is usable: the settings of a method
+    /// ([`limit_concurrency`](#method.limit_concurrency), [`priority`](#method.priority),
+    /// [`params`](#method.params) and [`named_params`](#method.named_params)) must apply to a
+    /// handler, possibly a default one, the named arguments of a method must have distinct names,
+    /// no [mounted](#method.mount) method may conflict with another handler, and the router must
+    /// have a handler if they are [required](#method.require_handlers). A
+    /// [`ServerBuilder`](struct.ServerBuilder.html) calls this when it builds a server.
+    pub fn validate(&self) -> Result<(), RouterError> {
+        let handlers = self.handlers.read().unwrap();
+        if let Some(method) = handlers.conflicts.first() {
+            return Err(RouterError::ConflictingMount {
+                method: method.clone(),
+            });
+        }
+        if handlers.require_handlers && handlers.requests.is_empty()
+            && handlers.notifications.is_empty()
+            && handlers.default_request.is_none()
+            && handlers.default_notification.is_none()
+        {
+            return Err(RouterError::Empty);
+        }
+        let handles_request = |method: &String| {
+            handlers.default_request.is_some() || handlers.requests.contains_key(method)
+        };
+        let handles_any = |method: &String| {
+            handles_request(method) || handlers.default_notification.is_some()
+                || handlers.notifications.contains_key(method)
+        };
+        let request_settings = handlers
+            .limits
+            .keys()
+            .map(|method| (method, "limit_concurrency"))
+            .chain(handlers.priorities.keys().map(|method| (method, "priority")));
+        for (method, setting) in request_settings {
+            if !handles_request(method) {
+                return Err(RouterError::Unhandled {
+                    method: method.clone(),
+                    setting: setting,
+                });
+            }
+        }
+        let call_settings = handlers
+            .params
+            .keys()
+            .map(|method| (method, "params"))
+            .chain(handlers.names.keys().map(|method| (method, "named_params")));
+        for (method, setting) in call_settings {
+            if !handles_any(method) {
+                return Err(RouterError::Unhandled {
+                    method: method.clone(),
+                    setting: setting,
+                });
+            }
+        }
+        for (method, names) in &handlers.names {
+            for (i, name) in names.iter().enumerate() {
+                if names[..i].contains(name) {
+                    return Err(RouterError::DuplicateName {
+                        method: method.clone(),
+                        name: name.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServiceBuilder for MethodRouter {
+    type Service = MethodRouter;
+
+    fn build(&self, client: Client) -> Self::Service {
+        MethodRouter {
+            handlers: Arc::clone(&self.handlers),
+            pusher: Pusher::new(client),
+        }
+    }
+
+    fn registered_methods(&self) -> Vec<String> {
+        let handlers = self.handlers.read().unwrap();
+        let mut methods = handlers
+            .requests
+            .keys()
+            .chain(handlers.notifications.keys())
+            .cloned()
+            .collect::<Vec<String>>();
+        methods.sort();
+        methods.dedup();
+        methods
+    }
+
+    fn validate(&self) -> Result<(), RouterError> {
+        MethodRouter::validate(self)
+    }
+
+    fn protected_prefixes(&self) -> Vec<String> {
+        self.handlers.read().unwrap().protected.clone()
+    }
+}
+
+impl BoxedService for MethodRouter {
+    type Error = io::Error;
+    type T = Value;
+    type E = Value;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> MethodFuture {
+        // The handlers are called once the lock is released, so that they can register or
+        // unregister handlers themselves.
+        let (handler, default, limit, positional) = {
+            let handlers = self.handlers.read().unwrap();
+            let positional = match handlers.positional(method, params) {
+                Some(Ok(positional)) => Some(positional),
+                Some(Err(error)) => {
+                    debug!("Invalid named arguments for {}: {}", method, error.message);
+                    return Box::new(future::ok(Err(Value::from(error))));
+                }
+                None => None,
+            };
+            if let Some(spec) = handlers.params.get(method) {
+                let params = positional.as_ref().map_or(params, |params| &params[..]);
+                if let Err(error) = spec.check(params) {
+                    debug!("Invalid parameters for {}: {}", method, error.message);
+                    return Box::new(future::ok(Err(Value::from(error))));
+                }
+            }
+            (
+                handlers.requests.get(method).cloned(),
+                handlers.default_request.clone(),
+                handlers.limits.get(method).cloned(),
+                positional,
+            )
+        };
+        let params = positional.as_ref().map_or(params, |params| &params[..]);
+        match (handler, default, limit) {
+            (Some(handler), _, None) => handler(params, self.pusher.for_current_request()),
+            (Some(handler), _, Some(limit)) => {
+                let (params, pusher) = (params.to_vec(), self.pusher.for_current_request());
+                limited(&limit, method, move || handler(&params, pusher))
+            }
+            (None, Some(handler), None) => handler(method, params),
+            (None, Some(handler), Some(limit)) => {
+                let (method_name, params) = (method.to_owned(), params.to_vec());
+                limited(&limit, method, move || handler(&method_name, &params))
+            }
+            (None, None, _) => {
+                debug!("No handler for the requests for {}", method);
+                Box::new(future::ok(Err(Value::from(RpcError::method_not_found(method)))))
+            }
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &[Value]) -> MethodNotificationFuture {
+        let (handler, default, positional) = {
+            let handlers = self.handlers.read().unwrap();
+            let positional = match handlers.positional(method, params) {
+                Some(Ok(positional)) => Some(positional),
+                Some(Err(error)) => {
+                    debug!(
+                        "Invalid named arguments for {}, ignoring it: {}",
+                        method, error.message
+                    );
+                    return Box::new(future::ok(()));
+                }
+                None => None,
+            };
+            if let Some(spec) = handlers.params.get(method) {
+                let params = positional.as_ref().map_or(params, |params| &params[..]);
+                if let Err(error) = spec.check(params) {
+                    debug!("Invalid parameters for {}, ignoring it: {}", method, error.message);
+                    return Box::new(future::ok(()));
+                }
+            }
+            (
+                handlers.notifications.get(method).cloned(),
+                handlers.default_notification.clone(),
+                positional,
+            )
+        };
+        let params = positional.as_ref().map_or(params, |params| &params[..]);
+        if let Some(handler) = handler {
+            return handler(params);
+        }
+        match default {
+            Some(handler) => handler(method, params),
+            None => {
+                debug!("No handler for the notifications for {}, ignoring it", method);
+                Box::new(future::ok(()))
+            }
+        }
+    }
+
+    /// Return the methods that have a handler, unless there is a default handler, in which case
+    /// the methods are unknown.
+    fn methods(&self) -> Option<Vec<String>> {
+        let handlers = self.handlers.read().unwrap();
+        if handlers.default_request.is_some() {
+            return None;
+        }
+        let mut methods = handlers.requests.keys().cloned().collect::<Vec<String>>();
+        methods.sort();
+        Some(methods)
+    }
+
+    fn priority(&self, method: &str) -> Priority {
+        let handlers = self.handlers.read().unwrap();
+        handlers.priorities.get(method).cloned().unwrap_or_default()
+    }
+}
+
+#[test]
+fn method_router() {
+    use mock::TestClient;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("add", |params| {
+        let sum = params.iter().filter_map(Value::as_i64).sum::<i64>();
+        Box::new(future::ok(Ok(Value::from(sum))))
+    });
+    let mut client = TestClient::new(router.clone());
+    assert_eq!(client.request("add", &[1.into(), 2.into()]), Ok(Value::from(3)));
+    assert_eq!(
+        client.request("sub", &[]),
+        Err(Value::from(RpcError::method_not_found("sub")))
+    );
+
+    let _ = router.default_handler(|method, _params| {
+        Box::new(future::ok(Err(Value::from(format!("forwarded {}", method)))))
+    });
+    let mut client = TestClient::new(router);
+    assert_eq!(client.request("add", &[1.into(), 2.into()]), Ok(Value::from(3)));
+    assert_eq!(client.request("sub", &[]), Err(Value::from("forwarded sub")));
+}
+
+#[test]
+fn params_validation() {
+    use mock::TestClient;
+    use params::ParamType;
+
+    let mut spec = ParamsSpec::new();
+    let _ = spec.required(ParamType::Integer).required(ParamType::Integer);
+    let mut router = MethodRouter::new();
+    let _ = router
+        .request("add", |params| {
+            let sum = params[0].as_i64().unwrap() + params[1].as_i64().unwrap();
+            Box::new(future::ok(Ok(Value::from(sum))))
+        })
+        .params("add", spec);
+    let mut client = TestClient::new(router);
+    assert_eq!(client.request("add", &[1.into(), 2.into()]), Ok(Value::from(3)));
+    let error = RpcError::invalid_params("expected 2 parameters, got 1");
+    assert_eq!(client.request("add", &[1.into()]), Err(Value::from(error)));
+    let error = RpcError::invalid_params("parameter 1: expected an integer, got a string")
+        .with_data(Value::from(1));
+    assert_eq!(client.request("add", &[1.into(), "2".into()]), Err(Value::from(error)));
+}
+
+#[test]
+fn named_params() {
+    use mock::TestClient;
+    use options::ProtocolOptions;
+    use params::ParamType;
+
+    let mut spec = ParamsSpec::new();
+    let _ = spec.required(ParamType::Integer).optional(ParamType::Integer);
+    let mut router = MethodRouter::new();
+    let _ = router
+        .request("sub", |params| {
+            let b = params.get(1).and_then(Value::as_i64).unwrap_or(0);
+            Box::new(future::ok(Ok(Value::from(params[0].as_i64().unwrap() - b))))
+        })
+        .params("sub", spec)
+        .named_params("sub", &["a", "b"]);
+    let mut options = ProtocolOptions::default();
+    let _ = options.named_params(true);
+    let mut client = TestClient::with_options(router, options);
+    let args = |args: &[(&str, i64)]| {
+        let args = args.iter()
+            .map(|&(name, value)| (Value::from(name), Value::from(value)))
+            .collect();
+        [Value::Map(args)]
+    };
+    assert_eq!(client.request("sub", &args(&[("b", 1), ("a", 3)])), Ok(Value::from(2)));
+    assert_eq!(client.request("sub", &args(&[("a", 3)])), Ok(Value::from(3)));
+    assert_eq!(client.request("sub", &[3.into(), 1.into()]), Ok(Value::from(2)));
+    let error = RpcError::invalid_params("unknown parameter \"c\"");
+    assert_eq!(client.request("sub", &args(&[("c", 1)])), Err(Value::from(error)));
+    let error = RpcError::invalid_params("parameter 0: expected an integer, got nil")
+        .with_data(Value::from(0));
+    assert_eq!(client.request("sub", &args(&[("b", 1)])), Err(Value::from(error)));
+}
+
+#[test]
+fn dynamic_registration() {
+    use mock::TestClient;
+
+    let router = MethodRouter::new();
+    let mut client = TestClient::new(router.clone());
+    assert!(client.request("version", &[]).is_err());
+
+    assert!(!router.register("version", |_params| Box::new(future::ok(Ok(Value::from(1))))));
+    assert_eq!(client.request("version", &[]), Ok(Value::from(1)));
+    assert!(router.register("version", |_params| Box::new(future::ok(Ok(Value::from(2))))));
+    assert_eq!(client.request("version", &[]), Ok(Value::from(2)));
+
+    assert!(router.unregister("version"));
+    assert!(!router.unregister("version"));
+    assert_eq!(
+        client.request("version", &[]),
+        Err(Value::from(RpcError::method_not_found("version")))
+    );
+}
+
+#[test]
+fn concurrency_limits() {
+    let mut router = MethodRouter::new();
+    let _ = router
+        .request("compile", |_| Box::new(future::ok(Ok(Value::from("compiled")))))
+        .limit_concurrency("compile", 1, Some(1));
+    let busy = Err(Value::from(BUSY_ERROR));
+    let compiled = Ok(Value::from("compiled"));
+
+    let first = router.handle_request("compile", &[]);
+    let second = router.handle_request("compile", &[]);
+    assert_eq!(router.handle_request("compile", &[]).wait().unwrap(), busy);
+    // The second request runs once the first completes.
+    assert_eq!(first.wait().unwrap(), compiled);
+    assert_eq!(second.wait().unwrap(), compiled);
+
+    // Requests that are dropped release their place.
+    let first = router.handle_request("compile", &[]);
+    let second = router.handle_request("compile", &[]);
+    drop(second);
+    let third = router.handle_request("compile", &[]);
+    drop(first);
+    drop(third);
+    assert_eq!(router.handle_request("compile", &[]).wait().unwrap(), compiled);
+    let limit = router.handlers.read().unwrap().limits["compile"].clone();
+    assert_eq!(limit.lock().unwrap().running, 0);
+}
+
+#[test]
+fn progress_notifications() {
+    use futures::{Async, Poll, Stream};
+    use errors::CallError;
+    use mock::test_runtime;
+    use options::ProtocolOptions;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request_with_pusher("work", |_, pusher| {
+        for step in 0..3 {
+            let _ = pusher.notify("progress", &[Value::from(step)]);
+        }
+        Box::new(future::ok(Ok(Value::from("done"))))
+    });
+    // Read one message per poll, so that the messages are received one after the other.
+    let mut options = ProtocolOptions::default();
+    let _ = options.poll_budget(1);
+    let mut runtime = test_runtime(&router, 1, &options, 7);
+    let mut notifications = runtime.client(0).notifications();
+    let mut response = runtime.client(0).request("work", &[]);
+
+    // The notifications have all been received when the response is.
+    let mut progress = Vec::new();
+    let result = runtime.run(future::poll_fn(|| -> Poll<_, CallError> {
+        let result = match response.poll()? {
+            Async::Ready(result) => result,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        while let Ok(Async::Ready(Some(notification))) = notifications.poll() {
+            progress.push(notification.params[0].clone());
+        }
+        Ok(Async::Ready(result))
+    }));
+    assert_eq!(result.unwrap(), Ok(Value::from("done")));
+    assert_eq!(progress, vec![Value::from(0), Value::from(1), Value::from(2)]);
+}
+
+#[test]
+fn router_validation() {
+    let ok = || Box::new(future::ok(Ok(Value::Nil))) as MethodFuture;
+
+    // An empty router is valid, since handlers can be registered once the server runs, unless
+    // handlers are required.
+    assert_eq!(MethodRouter::new().validate(), Ok(()));
+    let mut router = MethodRouter::new();
+    let _ = router.require_handlers(true);
+    assert_eq!(router.validate(), Err(RouterError::Empty));
+
+    let mut router = MethodRouter::new();
+    let _ = router
+        .request("add", move |_| ok())
+        .priority("add", Priority::High)
+        .named_params("add", &["a", "b"]);
+    assert_eq!(router.validate(), Ok(()));
+
+    // A setting for a method without a handler.
+    let _ = router.limit_concurrency("ad", 1, None);
+    let unhandled = RouterError::Unhandled {
+        method: "ad".to_owned(),
+        setting: "limit_concurrency",
+    };
+    assert_eq!(router.validate(), Err(unhandled));
+    let _ = router.default_handler(move |_, _| ok());
+    assert_eq!(router.validate(), Ok(()));
+
+    let _ = router.named_params("add", &["a", "a"]);
+    let duplicate = RouterError::DuplicateName {
+        method: "add".to_owned(),
+        name: "a".to_owned(),
+    };
+    assert_eq!(router.validate(), Err(duplicate));
+
+    // Mounted routers, whose methods conflict once they are mounted twice.
+    let mut math = MethodRouter::new();
+    let _ = math.request("add", move |_| ok())
+        .notification("log", |_| Box::new(future::ok(())))
+        .priority("add", Priority::High);
+    let mut router = MethodRouter::new();
+    let _ = router.mount("math.", &math).mount("calc.", &math);
+    assert!(router.has_request("math.add") && router.has_notification("calc.log"));
+    assert_eq!(router.validate(), Ok(()));
+    let _ = router.mount("math.", &math);
+    let conflict = RouterError::ConflictingMount {
+        method: "math.add".to_owned(),
+    };
+    assert_eq!(router.validate(), Err(conflict));
+}
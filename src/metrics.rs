@@ -0,0 +1,269 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use audit::AuditOutcome;
+
+/// Hooks called by the endpoints to report what they do, so that services can be monitored (see
+/// [`ProtocolOptions::metrics`](struct.ProtocolOptions.html#method.metrics)).
+///
+/// Each connection is identified by a number, unique within the process (see
+/// [`Context::connection_id`](struct.Context.html#method.connection_id)). All the methods do
+/// nothing by default, so implementations only need to override the ones they are interested in.
+/// They are called on the reactor thread, so they should not block. See
+/// [`BasicMetrics`](struct.BasicMetrics.html) for a simple implementation.
+pub trait Metrics: Send + Sync {
+    /// A connection has been established.
+    fn connection_opened(&self, _connection: usize) {}
+
+    /// A connection has been closed.
+    fn connection_closed(&self, _connection: usize) {}
+
+    /// A request received on the connection has been answered, `latency` after it was received.
+    fn request_completed(
+        &self,
+        _connection: usize,
+        _method: &str,
+        _latency: Duration,
+        _outcome: AuditOutcome,
+    ) {
+    }
+
+    /// A notification has been received on the connection.
+    fn notification_received(&self, _connection: usize, _method: &str) {}
+
+    /// Bytes have been read from the connection.
+    fn bytes_read(&self, _connection: usize, _bytes: usize) {}
+
+    /// Bytes have been written to the connection.
+    fn bytes_written(&self, _connection: usize, _bytes: usize) {}
+
+    /// A server failed to accept a connection because the process or the system ran out of file
+    /// descriptors. It stops accepting connections for a while, and then retries.
+    fn accept_failed(&self, _error: &io::Error) {}
+}
+
+/// Wrapper around a `Metrics`, so that it can be part of the `ProtocolOptions`.
+#[derive(Clone)]
+pub(crate) struct MetricsHook(pub(crate) Arc<Metrics>);
+
+impl fmt::Debug for MetricsHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "MetricsHook")
+    }
+}
+
+struct Connection {
+    metrics: Arc<Metrics>,
+    id: usize,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.metrics.connection_closed(self.id);
+    }
+}
+
+/// The metrics of a connection. The connection is reported closed when all the clones are
+/// dropped.
+#[derive(Clone)]
+pub(crate) struct ConnectionMetrics(Arc<Connection>);
+
+impl ConnectionMetrics {
+    pub(crate) fn new(metrics: Arc<Metrics>, id: usize) -> Self {
+        metrics.connection_opened(id);
+        ConnectionMetrics(Arc::new(Connection {
+            metrics: metrics,
+            id: id,
+        }))
+    }
+
+    pub(crate) fn bytes_read(&self, bytes: usize) {
+        self.0.metrics.bytes_read(self.0.id, bytes);
+    }
+
+    pub(crate) fn bytes_written(&self, bytes: usize) {
+        self.0.metrics.bytes_written(self.0.id, bytes);
+    }
+
+    pub(crate) fn notification_received(&self, method: &str) {
+        self.0.metrics.notification_received(self.0.id, method);
+    }
+
+    /// Start measuring the latency of a request.
+    pub(crate) fn request(&self, method: &str) -> PendingMetrics {
+        PendingMetrics {
+            connection: self.clone(),
+            method: method.to_owned(),
+            start: Instant::now(),
+        }
+    }
+}
+
+/// A request that has not been answered yet.
+pub(crate) struct PendingMetrics {
+    connection: ConnectionMetrics,
+    method: String,
+    start: Instant,
+}
+
+impl PendingMetrics {
+    pub(crate) fn finish(self, outcome: AuditOutcome) {
+        let connection = &self.connection.0;
+        connection.metrics.request_completed(
+            connection.id,
+            &self.method,
+            self.start.elapsed(),
+            outcome,
+        );
+    }
+}
+
+/// Upper bounds of the buckets of the latency histogram of `BasicMetrics`, in milliseconds.
+const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1_000, 5_000];
+
+/// A `Metrics` implementation that keeps global counters, and a histogram of the latencies of the
+/// requests. The counters can be read at any time, from any thread, to export them to a
+/// monitoring system.
+#[derive(Default)]
+pub struct BasicMetrics {
+    open_connections: AtomicUsize,
+    requests: AtomicUsize,
+    errors: AtomicUsize,
+    notifications: AtomicUsize,
+    bytes_read: AtomicUsize,
+    bytes_written: AtomicUsize,
+    accept_failures: AtomicUsize,
+    // One more bucket than `LATENCY_BUCKETS_MS`, for the latencies above the last bound.
+    latencies: [AtomicUsize; 9],
+}
+
+impl BasicMetrics {
+    /// Create a set of counters, all at zero.
+    pub fn new() -> Self {
+        BasicMetrics::default()
+    }
+
+    /// Number of connections currently open.
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests answered.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that have not been answered successfully: errors, timeouts and
+    /// rejections. Requests abandoned because their connection was closed are not counted.
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of notifications received.
+    pub fn notifications(&self) -> usize {
+        self.notifications.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes read, on all the connections.
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes written, on all the connections.
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Number of times a server failed to accept a connection because it ran out of file
+    /// descriptors.
+    pub fn accept_failures(&self) -> usize {
+        self.accept_failures.load(Ordering::Relaxed)
+    }
+
+    /// Return the latency histogram of the requests, as pairs of upper bound and number of
+    /// requests. The last bucket has no upper bound.
+    pub fn latency_histogram(&self) -> Vec<(Option<Duration>, usize)> {
+        let bounds = LATENCY_BUCKETS_MS
+            .iter()
+            .map(|ms| Some(Duration::from_millis(*ms)))
+            .chain(Some(None));
+        bounds
+            .zip(self.latencies.iter())
+            .map(|(bound, count)| (bound, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl Metrics for BasicMetrics {
+    fn connection_opened(&self, _connection: usize) {
+        let _ = self.open_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self, _connection: usize) {
+        let _ = self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn request_completed(
+        &self,
+        _connection: usize,
+        _method: &str,
+        latency: Duration,
+        outcome: AuditOutcome,
+    ) {
+        match outcome {
+            AuditOutcome::Abandoned => return,
+            AuditOutcome::Success => {}
+            _ => {
+                let _ = self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let _ = self.requests.fetch_add(1, Ordering::Relaxed);
+        let ms = latency.as_secs() * 1_000 + u64::from(latency.subsec_nanos() / 1_000_000);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms < *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        let _ = self.latencies[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn notification_received(&self, _connection: usize, _method: &str) {
+        let _ = self.notifications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_read(&self, _connection: usize, bytes: usize) {
+        let _ = self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, _connection: usize, bytes: usize) {
+        let _ = self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn accept_failed(&self, _error: &io::Error) {
+        let _ = self.accept_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn basic_metrics() {
+    let metrics = Arc::new(BasicMetrics::new());
+    {
+        let connection = ConnectionMetrics::new(Arc::clone(&metrics) as Arc<Metrics>, 0);
+        assert_eq!(metrics.open_connections(), 1);
+        connection.bytes_read(10);
+        connection.notification_received("ping");
+        connection.request("add").finish(AuditOutcome::Success);
+        connection.request("add").finish(AuditOutcome::TimedOut);
+        connection.request("add").finish(AuditOutcome::Abandoned);
+    }
+    assert_eq!(metrics.open_connections(), 0);
+    assert_eq!(metrics.bytes_read(), 10);
+    assert_eq!(metrics.notifications(), 1);
+    assert_eq!(metrics.requests(), 2);
+    assert_eq!(metrics.errors(), 1);
+    let histogram = metrics.latency_histogram();
+    assert_eq!(histogram.len(), 9);
+    assert_eq!(histogram[0], (Some(Duration::from_millis(1)), 2));
+}
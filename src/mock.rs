@@ -0,0 +1,535 @@
+//! Utilities to test services without sockets or reactor.
+//!
+//! [`duplex`](fn.duplex.html) creates a pair of connected in-memory streams, and
+//! [`TestClient`](struct.TestClient.html) uses it to connect a client to a service, so that
+//! requests go through the same encoding and dispatching as on a real connection:
+//!
+//! ```rust,ignore
+//! let mut client = TestClient::new(Calculator);
+//! assert_eq!(client.request("add", &[1.into(), 2.into()]), Ok(3.into()));
+//! ```
+//!
+//! [`test_runtime`](fn.test_runtime.html) connects several clients to a server, and drives all
+//! the connections in an order drawn from a seed, with a simulated clock, so that a test that
+//! depends on how the connections interleave, or on timeouts, can be replayed exactly.
+use std::cmp;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use futures::{executor, future, Async, Future, Poll};
+use futures::executor::{Notify, NotifyHandle};
+use futures::future::Fuse;
+use futures::task::{self, Task};
+use rmpv::Value;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use clock::Clock;
+use endpoint::{Client, Endpoint, Service, ServiceBuilder};
+use net::NoService;
+use options::ProtocolOptions;
+
+/// One direction of a duplex stream.
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool,
+    // The task waiting for bytes to read.
+    reader: Option<Task>,
+}
+
+impl Pipe {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+}
+
+/// One end of an in-memory stream, created with [`duplex`](fn.duplex.html). The bytes written to
+/// one end can be read from the other.
+///
+/// Writes never block: the bytes are buffered until they are read. Dropping or shutting down one
+/// end closes the stream: the other end reads the remaining bytes, and then gets EOF.
+pub struct DuplexStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// Create a pair of connected in-memory streams.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a = Arc::new(Mutex::new(Pipe::default()));
+    let b = Arc::new(Mutex::new(Pipe::default()));
+    let left = DuplexStream {
+        read: Arc::clone(&a),
+        write: Arc::clone(&b),
+    };
+    let right = DuplexStream { read: b, write: a };
+    (left, right)
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl AsyncRead for DuplexStream {}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.buf.extend(buf);
+        if let Some(task) = pipe.reader.take() {
+            task.notify();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.write.lock().unwrap().close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.write.lock().unwrap().close();
+        self.read.lock().unwrap().close();
+    }
+}
+
+/// A client connected to a service through an in-memory stream. Both endpoints are driven by the
+/// calls to [`request`](#method.request), [`notify`](#method.notify) and [`run`](#method.run),
+/// on the current thread, so no reactor is needed. Services that use timers or sockets still need
+/// one, though.
+pub struct TestClient<S: Service> {
+    server: Fuse<Endpoint<S, DuplexStream>>,
+    endpoint: Fuse<Endpoint<NoService, DuplexStream>>,
+    client: Client,
+}
+
+impl<S: Service> TestClient<S> {
+    /// Connect a client to `service`, with the default options.
+    pub fn new(service: S) -> Self {
+        TestClient::with_options(service, ProtocolOptions::default())
+    }
+
+    /// Connect a client to `service`. The options are used by both ends of the connection.
+    pub fn with_options(service: S, options: ProtocolOptions) -> Self {
+        let (server_stream, client_stream) = duplex();
+        let mut server = Endpoint::new(server_stream, options.clone());
+        let _ = server.set_client();
+        server.set_server(service);
+        let mut endpoint = Endpoint::new(client_stream, options);
+        let client = endpoint.set_client();
+        TestClient {
+            server: server.fuse(),
+            endpoint: endpoint.fuse(),
+            client: client,
+        }
+    }
+
+    /// Return the client, to send batches or zero-copy requests for instance. The futures it
+    /// returns only make progress when they are passed to [`run`](#method.run).
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Drive both endpoints until `future` completes, and return its result.
+    pub fn run<F: Future>(&mut self, future: F) -> Result<F::Item, F::Error> {
+        let server = &mut self.server;
+        let endpoint = &mut self.endpoint;
+        let mut future = future;
+        future::poll_fn(move || {
+            let _ = endpoint.poll();
+            let _ = server.poll();
+            // The server may have answered already.
+            let _ = endpoint.poll();
+            future.poll()
+        }).wait()
+    }
+
+    /// Send a request to the service, and return its response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection is closed before the response is received.
+    pub fn request(&mut self, method: &str, params: &[Value]) -> Result<Value, Value> {
+        let response = self.client.request(method, params);
+        match self.run(response) {
+            Ok(response) => response,
+            Err(_) => panic!("The connection was closed before {} was answered", method),
+        }
+    }
+
+    /// Send a notification to the service, and wait until it has been handled, if it is handled
+    /// synchronously.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection is closed before the notification is sent.
+    pub fn notify(&mut self, method: &str, params: &[Value]) {
+        let ack = self.client.notify(method, params);
+        if self.run(ack).is_err() {
+            panic!("The connection was closed before {} was sent", method);
+        }
+        let _ = self.run(future::ok::<(), ()>(()));
+    }
+}
+
+/// Error returned by [`TestRuntime::run`](struct.TestRuntime.html#method.run).
+#[derive(Debug, PartialEq)]
+pub enum RunError<E> {
+    /// The future failed.
+    Failed(E),
+    /// The future cannot complete: no endpoint can make progress, and no timer is waiting.
+    Stalled,
+}
+
+impl<E: fmt::Display> fmt::Display for RunError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::Failed(ref e) => write!(f, "{}", e),
+            RunError::Stalled => write!(f, "no endpoint can make progress"),
+        }
+    }
+}
+
+impl<E: Error> Error for RunError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            RunError::Failed(ref e) => e.description(),
+            RunError::Stalled => "no endpoint can make progress",
+        }
+    }
+}
+
+/// Records whether the endpoints were woken up while they were polled.
+struct Wakeups(AtomicBool);
+
+impl Notify for Wakeups {
+    fn notify(&self, _id: usize) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A server and several clients connected through in-memory streams, created with
+/// [`test_runtime`](fn.test_runtime.html).
+///
+/// All the connections are driven on the current thread by [`run`](#method.run). Each time, the
+/// endpoints are polled in an order drawn from a pseudo-random generator, so that the requests of
+/// the clients, and the responses of the server, interleave in many ways across seeds but in
+/// exactly the same way for a given seed.
+///
+/// Time is simulated: the endpoints use a [manual clock](../struct.Clock.html#method.manual),
+/// which only moves when [`advance`](#method.advance) is called, or when `run` is waiting for a
+/// timer, so that request timeouts, idle timeouts, pings and disconnection deadlines fire
+/// deterministically and without waiting. Services that use timers of their own, or sockets,
+/// still need a reactor.
+pub struct TestRuntime<S: Service> {
+    servers: Vec<Fuse<Endpoint<S, DuplexStream>>>,
+    endpoints: Vec<Fuse<Endpoint<NoService, DuplexStream>>>,
+    clients: Vec<Client>,
+    clock: Clock,
+    seed: u64,
+    state: u64,
+}
+
+/// Connect `clients` clients to a server that builds its services with `service_builder`, and
+/// drive them in an order drawn from `seed`. The options are used by all the endpoints, with a
+/// new manual clock unless they already use one.
+pub fn test_runtime<B: ServiceBuilder>(
+    service_builder: &B,
+    clients: usize,
+    options: &ProtocolOptions,
+    seed: u64,
+) -> TestRuntime<B::Service> {
+    let mut options = options.clone();
+    if !options.get_clock().is_manual() {
+        let _ = options.clock(Clock::manual());
+    }
+    let mut runtime = TestRuntime {
+        servers: Vec::with_capacity(clients),
+        endpoints: Vec::with_capacity(clients),
+        clients: Vec::with_capacity(clients),
+        clock: options.get_clock().clone(),
+        seed: seed,
+        // The generator is stuck on zero.
+        state: cmp::max(seed, 1),
+    };
+    for _ in 0..clients {
+        let (server_stream, client_stream) = duplex();
+        let mut server = Endpoint::new(server_stream, options.clone());
+        let service = service_builder.build(server.set_client());
+        server.set_server(service);
+        let mut endpoint = Endpoint::new(client_stream, options.clone());
+        runtime.clients.push(endpoint.set_client());
+        runtime.servers.push(server.fuse());
+        runtime.endpoints.push(endpoint.fuse());
+    }
+    runtime
+}
+
+impl<S: Service> TestRuntime<S> {
+    /// Return the seed the order of the polls is drawn from, to report it when a test fails.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Return the clients, in the order they connected.
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// Return the `i`-th client.
+    pub fn client(&self, i: usize) -> &Client {
+        &self.clients[i]
+    }
+
+    /// Return the clock of the endpoints.
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Move the clock of the endpoints forward by `duration`. The timers that expire are handled
+    /// by the next call to [`run`](#method.run).
+    pub fn advance(&mut self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    /// Drive all the connections until `future` completes, and return its result. When no
+    /// endpoint can make progress, the clock is advanced to the next deadline of their timers.
+    /// If no timer is waiting either, `RunError::Stalled` is returned instead of blocking
+    /// forever.
+    pub fn run<F: Future>(&mut self, future: F) -> Result<F::Item, RunError<F::Error>> {
+        let wakeups = Arc::new(Wakeups(AtomicBool::new(false)));
+        let notify = NotifyHandle::from(Arc::clone(&wakeups));
+        let mut future = future;
+        loop {
+            wakeups.0.store(false, Ordering::SeqCst);
+            let result = {
+                let round = future::poll_fn(|| {
+                    self.poll_endpoints();
+                    future.poll()
+                });
+                executor::spawn(round).poll_future_notify(&notify, 0)
+            };
+            match result {
+                Ok(Async::Ready(item)) => return Ok(item),
+                Err(e) => return Err(RunError::Failed(e)),
+                Ok(Async::NotReady) => {}
+            }
+            // Messages may still be in flight between the endpoints. Otherwise, only the timers
+            // can wake them up.
+            if !wakeups.0.load(Ordering::SeqCst) && !self.clock.advance_to_next_timer() {
+                return Err(RunError::Stalled);
+            }
+        }
+    }
+
+    /// Poll each endpoint once, the servers being numbered after the clients, in a random order
+    /// (Fisher-Yates shuffle).
+    fn poll_endpoints(&mut self) {
+        let count = self.endpoints.len() + self.servers.len();
+        let mut order = (0..count).collect::<Vec<usize>>();
+        for i in (1..count).rev() {
+            let j = (self.next_random() % (i as u64 + 1)) as usize;
+            order.swap(i, j);
+        }
+        for i in order {
+            if i < self.endpoints.len() {
+                let _ = self.endpoints[i].poll();
+            } else {
+                let _ = self.servers[i - self.endpoints.len()].poll();
+            }
+        }
+    }
+
+    /// Next number of the xorshift64* generator.
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+#[test]
+fn test_client() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use futures::future::FutureResult;
+
+    struct Counter(Rc<Cell<i64>>);
+
+    impl Service for Counter {
+        type Error = io::Error;
+        type T = Value;
+        type E = String;
+        type RequestFuture = FutureResult<Result<Value, String>, io::Error>;
+        type NotificationFuture = FutureResult<(), io::Error>;
+
+        fn handle_request(&mut self, method: &str, _params: &[Value]) -> Self::RequestFuture {
+            match method {
+                "get" => future::ok(Ok(Value::from(self.0.get()))),
+                _ => future::ok(Err(format!("unknown method {}", method))),
+            }
+        }
+
+        fn handle_notification(
+            &mut self,
+            _method: &str,
+            params: &[Value],
+        ) -> Self::NotificationFuture {
+            self.0.set(self.0.get() + params[0].as_i64().unwrap());
+            future::ok(())
+        }
+    }
+
+    let counter = Rc::new(Cell::new(0));
+    let mut client = TestClient::new(Counter(Rc::clone(&counter)));
+    client.notify("add", &[Value::from(2)]);
+    assert_eq!(counter.get(), 2);
+    assert_eq!(client.request("get", &[]), Ok(Value::from(2)));
+    assert_eq!(
+        client.request("set", &[]),
+        Err(Value::from("unknown method set"))
+    );
+}
+
+#[test]
+fn deterministic_runtime() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use futures::future::FutureResult;
+
+    #[derive(Clone)]
+    struct Recorder(Rc<RefCell<Vec<i64>>>);
+
+    impl Service for Recorder {
+        type Error = io::Error;
+        type T = Value;
+        type E = Value;
+        type RequestFuture = FutureResult<Result<Value, Value>, io::Error>;
+        type NotificationFuture = FutureResult<(), io::Error>;
+
+        fn handle_request(&mut self, _method: &str, params: &[Value]) -> Self::RequestFuture {
+            self.0.borrow_mut().push(params[0].as_i64().unwrap());
+            future::ok(Ok(Value::Nil))
+        }
+
+        fn handle_notification(&mut self, _: &str, _: &[Value]) -> Self::NotificationFuture {
+            future::ok(())
+        }
+    }
+
+    impl ServiceBuilder for Recorder {
+        type Service = Recorder;
+
+        fn build(&self, _client: Client) -> Self::Service {
+            self.clone()
+        }
+    }
+
+    let record = |seed| {
+        let recorder = Recorder(Rc::new(RefCell::new(Vec::new())));
+        let mut runtime = test_runtime(&recorder, 4, &ProtocolOptions::default(), seed);
+        let mut responses = Vec::new();
+        for i in 0..20 {
+            responses.push(runtime.client(i % 4).request("record", &[Value::from(i)]));
+        }
+        let _ = runtime.run(future::join_all(responses)).unwrap();
+        let calls = recorder.0.borrow().clone();
+        calls
+    };
+    let calls = record(42);
+    assert_eq!(calls.len(), 20);
+    assert_eq!(record(42), calls);
+    // Other seeds interleave the clients differently.
+    assert_ne!(record(7), calls);
+}
+
+#[test]
+fn simulated_time() {
+    use futures::future::Empty;
+
+    struct Stuck;
+
+    impl Service for Stuck {
+        type Error = io::Error;
+        type T = Value;
+        type E = Value;
+        type RequestFuture = Empty<Result<Value, Value>, io::Error>;
+        type NotificationFuture = Empty<(), io::Error>;
+
+        fn handle_request(&mut self, _method: &str, _params: &[Value]) -> Self::RequestFuture {
+            future::empty()
+        }
+
+        fn handle_notification(&mut self, _: &str, _: &[Value]) -> Self::NotificationFuture {
+            future::empty()
+        }
+    }
+
+    impl ServiceBuilder for Stuck {
+        type Service = Stuck;
+
+        fn build(&self, _client: Client) -> Self::Service {
+            Stuck
+        }
+    }
+
+    let mut options = ProtocolOptions::default();
+    let _ = options.method_timeout("slow", Some(Duration::from_secs(30)));
+    let mut runtime = test_runtime(&Stuck, 1, &options, 1);
+    let start = runtime.clock().now();
+
+    // The clock jumps to the timeout of the request, since nothing else can happen.
+    let response = runtime.client(0).request("slow", &[]);
+    let error = runtime.run(response).unwrap().unwrap_err();
+    assert_eq!(error, Value::from("request timed out"));
+    assert_eq!(runtime.clock().now() - start, Duration::from_secs(30));
+
+    // Without a timeout, the request is never answered.
+    let response = runtime.client(0).request("forever", &[]);
+    assert_eq!(runtime.run(response), Err(RunError::Stalled));
+    assert_eq!(runtime.clock().now() - start, Duration::from_secs(30));
+
+    // Idle connections are closed once the clock reaches their timeout.
+    let _ = options.idle_timeout(Some(Duration::from_secs(60)));
+    let mut runtime = test_runtime(&Stuck, 1, &options, 1);
+    let start = runtime.clock().now();
+    runtime.advance(Duration::from_secs(59));
+    let idle = runtime.run(future::empty::<(), ()>());
+    assert_eq!(idle, Err(RunError::Stalled));
+    assert_eq!(runtime.clock().now() - start, Duration::from_secs(60));
+    let response = runtime.client(0).request("slow", &[]);
+    assert!(runtime.run(response).is_err());
+}
@@ -1,33 +1,77 @@
-use futures::{Async, Canceled, Future, Poll, Stream};
+use futures::{future, Async, Canceled, Future, Poll};
+use futures::future::FutureResult;
 use futures::sync::oneshot;
-use tokio_core::reactor::Handle;
+use net2::TcpBuilder;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_tls::TlsConnectorExt;
-use tokio_core::net::{TcpListener, TcpStream};
-use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use std::fmt;
+use std::net::{self, SocketAddr};
+use std::time::Duration;
 use rmpv::Value;
 use std::io;
 
 use native_tls::TlsConnector;
-use endpoint::{Client, Endpoint, Service, ServiceBuilder};
-
-/// Start a `MessagePack-RPC` server.
+use endpoint::{Client, Endpoint, Response, Service, ServiceBuilder};
+use config::ClientConfig;
+use message::Notification;
+use options::ProtocolOptions;
+use resolver::resolve;
+use server::{ServerBuilder, SocketOptions};
+#[cfg(feature = "websocket")]
+use websocket;
+
+/// Start a `MessagePack-RPC` server, with the default options. Use a
+/// [`ServerBuilder`](struct.ServerBuilder.html) to configure the sockets, the transport, etc., or
+/// to get the address of a server bound to port 0 (see
+/// [`ServerBuilder::spawn`](struct.ServerBuilder.html#method.spawn)).
 pub fn serve<B: ServiceBuilder + 'static>(
     address: SocketAddr,
     service_builder: B,
     handle: Handle,
 ) -> Box<Future<Item = (), Error = ()>> {
-    let listener = TcpListener::bind(&address, &handle)
-        .unwrap()
-        .incoming()
-        .for_each(move |(stream, _address)| {
-            let mut endpoint = Endpoint::new(stream);
-            let client_proxy = endpoint.set_client();
-            endpoint.set_server(service_builder.build(client_proxy));
-            handle.spawn(endpoint.map_err(|_| ()));
-            Ok(())
-        })
-        .map_err(|_| ());
-    Box::new(listener)
+    serve_with_options(address, service_builder, handle, ProtocolOptions::default())
+}
+
+/// Start a `MessagePack-RPC` server. The given options are used for each connection the server
+/// accepts. Use a [`ServerBuilder`](struct.ServerBuilder.html) to configure the sockets, the
+/// transport, etc.
+pub fn serve_with_options<B: ServiceBuilder + 'static>(
+    address: SocketAddr,
+    service_builder: B,
+    handle: Handle,
+    options: ProtocolOptions,
+) -> Box<Future<Item = (), Error = ()>> {
+    let server = ServerBuilder::new(address)
+        .set_protocol_options(options)
+        .build(service_builder, &handle)
+        .unwrap();
+    Box::new(server.map_err(|_| ()))
+}
+
+/// Connect a client to the `MessagePack-RPC` server at `host`, a host name or an IP address
+/// followed by a port, like `"rpc.example.com:5000"`, with the default options. Use
+/// [`ClientOnlyConnector::from_host`](struct.ClientOnlyConnector.html#method.from_host) to
+/// configure the connection.
+pub fn connect_to(host: &str, handle: &Handle) -> Connection {
+    ClientOnlyConnector::from_host(host, handle).connect()
+}
+
+/// What a `Connector` connects to.
+enum Target<'a> {
+    Address(&'a SocketAddr),
+    // A host name and a port, resolved when connecting.
+    Host(String),
+}
+
+impl<'a> fmt::Display for Target<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Address(address) => address.fmt(f),
+            Target::Host(ref host) => host.fmt(f),
+        }
+    }
 }
 
 /// A `Connector` is used to initiate a connection with a remote `MessagePack-RPC` endpoint.
@@ -41,24 +85,151 @@ pub fn serve<B: ServiceBuilder + 'static>(
 /// [`ClientOnlyConnector`](struct.ClientOnlyConnector.html).
 pub struct Connector<'a, 'b, S> {
     service_builder: Option<S>,
-    address: &'a SocketAddr,
+    target: Target<'a>,
+    // The addresses tried in turn when the connection to the previous one fails.
+    fallbacks: Vec<SocketAddr>,
     handle: &'b Handle,
     tls: bool,
     tls_domain: Option<String>,
+    options: ProtocolOptions,
+    sockets: SocketOptions,
+    connect_timeout: Option<Duration>,
+    local_addr: Option<SocketAddr>,
+    // The notifications sent as soon as the connection is established.
+    notifications: Vec<Notification>,
+    // The method and parameters of the request that authenticates the connection.
+    credentials: Option<(String, Vec<Value>)>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<(String, String)>,
 }
 
 impl<'a, 'b, S: ServiceBuilder + Sync + Send + 'static> Connector<'a, 'b, S> {
     /// Create a new `Connector`. `address` is the address of the remote `MessagePack-RPC` server.
     pub fn new(address: &'a SocketAddr, handle: &'b Handle) -> Self {
+        Connector::with_target(Target::Address(address), handle)
+    }
+
+    /// Create a new `Connector` for the remote `MessagePack-RPC` server at `host`, a host name or
+    /// an IP address followed by a port, like `"rpc.example.com:5000"`. The host name is resolved
+    /// when connecting, on another thread so that the reactor is not blocked, and the resolved
+    /// addresses are tried in turn, before the
+    /// [fallback addresses](#method.set_fallback_addrs).
+    pub fn from_host(host: &str, handle: &'b Handle) -> Self {
+        Connector::with_target(Target::Host(host.to_owned()), handle)
+    }
+
+    fn with_target(target: Target<'a>, handle: &'b Handle) -> Self {
         Connector {
             service_builder: None,
-            address: address,
+            target: target,
+            fallbacks: Vec::new(),
             handle: handle,
             tls: false,
             tls_domain: None,
+            options: ProtocolOptions::default(),
+            sockets: SocketOptions::default(),
+            connect_timeout: None,
+            local_addr: None,
+            notifications: Vec::new(),
+            credentials: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
         }
     }
 
+    /// Create a new `Connector` from the given configuration.
+    pub fn from_config(config: &'a ClientConfig, handle: &'b Handle) -> Self {
+        let mut connector = Connector::new(&config.address, handle);
+        let _ = connector
+            .set_fallback_addrs(&config.fallback_addresses)
+            .set_protocol_options(ProtocolOptions::from(&config.protocol));
+        if let Some(timeout) = config.connect_timeout_ms {
+            let _ = connector.set_connect_timeout(Some(Duration::from_millis(timeout)));
+        }
+        if let Some(nodelay) = config.nodelay {
+            let _ = connector.set_nodelay(nodelay);
+        }
+        if let Some(keepalive) = config.keepalive_ms {
+            let _ = connector.set_keepalive(Some(Duration::from_millis(keepalive)));
+        }
+        if config.local_address.is_some() {
+            let _ = connector.set_local_addr(config.local_address);
+        }
+        if let Some(ref tls) = config.tls {
+            match tls.domain {
+                Some(ref domain) => {
+                    let _ = connector.set_tls_connector(domain.clone());
+                }
+                None => {
+                    let _ = connector.set_tls_connector_with_hostname_verification_disabled();
+                }
+            }
+        }
+        connector
+    }
+
+    /// Set the options used for the connection.
+    pub fn set_protocol_options(&mut self, options: ProtocolOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Set the addresses to try, in order, when the TCP connection to the main address fails, for
+    /// servers that run as several replicas. The connection fails if none of them can be reached.
+    /// The connect timeout applies to each address.
+    pub fn set_fallback_addrs(&mut self, addresses: &[SocketAddr]) -> &mut Self {
+        self.fallbacks = addresses.to_vec();
+        self
+    }
+
+    /// Fail the connection if the TCP connection is not established within the given time. By
+    /// default, the timeout of the operating system applies.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the connection, so that small messages are sent immediately instead
+    /// of being delayed by Nagle's algorithm. It is not set by default.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.sockets.nodelay = nodelay;
+        self
+    }
+
+    /// Enable TCP keepalive on the connection, with the given interval. It is disabled by default.
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) -> &mut Self {
+        self.sockets.keepalive = keepalive;
+        self
+    }
+
+    /// Bind the local end of the connection to the given address before connecting, to choose
+    /// the network interface or the source port. By default, the operating system chooses.
+    pub fn set_local_addr(&mut self, address: Option<SocketAddr>) -> &mut Self {
+        self.local_addr = address;
+        self
+    }
+
+    /// Send the given notification as soon as the connection is established, before the requests
+    /// and notifications sent with the `Client`, to identify the client for instance. The
+    /// notifications are sent in the order they are added.
+    pub fn add_initial_notification(&mut self, method: &str, params: &[Value]) -> &mut Self {
+        self.notifications.push(Notification {
+            method: method.to_owned(),
+            params: params.to_vec(),
+        });
+        self
+    }
+
+    /// Send a request with the given method and parameters as the first call of the connection,
+    /// for servers that authenticate their connections (see
+    /// [`Authenticator`](trait.Authenticator.html)). The connection is only established once the
+    /// server accepts the request, and fails with `PermissionDenied` if it answers with an error.
+    /// The initial notifications are sent after this request.
+    pub fn set_credentials(&mut self, method: &str, params: &[Value]) -> &mut Self {
+        self.credentials = Some((method.to_owned(), params.to_vec()));
+        self
+    }
+
     /// Enable TLS for this connection. `domain` is the hostname of the remote endpoint so which we
     /// are connecting.
     pub fn set_tls_connector(&mut self, domain: String) -> &mut Self {
@@ -76,6 +247,15 @@ impl<'a, 'b, S: ServiceBuilder + Sync + Send + 'static> Connector<'a, 'b, S> {
         self
     }
 
+    /// Connect with a WebSocket instead of raw TCP, for servers that sit behind an HTTP reverse
+    /// proxy. The upgrade request is sent for `path`, with `host` as `Host` header. If TLS is
+    /// enabled, the WebSocket runs on top of it.
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket(&mut self, host: &str, path: &str) -> &mut Self {
+        self.websocket = Some((host.to_owned(), path.to_owned()));
+        self
+    }
+
     /// Make the client able to handle incoming requests and notification using the given service.
     /// Once the connection is established, the client will act as a server and answer requests and
     /// notifications in background, using this service.
@@ -86,7 +266,7 @@ impl<'a, 'b, S: ServiceBuilder + Sync + Send + 'static> Connector<'a, 'b, S> {
 
     /// Connect to the remote `MessagePack-RPC` endpoint. This consumes the `Connector`.
     pub fn connect(&mut self) -> Connection {
-        trace!("Trying to connect to {}.", self.address);
+        trace!("Trying to connect to {}.", self.target);
 
         let (connection, client_tx, error_tx) = Connection::new();
 
@@ -105,45 +285,34 @@ impl<'a, 'b, S: ServiceBuilder + Sync + Send + 'static> Connector<'a, 'b, S> {
     // I thought is would be BoxFuture<Endpoint<S, TlsStream<TcpStream>>, ()>
     fn tls_connect(
         &mut self,
-        client_tx: oneshot::Sender<Client>,
+        client_tx: ClientTx,
         error_tx: oneshot::Sender<io::Error>,
     ) -> Box<Future<Item = (), Error = ()>> {
-        let tcp_connection = TcpStream::connect(self.address, self.handle);
+        let tcp_connection = self.tcp_stream();
 
         let domain = self.tls_domain.take();
-        let tls_handshake = tcp_connection.and_then(move |stream| {
-            trace!("TCP connection established. Starting TLS handshake.");
+        let tls_handshake = tcp_connection.and_then(move |(stream, address)| {
+            trace!("TCP connection established with {}. Starting TLS handshake.", address);
             let tls_connector =  TlsConnector::builder().unwrap().build().unwrap();
             if let Some(domain) = domain {
                 tls_connector.connect_async(&domain, stream)
             } else {
                 tls_connector.danger_connect_async_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
-            }.map_err(|e| { io::Error::new(io::ErrorKind::Other, e) })
+            }.map(move |stream| (stream, address))
+            .map_err(|e| { io::Error::new(io::ErrorKind::Other, e) })
         });
 
-        let service_builder = self.service_builder.take();
+        let setup = self.setup(client_tx);
         let endpoint = tls_handshake
-            .and_then(move |stream| {
+            .and_then(move |(stream, address)| {
                 trace!("TLS handshake done.");
-
-                let mut endpoint = Endpoint::new(stream);
-
-                let client_proxy = endpoint.set_client();
-                if client_tx.send(client_proxy.clone()).is_err() {
-                    panic!("Failed to send client to connection.");
-                }
-
-                if let Some(service_builder) = service_builder {
-                    endpoint.set_server(service_builder.build(client_proxy));
-                }
-
-                endpoint
+                setup.start(stream, address)
             })
             .or_else(|e| {
                 error!("Connection failed: {:?}.", e);
-                if let Err(e) = error_tx.send(e) {
-                    panic!("Failed to send client to connection: {:?}", e);
-                }
+                // If the connection has already been established, the `Connection` is gone and
+                // nobody is waiting for this error anymore.
+                let _ = error_tx.send(e);
                 Err(())
             });
 
@@ -154,63 +323,234 @@ impl<'a, 'b, S: ServiceBuilder + Sync + Send + 'static> Connector<'a, 'b, S> {
     // I thought is would be BoxFuture<Endpoint<S, TcpStream>, ()>
     fn tcp_connect(
         &mut self,
-        client_tx: oneshot::Sender<Client>,
+        client_tx: ClientTx,
         error_tx: oneshot::Sender<io::Error>,
     ) -> Box<Future<Item = (), Error = ()>> {
-        let service_builder = self.service_builder.take();
-        let endpoint = TcpStream::connect(self.address, self.handle)
-            .and_then(move |stream| {
-                trace!("TCP connection established.");
-
-                let mut endpoint = Endpoint::new(stream);
-
-                let client_proxy = endpoint.set_client();
-                if client_tx.send(client_proxy.clone()).is_err() {
-                    panic!("Failed to send client to connection.");
-                }
-
-                if let Some(service_builder) = service_builder {
-                    endpoint.set_server(service_builder.build(client_proxy));
-                }
-
-                endpoint
+        let setup = self.setup(client_tx);
+        let endpoint = self.tcp_stream()
+            .and_then(move |(stream, address)| {
+                trace!("TCP connection established with {}.", address);
+                setup.start(stream, address)
             })
             .or_else(|e| {
                 error!("Connection failed: {:?}.", e);
-                if let Err(e) = error_tx.send(e) {
-                    panic!("Failed to send client to connection: {:?}", e);
-                }
+                // If the connection has already been established, the `Connection` is gone and
+                // nobody is waiting for this error anymore.
+                let _ = error_tx.send(e);
                 Err(())
             });
 
         Box::new(endpoint)
     }
+
+    /// Return a future that establishes the TCP connection, with the main address, one of the
+    /// addresses of the host, or one of the fallbacks, and gives the address it is established
+    /// with.
+    fn tcp_stream(&self) -> Box<Future<Item = (TcpStream, SocketAddr), Error = io::Error>> {
+        let tcp = TcpConnect {
+            local_addr: self.local_addr,
+            sockets: self.sockets,
+            connect_timeout: self.connect_timeout,
+            handle: self.handle.clone(),
+        };
+        let fallbacks = self.fallbacks.clone();
+        let addresses = match self.target {
+            Target::Address(address) => Box::new(future::ok(vec![*address])),
+            Target::Host(ref host) => resolve(host),
+        };
+        let stream = addresses.and_then(move |addresses| {
+            trace!("Connecting to {:?}", addresses);
+            tcp.connect_any(addresses.into_iter().chain(fallbacks))
+        });
+        Box::new(stream)
+    }
+
+    fn setup(&mut self, client_tx: ClientTx) -> Setup<S> {
+        Setup {
+            service_builder: self.service_builder.take(),
+            notifications: self.notifications.clone(),
+            credentials: self.credentials.clone(),
+            options: self.options.clone(),
+            reactor: self.handle.clone(),
+            client_tx: client_tx,
+            #[cfg(feature = "websocket")]
+            websocket: self.websocket.take(),
+        }
+    }
+}
+
+/// What is needed to establish a TCP connection.
+#[derive(Clone)]
+struct TcpConnect {
+    local_addr: Option<SocketAddr>,
+    sockets: SocketOptions,
+    connect_timeout: Option<Duration>,
+    handle: Handle,
+}
+
+impl TcpConnect {
+    /// Return a future that establishes a TCP connection with the first of the addresses that
+    /// can be reached.
+    fn connect_any<I>(
+        &self,
+        addresses: I,
+    ) -> Box<Future<Item = (TcpStream, SocketAddr), Error = io::Error>>
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let mut addresses = addresses.into_iter();
+        let mut stream = match addresses.next() {
+            Some(address) => self.connect(address),
+            None => {
+                let error = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+                return Box::new(future::err(error));
+            }
+        };
+        for address in addresses {
+            let tcp = self.clone();
+            stream = Box::new(stream.or_else(move |e| {
+                warn!("Connection failed: {}. Trying {}.", e, address);
+                tcp.connect(address)
+            }));
+        }
+        stream
+    }
+
+    /// Return a future that establishes a TCP connection with `address`.
+    fn connect(
+        &self,
+        address: SocketAddr,
+    ) -> Box<Future<Item = (TcpStream, SocketAddr), Error = io::Error>> {
+        let connect: Box<Future<Item = TcpStream, Error = io::Error>> = match self.local_addr {
+            Some(ref local_addr) => match bind(local_addr) {
+                Ok(stream) => TcpStream::connect_stream(stream, &address, &self.handle),
+                Err(e) => return Box::new(future::err(e)),
+            },
+            None => Box::new(TcpStream::connect(&address, &self.handle)),
+        };
+        let sockets = self.sockets;
+        let connect = connect.and_then(move |stream| {
+            sockets.apply(&stream)?;
+            Ok((stream, address))
+        });
+        let timeout = match self.connect_timeout {
+            Some(timeout) => timeout,
+            None => return Box::new(connect),
+        };
+        let timeout = match Timeout::new(timeout, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let timeout = timeout.and_then(|()| {
+            Err::<(TcpStream, SocketAddr), _>(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection timed out",
+            ))
+        });
+        Box::new(
+            connect
+                .select(timeout)
+                .map(|(stream, _)| stream)
+                .map_err(|(e, _)| e),
+        )
+    }
+}
+
+/// Create a socket bound to the given local address, to connect from it.
+fn bind(local_addr: &SocketAddr) -> io::Result<net::TcpStream> {
+    let builder = match *local_addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    let _ = builder.bind(local_addr)?;
+    builder.to_tcp_stream()
+}
+
+/// What is needed to start the endpoint, once the connection is established.
+struct Setup<S> {
+    service_builder: Option<S>,
+    notifications: Vec<Notification>,
+    credentials: Option<(String, Vec<Value>)>,
+    options: ProtocolOptions,
+    reactor: Handle,
+    client_tx: ClientTx,
+    #[cfg(feature = "websocket")]
+    websocket: Option<(String, String)>,
+}
+
+impl<S: ServiceBuilder + 'static> Setup<S> {
+    /// Return a future that runs the endpoint on the given stream, after the WebSocket handshake
+    /// if needed.
+    fn start<T>(self, stream: T, address: SocketAddr) -> Box<Future<Item = (), Error = io::Error>>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        #[cfg(feature = "websocket")]
+        {
+            if let Some((host, path)) = self.websocket.clone() {
+                let mut handshake = websocket::connect(stream, &host, &path);
+                let _ = handshake.set_max_frame_size(self.options.get_websocket_max_frame_size());
+                let endpoint = handshake.and_then(move |stream| {
+                    trace!("WebSocket handshake done.");
+                    let mut endpoint = self.endpoint(stream, address);
+                    endpoint.set_message_aligned_writes();
+                    endpoint
+                });
+                return Box::new(endpoint);
+            }
+        }
+        Box::new(self.endpoint(stream, address))
+    }
+
+    fn endpoint<T>(self, stream: T, address: SocketAddr) -> Endpoint<S::Service, T>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let mut endpoint = Endpoint::new(stream, self.options);
+        endpoint.set_reactor(self.reactor);
+        endpoint.set_peer_addr(address);
+        let client_proxy = endpoint.set_client();
+        // The credentials are sent first, and the notifications once they are accepted.
+        let authentication = self.credentials
+            .map(|(method, params)| client_proxy.request(&method, &params));
+        for notification in self.notifications {
+            if authentication.is_some() {
+                let _ = client_proxy.notify(&notification.method, &notification.params);
+            } else {
+                endpoint.send_notification(notification);
+            }
+        }
+
+        if self.client_tx.send((client_proxy.clone(), authentication)).is_err() {
+            panic!("Failed to send client to connection.");
+        }
+
+        if let Some(service_builder) = self.service_builder {
+            endpoint.set_server(service_builder.build(client_proxy));
+        }
+
+        endpoint
+    }
 }
 
 /// A dummy Service that is used for endpoints that act as pure clients, i.e. that do not need to
 /// act handle incoming requests or notifications.
-struct NoService;
+pub(crate) struct NoService;
 
 impl Service for NoService {
     type Error = io::Error;
     type T = String;
     type E = String;
+    type RequestFuture = FutureResult<Result<Self::T, Self::E>, Self::Error>;
+    type NotificationFuture = FutureResult<(), Self::Error>;
 
     /// Handle a `MessagePack-RPC` request by panicking
-    fn handle_request(
-        &mut self,
-        _method: &str,
-        _params: &[Value],
-    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+    fn handle_request(&mut self, _method: &str, _params: &[Value]) -> Self::RequestFuture {
         panic!("This endpoint does not handle requests");
     }
 
     /// Handle a `MessagePack-RPC` notification by panicking
-    fn handle_notification(
-        &mut self,
-        _method: &str,
-        _params: &[Value],
-    ) -> Box<Future<Item = (), Error = Self::Error>> {
+    fn handle_notification(&mut self, _method: &str, _params: &[Value]) -> Self::NotificationFuture {
         panic!("This endpoint does not handle notifications");
     }
 }
@@ -241,11 +581,71 @@ impl<'a, 'b> ClientOnlyConnector<'a, 'b> {
         ClientOnlyConnector(Connector::<'a, 'b, NoService>::new(address, handle))
     }
 
+    /// Create a new `ClientOnlyConnector` for the server at `host` (see
+    /// [`Connector::from_host`](struct.Connector.html#method.from_host)).
+    pub fn from_host(host: &str, handle: &'b Handle) -> Self {
+        ClientOnlyConnector(Connector::<'a, 'b, NoService>::from_host(host, handle))
+    }
+
+    /// Create a new `ClientOnlyConnector` from the given configuration.
+    pub fn from_config(config: &'a ClientConfig, handle: &'b Handle) -> Self {
+        ClientOnlyConnector(Connector::<'a, 'b, NoService>::from_config(config, handle))
+    }
+
     /// Connect to the remote `MessagePack-RPC` server.
     pub fn connect(&mut self) -> Connection {
         self.0.connect()
     }
 
+    /// Set the options used for the connection.
+    pub fn set_protocol_options(&mut self, options: ProtocolOptions) -> &mut Self {
+        let _ = self.0.set_protocol_options(options);
+        self
+    }
+
+    /// See [`Connector::set_fallback_addrs`](struct.Connector.html#method.set_fallback_addrs).
+    pub fn set_fallback_addrs(&mut self, addresses: &[SocketAddr]) -> &mut Self {
+        let _ = self.0.set_fallback_addrs(addresses);
+        self
+    }
+
+    /// See [`Connector::set_connect_timeout`](struct.Connector.html#method.set_connect_timeout).
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        let _ = self.0.set_connect_timeout(timeout);
+        self
+    }
+
+    /// See [`Connector::set_nodelay`](struct.Connector.html#method.set_nodelay).
+    pub fn set_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        let _ = self.0.set_nodelay(nodelay);
+        self
+    }
+
+    /// See [`Connector::set_keepalive`](struct.Connector.html#method.set_keepalive).
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) -> &mut Self {
+        let _ = self.0.set_keepalive(keepalive);
+        self
+    }
+
+    /// See [`Connector::set_local_addr`](struct.Connector.html#method.set_local_addr).
+    pub fn set_local_addr(&mut self, address: Option<SocketAddr>) -> &mut Self {
+        let _ = self.0.set_local_addr(address);
+        self
+    }
+
+    /// See
+    /// [`Connector::add_initial_notification`](struct.Connector.html#method.add_initial_notification).
+    pub fn add_initial_notification(&mut self, method: &str, params: &[Value]) -> &mut Self {
+        let _ = self.0.add_initial_notification(method, params);
+        self
+    }
+
+    /// See [`Connector::set_credentials`](struct.Connector.html#method.set_credentials).
+    pub fn set_credentials(&mut self, method: &str, params: &[Value]) -> &mut Self {
+        let _ = self.0.set_credentials(method, params);
+        self
+    }
+
     /// Enable TLS for this connection. `domain` is the hostname of the remote endpoint so which we
     /// are connecting.
     pub fn set_tls_connector(&mut self, domain: String) -> &mut Self {
@@ -261,22 +661,36 @@ impl<'a, 'b> ClientOnlyConnector<'a, 'b> {
             .set_tls_connector_with_hostname_verification_disabled();
         self
     }
+
+    /// Connect with a WebSocket instead of raw TCP (see
+    /// [`Connector::set_websocket`](struct.Connector.html#method.set_websocket)).
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket(&mut self, host: &str, path: &str) -> &mut Self {
+        let _ = self.0.set_websocket(host, path);
+        self
+    }
 }
 
+/// The client of a new connection, with the response to its credentials if it sent some.
+type ClientTx = oneshot::Sender<(Client, Option<Response>)>;
+
 /// A future that returns a `MessagePack-RPC` endpoint when it completes successfully.
 pub struct Connection {
-    client_rx: oneshot::Receiver<Client>,
+    client_rx: oneshot::Receiver<(Client, Option<Response>)>,
     error_rx: oneshot::Receiver<io::Error>,
+    // Set while the server checks the credentials.
+    authentication: Option<(Client, Response)>,
 }
 
 impl Connection {
-    fn new() -> (Self, oneshot::Sender<Client>, oneshot::Sender<io::Error>) {
+    fn new() -> (Self, ClientTx, oneshot::Sender<io::Error>) {
         let (client_tx, client_rx) = oneshot::channel();
         let (error_tx, error_rx) = oneshot::channel();
 
         let connection = Connection {
             client_rx: client_rx,
             error_rx: error_rx,
+            authentication: None,
         };
 
         (connection, client_tx, error_tx)
@@ -292,9 +706,27 @@ impl Future for Connection {
     // Also, it would be *much* nicer to have only one channel that gives us
     // Result<Client, io::Error> instead of two distinct channels.
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some((_, ref mut response)) = self.authentication {
+            match response.poll() {
+                Ok(Async::Ready(Ok(_))) => {}
+                Ok(Async::Ready(Err(error))) => {
+                    let error = format!("credentials rejected: {}", error);
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, error));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, e)),
+            }
+        }
+        if let Some((client, _)) = self.authentication.take() {
+            return Ok(Async::Ready(client));
+        }
         match (self.client_rx.poll(), self.error_rx.poll()) {
-            // We have a client, return it
-            (Ok(Async::Ready(client)), _) => Ok(Async::Ready(client)),
+            // We have a client, return it, once its credentials are accepted if needed
+            (Ok(Async::Ready((client, None))), _) => Ok(Async::Ready(client)),
+            (Ok(Async::Ready((client, Some(response)))), _) => {
+                self.authentication = Some((client, response));
+                self.poll()
+            }
             // We have an error, return it
             (_, Ok(Async::Ready(e))) => Err(e),
             // Both channels got closed before we received either an error or a client...
@@ -308,3 +740,30 @@ impl Future for Connection {
         }
     }
 }
+
+#[test]
+fn connect_to_host() {
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut router = MethodRouter::new();
+    let _ = router.request("ping", |_params| Box::new(future::ok(Ok(Value::from("pong")))));
+    let any = "127.0.0.1:0".parse().unwrap();
+    let server = ServerBuilder::new(any).spawn(router, &handle).unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    // localhost may resolve to ::1 first, where the server does not listen.
+    let client = core.run(connect_to(&format!("localhost:{}", port), &handle))
+        .unwrap();
+    assert_eq!(client.context().peer_addr(), server.local_addr());
+    let response = core.run(client.request("ping", &[])).unwrap();
+    assert_eq!(response, Ok(Value::from("pong")));
+
+    // The port is missing.
+    match core.run(connect_to("localhost", &handle)) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        Ok(_) => panic!("connected without a port"),
+    }
+}
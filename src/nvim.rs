@@ -0,0 +1,262 @@
+//! Helpers for the conventions of [neovim](https://neovim.io)'s `MessagePack-RPC` API. They are
+//! only built with the `nvim` feature.
+//!
+//! - Buffers, windows and tabpages are sent as msgpack ext values holding their id (see
+//!   [`Handle`](enum.Handle.html)).
+//! - Errors that are not the response to a request, such as those of notifications, are sent
+//!   back as `nvim_error_event` notifications (see [`ErrorEvent`](struct.ErrorEvent.html)).
+//! - The functions, types and version of the API can be discovered with `nvim_get_api_info`
+//!   (see [`get_api_info`](fn.get_api_info.html)).
+use std::io;
+
+use futures::Future;
+use rmp::decode as rmp_decode;
+use rmp::encode as rmp_encode;
+use rmpv::Value;
+
+use endpoint::Client;
+use errors::CallError;
+
+/// Method of the notifications neovim sends when it fails to handle a notification.
+pub const ERROR_EVENT_METHOD: &str = "nvim_error_event";
+
+/// Method of the request that returns the channel id of the connection and the API metadata.
+pub const GET_API_INFO_METHOD: &str = "nvim_get_api_info";
+
+/// A reference to a neovim buffer, window or tabpage.
+///
+/// Handles are sent as ext values whose data is the id of the object, encoded as a msgpack
+/// integer. The ext types are the ones neovim has always used, and also advertises in its API
+/// metadata (see [`ApiInfo::ext_type`](struct.ApiInfo.html#method.ext_type)).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handle {
+    /// A buffer, whose ext type is 0.
+    Buffer(i64),
+    /// A window, whose ext type is 1.
+    Window(i64),
+    /// A tabpage, whose ext type is 2.
+    Tabpage(i64),
+}
+
+impl Handle {
+    /// Return the ext type of the handle.
+    pub fn ext_type(&self) -> i8 {
+        match *self {
+            Handle::Buffer(_) => 0,
+            Handle::Window(_) => 1,
+            Handle::Tabpage(_) => 2,
+        }
+    }
+
+    /// Return the id of the object.
+    pub fn id(&self) -> i64 {
+        match *self {
+            Handle::Buffer(id) | Handle::Window(id) | Handle::Tabpage(id) => id,
+        }
+    }
+
+    /// Encode the handle, to use it as a parameter.
+    pub fn to_value(&self) -> Value {
+        let mut data = Vec::new();
+        // Writing to a `Vec` does not fail.
+        let _ = rmp_encode::write_sint(&mut data, self.id());
+        Value::Ext(self.ext_type(), data)
+    }
+
+    /// Decode a handle received in a response or a notification. Return `None` if `value` is not
+    /// a handle.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let (ext_type, data) = match *value {
+            Value::Ext(ext_type, ref data) => (ext_type, data),
+            _ => return None,
+        };
+        let mut data = io::Cursor::new(data);
+        let id = match rmp_decode::read_int::<i64, _>(&mut data) {
+            Ok(id) if data.position() as usize == data.get_ref().len() => id,
+            _ => return None,
+        };
+        match ext_type {
+            0 => Some(Handle::Buffer(id)),
+            1 => Some(Handle::Window(id)),
+            2 => Some(Handle::Tabpage(id)),
+            _ => None,
+        }
+    }
+}
+
+impl From<Handle> for Value {
+    fn from(handle: Handle) -> Self {
+        handle.to_value()
+    }
+}
+
+/// An error reported by an `nvim_error_event` notification.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorEvent {
+    /// The kind of error: 0 for an exception, 1 for a validation error.
+    pub kind: i64,
+    /// Description of the error.
+    pub message: String,
+}
+
+impl ErrorEvent {
+    /// Build an `ErrorEvent` from the parameters of an `nvim_error_event` notification. Return
+    /// `None` if they are invalid.
+    pub fn from_params(params: &[Value]) -> Option<Self> {
+        if params.len() < 2 {
+            return None;
+        }
+        match (params[0].as_i64(), params[1].as_str()) {
+            (Some(kind), Some(message)) => Some(ErrorEvent {
+                kind: kind,
+                message: message.to_owned(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The result of `nvim_get_api_info`: the id of the channel of the connection, and the metadata
+/// of the API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiInfo {
+    channel_id: u64,
+    metadata: Value,
+}
+
+impl ApiInfo {
+    /// Decode the result of `nvim_get_api_info`. Return `None` if it is invalid.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let info = match value.as_array() {
+            Some(info) if info.len() == 2 && info[1].as_map().is_some() => info,
+            _ => return None,
+        };
+        info[0].as_u64().map(|channel_id| ApiInfo {
+            channel_id: channel_id,
+            metadata: info[1].clone(),
+        })
+    }
+
+    /// Return the id neovim gave to the connection, which is needed by some functions, such as
+    /// `rpcnotify`.
+    pub fn channel_id(&self) -> u64 {
+        self.channel_id
+    }
+
+    /// Return the metadata of the API, as neovim sent it.
+    pub fn metadata(&self) -> &Value {
+        &self.metadata
+    }
+
+    /// Return the version of the API, if the metadata has one.
+    pub fn api_level(&self) -> Option<u64> {
+        self.field("version")
+            .and_then(|version| field(version, "api_level"))
+            .and_then(Value::as_u64)
+    }
+
+    /// Return the names of the functions of the API.
+    pub fn functions(&self) -> Vec<&str> {
+        match self.field("functions").and_then(Value::as_array) {
+            Some(functions) => functions
+                .iter()
+                .filter_map(|function| field(function, "name").and_then(Value::as_str))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Return `true` if the API has the given function.
+    pub fn has_function(&self, name: &str) -> bool {
+        self.functions().contains(&name)
+    }
+
+    /// Return the ext type of the given type of handle (`"Buffer"`, `"Window"` or `"Tabpage"`),
+    /// if the metadata has it.
+    pub fn ext_type(&self, handle_type: &str) -> Option<i8> {
+        self.field("types")
+            .and_then(|types| field(types, handle_type))
+            .and_then(|handle_type| field(handle_type, "id"))
+            .and_then(Value::as_i64)
+            .map(|id| id as i8)
+    }
+
+    fn field(&self, key: &str) -> Option<&Value> {
+        field(&self.metadata, key)
+    }
+}
+
+/// Return the value of `key` in the map `value`.
+fn field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value.as_map() {
+        Some(map) => map.iter()
+            .find(|&&(ref k, _)| k.as_str() == Some(key))
+            .map(|&(_, ref v)| v),
+        None => None,
+    }
+}
+
+/// Request the channel id and the API metadata from neovim. The response is an error if neovim
+/// returned an error, or returned something that is not API information, in which case the error
+/// is the result.
+pub fn get_api_info(
+    client: &Client,
+) -> Box<Future<Item = Result<ApiInfo, Value>, Error = CallError>> {
+    let response = client
+        .request(GET_API_INFO_METHOD, &[])
+        .map(|response| match response {
+            Ok(info) => ApiInfo::from_value(&info).ok_or(info),
+            Err(e) => Err(e),
+        });
+    Box::new(response)
+}
+
+#[test]
+fn handles() {
+    for handle in &[Handle::Buffer(1), Handle::Window(1000), Handle::Tabpage(-3)] {
+        assert_eq!(Handle::from_value(&handle.to_value()), Some(*handle));
+    }
+    assert_eq!(Handle::Window(1000).to_value(), Value::Ext(1, vec![0xcd, 0x03, 0xe8]));
+    assert_eq!(Handle::from_value(&Value::Ext(5, vec![1])), None);
+    assert_eq!(Handle::from_value(&Value::Ext(0, vec![1, 2])), None);
+    assert_eq!(Handle::from_value(&Value::from(1)), None);
+
+    let error = ErrorEvent::from_params(&[Value::from(1), Value::from("Invalid method")]);
+    assert_eq!(error.unwrap().message, "Invalid method");
+    assert_eq!(ErrorEvent::from_params(&[Value::from("oops")]), None);
+}
+
+#[test]
+fn api_info() {
+    use futures::future;
+    use methods::MethodRouter;
+    use mock::TestClient;
+
+    let map = |entries: Vec<(&str, Value)>| {
+        Value::Map(entries.into_iter().map(|(k, v)| (Value::from(k), v)).collect())
+    };
+    let metadata = map(vec![
+        ("version", map(vec![("api_level", Value::from(6))])),
+        (
+            "functions",
+            Value::from(vec![
+                map(vec![("name", Value::from("nvim_command"))]),
+                map(vec![("name", Value::from("nvim_buf_get_lines"))]),
+            ]),
+        ),
+        ("types", map(vec![("Window", map(vec![("id", Value::from(1))]))])),
+    ]);
+    let info = Value::from(vec![Value::from(3), metadata]);
+
+    let mut router = MethodRouter::new();
+    let _ = router.request(GET_API_INFO_METHOD, move |_| Box::new(future::ok(Ok(info.clone()))));
+    let mut client = TestClient::new(router);
+    let info = get_api_info(client.client());
+    let info = client.run(info).unwrap().unwrap();
+    assert_eq!(info.channel_id(), 3);
+    assert_eq!(info.api_level(), Some(6));
+    assert_eq!(info.functions(), vec!["nvim_command", "nvim_buf_get_lines"]);
+    assert!(info.has_function("nvim_buf_get_lines"));
+    assert_eq!(info.ext_type("Window"), Some(1));
+    assert_eq!(info.ext_type("Buffer"), None);
+}
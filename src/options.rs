@@ -0,0 +1,853 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmpv::Value;
+
+use anomaly::{AnomalyHandler, AnomalyHook};
+use audit::{AuditHook, AuditLog};
+use auth::{AuthHook, Authenticator};
+use clock::Clock;
+use dump::ProtocolDump;
+#[cfg(feature = "serde-params")]
+use ext::ExtRegistry;
+use metrics::{Metrics, MetricsHook};
+use hello::Hello;
+use message::{EmptyParams, DEFAULT_MAX_DEPTH};
+use redact::Redactions;
+
+/// Default maximum number of incoming messages an endpoint handles each time it is polled.
+const DEFAULT_POLL_BUDGET: usize = 128;
+
+/// Default error sent in response to the requests received while the server is draining.
+const DEFAULT_SHUTDOWN_ERROR: &str = "server is shutting down";
+
+/// What an endpoint does with a request whose id is the id of a request it is still handling (see
+/// [`ProtocolOptions::duplicate_ids`](struct.ProtocolOptions.html#method.duplicate_ids)).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateIdPolicy {
+    /// The request is answered right away with an "invalid request"
+    /// [`RpcError`](struct.RpcError.html), without being handled. Since the response has the same
+    /// id as the response to the first request, it is not ordered with the other responses.
+    Reject,
+    /// The request is handled once the response to the first request has been sent.
+    Queue,
+    /// The connection is closed: the remote endpoint cannot tell the responses apart anyway.
+    Close,
+}
+
+impl Default for DuplicateIdPolicy {
+    fn default() -> Self {
+        DuplicateIdPolicy::Reject
+    }
+}
+
+/// What an endpoint does with a message that breaks the protocol, but does not prevent it from
+/// reading the following messages (see
+/// [`ProtocolOptions::invalid_messages`](struct.ProtocolOptions.html#method.invalid_messages) and
+/// [`ProtocolOptions::unexpected_responses`](struct.ProtocolOptions.html#method.unexpected_responses)).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolPolicy {
+    /// The message is dropped silently.
+    Ignore,
+    /// The message is dropped, and a warning is logged.
+    LogAndIgnore,
+    /// The connection is closed.
+    CloseConnection,
+}
+
+impl Default for ProtocolPolicy {
+    fn default() -> Self {
+        ProtocolPolicy::LogAndIgnore
+    }
+}
+
+/// Limits applied to the requests and notifications an endpoint receives. Unlike the other
+/// options, they can be changed while a server is running (see
+/// [`ServerHandle::set_limits`](struct.ServerHandle.html#method.set_limits)), for instance to
+/// tighten them during an incident.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    poll_budget: usize,
+    max_in_flight: Option<usize>,
+    request_timeout: Option<Duration>,
+    principal_max_in_flight: Option<usize>,
+    principal_rate: Option<u32>,
+    principal_bandwidth: Option<u32>,
+    connection_rate: Option<u32>,
+    peer_rate: Option<u32>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            poll_budget: DEFAULT_POLL_BUDGET,
+            max_in_flight: None,
+            request_timeout: None,
+            principal_max_in_flight: None,
+            principal_rate: None,
+            principal_bandwidth: None,
+            connection_rate: None,
+            peer_rate: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Create a new set of limits, with the default values.
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    /// Set the maximum number of incoming messages an endpoint reads each time it is polled.
+    /// Once the budget is exhausted, the endpoint yields to let other tasks make progress, so that
+    /// a single busy connection cannot starve the others. The default budget is 128.
+    pub fn poll_budget(&mut self, budget: usize) -> &mut Self {
+        self.poll_budget = budget;
+        self
+    }
+
+    /// Return the maximum number of incoming messages read each time an endpoint is polled.
+    pub fn get_poll_budget(&self) -> usize {
+        self.poll_budget
+    }
+
+    /// Set the maximum number of requests handled concurrently on a connection. Once it is
+    /// reached, the endpoint stops reading from the connection until some of these requests
+    /// complete. By default, there is no limit.
+    pub fn max_in_flight(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_in_flight = max;
+        self
+    }
+
+    /// Return the maximum number of requests handled concurrently on a connection.
+    pub fn get_max_in_flight(&self) -> Option<usize> {
+        self.max_in_flight
+    }
+
+    /// Set how long a request can be handled before the endpoint gives up and answers it with
+    /// a `"request timed out"` error. Changing it only affects the requests received afterwards.
+    /// By default, there is no timeout.
+    pub fn request_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Return how long a request can be handled before it times out.
+    pub fn get_request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// Set the maximum number of requests handled concurrently for a given principal (see
+    /// [`Context::set_principal`](struct.Context.html#method.set_principal)), across all its
+    /// connections to the server. Unlike `max_in_flight`, requests over this quota are answered
+    /// with a `"quota exceeded"` error, since the other connections of the principal cannot be
+    /// slowed down. Requests from connections without a principal are not affected. By default,
+    /// there is no limit.
+    pub fn principal_max_in_flight(&mut self, max: Option<usize>) -> &mut Self {
+        self.principal_max_in_flight = max;
+        self
+    }
+
+    /// Return the maximum number of requests handled concurrently for a given principal.
+    pub fn get_principal_max_in_flight(&self) -> Option<usize> {
+        self.principal_max_in_flight
+    }
+
+    /// Set the maximum number of requests per second accepted from a given principal, across all
+    /// its connections to the server. Requests over this quota are answered with a `"quota
+    /// exceeded"` error. By default, there is no limit.
+    pub fn principal_rate(&mut self, rate: Option<u32>) -> &mut Self {
+        self.principal_rate = rate;
+        self
+    }
+
+    /// Return the maximum number of requests per second accepted from a given principal.
+    pub fn get_principal_rate(&self) -> Option<u32> {
+        self.principal_rate
+    }
+
+    /// Set the maximum number of bytes per second accepted in the requests of a given principal,
+    /// across all its connections to the server, counting the method and the encoded parameters
+    /// of each request. The bytes are limited by a token bucket that holds one second worth of
+    /// bytes: a request is accepted as long as the bucket is not empty, even if it is larger than
+    /// what is left, and the next ones are answered with a `"quota exceeded"` error until the
+    /// bucket is refilled. By default, there is no limit.
+    pub fn principal_bandwidth(&mut self, bytes: Option<u32>) -> &mut Self {
+        self.principal_bandwidth = bytes;
+        self
+    }
+
+    /// Return the maximum number of bytes per second accepted in the requests of a given
+    /// principal.
+    pub fn get_principal_bandwidth(&self) -> Option<u32> {
+        self.principal_bandwidth
+    }
+
+    /// Set the maximum number of requests per second accepted on a connection. The requests are
+    /// limited by a token bucket, which allows bursts of up to one second worth of requests.
+    /// Requests over the limit are answered with a
+    /// [`RpcError::rate_limited`](struct.RpcError.html#method.rate_limited) error. By default,
+    /// there is no limit.
+    pub fn connection_rate(&mut self, rate: Option<u32>) -> &mut Self {
+        self.connection_rate = rate;
+        self
+    }
+
+    /// Return the maximum number of requests per second accepted on a connection.
+    pub fn get_connection_rate(&self) -> Option<u32> {
+        self.connection_rate
+    }
+
+    /// Set the maximum number of requests per second a server accepts from a given IP address,
+    /// across all the connections from this address. It works like `connection_rate`, but cannot
+    /// be bypassed by opening more connections. By default, there is no limit.
+    pub fn peer_rate(&mut self, rate: Option<u32>) -> &mut Self {
+        self.peer_rate = rate;
+        self
+    }
+
+    /// Return the maximum number of requests per second accepted from a given IP address.
+    pub fn get_peer_rate(&self) -> Option<u32> {
+        self.peer_rate
+    }
+}
+
+/// Options that control how an endpoint speaks the `MessagePack-RPC` protocol on a connection.
+///
+/// The default options match the behavior described in the specification: responses are sent
+/// as soon as they are available, which means they may not be in the same order as the requests.
+#[derive(Clone, Debug)]
+pub struct ProtocolOptions {
+    ordered_responses: bool,
+    limits: Limits,
+    hello: Option<Hello>,
+    shutdown_error: Option<Value>,
+    zero_copy_binary: Option<usize>,
+    lenient_decoding: bool,
+    legacy_compatibility: bool,
+    named_params: bool,
+    empty_params: Option<EmptyParams>,
+    max_message_size: Option<usize>,
+    max_nesting_depth: usize,
+    max_elements: Option<usize>,
+    encode_buffer: Option<usize>,
+    adaptive_read_buffer: Option<usize>,
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+    #[cfg(feature = "websocket")]
+    websocket_max_frame_size: Option<usize>,
+    #[cfg(feature = "serde-params")]
+    ext_registry: Option<ExtRegistry>,
+    audit_log: Option<AuditHook>,
+    authenticator: Option<AuthHook>,
+    metrics: Option<MetricsHook>,
+    anomaly_handler: Option<AnomalyHook>,
+    dump: Option<ProtocolDump>,
+    redactions: Redactions,
+    idle_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    health_check: bool,
+    method_timeouts: HashMap<String, Duration>,
+    clock: Clock,
+    read_rate: Option<u32>,
+    write_rate: Option<u32>,
+    max_request_id: u32,
+    spawn_handlers: bool,
+    duplicate_ids: DuplicateIdPolicy,
+    invalid_messages: ProtocolPolicy,
+    unexpected_responses: ProtocolPolicy,
+}
+
+impl Default for ProtocolOptions {
+    fn default() -> Self {
+        ProtocolOptions {
+            ordered_responses: false,
+            limits: Limits::default(),
+            hello: None,
+            shutdown_error: Some(Value::from(DEFAULT_SHUTDOWN_ERROR)),
+            zero_copy_binary: None,
+            lenient_decoding: false,
+            legacy_compatibility: false,
+            named_params: false,
+            empty_params: None,
+            max_message_size: None,
+            max_nesting_depth: DEFAULT_MAX_DEPTH,
+            max_elements: None,
+            encode_buffer: None,
+            adaptive_read_buffer: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "websocket")]
+            websocket_max_frame_size: None,
+            #[cfg(feature = "serde-params")]
+            ext_registry: None,
+            audit_log: None,
+            authenticator: None,
+            metrics: None,
+            anomaly_handler: None,
+            dump: None,
+            redactions: Redactions::default(),
+            idle_timeout: None,
+            keepalive_interval: None,
+            health_check: true,
+            method_timeouts: HashMap::new(),
+            clock: Clock::system(),
+            read_rate: None,
+            write_rate: None,
+            max_request_id: u32::max_value(),
+            spawn_handlers: false,
+            duplicate_ids: DuplicateIdPolicy::default(),
+            invalid_messages: ProtocolPolicy::LogAndIgnore,
+            unexpected_responses: ProtocolPolicy::CloseConnection,
+        }
+    }
+}
+
+impl ProtocolOptions {
+    /// Create a new set of options, with the default values.
+    pub fn new() -> Self {
+        ProtocolOptions::default()
+    }
+
+    /// If `enabled` is `true`, responses are sent in the order in which the requests have been
+    /// received, instead of being sent as soon as they are ready. A response that completes
+    /// early is buffered until the responses to all the requests received before it have been
+    /// sent. This is useful for peers that do not match responses by id.
+    pub fn ordered_responses(&mut self, enabled: bool) -> &mut Self {
+        self.ordered_responses = enabled;
+        self
+    }
+
+    /// Return `true` if responses are sent in the order the requests have been received.
+    pub fn has_ordered_responses(&self) -> bool {
+        self.ordered_responses
+    }
+
+    /// Set the limits applied to the requests and notifications received on the connection.
+    pub fn limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Return the limits applied to the requests and notifications received on the connection.
+    pub fn get_limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Set the poll budget of the endpoint (see
+    /// [`Limits::poll_budget`](struct.Limits.html#method.poll_budget)).
+    pub fn poll_budget(&mut self, budget: usize) -> &mut Self {
+        let _ = self.limits.poll_budget(budget);
+        self
+    }
+
+    /// Return the maximum number of incoming messages read each time an endpoint is polled.
+    pub fn get_poll_budget(&self) -> usize {
+        self.limits.get_poll_budget()
+    }
+
+    /// If `hello` is not `None`, send it to the remote endpoint as the first message on the
+    /// connection. The `Hello` sent by the remote endpoint, if any, is available through the
+    /// connection [`Context`](struct.Context.html). By default, no `Hello` is sent.
+    pub fn hello(&mut self, hello: Option<Hello>) -> &mut Self {
+        self.hello = hello;
+        self
+    }
+
+    /// Return the `Hello` sent when a connection is established.
+    pub fn get_hello(&self) -> Option<&Hello> {
+        self.hello.as_ref()
+    }
+
+    /// Set the error sent in response to the requests received while the server is draining (see
+    /// [`ServerHandle::drain`](struct.ServerHandle.html#method.drain)). Such requests are answered
+    /// immediately, without being handled. If `error` is `None`, they are handled as usual. The
+    /// default error is the string `"server is shutting down"`.
+    pub fn shutdown_error(&mut self, error: Option<Value>) -> &mut Self {
+        self.shutdown_error = error;
+        self
+    }
+
+    /// Return the error sent in response to the requests received while the server is draining.
+    pub fn get_shutdown_error(&self) -> Option<&Value> {
+        self.shutdown_error.as_ref()
+    }
+
+    /// If `threshold` is not `None`, the binary parameters of incoming requests that are at least
+    /// `threshold` bytes long are not copied: they are passed to
+    /// [`Service::handle_request_zero_copy`](trait.Service.html#method.handle_request_zero_copy)
+    /// as `Param::Binary` slices of the receive buffer. Note that such a slice keeps the whole
+    /// receive buffer alive as long as it is not dropped. By default, all the parameters are
+    /// copied.
+    pub fn zero_copy_binary(&mut self, threshold: Option<usize>) -> &mut Self {
+        self.zero_copy_binary = threshold;
+        self
+    }
+
+    /// Return the size above which the binary parameters of incoming requests are not copied.
+    pub fn get_zero_copy_binary(&self) -> Option<usize> {
+        self.zero_copy_binary
+    }
+
+    /// If `enabled` is `true`, incoming messages that deviate from the specification in some
+    /// common ways are accepted: method names encoded as `bin` instead of `str`, parameters
+    /// encoded as a map instead of an array, which are normalized into a single parameter holding
+    /// the map, and successful responses without the error item. By default, these messages are
+    /// invalid and are dropped.
+    pub fn lenient_decoding(&mut self, enabled: bool) -> &mut Self {
+        self.lenient_decoding = enabled;
+        self
+    }
+
+    /// Return `true` if incoming messages are decoded in lenient mode.
+    pub fn has_lenient_decoding(&self) -> bool {
+        self.lenient_decoding
+    }
+
+    /// If `enabled` is `true`, the connection mirrors the quirks of the reference C++ and Ruby
+    /// implementations, to talk to legacy services that cannot be changed:
+    ///
+    /// - requests and notifications without parameters are sent with `nil` parameters instead
+    ///   of an empty array, unless [`empty_params`](#method.empty_params) is set;
+    /// - the errors of the responses that are an integer code, with the error message in the
+    ///   result, are received as an [`RpcError`](struct.RpcError.html) with this code and this
+    ///   message;
+    /// - incoming messages are decoded in lenient mode (see
+    ///   [`lenient_decoding`](#method.lenient_decoding)).
+    ///
+    /// It is disabled by default.
+    pub fn legacy_compatibility(&mut self, enabled: bool) -> &mut Self {
+        self.legacy_compatibility = enabled;
+        self
+    }
+
+    /// Return `true` if the connection mirrors the quirks of the reference implementations.
+    pub fn has_legacy_compatibility(&self) -> bool {
+        self.legacy_compatibility
+    }
+
+    /// If `enabled` is `true`, the connection speaks the dialects of `MessagePack-RPC` where the
+    /// parameters are a map of named arguments instead of an array:
+    ///
+    /// - the requests and notifications whose only parameter is a map, like those sent with
+    ///   [`Client::request_named`](struct.Client.html#method.request_named), are sent with this
+    ///   map as their parameters;
+    /// - incoming parameters encoded as a map are accepted, and normalized into a single
+    ///   parameter holding the map, which
+    ///   [`MethodRouter::named_params`](struct.MethodRouter.html#method.named_params) can turn
+    ///   back into positional parameters. Parameters encoded as an array are still accepted.
+    ///
+    /// It is disabled by default.
+    pub fn named_params(&mut self, enabled: bool) -> &mut Self {
+        self.named_params = enabled;
+        self
+    }
+
+    /// Return `true` if the parameters can be sent and received as maps of named arguments.
+    pub fn has_named_params(&self) -> bool {
+        self.named_params
+    }
+
+    /// Set how the requests and notifications without parameters are sent. By default, they are
+    /// sent with an empty array, or with `nil` if
+    /// [`legacy_compatibility`](#method.legacy_compatibility) is enabled. Incoming messages with
+    /// either form are always accepted.
+    pub fn empty_params(&mut self, encoding: EmptyParams) -> &mut Self {
+        self.empty_params = Some(encoding);
+        self
+    }
+
+    /// Return how the requests and notifications without parameters are sent.
+    pub fn get_empty_params(&self) -> EmptyParams {
+        match self.empty_params {
+            Some(encoding) => encoding,
+            None if self.legacy_compatibility => EmptyParams::Nil,
+            None => EmptyParams::Array,
+        }
+    }
+
+    /// If `max` is not `None`, incoming messages larger than `max` bytes are rejected: the
+    /// connection is closed with an `InvalidData` error as soon as a message is known to be too
+    /// large, usually from the lengths in its first bytes, without buffering it. This protects
+    /// against peers sending huge or malicious messages. By default, messages of any size are
+    /// accepted.
+    pub fn max_message_size(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_message_size = max;
+        self
+    }
+
+    /// Return the size above which incoming messages are rejected.
+    pub fn get_max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Set the maximum number of arrays and maps a value of an incoming message can be nested
+    /// in. Values are decoded recursively, so this bounds the stack used by the decoder: messages
+    /// nested more deeply are skipped. The default depth is 64.
+    pub fn max_nesting_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_nesting_depth = depth;
+        self
+    }
+
+    /// Return the maximum number of arrays and maps a value can be nested in.
+    pub fn get_max_nesting_depth(&self) -> usize {
+        self.max_nesting_depth
+    }
+
+    /// If `max` is not `None`, incoming messages that hold more than `max` values, counting
+    /// the items of the arrays and the keys and values of the maps, are skipped. By default,
+    /// messages can hold any number of values.
+    pub fn max_elements(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_elements = max;
+        self
+    }
+
+    /// Return the maximum number of values an incoming message can hold.
+    pub fn get_max_elements(&self) -> Option<usize> {
+        self.max_elements
+    }
+
+    /// If `size` is not `None`, each connection encodes its outgoing messages one after the other
+    /// into a buffer of `size` bytes allocated ahead, and only allocates another one when it is
+    /// nearly full. A buffer whose messages have all been written out is reused. This saves an
+    /// allocation per message on connections that send many small messages. By default, the
+    /// buffer of each message is allocated when it is encoded.
+    pub fn encode_buffer(&mut self, size: Option<usize>) -> &mut Self {
+        self.encode_buffer = size;
+        self
+    }
+
+    /// Return the size of the buffers outgoing messages are encoded into.
+    pub fn get_encode_buffer(&self) -> Option<usize> {
+        self.encode_buffer
+    }
+
+    /// If `max` is not `None`, the space reserved in the receive buffer before each read follows
+    /// the size of the incoming messages, up to `max` bytes: the space left for the message being
+    /// received when its first bytes give its size, and the average size of the recent messages
+    /// otherwise. Large messages are then received in fewer reads, without growing the buffer
+    /// again and again. By default, 8KB are reserved before each read.
+    pub fn adaptive_read_buffer(&mut self, max: Option<usize>) -> &mut Self {
+        self.adaptive_read_buffer = max;
+        self
+    }
+
+    /// Return the maximum space reserved in the receive buffer before a read.
+    pub fn get_adaptive_read_buffer(&self) -> Option<usize> {
+        self.adaptive_read_buffer
+    }
+
+    /// If `threshold` is not `None`, the messages at least `threshold` bytes long are compressed
+    /// with LZ4, unless that does not make them smaller. Compression is negotiated: the `lz4`
+    /// feature is advertised in the [`Hello`](#method.hello) sent to the remote endpoint (the
+    /// default `Hello` is sent if none is set), and messages are only compressed once the remote
+    /// endpoint advertised it too. Both endpoints must enable compression, and there is no
+    /// benefit for small messages or already compressed data. By default, messages are not
+    /// compressed. This option is only available with the `compression` feature.
+    #[cfg(feature = "compression")]
+    pub fn compression(&mut self, threshold: Option<usize>) -> &mut Self {
+        self.compression = threshold;
+        self
+    }
+
+    /// Return the size above which messages are compressed.
+    #[cfg(feature = "compression")]
+    pub fn get_compression(&self) -> Option<usize> {
+        self.compression
+    }
+
+    /// If `max` is not `None`, the connection is closed as soon as the WebSocket peer announces a
+    /// frame larger than `max` bytes, before the frame is buffered. By default, the limit is the
+    /// [`max_message_size`](#method.max_message_size), and frames of any size are accepted if
+    /// neither is set. This option is only available with the `websocket` feature.
+    #[cfg(feature = "websocket")]
+    pub fn websocket_max_frame_size(&mut self, max: Option<usize>) -> &mut Self {
+        self.websocket_max_frame_size = max;
+        self
+    }
+
+    /// Return the size above which incoming WebSocket frames are rejected.
+    #[cfg(feature = "websocket")]
+    pub fn get_websocket_max_frame_size(&self) -> Option<usize> {
+        self.websocket_max_frame_size.or(self.max_message_size)
+    }
+
+    /// If `registry` is not `None`, the extension values of the types it knows are decoded in the
+    /// parameters and results received (see [`ExtRegistry`](struct.ExtRegistry.html)). By default,
+    /// all the extension values are passed as `Value::Ext`. This option is only available with the
+    /// `serde-params` feature.
+    #[cfg(feature = "serde-params")]
+    pub fn ext_registry(&mut self, registry: Option<ExtRegistry>) -> &mut Self {
+        self.ext_registry = registry;
+        self
+    }
+
+    /// Return the extension types decoded in the messages received.
+    #[cfg(feature = "serde-params")]
+    pub fn get_ext_registry(&self) -> Option<&ExtRegistry> {
+        self.ext_registry.as_ref()
+    }
+
+    /// If `log` is not `None`, it is called with an [`AuditRecord`](struct.AuditRecord.html)
+    /// each time a request is answered. By default, no audit trail is kept.
+    pub fn audit_log(&mut self, log: Option<Arc<AuditLog>>) -> &mut Self {
+        self.audit_log = log.map(AuditHook);
+        self
+    }
+
+    /// Return the hook that records the requests that have been answered.
+    pub fn get_audit_log(&self) -> Option<Arc<AuditLog>> {
+        self.audit_log.as_ref().map(|hook| Arc::clone(&hook.0))
+    }
+
+    /// If `authenticator` is not `None`, the first request of each connection is passed to it
+    /// instead of the service, and the connection is closed unless it is accepted (see
+    /// [`Authenticator`](trait.Authenticator.html)). By default, connections are not
+    /// authenticated.
+    pub fn authenticator(&mut self, authenticator: Option<Arc<Authenticator>>) -> &mut Self {
+        self.authenticator = authenticator.map(AuthHook);
+        self
+    }
+
+    /// Return the hook that authenticates the connections.
+    pub fn get_authenticator(&self) -> Option<Arc<Authenticator>> {
+        self.authenticator.as_ref().map(|hook| Arc::clone(&hook.0))
+    }
+
+    /// If `metrics` is not `None`, it is told about the connections, the requests and
+    /// notifications they receive, and the bytes they read and write. By default, nothing is
+    /// reported.
+    pub fn metrics(&mut self, metrics: Option<Arc<Metrics>>) -> &mut Self {
+        self.metrics = metrics.map(MetricsHook);
+        self
+    }
+
+    /// Return the hooks the connections report to.
+    pub fn get_metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.as_ref().map(|hook| Arc::clone(&hook.0))
+    }
+
+    /// If `handler` is not `None`, it is called with the [anomalies](enum.Anomaly.html) detected
+    /// by the client of the connection, such as responses to requests that are not in flight. By
+    /// default, anomalies are only logged.
+    pub fn anomaly_handler(&mut self, handler: Option<Arc<AnomalyHandler>>) -> &mut Self {
+        self.anomaly_handler = handler.map(AnomalyHook);
+        self
+    }
+
+    /// Return the hook the anomalies are reported to.
+    pub fn get_anomaly_handler(&self) -> Option<Arc<AnomalyHandler>> {
+        self.anomaly_handler.as_ref().map(|hook| Arc::clone(&hook.0))
+    }
+
+    /// If `dump` is not `None`, every message sent or received is reported to it, while it is
+    /// enabled (see [`ProtocolDump`](struct.ProtocolDump.html)). By default, messages are not
+    /// dumped.
+    pub fn dump(&mut self, dump: Option<ProtocolDump>) -> &mut Self {
+        self.dump = dump;
+        self
+    }
+
+    /// Return the dump the messages are reported to.
+    pub fn get_dump(&self) -> Option<&ProtocolDump> {
+        self.dump.as_ref()
+    }
+
+    /// Set the functions that hide the sensitive parameters of requests and notifications from
+    /// the logs. By default, parameters are logged as they are.
+    pub fn redactions(&mut self, redactions: Redactions) -> &mut Self {
+        self.redactions = redactions;
+        self
+    }
+
+    /// Return the functions that hide the sensitive parameters from the logs.
+    pub fn get_redactions(&self) -> &Redactions {
+        &self.redactions
+    }
+
+    /// Close the connection when no message has been received for `timeout`, and no request is
+    /// in flight. This gets rid of the connections of peers that crashed, as long as the peers
+    /// that are alive send [pings](#method.keepalive_interval) or requests often enough. By
+    /// default, connections are never closed for being idle. The endpoint must run on a reactor,
+    /// or use a manual [clock](#method.clock), for the timeout to apply.
+    pub fn idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Return how long a connection can be idle before it is closed.
+    pub fn get_idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Send a `"$/ping"` notification without parameters to the remote endpoint every
+    /// `interval`, so that it does not consider the connection idle, and so that a connection
+    /// to a peer that disappeared eventually fails instead of staying half-open. Endpoints of
+    /// this crate ignore these notifications, but other implementations receive them as regular
+    /// notifications. By default, no ping is sent. The endpoint must run on a reactor, or use a
+    /// manual [clock](#method.clock), for the pings to be sent.
+    pub fn keepalive_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// Return how often pings are sent to the remote endpoint.
+    pub fn get_keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval
+    }
+
+    /// Answer the `"$/ping"` requests, so that load balancers and monitors can check that the
+    /// endpoint is alive (see [`Client::ping`](struct.Client.html#method.ping)). They are
+    /// answered by the endpoint itself, without going through the service nor the
+    /// authentication, with `"ok"`, or with a `"server is draining"` error once the server is
+    /// draining. If disabled, they are answered with an "unsupported feature" error. Enabled by
+    /// default.
+    pub fn health_check(&mut self, enabled: bool) -> &mut Self {
+        self.health_check = enabled;
+        self
+    }
+
+    /// Return `true` if the `"$/ping"` requests are answered.
+    pub fn has_health_check(&self) -> bool {
+        self.health_check
+    }
+
+    /// Set how long the requests to the given method can be handled before they time out. It
+    /// overrides [`Limits::request_timeout`](struct.Limits.html#method.request_timeout) for this
+    /// method. If `timeout` is `None`, the method uses the timeout of the limits again.
+    pub fn method_timeout(&mut self, method: &str, timeout: Option<Duration>) -> &mut Self {
+        match timeout {
+            Some(timeout) => {
+                let _ = self.method_timeouts.insert(method.to_owned(), timeout);
+            }
+            None => {
+                let _ = self.method_timeouts.remove(method);
+            }
+        }
+        self
+    }
+
+    /// Return the timeout set for the given method, if any.
+    pub fn get_method_timeout(&self, method: &str) -> Option<Duration> {
+        self.method_timeouts.get(method).cloned()
+    }
+
+    /// Set the clock used to time out requests and idle connections, to send pings, and to give
+    /// up on the requests in flight of the connections being closed. With a
+    /// [manual clock](struct.Clock.html#method.manual), time only passes when the clock is
+    /// advanced, and no reactor is needed. By default, the system clock is used.
+    pub fn clock(&mut self, clock: Clock) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Return the clock used by the timers of the endpoint.
+    pub fn get_clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Limit the number of bytes read from the connection, in bytes per second, so that a peer
+    /// transferring bulk data does not take all the bandwidth of the host. Reads are allowed to
+    /// burst up to one second worth of bytes. By default, reads are not limited. The endpoint
+    /// must run on a reactor for the limit to apply.
+    pub fn read_rate(&mut self, rate: Option<u32>) -> &mut Self {
+        self.read_rate = rate;
+        self
+    }
+
+    /// Return the maximum number of bytes read from the connection per second.
+    pub fn get_read_rate(&self) -> Option<u32> {
+        self.read_rate
+    }
+
+    /// Like [`read_rate`](#method.read_rate), for the bytes written to the connection. Once the
+    /// limit is reached, the messages are queued, and the client stops sending requests until
+    /// the queue is drained.
+    pub fn write_rate(&mut self, rate: Option<u32>) -> &mut Self {
+        self.write_rate = rate;
+        self
+    }
+
+    /// Return the maximum number of bytes written to the connection per second.
+    pub fn get_write_rate(&self) -> Option<u32> {
+        self.write_rate
+    }
+
+    /// Set the largest id given to the requests sent by the client. Ids start at 1, wrap around
+    /// to 0 after `max`, and skip the ids of the requests still in flight, so at most `max + 1`
+    /// requests can be in flight: the requests sent beyond that fail immediately with an error.
+    /// This is useful for peers that store ids in a smaller integer type. By default, all the
+    /// `u32` ids are used.
+    pub fn max_request_id(&mut self, max: u32) -> &mut Self {
+        self.max_request_id = max;
+        self
+    }
+
+    /// Return the largest id given to the requests sent by the client.
+    pub fn get_max_request_id(&self) -> u32 {
+        self.max_request_id
+    }
+
+    /// If `enabled` is `true`, the futures returned by the service are spawned on the reactor
+    /// instead of being polled by the connection, and their results are sent back to the
+    /// connection once they complete. A handler that does a lot of work when it is polled then
+    /// does not delay the reading of the following messages and the sending of the other
+    /// responses. This costs a task and a channel per message, and requires the endpoint to run
+    /// on a reactor: it is ignored otherwise. By default, the handlers are polled inline.
+    ///
+    /// The handlers still run on the thread of the reactor, because the service futures do not
+    /// have to be `Send`. Blocking work should be moved to a thread pool by the service itself,
+    /// with `futures-cpupool` for instance.
+    pub fn spawn_handlers(&mut self, enabled: bool) -> &mut Self {
+        self.spawn_handlers = enabled;
+        self
+    }
+
+    /// Return `true` if the handlers are spawned on the reactor.
+    pub fn has_spawned_handlers(&self) -> bool {
+        self.spawn_handlers
+    }
+
+    /// Set what happens when the remote endpoint sends a request with the id of a request that
+    /// has not been answered yet. Handling both would send two responses with the same id, that
+    /// the remote endpoint could not tell apart. By default, the second request is rejected.
+    pub fn duplicate_ids(&mut self, policy: DuplicateIdPolicy) -> &mut Self {
+        self.duplicate_ids = policy;
+        self
+    }
+
+    /// Return what happens when a request has the id of a request that has not been answered yet.
+    pub fn get_duplicate_ids(&self) -> DuplicateIdPolicy {
+        self.duplicate_ids
+    }
+
+    /// Set what happens when a message cannot be decoded: it is not a valid `MessagePack-RPC`
+    /// message, its type is unknown, or it exceeds the limits on the nesting depth and the number
+    /// of elements. Data that is not even valid msgpack always closes the connection, since the
+    /// following messages cannot be found. By default, invalid messages are logged and ignored.
+    pub fn invalid_messages(&mut self, policy: ProtocolPolicy) -> &mut Self {
+        self.invalid_messages = policy;
+        self
+    }
+
+    /// Return what happens when a message cannot be decoded.
+    pub fn get_invalid_messages(&self) -> ProtocolPolicy {
+        self.invalid_messages
+    }
+
+    /// Set what happens when the remote endpoint sends a response that matches no request: its id
+    /// is not the id of a request in flight (see
+    /// [`Anomaly::UnknownResponse`](enum.Anomaly.html#variant.UnknownResponse)), or this endpoint
+    /// does not send requests. The response is reported as an anomaly in any case. By default,
+    /// the connection is closed, since the responses that follow cannot be trusted either.
+    pub fn unexpected_responses(&mut self, policy: ProtocolPolicy) -> &mut Self {
+        self.unexpected_responses = policy;
+        self
+    }
+
+    /// Return what happens when a response matches no request.
+    pub fn get_unexpected_responses(&self) -> ProtocolPolicy {
+        self.unexpected_responses
+    }
+}
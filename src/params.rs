@@ -0,0 +1,305 @@
+use std::{cmp, fmt};
+
+use rmpv::Value;
+#[cfg(feature = "serde-params")]
+use rmpv::ext;
+#[cfg(feature = "serde-params")]
+use serde::de::DeserializeOwned;
+
+use rpc_error::RpcError;
+
+/// The type of value expected for a parameter (see [`ParamsSpec`](struct.ParamsSpec.html)).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    /// Any value.
+    Any,
+    /// `nil`.
+    Nil,
+    /// A boolean.
+    Boolean,
+    /// An integer.
+    Integer,
+    /// A float, or an integer: many encoders write round floats as integers.
+    Float,
+    /// A string.
+    String,
+    /// A binary blob.
+    Binary,
+    /// An array.
+    Array,
+    /// A map.
+    Map,
+    /// An extension value.
+    Ext,
+}
+
+impl ParamType {
+    /// Return `true` if `value` has this type.
+    pub fn matches(&self, value: &Value) -> bool {
+        match (*self, value) {
+            (ParamType::Any, _)
+            | (ParamType::Nil, &Value::Nil)
+            | (ParamType::Boolean, &Value::Boolean(_))
+            | (ParamType::Integer, &Value::Integer(_))
+            | (ParamType::Float, &Value::F32(_))
+            | (ParamType::Float, &Value::F64(_))
+            | (ParamType::Float, &Value::Integer(_))
+            | (ParamType::String, &Value::String(_))
+            | (ParamType::Binary, &Value::Binary(_))
+            | (ParamType::Array, &Value::Array(_))
+            | (ParamType::Map, &Value::Map(_))
+            | (ParamType::Ext, &Value::Ext(..)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ParamType::Any => "any value",
+            ParamType::Nil => "nil",
+            ParamType::Boolean => "a boolean",
+            ParamType::Integer => "an integer",
+            ParamType::Float => "a number",
+            ParamType::String => "a string",
+            ParamType::Binary => "a binary",
+            ParamType::Array => "an array",
+            ParamType::Map => "a map",
+            ParamType::Ext => "an extension value",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Return the type of `value`, to describe it in errors.
+fn type_of(value: &Value) -> ParamType {
+    match *value {
+        Value::Nil => ParamType::Nil,
+        Value::Boolean(_) => ParamType::Boolean,
+        Value::Integer(_) => ParamType::Integer,
+        Value::F32(_) | Value::F64(_) => ParamType::Float,
+        Value::String(_) => ParamType::String,
+        Value::Binary(_) => ParamType::Binary,
+        Value::Array(_) => ParamType::Array,
+        Value::Map(_) => ParamType::Map,
+        Value::Ext(..) => ParamType::Ext,
+    }
+}
+
+/// The parameters a method expects: their number, and the type of each of them. The optional
+/// parameters come after the required ones, and the calls can leave out any number of them,
+/// starting from the last.
+///
+/// Specifications can be attached to the methods of a [`MethodRouter`](struct.MethodRouter.html)
+/// (see [`MethodRouter::params`](struct.MethodRouter.html#method.params)), so that the handlers
+/// only get parameters of the expected types:
+///
+/// ```rust,ignore
+/// let mut spec = ParamsSpec::new();
+/// let _ = spec.required(ParamType::String).optional(ParamType::Integer);
+/// let _ = router.request("get", get).params("get", spec);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParamsSpec {
+    required: Vec<ParamType>,
+    optional: Vec<ParamType>,
+    extra: Option<ParamType>,
+}
+
+impl ParamsSpec {
+    /// Create a specification for methods that take no parameters.
+    pub fn new() -> Self {
+        ParamsSpec::default()
+    }
+
+    /// Add a required parameter, after the previous ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an optional parameter has already been added.
+    pub fn required(&mut self, param: ParamType) -> &mut Self {
+        assert!(
+            self.optional.is_empty(),
+            "required parameters must come before the optional ones"
+        );
+        self.required.push(param);
+        self
+    }
+
+    /// Add an optional parameter, after the previous ones.
+    pub fn optional(&mut self, param: ParamType) -> &mut Self {
+        self.optional.push(param);
+        self
+    }
+
+    /// If `param` is not `None`, accept any number of parameters of this type after the declared
+    /// ones, like the values of a variadic method. By default, extra parameters are rejected.
+    pub fn extra(&mut self, param: Option<ParamType>) -> &mut Self {
+        self.extra = param;
+        self
+    }
+
+    /// Check the parameters of a call. The error is an "invalid params"
+    /// [`RpcError`](struct.RpcError.html) that tells what is wrong, with the position of the
+    /// invalid parameter as data, if there is one.
+    pub fn check(&self, params: &[Value]) -> Result<(), RpcError> {
+        let min = self.required.len();
+        let max = min + self.optional.len();
+        if params.len() < min || (params.len() > max && self.extra.is_none()) {
+            let expected = if self.extra.is_some() {
+                format!("at least {} parameters", min)
+            } else if min == 1 && max == 1 {
+                "1 parameter".to_owned()
+            } else if min == max {
+                format!("{} parameters", min)
+            } else {
+                format!("{} to {} parameters", min, max)
+            };
+            let message = format!("expected {}, got {}", expected, params.len());
+            return Err(RpcError::invalid_params(&message));
+        }
+        let expected = self.required
+            .iter()
+            .chain(&self.optional)
+            .chain(self.extra.iter().cycle());
+        for (position, (param, value)) in expected.zip(params).enumerate() {
+            if !param.matches(value) {
+                let message = format!(
+                    "parameter {}: expected {}, got {}",
+                    position,
+                    param,
+                    type_of(value)
+                );
+                return Err(RpcError::invalid_params(&message).with_data(Value::from(position)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turn named arguments into positional parameters, in the order of `names`. The arguments that
+/// are left out become `nil`, except those after the last one given, which are left out too.
+/// Fail with an "invalid params" error if an argument is not in `names`.
+pub(crate) fn to_positional<S>(names: &[S], args: &[(Value, Value)]) -> Result<Vec<Value>, RpcError>
+where
+    S: AsRef<str>,
+{
+    let mut params = vec![Value::Nil; names.len()];
+    let mut len = 0;
+    for &(ref name, ref value) in args {
+        let index = name.as_str()
+            .and_then(|name| names.iter().position(|known| known.as_ref() == name));
+        match index {
+            Some(index) => {
+                params[index] = value.clone();
+                len = cmp::max(len, index + 1);
+            }
+            None => {
+                let message = format!("unknown parameter {}", name);
+                return Err(RpcError::invalid_params(&message));
+            }
+        }
+    }
+    params.truncate(len);
+    Ok(params)
+}
+
+/// Deserialize the parameters of a call into a `T`, typically a struct with one field per
+/// parameter. The parameters can be named arguments, sent as a single map, or positional
+/// parameters, that are named after `names` in order. Fail with an "invalid params"
+/// [`RpcError`](struct.RpcError.html) if they do not match `T`.
+///
+/// ```rust,ignore
+/// #[derive(Deserialize)]
+/// struct Resize {
+///     width: u32,
+///     height: u32,
+/// }
+///
+/// let _ = router.request("resize", |params| {
+///     let size: Resize = match from_params(&["width", "height"], params) {
+///         Ok(size) => size,
+///         Err(e) => return Box::new(future::ok(Err(Value::from(e)))),
+///     };
+///     // ...
+/// });
+/// ```
+#[cfg(feature = "serde-params")]
+pub fn from_params<T: DeserializeOwned>(names: &[&str], params: &[Value]) -> Result<T, RpcError> {
+    let args = match params.first() {
+        Some(&Value::Map(_)) if params.len() == 1 => params[0].clone(),
+        _ if params.len() > names.len() => {
+            let (max, len) = (names.len(), params.len());
+            let message = format!("expected at most {} parameters, got {}", max, len);
+            return Err(RpcError::invalid_params(&message));
+        }
+        _ => Value::Map(
+            names
+                .iter()
+                .zip(params)
+                .map(|(name, value)| (Value::from(*name), value.clone()))
+                .collect(),
+        ),
+    };
+    ext::from_value(args).map_err(|e| RpcError::invalid_params(&e.to_string()))
+}
+
+#[test]
+fn check_params() {
+    let mut spec = ParamsSpec::new();
+    let _ = spec.required(ParamType::String)
+        .optional(ParamType::Float)
+        .optional(ParamType::Any);
+    assert!(spec.check(&[Value::from("key")]).is_ok());
+    assert!(spec.check(&[Value::from("key"), Value::from(1), Value::Nil]).is_ok());
+    assert!(spec.check(&[Value::from("key"), Value::from(1.5)]).is_ok());
+
+    let error = spec.check(&[]).unwrap_err();
+    assert_eq!(error.code, RpcError::INVALID_PARAMS);
+    assert_eq!(error.message, "expected 1 to 3 parameters, got 0");
+    let error = spec.check(&[Value::from("key"), Value::from("1")]).unwrap_err();
+    assert_eq!(error.message, "parameter 1: expected a number, got a string");
+    assert_eq!(error.data, Some(Value::from(1)));
+    assert!(spec.check(&vec![Value::Nil; 4]).is_err());
+
+    // Variadic methods.
+    let mut spec = ParamsSpec::new();
+    let _ = spec.extra(Some(ParamType::Integer));
+    assert!(spec.check(&[]).is_ok());
+    assert!(spec.check(&[Value::from(1), Value::from(2), Value::from(3)]).is_ok());
+    let error = spec.check(&[Value::from(1), Value::Nil]).unwrap_err();
+    assert_eq!(error.message, "parameter 1: expected an integer, got nil");
+}
+
+#[test]
+fn named_params() {
+    let names = ["key", "value", "ttl"];
+    let args = vec![(Value::from("ttl"), Value::from(60)), (Value::from("key"), Value::from("a"))];
+    let params = to_positional(&names, &args).unwrap();
+    assert_eq!(params, vec![Value::from("a"), Value::Nil, Value::from(60)]);
+    let args = vec![(Value::from("key"), Value::from("a"))];
+    assert_eq!(to_positional(&names, &args).unwrap(), vec![Value::from("a")]);
+    let args = vec![(Value::from("expiry"), Value::from(60))];
+    let error = to_positional(&names, &args).unwrap_err();
+    assert_eq!(error.message, "unknown parameter \"expiry\"");
+}
+
+#[cfg(feature = "serde-params")]
+#[test]
+fn typed_params() {
+    use std::collections::HashMap;
+
+    let names = ["width", "height"];
+    let params = [Value::from(3), Value::from(4)];
+    let size: HashMap<String, u32> = from_params(&names, &params).unwrap();
+    assert_eq!((size["width"], size["height"]), (3, 4));
+    let args = Value::Map(vec![(Value::from("height"), Value::from(5))]);
+    let size: HashMap<String, u32> = from_params(&names, &[args]).unwrap();
+    assert_eq!(size.len(), 1);
+    assert_eq!(size["height"], 5);
+    let params = [Value::from(1), Value::from(2), Value::from(3)];
+    let error = from_params::<HashMap<String, u32>>(&names, &params).unwrap_err();
+    assert_eq!(error.message, "expected at most 2 parameters, got 3");
+    assert!(from_params::<HashMap<String, u32>>(&names, &[Value::from("3")]).is_err());
+}
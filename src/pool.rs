@@ -0,0 +1,316 @@
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::future::{Either, Loop};
+use rmpv::Value;
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::ClientError;
+use keepalive::PING_METHOD;
+use options::ProtocolOptions;
+use reconnect::{Backoff, ReconnectingClient};
+
+/// Defines how a [`ClientPool`](struct.ClientPool.html) picks the connection used for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balancing {
+    /// Use each connection in turn.
+    RoundRobin,
+    /// Use the connection with the fewest requests in flight.
+    LeastLoaded,
+}
+
+struct Member {
+    client: ReconnectingClient,
+    in_flight: Cell<usize>,
+    // When a request fails because the connection was lost, the connection is considered
+    // unhealthy and is not used until this instant, unless all the other connections are
+    // unhealthy too.
+    unhealthy_until: Cell<Option<Instant>>,
+    // Whether the connection is being probed, while it is unhealthy.
+    probing: Cell<bool>,
+}
+
+impl Member {
+    fn is_healthy(&self, now: Instant) -> bool {
+        match self.unhealthy_until.get() {
+            Some(instant) => instant <= now,
+            None => true,
+        }
+    }
+}
+
+/// Counts a request in flight on a connection of a pool, until it is dropped: when the request
+/// completes, or when its future is dropped before.
+struct InFlight {
+    members: Rc<Vec<Member>>,
+    idx: usize,
+}
+
+impl InFlight {
+    fn new(members: Rc<Vec<Member>>, idx: usize) -> Self {
+        {
+            let member = &members[idx];
+            member.in_flight.set(member.in_flight.get() + 1);
+        }
+        InFlight {
+            members: members,
+            idx: idx,
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let member = &self.members[self.idx];
+        member.in_flight.set(member.in_flight.get() - 1);
+    }
+}
+
+/// A pool of connections to the same `MessagePack-RPC` server. Requests and notifications are
+/// distributed across the connections.
+///
+/// Each connection is a [`ReconnectingClient`](struct.ReconnectingClient.html): connections are
+/// only established when they are first used, and re-established when they are lost.
+/// Connections on which a request failed are marked as unhealthy, and are avoided for a while,
+/// or until they answer a probe (see [`set_probe_interval`](#method.set_probe_interval)).
+/// For servers that run as several replicas, the connections are spread across the replicas (see
+/// [`with_addresses`](#method.with_addresses)).
+///
+/// `ClientPool` is cheap to clone: all the clones share the same connections.
+#[derive(Clone)]
+pub struct ClientPool {
+    members: Rc<Vec<Member>>,
+    next: Rc<Cell<usize>>,
+    balancing: Balancing,
+    unhealthy_delay: Duration,
+    probe_interval: Option<Duration>,
+    handle: Handle,
+}
+
+impl ClientPool {
+    /// Create a pool of `size` connections to the server at `address`. This does not connect
+    /// yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn new(address: SocketAddr, size: usize, handle: &Handle) -> Self {
+        ClientPool::with_addresses(&[address], size, handle)
+    }
+
+    /// Create a pool of `size` connections to a server that runs as several replicas, with the
+    /// given addresses. The `i`-th connection is established with the `i % addresses.len()`-th
+    /// address, and fails over to the following ones when it cannot be reached (see
+    /// [`ReconnectingClient::with_addresses`](struct.ReconnectingClient.html#method.with_addresses)).
+    /// This does not connect yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0, or if `addresses` is empty.
+    pub fn with_addresses(addresses: &[SocketAddr], size: usize, handle: &Handle) -> Self {
+        assert!(size > 0, "a client pool needs at least one connection");
+        assert!(!addresses.is_empty(), "a client pool needs at least one address");
+        let members = (0..size)
+            .map(|idx| {
+                let first = idx % addresses.len();
+                let mut rotated = addresses[first..].to_vec();
+                rotated.extend_from_slice(&addresses[..first]);
+                Member {
+                    client: ReconnectingClient::with_addresses(rotated, handle),
+                    in_flight: Cell::new(0),
+                    unhealthy_until: Cell::new(None),
+                    probing: Cell::new(false),
+                }
+            })
+            .collect();
+        ClientPool {
+            members: Rc::new(members),
+            next: Rc::new(Cell::new(0)),
+            balancing: Balancing::RoundRobin,
+            unhealthy_delay: Duration::from_secs(5),
+            probe_interval: None,
+            handle: handle.clone(),
+        }
+    }
+
+    /// Set how connections are picked. The default is `Balancing::RoundRobin`.
+    pub fn set_balancing(&mut self, balancing: Balancing) -> &mut Self {
+        self.balancing = balancing;
+        self
+    }
+
+    /// Set how long a connection on which a request failed is avoided. The default is 5 seconds.
+    pub fn set_unhealthy_delay(&mut self, delay: Duration) -> &mut Self {
+        self.unhealthy_delay = delay;
+        self
+    }
+
+    /// If `interval` is not `None`, the unhealthy connections are probed with a `"$/ping"`
+    /// request every `interval`, and are used again as soon as they answer it, even with an
+    /// error, instead of being avoided for the whole unhealthy delay. A probe that fails extends
+    /// the delay. By default, unhealthy connections are not probed.
+    pub fn set_probe_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    /// Set the backoff policy used by each connection to reconnect.
+    pub fn set_backoff(&mut self, backoff: Backoff) -> &mut Self {
+        for member in self.members.iter() {
+            let _ = member.client.clone().set_backoff(backoff.clone());
+        }
+        self
+    }
+
+    /// Set the options used for the connections.
+    pub fn set_protocol_options(&mut self, options: ProtocolOptions) -> &mut Self {
+        for member in self.members.iter() {
+            let _ = member.client.clone().set_protocol_options(options.clone());
+        }
+        self
+    }
+
+    /// Send a `MessagePack-RPC` request on one of the connections of the pool. The future fails
+    /// like [`ReconnectingClient::request`](struct.ReconnectingClient.html#method.request).
+    pub fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ClientError>> {
+        let idx = self.pick();
+        let in_flight = InFlight::new(Rc::clone(&self.members), idx);
+        let pool = self.clone();
+        let response = self.members[idx]
+            .client
+            .request(method, params)
+            .then(move |result| {
+                drop(in_flight);
+                match result {
+                    Ok(_) => pool.members[idx].unhealthy_until.set(None),
+                    Err(ref e) => {
+                        warn!("Request failed on connection {} of the pool: {}", idx, e);
+                        pool.mark_unhealthy(idx);
+                    }
+                }
+                result
+            });
+        Box::new(response)
+    }
+
+    /// Send a `MessagePack-RPC` notification on one of the connections of the pool.
+    pub fn notify(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = (), Error = ClientError>> {
+        let idx = self.pick();
+        self.members[idx].client.notify(method, params)
+    }
+
+    /// Avoid the `idx`-th connection for the unhealthy delay, and start probing it if probes are
+    /// enabled.
+    fn mark_unhealthy(&self, idx: usize) {
+        let member = &self.members[idx];
+        member
+            .unhealthy_until
+            .set(Some(Instant::now() + self.unhealthy_delay));
+        let interval = match self.probe_interval {
+            Some(interval) if !member.probing.get() => interval,
+            _ => return,
+        };
+        member.probing.set(true);
+        let pool = self.clone();
+        let probes = future::loop_fn((), move |()| {
+            let pool = pool.clone();
+            future::result(Timeout::new(interval, &pool.handle))
+                .flatten()
+                .map_err(|e| error!("Failed to create a probe timer: {}", e))
+                .and_then(move |()| {
+                    if pool.members[idx].unhealthy_until.get().is_none() {
+                        // A request succeeded in the meantime.
+                        return Either::A(future::ok(Loop::Break(())));
+                    }
+                    let probe = pool.members[idx].client.request(PING_METHOD, &[]);
+                    Either::B(probe.then(move |result| {
+                        let member = &pool.members[idx];
+                        if result.is_ok() {
+                            debug!("Connection {} of the pool answered a probe", idx);
+                            member.unhealthy_until.set(None);
+                            return Ok(Loop::Break(()));
+                        }
+                        let retry = Instant::now() + pool.unhealthy_delay;
+                        member.unhealthy_until.set(Some(retry));
+                        Ok(Loop::Continue(()))
+                    }))
+                })
+        });
+        let pool = self.clone();
+        self.handle.spawn(probes.then(move |_| {
+            pool.members[idx].probing.set(false);
+            Ok(())
+        }));
+    }
+
+    fn pick(&self) -> usize {
+        let now = Instant::now();
+        let len = self.members.len();
+        // Only consider healthy connections, unless there is none.
+        let mut candidates: Vec<usize> = (0..len)
+            .filter(|idx| self.members[*idx].is_healthy(now))
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..len).collect();
+        }
+
+        match self.balancing {
+            Balancing::RoundRobin => {
+                let next = self.next.get();
+                self.next.set(next.wrapping_add(1));
+                candidates[next % candidates.len()]
+            }
+            Balancing::LeastLoaded => *candidates
+                .iter()
+                .min_by_key(|idx| self.members[**idx].in_flight.get())
+                .unwrap(),
+        }
+    }
+}
+
+#[test]
+fn health() {
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+    use server::ServerBuilder;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut router = MethodRouter::new();
+    let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+    let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+        .spawn(router, &handle)
+        .unwrap();
+    let mut pool = ClientPool::new(server.local_addr().unwrap(), 1, &handle);
+
+    // A request whose future is dropped is no longer in flight.
+    let request = pool.request("echo", &[Value::from(1)]);
+    assert_eq!(pool.members[0].in_flight.get(), 1);
+    drop(request);
+    assert_eq!(pool.members[0].in_flight.get(), 0);
+
+    // An unhealthy connection is used again as soon as it answers a probe.
+    let _ = pool.set_unhealthy_delay(Duration::from_secs(3600))
+        .set_probe_interval(Some(Duration::from_millis(10)));
+    pool.mark_unhealthy(0);
+    assert!(!pool.members[0].is_healthy(Instant::now()));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !pool.members[0].is_healthy(Instant::now()) && Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(10)));
+    }
+    assert!(pool.members[0].is_healthy(Instant::now()));
+    assert!(!pool.members[0].probing.get());
+    let response = pool.request("echo", &[Value::from(2)]);
+    assert_eq!(core.run(response).unwrap(), Ok(Value::from(2)));
+}
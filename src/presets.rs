@@ -0,0 +1,211 @@
+//! Ready-made services, to try the crate, or to have a non-trivial server to test and benchmark
+//! clients against. They are only built with the `presets` feature.
+//!
+//! Both services are [`ServiceBuilder`](../trait.ServiceBuilder.html)s whose connections share
+//! the same state, and answer invalid requests with an [`RpcError`](../struct.RpcError.html).
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, FutureResult};
+use rmpv::Value;
+
+use endpoint::{Client, Service, ServiceBuilder};
+use rpc_error::RpcError;
+
+type PresetFuture = FutureResult<Result<Value, RpcError>, io::Error>;
+
+/// A calculator holding one integer, the result.
+///
+/// | Method | Parameters | Result |
+/// |--------|------------|--------|
+/// | `add` or `+` | any number of integers | adds them to the result, and returns it |
+/// | `sub` or `-` | any number of integers | subtracts them from the result, and returns it |
+/// | `res` or `=` | none | the result |
+/// | `clear` | none | sets the result to 0, and returns it |
+///
+/// The notifications are ignored.
+#[derive(Clone, Default)]
+pub struct Calculator {
+    value: Arc<Mutex<i64>>,
+}
+
+impl Calculator {
+    /// Create a calculator whose result is 0.
+    pub fn new() -> Self {
+        Calculator::default()
+    }
+
+    fn sum(params: &[Value]) -> Result<i64, RpcError> {
+        let mut sum: i64 = 0;
+        for param in params {
+            let int = match param.as_i64() {
+                Some(int) => int,
+                None => return Err(RpcError::invalid_params("expected integers")),
+            };
+            sum = match sum.checked_add(int) {
+                Some(sum) => sum,
+                None => return Err(RpcError::invalid_params("integer overflow")),
+            };
+        }
+        Ok(sum)
+    }
+
+    fn apply(&self, method: &str, params: &[Value]) -> Result<i64, RpcError> {
+        let mut value = self.value.lock().unwrap();
+        let result = match method {
+            "add" | "+" => value.checked_add(Calculator::sum(params)?),
+            "sub" | "-" => value.checked_sub(Calculator::sum(params)?),
+            "res" | "=" => Some(*value),
+            "clear" => Some(0),
+            method => return Err(RpcError::method_not_found(method)),
+        };
+        match result {
+            Some(result) => {
+                *value = result;
+                Ok(result)
+            }
+            None => Err(RpcError::invalid_params("integer overflow")),
+        }
+    }
+}
+
+impl Service for Calculator {
+    type T = Value;
+    type E = RpcError;
+    type Error = io::Error;
+    type RequestFuture = PresetFuture;
+    type NotificationFuture = FutureResult<(), io::Error>;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
+        future::ok(self.apply(method, params).map(Value::from))
+    }
+
+    fn handle_notification(&mut self, _: &str, _: &[Value]) -> Self::NotificationFuture {
+        future::ok(())
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        let methods = ["+", "-", "=", "add", "clear", "res", "sub"];
+        Some(methods.iter().map(|method| method.to_string()).collect())
+    }
+}
+
+impl ServiceBuilder for Calculator {
+    type Service = Calculator;
+
+    fn build(&self, _client: Client) -> Self::Service {
+        self.clone()
+    }
+}
+
+/// An in-memory map from strings to values.
+///
+/// | Method | Parameters | Result |
+/// |--------|------------|--------|
+/// | `get` | key | the value of the key, or `nil` |
+/// | `set` | key, value | sets the value of the key, and returns the previous one, or `nil` |
+/// | `delete` | key | removes the key, and returns its value, or `nil` |
+/// | `keys` | none | the keys, sorted |
+///
+/// `set` and `delete` can also be sent as notifications.
+#[derive(Clone, Default)]
+pub struct KeyValueStore {
+    values: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl KeyValueStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        KeyValueStore::default()
+    }
+
+    fn apply(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        let mut values = self.values.lock().unwrap();
+        let key = params.get(0).and_then(Value::as_str);
+        let previous = match (method, key, params.len()) {
+            ("get", Some(key), 1) => values.get(key).cloned(),
+            ("set", Some(key), 2) => values.insert(key.to_owned(), params[1].clone()),
+            ("delete", Some(key), 1) => values.remove(key),
+            ("keys", None, 0) => {
+                let mut keys = values.keys().cloned().collect::<Vec<String>>();
+                keys.sort();
+                return Ok(Value::from(keys.into_iter().map(Value::from).collect::<Vec<_>>()));
+            }
+            ("get", _, _) | ("delete", _, _) => {
+                return Err(RpcError::invalid_params("expected a string key"))
+            }
+            ("set", _, _) => {
+                return Err(RpcError::invalid_params("expected a string key and a value"))
+            }
+            ("keys", _, _) => return Err(RpcError::invalid_params("expected no parameters")),
+            (method, _, _) => return Err(RpcError::method_not_found(method)),
+        };
+        Ok(previous.unwrap_or(Value::Nil))
+    }
+}
+
+impl Service for KeyValueStore {
+    type T = Value;
+    type E = RpcError;
+    type Error = io::Error;
+    type RequestFuture = PresetFuture;
+    type NotificationFuture = FutureResult<(), io::Error>;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
+        future::ok(self.apply(method, params))
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &[Value]) -> Self::NotificationFuture {
+        if method == "set" || method == "delete" {
+            if let Err(e) = self.apply(method, params) {
+                warn!("Invalid {} notification: {}", method, e);
+            }
+        }
+        future::ok(())
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        let methods = ["delete", "get", "keys", "set"];
+        Some(methods.iter().map(|method| method.to_string()).collect())
+    }
+}
+
+impl ServiceBuilder for KeyValueStore {
+    type Service = KeyValueStore;
+
+    fn build(&self, _client: Client) -> Self::Service {
+        self.clone()
+    }
+}
+
+#[test]
+fn calculator() {
+    use mock::TestClient;
+
+    let mut client = TestClient::new(Calculator::new());
+    assert_eq!(client.request("add", &[1.into(), 2.into(), 3.into()]), Ok(6.into()));
+    assert_eq!(client.request("-", &[10.into()]), Ok((-4).into()));
+    assert_eq!(client.request("=", &[]), Ok((-4).into()));
+    assert_eq!(client.request("clear", &[]), Ok(0.into()));
+    let error = client.request("add", &["one".into()]).unwrap_err();
+    assert_eq!(RpcError::from_value(&error).unwrap().code, RpcError::INVALID_PARAMS);
+    let error = client.request("mul", &[]).unwrap_err();
+    assert_eq!(RpcError::from_value(&error).unwrap().code, RpcError::METHOD_NOT_FOUND);
+}
+
+#[test]
+fn key_value_store() {
+    use mock::TestClient;
+
+    let mut client = TestClient::new(KeyValueStore::new());
+    assert_eq!(client.request("get", &["a".into()]), Ok(Value::Nil));
+    assert_eq!(client.request("set", &["a".into(), 1.into()]), Ok(Value::Nil));
+    assert_eq!(client.request("set", &["a".into(), 2.into()]), Ok(1.into()));
+    client.notify("set", &["b".into(), 3.into()]);
+    assert_eq!(client.request("keys", &[]), Ok(Value::from(vec![Value::from("a"), "b".into()])));
+    assert_eq!(client.request("delete", &["a".into()]), Ok(2.into()));
+    assert_eq!(client.request("get", &["a".into()]), Ok(Value::Nil));
+    let error = client.request("get", &[1.into()]).unwrap_err();
+    assert_eq!(RpcError::from_value(&error).unwrap().code, RpcError::INVALID_PARAMS);
+}
@@ -0,0 +1,144 @@
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::FuturesUnordered;
+
+/// Maximum number of low priority requests answered each time an endpoint is polled, while
+/// requests of higher priority are in flight.
+const LOW_PRIORITY_BURST: usize = 4;
+
+/// Priority of the requests for a method (see
+/// [`Service::priority`](trait.Service.html#method.priority)).
+///
+/// When several requests are in flight on a connection, the handlers of the requests with a
+/// higher priority are polled first, and their responses are sent first. While requests of a
+/// higher priority are in flight, only a few low priority requests are answered at a time, so
+/// that bulk requests do not starve control requests. Priorities do not reorder the responses
+/// when they are sent in order (see
+/// [`ProtocolOptions::ordered_responses`](struct.ProtocolOptions.html#method.ordered_responses)).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Bulk requests, answered after the others.
+    Low,
+    /// The default priority.
+    Normal,
+    /// Requests answered before the others.
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Sets of futures polled by decreasing priority.
+pub(crate) struct PrioritizedTasks<F> {
+    high: FuturesUnordered<F>,
+    normal: FuturesUnordered<F>,
+    low: FuturesUnordered<F>,
+    // Low priority futures that can still complete in the current round.
+    low_budget: usize,
+}
+
+impl<F: Future> PrioritizedTasks<F> {
+    pub(crate) fn new() -> Self {
+        PrioritizedTasks {
+            high: FuturesUnordered::new(),
+            normal: FuturesUnordered::new(),
+            low: FuturesUnordered::new(),
+            low_budget: LOW_PRIORITY_BURST,
+        }
+    }
+
+    pub(crate) fn push(&mut self, task: F, priority: Priority) {
+        match priority {
+            Priority::High => self.high.push(task),
+            Priority::Normal => self.normal.push(task),
+            Priority::Low => self.low.push(task),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.high.len() + self.normal.len() + self.low.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Start a new round: the low priority futures get a new budget.
+    pub(crate) fn start_round(&mut self) {
+        self.low_budget = LOW_PRIORITY_BURST;
+    }
+
+    /// Return `true` if low priority futures may have been left out of the current round, in
+    /// which case another round should be started soon.
+    pub(crate) fn is_throttled(&self) -> bool {
+        self.low_budget == 0 && !self.low.is_empty()
+    }
+
+    /// Poll the futures that have been notified, by decreasing priority, and return the outcome
+    /// of the first one that completes. Like `FuturesUnordered`, it returns `Ready(None)` if
+    /// there are no futures, and can be polled again after an error.
+    pub(crate) fn poll(&mut self) -> Poll<Option<F::Item>, F::Error> {
+        let mut pending = false;
+        for set in &mut [&mut self.high, &mut self.normal] {
+            match set.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => {}
+                Async::NotReady => pending = true,
+            }
+        }
+        if pending && self.low_budget == 0 {
+            return Ok(Async::NotReady);
+        }
+        match self.low.poll() {
+            Ok(Async::Ready(Some(item))) => {
+                if pending {
+                    self.low_budget -= 1;
+                }
+                Ok(Async::Ready(Some(item)))
+            }
+            Ok(Async::Ready(None)) if pending => Ok(Async::NotReady),
+            result => result,
+        }
+    }
+}
+
+#[test]
+fn prioritized_tasks() {
+    use futures::future::{self, FutureResult};
+    use futures::sync::oneshot;
+
+    let _ = future::lazy(|| {
+        let mut tasks = PrioritizedTasks::<FutureResult<u32, ()>>::new();
+        for i in 0..8 {
+            tasks.push(future::ok(i), Priority::Low);
+        }
+        tasks.push(future::ok(100), Priority::Normal);
+        tasks.push(future::ok(200), Priority::High);
+        assert_eq!(tasks.poll(), Ok(Async::Ready(Some(200))));
+        assert_eq!(tasks.poll(), Ok(Async::Ready(Some(100))));
+        // Nothing of a higher priority is in flight anymore: the low priority tasks all complete.
+        for i in 0..8 {
+            assert_eq!(tasks.poll(), Ok(Async::Ready(Some(i))));
+        }
+        assert_eq!(tasks.poll(), Ok(Async::Ready(None)));
+
+        // While a request of a higher priority is in flight, only a few low priority tasks
+        // complete in each round.
+        let mut tasks = PrioritizedTasks::new();
+        let (_tx, rx) = oneshot::channel::<u32>();
+        tasks.push(future::Either::A(rx.map_err(|_| ())), Priority::High);
+        for i in 0..8 {
+            tasks.push(future::Either::B(future::ok(i)), Priority::Low);
+        }
+        for i in 0..LOW_PRIORITY_BURST {
+            assert_eq!(tasks.poll(), Ok(Async::Ready(Some(i as u32))));
+        }
+        assert_eq!(tasks.poll(), Ok(Async::NotReady));
+        assert!(tasks.is_throttled());
+        tasks.start_round();
+        assert_eq!(tasks.poll(), Ok(Async::Ready(Some(LOW_PRIORITY_BURST as u32))));
+        future::ok::<(), ()>(())
+    }).wait();
+}
@@ -0,0 +1,414 @@
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use futures::future::{Either, Loop};
+use rmpv::Value;
+use tokio_core::reactor::{Handle, Timeout};
+
+use audit::digest_value;
+use endpoint::{BoxedService, Client, ServiceBuilder};
+use message::Notification;
+use pool::ClientPool;
+use reconnect::ReconnectingClient;
+use rewrite::MethodRewrites;
+
+/// A client a [`ProxyService`](struct.ProxyService.html) can forward requests and notifications
+/// to. It is implemented by all the clients of this crate.
+pub trait Upstream {
+    /// Send a request. The future fails if the response cannot be received.
+    fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ()>>;
+
+    /// Send a notification.
+    fn notify(&self, method: &str, params: &[Value]) -> Box<Future<Item = (), Error = ()>>;
+
+    /// Return a stream of the notifications sent by the upstream server, starting from now, or
+    /// `None` if they cannot be received. By default, they cannot.
+    fn notifications(&self) -> Option<Box<Stream<Item = Notification, Error = ()>>> {
+        None
+    }
+}
+
+impl Upstream for Client {
+    fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ()>> {
+        Box::new(Client::request(self, method, params).map_err(|_| ()))
+    }
+
+    fn notify(&self, method: &str, params: &[Value]) -> Box<Future<Item = (), Error = ()>> {
+        Box::new(Client::notify(self, method, params))
+    }
+
+    fn notifications(&self) -> Option<Box<Stream<Item = Notification, Error = ()>>> {
+        Some(Box::new(Client::notifications(self)))
+    }
+}
+
+impl Upstream for ReconnectingClient {
+    fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ()>> {
+        Box::new(ReconnectingClient::request(self, method, params).map_err(|_| ()))
+    }
+
+    fn notify(&self, method: &str, params: &[Value]) -> Box<Future<Item = (), Error = ()>> {
+        Box::new(ReconnectingClient::notify(self, method, params).map_err(|_| ()))
+    }
+}
+
+impl Upstream for ClientPool {
+    fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ()>> {
+        Box::new(ClientPool::request(self, method, params).map_err(|_| ()))
+    }
+
+    fn notify(&self, method: &str, params: &[Value]) -> Box<Future<Item = (), Error = ()>> {
+        Box::new(ClientPool::notify(self, method, params).map_err(|_| ()))
+    }
+}
+
+/// An upstream that dispatches each request and notification to one of several upstreams, picked
+/// by a routing function. Used with a [`ProxyService`](struct.ProxyService.html), it turns the
+/// proxy into a router that shards the traffic by tenant, by key, or by method.
+///
+/// The routing function is given the method (after the rewrites of the proxy) and the parameters,
+/// and returns the index of the upstream to use. The index is taken modulo the number of
+/// upstreams.
+pub struct Router<U> {
+    upstreams: Vec<U>,
+    route: Rc<Fn(&str, &[Value]) -> usize>,
+}
+
+impl<U: Upstream> Router<U> {
+    /// Create a router that sends each message to `upstreams[route(method, params)]`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `upstreams` is empty.
+    pub fn new<F>(upstreams: Vec<U>, route: F) -> Self
+    where
+        F: Fn(&str, &[Value]) -> usize + 'static,
+    {
+        assert!(!upstreams.is_empty(), "a router needs at least one upstream");
+        Router {
+            upstreams: upstreams,
+            route: Rc::new(route),
+        }
+    }
+
+    /// Create a router that shards the messages by the hash of their parameter at the given
+    /// position, such as a tenant or a key. The hash is stable, so a given key is always sent to
+    /// the same upstream, as long as the list of upstreams does not change. Messages that do not
+    /// have this parameter are sent to the first upstream.
+    pub fn by_param(upstreams: Vec<U>, position: usize) -> Self {
+        let shards = upstreams.len();
+        Router::new(upstreams, move |_method, params| match params.get(position) {
+            Some(key) => (digest_value(key) % shards as u64) as usize,
+            None => 0,
+        })
+    }
+
+    fn pick(&self, method: &str, params: &[Value]) -> &U {
+        let index = (self.route)(method, params) % self.upstreams.len();
+        trace!("Routing {} to upstream {}", method, index);
+        &self.upstreams[index]
+    }
+}
+
+impl<U: Clone> Clone for Router<U> {
+    fn clone(&self) -> Self {
+        Router {
+            upstreams: self.upstreams.clone(),
+            route: Rc::clone(&self.route),
+        }
+    }
+}
+
+impl<U: Upstream> Upstream for Router<U> {
+    fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ()>> {
+        self.pick(method, params).request(method, params)
+    }
+
+    fn notify(&self, method: &str, params: &[Value]) -> Box<Future<Item = (), Error = ()>> {
+        self.pick(method, params).notify(method, params)
+    }
+
+    /// The notifications of all the upstreams that have some, merged.
+    fn notifications(&self) -> Option<Box<Stream<Item = Notification, Error = ()>>> {
+        let mut merged: Option<Box<Stream<Item = Notification, Error = ()>>> = None;
+        for notifications in self.upstreams.iter().filter_map(Upstream::notifications) {
+            merged = Some(match merged {
+                Some(merged) => Box::new(merged.select(notifications)),
+                None => notifications,
+            });
+        }
+        merged
+    }
+}
+
+/// A service that forwards every request and notification it receives to an upstream server,
+/// and relays the responses back. It is the building block for gateways, sidecars, or tools that
+/// inspect the traffic between two endpoints.
+///
+/// When the upstream does not answer a request in time (see
+/// [`set_timeout`](#method.set_timeout)), or when its connection is lost, the request is sent
+/// again, up to the number of retries (see [`set_retries`](#method.set_retries)). Once they are
+/// exhausted, the request is answered with an error. The errors sent by the upstream server are
+/// relayed as they are, and are never retried.
+///
+/// The methods can be renamed before being forwarded (see
+/// [`set_rewrites`](#method.set_rewrites)). To spread the traffic over several upstream servers,
+/// use a [`Router`](struct.Router.html) as upstream. The notifications sent by the upstream
+/// servers can be relayed too (see
+/// [`set_relay_notifications`](#method.set_relay_notifications)).
+///
+/// `ProxyService` is also a `ServiceBuilder`: all the connections the proxy accepts share the same
+/// upstream.
+#[derive(Clone)]
+pub struct ProxyService<U> {
+    upstream: U,
+    handle: Handle,
+    timeout: Option<Duration>,
+    retries: u32,
+    rewrites: MethodRewrites,
+    relay_notifications: bool,
+}
+
+impl<U: Upstream + Clone + 'static> ProxyService<U> {
+    /// Create a proxy that forwards requests to `upstream`, without timeout and without retries.
+    pub fn new(upstream: U, handle: &Handle) -> Self {
+        ProxyService {
+            upstream: upstream,
+            handle: handle.clone(),
+            timeout: None,
+            retries: 0,
+            rewrites: MethodRewrites::default(),
+            relay_notifications: false,
+        }
+    }
+
+    /// Set how long the proxy waits for the response of the upstream server, for each attempt.
+    /// By default, it waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many times a request is sent again when an attempt times out or fails. Only use
+    /// this if the requests are idempotent: the upstream server may have handled them already.
+    /// By default, requests are not retried.
+    pub fn set_retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the rules applied to the methods of the requests and notifications before they are
+    /// forwarded. By default, methods are forwarded unchanged.
+    pub fn set_rewrites(&mut self, rewrites: MethodRewrites) -> &mut Self {
+        self.rewrites = rewrites;
+        self
+    }
+
+    /// If `enabled` is `true`, the notifications sent by the upstream server are relayed to the
+    /// connections of the proxy, as they are. Since all the connections share the upstream, each
+    /// notification is sent to all of them. It requires an upstream that can receive
+    /// notifications, such as a [`Client`](struct.Client.html) without a service: it has no
+    /// effect otherwise. By default, the notifications of the upstream server are dropped.
+    pub fn set_relay_notifications(&mut self, enabled: bool) -> &mut Self {
+        self.relay_notifications = enabled;
+        self
+    }
+
+    /// Send the request once, and wait for the response at most `timeout`.
+    fn attempt(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = io::Error>> {
+        let request = self.upstream.request(method, params).map_err(|()| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "the upstream connection was lost")
+        });
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Box::new(request),
+        };
+        let timer = match Timeout::new(timeout, &self.handle) {
+            Ok(timer) => timer,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let request = request.select2(timer).then(|result| match result {
+            Ok(Either::A((response, _))) => Ok(response),
+            Ok(Either::B(_)) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the upstream server did not answer in time",
+            )),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        });
+        Box::new(request)
+    }
+}
+
+impl<U: Upstream + Clone + 'static> BoxedService for ProxyService<U> {
+    type Error = io::Error;
+    type T = Value;
+    type E = Value;
+
+    fn handle_request(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Self::T, Self::E>, Error = Self::Error>> {
+        let method = self.rewrites.rewrite(method).into_owned();
+        trace!("Forwarding request {} upstream", method);
+        let this = self.clone();
+        let params = Vec::from(params);
+        let response = future::loop_fn(0, move |attempt| {
+            let retries = this.retries;
+            let method = method.clone();
+            this.attempt(&method, &params).then(move |result| match result {
+                Ok(response) => Ok(Loop::Break(response)),
+                Err(e) => {
+                    warn!("Upstream request {} failed: {}", method, e);
+                    if attempt < retries {
+                        Ok(Loop::Continue(attempt + 1))
+                    } else {
+                        Err(e)
+                    }
+                }
+            })
+        });
+        Box::new(response)
+    }
+
+    fn handle_notification(
+        &mut self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = (), Error = Self::Error>> {
+        let method = self.rewrites.rewrite(method).into_owned();
+        trace!("Forwarding notification {} upstream", method);
+        let notification = self.upstream.notify(&method, params).or_else(move |()| {
+            warn!("Failed to forward notification {} upstream", method);
+            Ok(())
+        });
+        Box::new(notification)
+    }
+}
+
+impl<U: Upstream + Clone + 'static> ServiceBuilder for ProxyService<U> {
+    type Service = Self;
+
+    fn build(&self, client: Client) -> Self {
+        if self.relay_notifications {
+            match self.upstream.notifications() {
+                Some(notifications) => relay(notifications, client, &self.handle),
+                None => warn!("The upstream of the proxy cannot relay notifications"),
+            }
+        }
+        self.clone()
+    }
+}
+
+/// Send the notifications of the upstream server to a connection of the proxy, until one of them
+/// is closed. A connection that is closed is only noticed when the next notification is relayed.
+fn relay(
+    notifications: Box<Stream<Item = Notification, Error = ()>>,
+    downstream: Client,
+    handle: &Handle,
+) {
+    let relay = notifications.for_each(move |notification| {
+        trace!("Relaying notification {} downstream", notification.method);
+        downstream.notify(&notification.method, &notification.params)
+    });
+    handle.spawn(relay);
+}
+
+#[test]
+fn router() {
+    use std::cell::RefCell;
+
+    #[derive(Clone)]
+    struct Recorder(usize, Rc<RefCell<Vec<usize>>>);
+
+    impl Upstream for Recorder {
+        fn request(
+            &self,
+            _method: &str,
+            _params: &[Value],
+        ) -> Box<Future<Item = Result<Value, Value>, Error = ()>> {
+            self.1.borrow_mut().push(self.0);
+            Box::new(future::ok(Ok(Value::Nil)))
+        }
+
+        fn notify(&self, _method: &str, _params: &[Value]) -> Box<Future<Item = (), Error = ()>> {
+            self.1.borrow_mut().push(self.0);
+            Box::new(future::ok(()))
+        }
+    }
+
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let upstreams = vec![Recorder(0, calls.clone()), Recorder(1, calls.clone())];
+    let router = Router::new(upstreams.clone(), |method, _| if method == "write" { 1 } else { 0 });
+    let _ = router.request("read", &[]);
+    let _ = router.notify("write", &[]);
+    assert_eq!(*calls.borrow(), vec![0, 1]);
+
+    calls.borrow_mut().clear();
+    let router = Router::by_param(upstreams, 0);
+    let _ = router.request("get", &[Value::from("tenant-a")]);
+    let _ = router.request("put", &[Value::from("tenant-a"), Value::from(1)]);
+    let _ = router.request("get", &[]);
+    let calls = calls.borrow();
+    assert_eq!(calls[0], calls[1]);
+    assert_eq!(calls[2], 0);
+}
+
+#[test]
+fn relay_notifications() {
+    use methods::MethodRouter;
+    use net::ClientOnlyConnector;
+    use server::ServerBuilder;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut router = MethodRouter::new();
+    let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+    let any = "127.0.0.1:0".parse().unwrap();
+    let backend = ServerBuilder::new(any).spawn(router, &handle).unwrap();
+    let address = backend.local_addr().unwrap();
+    let upstream = core.run(ClientOnlyConnector::new(&address, &handle).connect()).unwrap();
+
+    let mut proxy = ProxyService::new(upstream, &handle);
+    let _ = proxy.set_relay_notifications(true);
+    let gateway = ServerBuilder::new(any).spawn(proxy, &handle).unwrap();
+    let address = gateway.local_addr().unwrap();
+    let client = core.run(ClientOnlyConnector::new(&address, &handle).connect()).unwrap();
+    let notifications = client.notifications();
+    let response = client.request("echo", &[Value::from(1)]);
+    assert_eq!(core.run(response).unwrap(), Ok(Value::from(1)));
+
+    // The notifications of the backend go through the proxy.
+    assert_eq!(backend.broadcast("event", &[Value::from(2)]), 1);
+    let (notification, _) = core.run(notifications.into_future()).map_err(|_| ()).unwrap();
+    let notification = notification.unwrap();
+    assert_eq!(notification.method, "event");
+    assert_eq!(notification.params, vec![Value::from(2)]);
+}
@@ -0,0 +1,672 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::iter;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use rmpv::Value;
+use tokio_core::reactor::{Handle, Timeout};
+
+use config::ClientConfig;
+use endpoint::Client;
+use errors::{CallError, ClientError};
+use net::ClientOnlyConnector;
+use options::ProtocolOptions;
+use resolver::Resolver;
+
+/// Defines how long a [`ReconnectingClient`](struct.ReconnectingClient.html) waits between two
+/// connection attempts. The delay starts at `initial_delay` and doubles after each failed attempt,
+/// up to `max_delay`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Create a new exponential backoff, that retries forever.
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Self {
+        Backoff {
+            initial_delay: initial_delay,
+            max_delay: max_delay,
+            max_attempts: None,
+        }
+    }
+
+    /// Give up after `max_attempts` consecutive failed connection attempts. When the client gives
+    /// up, all the requests waiting for a connection fail, and the next request starts a new
+    /// series of attempts.
+    pub fn set_max_attempts(&mut self, max_attempts: Option<u32>) -> &mut Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Return the delay before the first retry.
+    pub fn initial_delay(&self) -> Duration {
+        self.initial_delay
+    }
+
+    /// Return the maximum delay between two attempts.
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    /// Return the delay before the given attempt. The first attempt is immediate.
+    fn delay(&self, attempt: u32) -> Duration {
+        if attempt == 0 {
+            return Duration::from_secs(0);
+        }
+        let factor = 1 << cmp::min(attempt - 1, 31);
+        match self.initial_delay.checked_mul(factor) {
+            Some(delay) => cmp::min(delay, self.max_delay),
+            None => self.max_delay,
+        }
+    }
+
+    fn is_exhausted(&self, attempt: u32) -> bool {
+        match self.max_attempts {
+            Some(max_attempts) => attempt >= max_attempts,
+            None => false,
+        }
+    }
+}
+
+/// Defines what happens to the requests that are in flight when the connection is lost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayPolicy {
+    /// The requests fail.
+    Fail,
+    /// The requests are sent again once the connection is re-established, at most the given
+    /// number of times. Requests are only sent again if the connection was lost (see
+    /// [`CallError::is_connection_lost`](enum.CallError.html#method.is_connection_lost)), not if
+    /// the server broke the protocol. Only use this if the requests are idempotent: the server may have handled
+    /// them already before the connection was lost.
+    Retry(u32),
+}
+
+/// Defines what happens to a request or a notification sent while the connection is being
+/// established, when the queue of the calls waiting for it is full (see
+/// [`ReconnectingClient::set_queue_capacity`](struct.ReconnectingClient.html#method.set_queue_capacity)).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The new call fails immediately.
+    RejectNew,
+    /// The oldest waiting call fails, to make room for the new one.
+    DropOldest,
+}
+
+struct Inner {
+    addresses: Vec<SocketAddr>,
+    resolver: Option<Box<Resolver>>,
+    resolve_interval: Option<Duration>,
+    resolved_at: Option<Instant>,
+    // The index of the address tried first by the next connection.
+    first: usize,
+    handle: Handle,
+    backoff: Backoff,
+    policy: ReplayPolicy,
+    options: ProtocolOptions,
+    client: Option<Client>,
+    connecting: bool,
+    attempt: u32,
+    // Incremented each time a new connection is established, so that we don't mistake the loss of
+    // a previous connection for the loss of the current one.
+    generation: u64,
+    // The calls waiting for a connection, oldest first. They get the connection, or the error
+    // of the last attempt if the client gives up.
+    waiting: VecDeque<oneshot::Sender<io::Result<Client>>>,
+    queue_capacity: Option<usize>,
+    overflow: OverflowPolicy,
+}
+
+impl Inner {
+    /// Describe the server, for the logs.
+    fn target(&self) -> String {
+        match self.addresses.len() {
+            1 => self.addresses[0].to_string(),
+            _ => format!("{:?}", self.addresses),
+        }
+    }
+
+    /// Return a future that resolves the addresses, if there is a resolver and they never were
+    /// resolved, or if the resolve interval elapsed.
+    fn resolve(&self) -> Option<Box<Future<Item = Vec<SocketAddr>, Error = io::Error>>> {
+        let stale = match (self.resolved_at, self.resolve_interval) {
+            (None, _) => true,
+            (Some(instant), Some(interval)) => instant.elapsed() >= interval,
+            (Some(_), None) => false,
+        };
+        match self.resolver {
+            Some(ref resolver) if stale => Some(resolver.resolve()),
+            _ => None,
+        }
+    }
+
+    /// Use the addresses just resolved. If the resolution failed, or gave no address, the
+    /// previous addresses are kept.
+    fn resolved(&mut self, resolved: io::Result<Vec<SocketAddr>>) {
+        match resolved {
+            Ok(ref addresses) if addresses.is_empty() => {
+                warn!("No address resolved. Keeping {}", self.target());
+            }
+            Ok(addresses) => {
+                trace!("Resolved {:?}", addresses);
+                self.addresses = addresses;
+            }
+            Err(e) => warn!("Failed to resolve the addresses: {}", e),
+        }
+        self.resolved_at = Some(Instant::now());
+    }
+
+    /// Return the addresses to try for the next connection, in order.
+    fn targets(&self) -> Vec<SocketAddr> {
+        let len = self.addresses.len();
+        (0..len)
+            .map(|i| self.addresses[(self.first + i) % len])
+            .collect()
+    }
+
+    /// Queue a call until the connection is established. Return `None` if the queue is full and
+    /// the call is rejected.
+    fn enqueue(&mut self) -> Option<oneshot::Receiver<io::Result<Client>>> {
+        // Calls that were dropped while waiting don't take any room.
+        self.waiting.retain(|tx| !tx.is_canceled());
+        if let Some(capacity) = self.queue_capacity {
+            if self.waiting.len() >= capacity {
+                if self.overflow == OverflowPolicy::RejectNew || capacity == 0 {
+                    warn!("Too many calls waiting for a connection to {}", self.target());
+                    return None;
+                }
+                warn!(
+                    "Too many calls waiting for a connection to {}. Dropping the oldest one.",
+                    self.target()
+                );
+                // Dropping the sender makes the call fail.
+                let _ = self.waiting.pop_front();
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        self.waiting.push_back(tx);
+        Some(rx)
+    }
+}
+
+/// A client that transparently reconnects to the remote `MessagePack-RPC` server when the
+/// connection is lost.
+///
+/// The connection is established lazily, when the first request or notification is sent. When
+/// it is lost, the client reconnects following its [`Backoff`](struct.Backoff.html) policy, and
+/// the requests that were in flight are handled according to its
+/// [`ReplayPolicy`](enum.ReplayPolicy.html). The calls made while the connection is being
+/// established are queued, and sent once it is.
+///
+/// Servers that run as several replicas are reached through any of their addresses (see
+/// [`with_addresses`](#method.with_addresses) and [`with_resolver`](#method.with_resolver)): each
+/// connection is established with the first address that can be reached, and when it is lost, the
+/// next connection starts with the following address.
+///
+/// `ReconnectingClient` is cheap to clone: all the clones share the same connection.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl ReconnectingClient {
+    /// Create a new client for the server at `address`. This does not connect yet.
+    pub fn new(address: SocketAddr, handle: &Handle) -> Self {
+        ReconnectingClient::with_addresses(vec![address], handle)
+    }
+
+    /// Create a new client for a server that runs as several replicas, with the given addresses.
+    /// Connections are established with the first address that can be reached, starting with
+    /// `addresses[0]`. This does not connect yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addresses` is empty.
+    pub fn with_addresses(addresses: Vec<SocketAddr>, handle: &Handle) -> Self {
+        assert!(!addresses.is_empty(), "a client needs at least one address");
+        ReconnectingClient::create(addresses, None, handle)
+    }
+
+    /// Create a new client that gets the addresses of the server from `resolver`, when it first
+    /// connects (see also [`set_resolve_interval`](#method.set_resolve_interval)). If the
+    /// resolver fails, the addresses it returned last are used. This does not connect, nor
+    /// resolve, yet.
+    pub fn with_resolver<R: Resolver + 'static>(resolver: R, handle: &Handle) -> Self {
+        ReconnectingClient::create(Vec::new(), Some(Box::new(resolver)), handle)
+    }
+
+    fn create(
+        addresses: Vec<SocketAddr>,
+        resolver: Option<Box<Resolver>>,
+        handle: &Handle,
+    ) -> Self {
+        let inner = Inner {
+            addresses: addresses,
+            resolver: resolver,
+            resolve_interval: None,
+            resolved_at: None,
+            first: 0,
+            handle: handle.clone(),
+            backoff: Backoff::default(),
+            policy: ReplayPolicy::Fail,
+            options: ProtocolOptions::default(),
+            client: None,
+            connecting: false,
+            attempt: 0,
+            generation: 0,
+            waiting: VecDeque::new(),
+            queue_capacity: None,
+            overflow: OverflowPolicy::RejectNew,
+        };
+        ReconnectingClient {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Create a new client from the given configuration. This does not connect yet.
+    ///
+    /// `ReconnectingClient` does not support TLS yet: the TLS configuration is ignored, and a
+    /// warning is logged if it is set.
+    pub fn from_config(config: &ClientConfig, handle: &Handle) -> Self {
+        if config.tls.is_some() {
+            warn!("ReconnectingClient does not support TLS. Ignoring the TLS configuration.");
+        }
+        let addresses = iter::once(config.address)
+            .chain(config.fallback_addresses.iter().cloned())
+            .collect();
+        let mut client = ReconnectingClient::with_addresses(addresses, handle);
+        let _ = client
+            .set_replay_policy(config.replay_policy())
+            .set_queue_capacity(config.queue_capacity, config.overflow_policy())
+            .set_protocol_options(ProtocolOptions::from(&config.protocol));
+        if let Some(ref backoff) = config.backoff {
+            let _ = client.set_backoff(Backoff::from(backoff));
+        }
+        client
+    }
+
+    /// Set the backoff policy used between connection attempts.
+    pub fn set_backoff(&mut self, backoff: Backoff) -> &mut Self {
+        self.inner.borrow_mut().backoff = backoff;
+        self
+    }
+
+    /// Set the policy applied to the requests in flight when the connection is lost. By default,
+    /// they fail.
+    pub fn set_replay_policy(&mut self, policy: ReplayPolicy) -> &mut Self {
+        self.inner.borrow_mut().policy = policy;
+        self
+    }
+
+    /// Set the maximum number of requests and notifications waiting for the connection to be
+    /// established, and what happens to the calls made when that many are waiting. By default,
+    /// the queue is unbounded. With a capacity of 0, calls made while the client is not connected
+    /// fail immediately.
+    pub fn set_queue_capacity(
+        &mut self,
+        capacity: Option<usize>,
+        overflow: OverflowPolicy,
+    ) -> &mut Self {
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.queue_capacity = capacity;
+            inner.overflow = overflow;
+        }
+        self
+    }
+
+    /// Set the options used for the connections.
+    pub fn set_protocol_options(&mut self, options: ProtocolOptions) -> &mut Self {
+        self.inner.borrow_mut().options = options;
+        self
+    }
+
+    /// For clients created with [`with_resolver`](#method.with_resolver), resolve the addresses
+    /// again before connecting if they were resolved more than `interval` ago. By default, they
+    /// are only resolved once.
+    pub fn set_resolve_interval(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.inner.borrow_mut().resolve_interval = interval;
+        self
+    }
+
+    /// Send a `MessagePack-RPC` request, connecting first if necessary. The future fails with
+    /// `ClientError::Connect` if no connection could be established, and with `ClientError::Call`
+    /// if the connection was lost and the replay policy does not allow the request to be sent
+    /// again.
+    pub fn request(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = Result<Value, Value>, Error = ClientError>> {
+        let this = self.clone();
+        let method = method.to_owned();
+        let params = Vec::from(params);
+        let request = future::loop_fn(0, move |replays| {
+            let this = this.clone();
+            let (method, params) = (method.clone(), params.clone());
+            this.client().and_then(move |client| {
+                client.request(&method, &params).then(move |result| match result {
+                    Ok(response) => Ok(Loop::Break(response)),
+                    Err(e) => {
+                        warn!("No response to {}: {}", method, e);
+                        let policy = this.inner.borrow().policy;
+                        match policy {
+                            ReplayPolicy::Retry(max) if replays < max && e.is_connection_lost() => {
+                                Ok(Loop::Continue(replays + 1))
+                            }
+                            _ => Err(ClientError::Call(e)),
+                        }
+                    }
+                })
+            })
+        });
+        Box::new(request)
+    }
+
+    /// Send a `MessagePack-RPC` notification, connecting first if necessary. Notifications are
+    /// never replayed: the future fails with `ClientError::Connect` if no connection could be
+    /// established, and with `ClientError::Call` if the connection was closed before the
+    /// notification was sent.
+    pub fn notify(
+        &self,
+        method: &str,
+        params: &[Value],
+    ) -> Box<Future<Item = (), Error = ClientError>> {
+        let method = method.to_owned();
+        let params = Vec::from(params);
+        Box::new(self.client().and_then(move |client| {
+            client
+                .notify(&method, &params)
+                .map_err(|()| ClientError::Call(CallError::ConnectionClosed))
+        }))
+    }
+
+    /// Return a future that resolves to the current connection, connecting first if necessary.
+    fn client(&self) -> Box<Future<Item = Client, Error = ClientError>> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(ref client) = inner.client {
+            return Box::new(future::ok(client.clone()));
+        }
+        let rx = inner.enqueue();
+        if !inner.connecting {
+            inner.connecting = true;
+            drop(inner);
+            self.connect();
+        }
+        match rx {
+            Some(rx) => Box::new(rx.then(|result| match result {
+                Ok(Ok(client)) => Ok(client),
+                Ok(Err(e)) => Err(ClientError::Connect(e)),
+                Err(_) => Err(not_connected("the call was dropped from the full queue")),
+            })),
+            None => Box::new(future::err(not_connected("too many calls are waiting"))),
+        }
+    }
+
+    fn connect(&self) {
+        let this = self.clone();
+        let inner = self.inner.borrow();
+        let delay = inner.backoff.delay(inner.attempt);
+        let handle = inner.handle.clone();
+        let options = inner.options.clone();
+
+        trace!("Connecting to {} in {:?}", inner.target(), delay);
+        let timeout = match Timeout::new(delay, &handle) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                error!("Failed to create a reconnection timer: {}", e);
+                drop(inner);
+                this.give_up(&e);
+                return;
+            }
+        };
+        let resolving = self.clone();
+        // The addresses are resolved once the delay elapsed, to get the most recent ones.
+        let connection = timeout.and_then(move |()| resolving.connection(&handle, options));
+        inner.handle.spawn(connection.then(move |result| {
+            match result {
+                Ok(client) => this.connected(client),
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", this.inner.borrow().target(), e);
+                    this.connection_failed(&e);
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    /// Return a future that connects to the first of the addresses that can be reached. The
+    /// addresses are resolved first if they are stale.
+    fn connection(
+        &self,
+        handle: &Handle,
+        options: ProtocolOptions,
+    ) -> Box<Future<Item = Client, Error = io::Error>> {
+        let resolution = self.inner.borrow().resolve();
+        let resolved: Box<Future<Item = (), Error = io::Error>> = match resolution {
+            Some(resolution) => {
+                let this = self.clone();
+                Box::new(resolution.then(move |result| {
+                    this.inner.borrow_mut().resolved(result);
+                    Ok(())
+                }))
+            }
+            None => Box::new(future::ok(())),
+        };
+        let this = self.clone();
+        let handle = handle.clone();
+        let connection = resolved.and_then(move |()| -> Box<Future<Item = _, Error = _>> {
+            let addresses = this.inner.borrow().targets();
+            match addresses.split_first() {
+                Some((address, fallbacks)) => Box::new(
+                    ClientOnlyConnector::new(address, &handle)
+                        .set_fallback_addrs(fallbacks)
+                        .set_protocol_options(options)
+                        .connect(),
+                ),
+                None => {
+                    let error = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+                    Box::new(future::err(error))
+                }
+            }
+        });
+        Box::new(connection)
+    }
+
+    fn connected(&self, client: Client) {
+        let mut inner = self.inner.borrow_mut();
+        trace!("Connected to {:?}", client.context().peer_addr());
+        // The next connection starts with the address that worked.
+        if let Some(address) = client.context().peer_addr() {
+            if let Some(index) = inner.addresses.iter().position(|a| *a == address) {
+                inner.first = index;
+            }
+        }
+        inner.connecting = false;
+        inner.attempt = 0;
+        inner.generation += 1;
+        inner.client = Some(client.clone());
+        for tx in inner.waiting.drain(..) {
+            let _ = tx.send(Ok(client.clone()));
+        }
+
+        // The notifications stream ends when the connection is closed, which lets us notice the
+        // loss of the connection even if no request is in flight.
+        let this = self.clone();
+        let generation = inner.generation;
+        inner.handle.spawn(
+            client
+                .notifications()
+                .for_each(|_| Ok(()))
+                .then(move |_| {
+                    this.connection_lost(generation);
+                    Ok(())
+                }),
+        );
+    }
+
+    fn connection_failed(&self, error: &io::Error) {
+        let exhausted = {
+            let mut inner = self.inner.borrow_mut();
+            inner.attempt += 1;
+            inner.backoff.is_exhausted(inner.attempt)
+        };
+        if exhausted {
+            self.give_up(error);
+        } else {
+            self.connect();
+        }
+    }
+
+    fn connection_lost(&self, generation: u64) {
+        let reconnect = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.generation != generation || inner.client.is_none() {
+                return;
+            }
+            warn!("Lost connection to {}", inner.target());
+            inner.client = None;
+            // The replica may be gone: fail over to the next one.
+            inner.first += 1;
+            if inner.connecting {
+                false
+            } else {
+                inner.connecting = true;
+                true
+            }
+        };
+        if reconnect {
+            self.connect();
+        }
+    }
+
+    fn give_up(&self, error: &io::Error) {
+        let mut inner = self.inner.borrow_mut();
+        error!("Giving up connecting to {}", inner.target());
+        inner.connecting = false;
+        inner.attempt = 0;
+        for tx in inner.waiting.drain(..) {
+            let _ = tx.send(Err(io::Error::new(error.kind(), error.to_string())));
+        }
+    }
+}
+
+/// Return the error of a call that could not wait for a connection.
+fn not_connected(reason: &str) -> ClientError {
+    ClientError::Connect(io::Error::new(io::ErrorKind::NotConnected, reason))
+}
+
+#[test]
+fn backoff_delay() {
+    let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+    assert_eq!(backoff.delay(0), Duration::from_secs(0));
+    assert_eq!(backoff.delay(1), Duration::from_millis(100));
+    assert_eq!(backoff.delay(2), Duration::from_millis(200));
+    assert_eq!(backoff.delay(4), Duration::from_millis(800));
+    assert_eq!(backoff.delay(5), Duration::from_secs(1));
+    assert_eq!(backoff.delay(1000), Duration::from_secs(1));
+}
+
+#[test]
+fn offline_queue() {
+    use tokio_core::reactor::Core;
+
+    let core = Core::new().unwrap();
+    let client = ReconnectingClient::new("127.0.0.1:1".parse().unwrap(), &core.handle());
+    let mut inner = client.inner.borrow_mut();
+    inner.queue_capacity = Some(2);
+    let first = inner.enqueue().unwrap();
+    let _second = inner.enqueue().unwrap();
+    assert!(inner.enqueue().is_none());
+
+    inner.overflow = OverflowPolicy::DropOldest;
+    let third = inner.enqueue().unwrap();
+    assert_eq!(inner.waiting.len(), 2);
+    assert!(first.wait().is_err());
+
+    // A call that is dropped frees its place.
+    drop(third);
+    let _fourth = inner.enqueue().unwrap();
+    assert_eq!(inner.waiting.len(), 2);
+}
+
+#[test]
+fn connection_errors() {
+    use std::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    // Nothing listens on a port that was just released.
+    let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut client = ReconnectingClient::new(dead, &core.handle());
+    let mut backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(1));
+    let _ = backoff.set_max_attempts(Some(1));
+    let _ = client.set_backoff(backoff);
+    match core.run(client.request("ping", &[])) {
+        Err(ClientError::Connect(ref e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match core.run(client.notify("event", &[])) {
+        Err(ClientError::Connect(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    let _ = client.set_queue_capacity(Some(0), OverflowPolicy::RejectNew);
+    match core.run(client.request("ping", &[])) {
+        Err(ClientError::Connect(ref e)) => assert_eq!(e.kind(), io::ErrorKind::NotConnected),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn failover() {
+    use std::cell::Cell;
+    use std::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+    use server::ServerBuilder;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut router = MethodRouter::new();
+    let _ = router.request("ping", |_params| Box::new(future::ok(Ok(Value::from("pong")))));
+    let any = "127.0.0.1:0".parse().unwrap();
+    let server = ServerBuilder::new(any).spawn(router, &handle).unwrap();
+    let live = server.local_addr().unwrap();
+    // Nothing listens on a port that was just released.
+    let dead = TcpListener::bind(any).unwrap().local_addr().unwrap();
+
+    let resolutions = Rc::new(Cell::new(0));
+    let count = Rc::clone(&resolutions);
+    let client = ReconnectingClient::with_resolver(
+        move || {
+            count.set(count.get() + 1);
+            Ok(vec![dead, live])
+        },
+        &handle,
+    );
+    let response = core.run(client.request("ping", &[])).unwrap();
+    assert_eq!(response, Ok(Value::from("pong")));
+    assert_eq!(resolutions.get(), 1);
+    // The next connection starts with the replica that answered.
+    assert_eq!(client.inner.borrow().targets(), vec![live, dead]);
+}
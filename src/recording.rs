@@ -0,0 +1,273 @@
+//! Recording and replay of `MessagePack-RPC` sessions, for regression tests.
+//!
+//! A [`Recording`](struct.Recording.html) keeps the messages an endpoint sends and receives, and
+//! can be saved to a file. It can later be replayed against a service: the requests and
+//! notifications the endpoint received are sent again, and the responses of the service are
+//! compared with the recorded ones:
+//!
+//! ```rust,ignore
+//! let recording = Recording::new();
+//! let mut options = ProtocolOptions::default();
+//! let _ = options.dump(Some(recording.dump()));
+//! // ... run a server with these options, and send it some requests.
+//! recording.save(&mut File::create("session.msgpack")?)?;
+//!
+//! // Later, in a test:
+//! let recording = Recording::load(&mut File::open("session.msgpack")?)?;
+//! let mismatches = recording.replay(MyService::new(), ProtocolOptions::default());
+//! assert!(mismatches.is_empty(), "{:?}", mismatches);
+//! ```
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use rmpv::{decode, encode, Value};
+
+use dump::{Direction, DumpRecord, ProtocolDump};
+use endpoint::{Service, BUILTIN_PREFIX};
+use message::{Id, Message, Param};
+use mock::TestClient;
+use options::ProtocolOptions;
+
+/// The messages sent and received by an endpoint, in order, as they are encoded on the wire.
+///
+/// The messages are recorded by the [`ProtocolDump`](struct.ProtocolDump.html) returned by
+/// [`dump`](#method.dump), which should only be given to the options of a single connection:
+/// the messages of several connections would be mixed up. The parameters hidden by the
+/// [redactions](struct.ProtocolOptions.html#method.redactions) are recorded redacted, so they
+/// cannot be replayed.
+///
+/// `Recording` is cheap to clone: all the clones share the same messages.
+#[derive(Clone, Default)]
+pub struct Recording {
+    records: Arc<Mutex<Vec<(Direction, Value)>>>,
+}
+
+impl Recording {
+    /// Create an empty recording.
+    pub fn new() -> Self {
+        Recording::default()
+    }
+
+    /// Return a dump that appends the messages it reports to this recording (see
+    /// [`ProtocolOptions::dump`](struct.ProtocolOptions.html#method.dump)).
+    pub fn dump(&self) -> ProtocolDump {
+        let records = Arc::clone(&self.records);
+        ProtocolDump::new(Arc::new(move |record: &DumpRecord| {
+            let entry = (record.direction(), record.message().clone());
+            records.lock().unwrap().push(entry);
+        }))
+    }
+
+    /// Return the recorded messages, with the direction they went in, in order.
+    pub fn records(&self) -> Vec<(Direction, Value)> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Write the recording to `writer`, as a msgpack array of `[direction, message]` pairs, the
+    /// direction being `"incoming"` or `"outgoing"`.
+    pub fn save<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let records = self.records()
+            .into_iter()
+            .map(|(direction, message)| {
+                let direction = match direction {
+                    Direction::Incoming => "incoming",
+                    Direction::Outgoing => "outgoing",
+                };
+                Value::Array(vec![Value::from(direction), message])
+            })
+            .collect();
+        encode::write_value(writer, &Value::Array(records))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    /// Read a recording written by [`save`](#method.save).
+    pub fn load<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid recording");
+        let value = decode::read_value(reader).map_err(|e| match e {
+            decode::Error::InvalidMarkerRead(e) | decode::Error::InvalidDataRead(e) => e,
+            _ => invalid(),
+        })?;
+        let mut records = Vec::new();
+        for record in value.as_array().ok_or_else(invalid)? {
+            let (direction, message) = match record.as_array() {
+                Some(pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+                _ => return Err(invalid()),
+            };
+            let direction = match direction.as_str() {
+                Some("incoming") => Direction::Incoming,
+                Some("outgoing") => Direction::Outgoing,
+                _ => return Err(invalid()),
+            };
+            records.push((direction, message.clone()));
+        }
+        Ok(Recording {
+            records: Arc::new(Mutex::new(records)),
+        })
+    }
+
+    /// Send the requests and notifications received by the recorded endpoint to `service`, in
+    /// order, and return the requests whose response differs from the recorded one. The options
+    /// are used by both ends of the connection, like in
+    /// [`TestClient::with_options`](mock/struct.TestClient.html#method.with_options).
+    ///
+    /// The messages are sent one after the other: each request is answered before the next
+    /// message is sent, so a service whose responses depend on how concurrent requests
+    /// interleave may answer differently. The builtin messages, whose method starts with `$/`,
+    /// are not replayed, and neither are the requests that were never answered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service closes the connection.
+    pub fn replay<S: Service>(&self, service: S, options: ProtocolOptions) -> Vec<Mismatch> {
+        let records = self.records();
+        let messages = records
+            .iter()
+            .map(|&(direction, ref message)| (direction, to_message(message)))
+            .collect::<Vec<_>>();
+        let mut client = TestClient::with_options(service, options);
+        let mut mismatches = Vec::new();
+        for (position, &(direction, ref message)) in messages.iter().enumerate() {
+            if direction != Direction::Incoming {
+                continue;
+            }
+            match *message {
+                Some(Message::Request(ref request)) => {
+                    if request.method.starts_with(BUILTIN_PREFIX) {
+                        continue;
+                    }
+                    let expected = match response(&messages[position + 1..], request.id) {
+                        Some(expected) => expected,
+                        None => continue,
+                    };
+                    let params = request
+                        .params
+                        .iter()
+                        .cloned()
+                        .map(Param::into_value)
+                        .collect::<Vec<_>>();
+                    let actual = client.request(&request.method, &params);
+                    if actual != expected {
+                        mismatches.push(Mismatch {
+                            method: request.method.clone(),
+                            params: params,
+                            expected: expected,
+                            actual: actual,
+                        });
+                    }
+                }
+                Some(Message::Notification(ref notification)) => {
+                    if !notification.method.starts_with(BUILTIN_PREFIX) {
+                        client.notify(&notification.method, &notification.params);
+                    }
+                }
+                _ => {}
+            }
+        }
+        mismatches
+    }
+}
+
+impl fmt::Debug for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Recording")
+            .field("records", &self.records.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Return the result of the first response with the given id sent among `messages`.
+fn response(messages: &[(Direction, Option<Message>)], id: Id) -> Option<Result<Value, Value>> {
+    messages.iter().filter_map(|entry| match *entry {
+        (Direction::Outgoing, Some(Message::Response(ref response))) if response.id == id => {
+            Some(response.result.clone())
+        }
+        _ => None,
+    }).next()
+}
+
+/// Decode a recorded message. Return `None` if it is not a valid message.
+fn to_message(value: &Value) -> Option<Message> {
+    let mut bytes = Vec::new();
+    encode::write_value(&mut bytes, value).ok()?;
+    Message::decode(&mut &bytes[..]).ok()
+}
+
+/// A request whose response differs from the recorded one, returned by
+/// [`Recording::replay`](struct.Recording.html#method.replay).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    /// The method of the request.
+    pub method: String,
+    /// The parameters of the request.
+    pub params: Vec<Value>,
+    /// The recorded response.
+    pub expected: Result<Value, Value>,
+    /// The response of the service.
+    pub actual: Result<Value, Value>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |result: &Result<Value, Value>| match *result {
+            Ok(ref value) => format!("{}", value),
+            Err(ref error) => format!("error {}", error),
+        };
+        write!(
+            f,
+            "{}({}): expected {}, got {}",
+            self.method,
+            Value::Array(self.params.clone()),
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+#[test]
+fn record_and_replay() {
+    use std::net::SocketAddr;
+    use futures::future;
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+    use net::ClientOnlyConnector;
+    use server::ServerBuilder;
+
+    fn router(offset: i64) -> MethodRouter {
+        let mut router = MethodRouter::new();
+        let _ = router.request("add", move |params| {
+            let sum = params.iter().filter_map(Value::as_i64).sum::<i64>() + offset;
+            Box::new(future::ok(Ok(Value::from(sum))))
+        });
+        router
+    }
+
+    let recording = Recording::new();
+    let mut options = ProtocolOptions::default();
+    let _ = options.dump(Some(recording.dump()));
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = ServerBuilder::new(any)
+        .set_protocol_options(options)
+        .spawn(router(0), &handle)
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    let client = core.run(ClientOnlyConnector::new(&address, &handle).connect())
+        .unwrap();
+    let sum = client.request("add", &[Value::from(1), Value::from(2)]);
+    assert_eq!(core.run(sum).unwrap(), Ok(Value::from(3)));
+    let sum = client.request("add", &[Value::from(3)]);
+    assert_eq!(core.run(sum).unwrap(), Ok(Value::from(3)));
+
+    let mut file = Vec::new();
+    recording.save(&mut file).unwrap();
+    let recording = Recording::load(&mut &file[..]).unwrap();
+    assert_eq!(recording.records().len(), 4);
+    assert!(recording.replay(router(0), ProtocolOptions::default()).is_empty());
+
+    // A regression.
+    let mismatches = recording.replay(router(1), ProtocolOptions::default());
+    assert_eq!(mismatches.len(), 2);
+    assert_eq!(mismatches[1].to_string(), "add([3]): expected 3, got 4");
+}
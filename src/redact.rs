@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use rmpv::Value;
+
+use message::{Message, Notification, Param, Request};
+
+/// Value that replaces the parameters hidden with
+/// [`Redactions::hide`](struct.Redactions.html#method.hide).
+const REDACTED: &str = "<redacted>";
+
+type Redact = Fn(&[Value]) -> Vec<Value> + Send + Sync;
+
+/// Per-method functions that hide sensitive parameters (tokens, passwords, etc.) from the logs
+/// (see [`ProtocolOptions::redactions`](struct.ProtocolOptions.html#method.redactions)).
+///
+/// Whenever the parameters of a request or notification are logged, they are passed through the
+/// function registered for its method, if any, and the result is logged instead. The messages
+/// that are sent and received are not modified.
+///
+/// `Redactions` is cheap to clone.
+#[derive(Clone, Default)]
+pub struct Redactions {
+    methods: Arc<HashMap<String, Arc<Redact>>>,
+}
+
+impl Redactions {
+    /// Create an empty set of redactions, that hides nothing.
+    pub fn new() -> Self {
+        Redactions::default()
+    }
+
+    /// Register the function that redacts the parameters of the given method. It replaces the
+    /// function previously registered for this method, if any.
+    pub fn add<F>(&mut self, method: &str, redact: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> Vec<Value> + Send + Sync + 'static,
+    {
+        let _ = Arc::make_mut(&mut self.methods).insert(method.to_owned(), Arc::new(redact));
+        self
+    }
+
+    /// Replace the parameters of the given method at the given positions by the string
+    /// `"<redacted>"`.
+    pub fn hide(&mut self, method: &str, positions: &[usize]) -> &mut Self {
+        let positions = positions.to_vec();
+        self.add(method, move |params| {
+            params
+                .iter()
+                .enumerate()
+                .map(|(i, param)| {
+                    if positions.contains(&i) {
+                        Value::from(REDACTED)
+                    } else {
+                        param.clone()
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Return the parameters of the given method, as they should be logged.
+    pub fn redact(&self, method: &str, params: &[Value]) -> Vec<Value> {
+        match self.methods.get(method) {
+            Some(redact) => redact(params),
+            None => params.to_vec(),
+        }
+    }
+
+    fn redact_params(&self, method: &str, params: &[Param]) -> Option<Vec<Value>> {
+        self.methods.get(method).map(|redact| {
+            let params = params
+                .iter()
+                .cloned()
+                .map(Param::into_value)
+                .collect::<Vec<Value>>();
+            redact(&params)
+        })
+    }
+
+    /// Wrap a message so that its `Debug` representation is redacted.
+    pub(crate) fn message<'a>(&'a self, message: &'a Message) -> Redacted<'a> {
+        Redacted {
+            redactions: self,
+            item: Item::Message(message),
+        }
+    }
+
+    /// Wrap a request so that its `Debug` representation is redacted.
+    pub(crate) fn request<'a>(&'a self, request: &'a Request) -> Redacted<'a> {
+        Redacted {
+            redactions: self,
+            item: Item::Request(request),
+        }
+    }
+
+    /// Wrap the parameters of a method so that their `Debug` representation is redacted.
+    pub(crate) fn params<'a>(&'a self, method: &'a str, params: &'a [Value]) -> Redacted<'a> {
+        Redacted {
+            redactions: self,
+            item: Item::Values(method, params),
+        }
+    }
+
+    /// Wrap the parameters of a method so that their `Debug` representation is redacted.
+    pub(crate) fn zero_copy_params<'a>(
+        &'a self,
+        method: &'a str,
+        params: &'a [Param],
+    ) -> Redacted<'a> {
+        Redacted {
+            redactions: self,
+            item: Item::Params(method, params),
+        }
+    }
+}
+
+impl fmt::Debug for Redactions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_set().entries(self.methods.keys()).finish()
+    }
+}
+
+enum Item<'a> {
+    Message(&'a Message),
+    Request(&'a Request),
+    Notification(&'a Notification),
+    Values(&'a str, &'a [Value]),
+    Params(&'a str, &'a [Param]),
+}
+
+/// A value whose `Debug` representation has its sensitive parameters redacted. The redaction
+/// only happens if the value is actually formatted, so that it costs nothing when the logs are
+/// disabled.
+pub(crate) struct Redacted<'a> {
+    redactions: &'a Redactions,
+    item: Item<'a>,
+}
+
+impl<'a> Redacted<'a> {
+    fn with(&self, item: Item<'a>) -> Self {
+        Redacted {
+            redactions: self.redactions,
+            item: item,
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.item {
+            Item::Message(&Message::Request(ref request)) => f.debug_tuple("Request")
+                .field(&self.with(Item::Request(request)))
+                .finish(),
+            Item::Message(&Message::Notification(ref notification)) => f.debug_tuple("Notification")
+                .field(&self.with(Item::Notification(notification)))
+                .finish(),
+            Item::Message(message) => message.fmt(f),
+            Item::Request(request) => f.debug_struct("Request")
+                .field("id", &request.id)
+                .field("method", &request.method)
+                .field("params", &self.with(Item::Params(&request.method, &request.params)))
+                .finish(),
+            Item::Notification(notification) => f.debug_struct("Notification")
+                .field("method", &notification.method)
+                .field(
+                    "params",
+                    &self.with(Item::Values(&notification.method, &notification.params)),
+                )
+                .finish(),
+            Item::Values(method, params) => match self.redactions.methods.get(method) {
+                Some(redact) => redact(params).fmt(f),
+                None => params.fmt(f),
+            },
+            Item::Params(method, params) => match self.redactions.redact_params(method, params) {
+                Some(params) => params.fmt(f),
+                None => params.fmt(f),
+            },
+        }
+    }
+}
+
+#[test]
+fn redacted_request() {
+    use message::Id;
+
+    let mut redactions = Redactions::new();
+    let _ = redactions.hide("login", &[1]);
+    let request = Request {
+        id: Id::Unsigned(1),
+        method: "login".to_owned(),
+        params: vec![Param::from(Value::from("alice")), Param::from(Value::from("hunter2"))],
+    };
+    let logged = format!("{:?}", redactions.request(&request));
+    assert!(logged.contains("alice"));
+    assert!(logged.contains(REDACTED));
+    assert!(!logged.contains("hunter2"));
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+
+/// Gives the addresses of the replicas of a server, for clients that connect to any of them (see
+/// [`ReconnectingClient::with_resolver`](struct.ReconnectingClient.html#method.with_resolver)).
+///
+/// Resolvers are called on the reactor, each time the client needs the addresses, and the futures
+/// they return run on the reactor too: lookups that block must run elsewhere. Functions returning
+/// the addresses directly are resolvers as well, and should return quickly.
+pub trait Resolver {
+    /// Return a future that resolves to the addresses of the server, in order of preference.
+    fn resolve(&self) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>>;
+}
+
+impl<F: Fn() -> io::Result<Vec<SocketAddr>>> Resolver for F {
+    fn resolve(&self) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+        Box::new(future::result(self()))
+    }
+}
+
+/// A [`Resolver`](trait.Resolver.html) that looks a host name up with the resolver of the
+/// operating system, so that the replicas can be listed in the DNS.
+///
+/// The lookup runs on a thread of its own, so that a slow DNS server does not block the reactor.
+/// Clients should still not re-resolve too often (see
+/// [`ReconnectingClient::set_resolve_interval`](struct.ReconnectingClient.html#method.set_resolve_interval)).
+#[derive(Clone, Debug)]
+pub struct HostResolver {
+    host: String,
+    port: u16,
+}
+
+impl HostResolver {
+    /// Create a resolver for the given host name and port.
+    pub fn new(host: &str, port: u16) -> Self {
+        HostResolver {
+            host: host.to_owned(),
+            port: port,
+        }
+    }
+}
+
+impl Resolver for HostResolver {
+    fn resolve(&self) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+        lookup((self.host.clone(), self.port))
+    }
+}
+
+/// Return a future that resolves `host`, a host name or an IP address followed by a port.
+pub(crate) fn resolve(host: &str) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+    lookup(host.to_owned())
+}
+
+/// Return a future that resolves `address`. The resolver of the operating system blocks, so it
+/// runs on a thread of its own.
+fn lookup<A>(address: A) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>>
+where
+    A: ToSocketAddrs + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let spawned = thread::Builder::new()
+        .name("rmp-rpc-resolver".to_owned())
+        .spawn(move || {
+            let addresses = address
+                .to_socket_addrs()
+                .map(|addresses| addresses.collect::<Vec<_>>());
+            let _ = tx.send(addresses);
+        });
+    if let Err(e) = spawned {
+        return Box::new(future::err(e));
+    }
+    let addresses = rx.then(|result| match result {
+        Ok(addresses) => addresses,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "the resolver thread panicked")),
+    });
+    Box::new(addresses)
+}
+
+#[test]
+fn host_resolver() {
+    let resolver = HostResolver::new("127.0.0.1", 4000);
+    let addresses = resolver.resolve().wait().unwrap();
+    assert_eq!(addresses, vec!["127.0.0.1:4000".parse().unwrap()]);
+}
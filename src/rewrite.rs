@@ -0,0 +1,65 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Rules that rename methods, so that a server can change the name of its methods while keeping
+/// the clients that use the old names working (see
+/// [`ProxyService::set_rewrites`](struct.ProxyService.html#method.set_rewrites)).
+///
+/// Aliases are applied first. If the method has no alias, the first prefix rule that matches is
+/// applied. Methods that match no rule are left unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct MethodRewrites {
+    aliases: HashMap<String, String>,
+    prefixes: Vec<(String, String)>,
+}
+
+impl MethodRewrites {
+    /// Create an empty set of rules, that leaves all the methods unchanged.
+    pub fn new() -> Self {
+        MethodRewrites::default()
+    }
+
+    /// Rename the method `from` into `to`.
+    pub fn alias(&mut self, from: &str, to: &str) -> &mut Self {
+        let _ = self.aliases.insert(from.to_owned(), to.to_owned());
+        self
+    }
+
+    /// Replace the prefix `from` of the methods that start with it by `to`. An empty `to` strips
+    /// the prefix.
+    pub fn replace_prefix(&mut self, from: &str, to: &str) -> &mut Self {
+        self.prefixes.push((from.to_owned(), to.to_owned()));
+        self
+    }
+
+    /// Return `true` if there is no rule.
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty() && self.prefixes.is_empty()
+    }
+
+    /// Apply the rules to the given method.
+    pub fn rewrite<'a>(&self, method: &'a str) -> Cow<'a, str> {
+        if let Some(to) = self.aliases.get(method) {
+            return Cow::Owned(to.clone());
+        }
+        for &(ref from, ref to) in &self.prefixes {
+            if method.starts_with(from.as_str()) {
+                return Cow::Owned(format!("{}{}", to, &method[from.len()..]));
+            }
+        }
+        Cow::Borrowed(method)
+    }
+}
+
+#[test]
+fn method_rewrites() {
+    let mut rewrites = MethodRewrites::new();
+    let _ = rewrites
+        .alias("old_add", "add")
+        .replace_prefix("legacy.", "")
+        .replace_prefix("v1/", "v2/");
+    assert_eq!(rewrites.rewrite("old_add"), "add");
+    assert_eq!(rewrites.rewrite("legacy.sub"), "sub");
+    assert_eq!(rewrites.rewrite("v1/mul"), "v2/mul");
+    assert_eq!(rewrites.rewrite("div"), "div");
+}
@@ -0,0 +1,167 @@
+use std::{error, fmt};
+
+use rmpv::Value;
+
+/// A structured error, that can be sent as the error of a response.
+///
+/// `MessagePack-RPC` lets the error of a response be any value. `RpcError` is a convention on top
+/// of it, borrowed from JSON-RPC: the error is a map with an integer `code`, a human readable
+/// `message`, and optional `data`. It can be used as the error type of a
+/// [`Service`](trait.Service.html), and errors received by a client can be converted back with
+/// [`RpcError::from_value`](#method.from_value).
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    /// Code identifying the kind of error. The codes from -32768 to -32000 are reserved for
+    /// the predefined errors.
+    pub code: i64,
+    /// Short description of the error.
+    pub message: String,
+    /// Additional information about the error.
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    /// Code of the error sent when a request is not valid, whatever its method.
+    pub const INVALID_REQUEST: i64 = -32_600;
+    /// Code of the error sent when the method does not exist.
+    pub const METHOD_NOT_FOUND: i64 = -32_601;
+    /// Code of the error sent when the parameters of a request are invalid.
+    pub const INVALID_PARAMS: i64 = -32_602;
+    /// Code of the error sent when the server failed to handle a request.
+    pub const INTERNAL_ERROR: i64 = -32_603;
+    /// Code of the error sent when the remote endpoint does not support a protocol extension,
+    /// like a builtin `$/` method it does not know.
+    pub const UNSUPPORTED_FEATURE: i64 = -32_000;
+    /// Code of the error sent when a request exceeds the rate limits of the server.
+    pub const RATE_LIMITED: i64 = -32_001;
+
+    /// Create a new error, without data.
+    pub fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code: code,
+            message: message.to_owned(),
+            data: None,
+        }
+    }
+
+    /// Create an "invalid request" error, with the given description.
+    pub fn invalid_request(message: &str) -> Self {
+        RpcError::new(RpcError::INVALID_REQUEST, message)
+    }
+
+    /// Create a "method not found" error for the given method.
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError::new(RpcError::METHOD_NOT_FOUND, "method not found")
+            .with_data(Value::from(method))
+    }
+
+    /// Create an "invalid params" error, with the given description.
+    pub fn invalid_params(message: &str) -> Self {
+        RpcError::new(RpcError::INVALID_PARAMS, message)
+    }
+
+    /// Create an "internal error", with the given description.
+    pub fn internal_error(message: &str) -> Self {
+        RpcError::new(RpcError::INTERNAL_ERROR, message)
+    }
+
+    /// Create an "unsupported feature" error for the given feature or builtin method. Clients
+    /// that get it can fall back to plain requests (see
+    /// [`is_unsupported_feature`](#method.is_unsupported_feature)).
+    pub fn unsupported_feature(feature: &str) -> Self {
+        RpcError::new(RpcError::UNSUPPORTED_FEATURE, "unsupported feature")
+            .with_data(Value::from(feature))
+    }
+
+    /// Create a "rate limited" error, sent instead of handling a request that exceeds the rate
+    /// limits of the server (see [`Limits::connection_rate`](struct.Limits.html#method.connection_rate)).
+    pub fn rate_limited() -> Self {
+        RpcError::new(RpcError::RATE_LIMITED, "rate limited")
+    }
+
+    /// Return `true` if `error`, received in a response, means that the remote endpoint does not
+    /// support the extension that was used: either it sent an "unsupported feature" error, or it
+    /// does not know the method at all, as older endpoints do.
+    pub fn is_unsupported_feature(error: &Value) -> bool {
+        match RpcError::from_value(error) {
+            Some(error) => {
+                error.code == RpcError::UNSUPPORTED_FEATURE
+                    || error.code == RpcError::METHOD_NOT_FOUND
+            }
+            None => false,
+        }
+    }
+
+    /// Attach data to the error.
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Decode an error received in a response. Return `None` if it does not follow the
+    /// `RpcError` convention, in which case the raw value should be used instead.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let map = match value.as_map() {
+            Some(map) => map,
+            None => return None,
+        };
+        let mut code = None;
+        let mut message = None;
+        let mut data = None;
+        for &(ref key, ref value) in map {
+            match key.as_str() {
+                Some("code") => code = value.as_i64(),
+                Some("message") => message = value.as_str(),
+                Some("data") => data = Some(value.clone()),
+                _ => {}
+            }
+        }
+        match (code, message) {
+            (Some(code), Some(message)) => Some(RpcError {
+                code: code,
+                message: message.to_owned(),
+                data: data,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<RpcError> for Value {
+    fn from(err: RpcError) -> Value {
+        let mut map = vec![
+            (Value::from("code"), Value::from(err.code)),
+            (Value::from("message"), Value::from(err.message)),
+        ];
+        if let Some(data) = err.data {
+            map.push((Value::from("data"), data));
+        }
+        Value::Map(map)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl error::Error for RpcError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+#[test]
+fn rpc_error_round_trip() {
+    let err = RpcError::method_not_found("foo");
+    let value = Value::from(err.clone());
+    assert_eq!(RpcError::from_value(&value), Some(err));
+    assert_eq!(RpcError::from_value(&Value::from("failed")), None);
+
+    assert!(RpcError::is_unsupported_feature(&value));
+    let value = Value::from(RpcError::unsupported_feature("$/cancel"));
+    assert!(RpcError::is_unsupported_feature(&value));
+    assert!(!RpcError::is_unsupported_feature(&Value::from(RpcError::internal_error("failed"))));
+    assert!(!RpcError::is_unsupported_feature(&Value::from("failed")));
+}
@@ -0,0 +1,129 @@
+//! Framing of msgpack messages: finding where a message ends in a receive buffer, without
+//! decoding it. This is shared by the codec of the futures 0.1 endpoints and by the
+//! [`tokio1`](../tokio1/index.html) bridge.
+use std::io;
+
+/// Progress of the scan of a message that has not been completely received yet. The size of a
+/// message is found by scanning it before decoding it, and the scan resumes where it stopped when
+/// more bytes arrive, so that a message received in many reads is scanned once, and decoded once.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Scan {
+    // Number of bytes of the message scanned so far: the values before are complete.
+    pub(crate) pos: usize,
+    // Number of values still to scan. Each of them takes at least one byte.
+    pub(crate) pending: usize,
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Scan { pos: 0, pending: 1 }
+    }
+}
+
+impl Scan {
+    /// Return the size of the msgpack value at the start of `buf`, or `None` if `buf` does not
+    /// hold all of it yet. The value is only scanned: nothing is allocated, however large the
+    /// lengths it declares, and an error is returned as soon as it is known to be larger than
+    /// `max` bytes.
+    pub(crate) fn advance(&mut self, buf: &[u8], max: usize) -> io::Result<Option<usize>> {
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message larger than {} bytes", max),
+            )
+        };
+        let mut pos = self.pos;
+        let mut pending = self.pending;
+        while pending > 0 {
+            self.pos = pos;
+            self.pending = pending;
+            if pos.saturating_add(pending) > max {
+                return Err(too_large());
+            }
+            if pos >= buf.len() {
+                return Ok(None);
+            }
+            pending -= 1;
+            let marker = buf[pos];
+            pos += 1;
+            // Number of bytes of the length that follows the marker, and size of the ext type.
+            let (len_bytes, extra) = match marker {
+                0x80..=0x8f => {
+                    pending = pending.saturating_add(2 * (marker & 0x0f) as usize);
+                    continue;
+                }
+                0x90..=0x9f => {
+                    pending = pending.saturating_add((marker & 0x0f) as usize);
+                    continue;
+                }
+                0xa0..=0xbf => {
+                    pos += (marker & 0x1f) as usize;
+                    continue;
+                }
+                0xdc..=0xdf => {
+                    let len_bytes = if marker & 1 == 0 { 2 } else { 4 };
+                    let len = match read_len(&buf[pos..], len_bytes) {
+                        Some(len) => len,
+                        None => return Ok(None),
+                    };
+                    pos += len_bytes;
+                    let values = if marker >= 0xde { len.saturating_mul(2) } else { len };
+                    pending = pending.saturating_add(values);
+                    continue;
+                }
+                0xc4 | 0xd9 => (1, 0),
+                0xc5 | 0xda => (2, 0),
+                0xc6 | 0xdb => (4, 0),
+                0xc7 => (1, 1),
+                0xc8 => (2, 1),
+                0xc9 => (4, 1),
+                _ => {
+                    pos += fixed_size(marker);
+                    continue;
+                }
+            };
+            let len = match read_len(&buf[pos..], len_bytes) {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            pos = (pos + len_bytes).saturating_add(len + extra);
+        }
+        self.pos = pos;
+        self.pending = 0;
+        if pos > max {
+            return Err(too_large());
+        }
+        if pos > buf.len() {
+            return Ok(None);
+        }
+        Ok(Some(pos))
+    }
+}
+
+/// Read the big endian length of `bytes` bytes at the start of `buf`.
+fn read_len(buf: &[u8], bytes: usize) -> Option<usize> {
+    if buf.len() < bytes {
+        return None;
+    }
+    Some(
+        buf[..bytes]
+            .iter()
+            .fold(0, |len, byte| len << 8 | *byte as usize),
+    )
+}
+
+/// Return the number of bytes that follow a marker of a fixed size type.
+fn fixed_size(marker: u8) -> usize {
+    match marker {
+        0xcc | 0xd0 => 1,
+        0xcd | 0xd1 | 0xd4 => 2,
+        0xd5 => 3,
+        0xca | 0xce | 0xd2 => 4,
+        0xd6 => 5,
+        0xcb | 0xcf | 0xd3 => 8,
+        0xd7 => 9,
+        0xd8 => 17,
+        // Fixints, nil, booleans, and the unused marker, which the decoder rejects.
+        _ => 0,
+    }
+}
@@ -0,0 +1,2041 @@
+use std::cell::Cell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+#[cfg(unix)]
+use std::os::unix::net;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Future, Poll, Stream};
+use futures::stream::FuturesUnordered;
+use futures::sync::mpsc;
+use futures::task::{self, Task};
+use native_tls::TlsAcceptor;
+use net2::TcpBuilder;
+use num_cpus;
+use rmp::encode as rmp_encode;
+use rmpv::{encode, Value};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::TlsAcceptorExt;
+#[cfg(unix)]
+use tokio_uds::{self, UnixListener, UnixStream};
+
+use config::ServerConfig;
+use endpoint::{Endpoint, ServiceBuilder, BUILTIN_PREFIX};
+use errors::{CallError, RouterError, ServerError};
+use lifecycle::{ConnectionEvent, ConnectionEvents};
+use message::{Notification, Param, Request};
+use options::{Limits, ProtocolOptions};
+use throttle::Bucket;
+#[cfg(feature = "websocket")]
+use websocket;
+
+/// Prefix of the methods reserved for extensions of the protocol, which services cannot handle.
+const EXTENSION_PREFIX: &str = "rpc.";
+
+/// Default size of the queue of pending connections of a listener.
+const DEFAULT_BACKLOG: i32 = 1024;
+
+/// How long a server stops accepting connections after running out of file descriptors. The delay
+/// doubles each time it fails again, up to `MAX_ACCEPT_DELAY`.
+const INITIAL_ACCEPT_DELAY_MS: u64 = 10;
+const MAX_ACCEPT_DELAY_MS: u64 = 1_000;
+
+/// Too many open files in the system. It has the same value on all the Unix platforms.
+#[cfg(unix)]
+const ENFILE: i32 = 23;
+/// Too many open files in the process.
+#[cfg(unix)]
+const EMFILE: i32 = 24;
+/// Too many open sockets in the process.
+#[cfg(windows)]
+const WSAEMFILE: i32 = 10_024;
+
+/// Return `true` if `error` means that the process or the system ran out of file descriptors.
+#[cfg(unix)]
+fn is_fd_exhaustion(error: &io::Error) -> bool {
+    let code = error.raw_os_error();
+    code == Some(ENFILE) || code == Some(EMFILE)
+}
+
+/// Return `true` if `error` means that the process ran out of sockets.
+#[cfg(windows)]
+fn is_fd_exhaustion(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(WSAEMFILE)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_fd_exhaustion(_error: &io::Error) -> bool {
+    false
+}
+
+/// Options of the sockets of the accepted connections, or of the connections of a client.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SocketOptions {
+    pub(crate) nodelay: bool,
+    pub(crate) keepalive: Option<Duration>,
+    pub(crate) recv_buffer_size: Option<usize>,
+    pub(crate) send_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_keepalive(self.keepalive)?;
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// A listener of a server.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio_uds::Incoming),
+}
+
+/// A connection accepted by a `Listener`.
+enum Accepted {
+    // The stream is not registered on any reactor yet, so that it can be sent to a worker.
+    Tcp(StdTcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    fn poll_accept(&mut self) -> Poll<Option<Accepted>, io::Error> {
+        let accepted = match *self {
+            Listener::Tcp(ref mut listener) => match listener.accept_std() {
+                Ok((stream, address)) => Some(Accepted::Tcp(stream, address)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(e),
+            },
+            #[cfg(unix)]
+            Listener::Unix(ref mut incoming) => match incoming.poll()? {
+                Async::Ready(accepted) => accepted.map(Accepted::Unix),
+                Async::NotReady => return Ok(Async::NotReady),
+            },
+        };
+        Ok(Async::Ready(accepted))
+    }
+}
+
+/// Wrapper around a `TlsAcceptor`, so that it can be part of the `ServerBuilder`.
+#[derive(Clone)]
+struct TlsHook(Arc<TlsAcceptor>);
+
+impl fmt::Debug for TlsHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "TlsHook")
+    }
+}
+
+/// A builder for [`Server`](struct.Server.html)s.
+#[derive(Clone, Debug)]
+pub struct ServerBuilder {
+    address: SocketAddr,
+    // The other addresses the server listens on.
+    addresses: Vec<SocketAddr>,
+    #[cfg(unix)]
+    unix_sockets: Vec<PathBuf>,
+    options: ProtocolOptions,
+    sockets: SocketOptions,
+    backlog: i32,
+    max_connections: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    close_idle_on_exhaustion: bool,
+    reserved_prefixes: Vec<String>,
+    tls: Option<TlsHook>,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
+    worker_threads: Option<usize>,
+}
+
+impl ServerBuilder {
+    /// Create a builder for a server listening on the given address.
+    pub fn new(address: SocketAddr) -> Self {
+        ServerBuilder {
+            address: address,
+            addresses: Vec::new(),
+            #[cfg(unix)]
+            unix_sockets: Vec::new(),
+            options: ProtocolOptions::default(),
+            sockets: SocketOptions::default(),
+            backlog: DEFAULT_BACKLOG,
+            max_connections: None,
+            handshake_timeout: None,
+            close_idle_on_exhaustion: false,
+            reserved_prefixes: Vec::new(),
+            tls: None,
+            #[cfg(feature = "websocket")]
+            websocket: false,
+            worker_threads: None,
+        }
+    }
+
+    /// Create a builder from the given configuration.
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let mut builder = ServerBuilder::new(config.address);
+        let _ = builder.set_protocol_options(ProtocolOptions::from(&config.protocol));
+        if let Some(nodelay) = config.nodelay {
+            let _ = builder.set_nodelay(nodelay);
+        }
+        if config.keepalive_ms.is_some() {
+            let _ = builder.set_keepalive(config.keepalive_ms.map(Duration::from_millis));
+        }
+        if let Some(backlog) = config.backlog {
+            let _ = builder.set_backlog(backlog);
+        }
+        if config.max_connections.is_some() {
+            let _ = builder.set_max_connections(config.max_connections);
+        }
+        if config.recv_buffer_size.is_some() {
+            let _ = builder.set_recv_buffer_size(config.recv_buffer_size);
+        }
+        if config.send_buffer_size.is_some() {
+            let _ = builder.set_send_buffer_size(config.send_buffer_size);
+        }
+        if config.handshake_timeout_ms.is_some() {
+            let timeout = config.handshake_timeout_ms.map(Duration::from_millis);
+            let _ = builder.set_handshake_timeout(timeout);
+        }
+        if let Some(close) = config.close_idle_on_exhaustion {
+            let _ = builder.set_close_idle_on_exhaustion(close);
+        }
+        for prefix in &config.reserved_prefixes {
+            let _ = builder.reserve_prefix(prefix);
+        }
+        if config.worker_threads.is_some() {
+            let _ = builder.set_worker_threads(config.worker_threads);
+        }
+        builder
+    }
+
+    /// Set the options used for each connection the server accepts.
+    pub fn set_protocol_options(&mut self, options: ProtocolOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Also listen on the given address. The connections accepted on all the addresses are
+    /// handled by the same server, with the same options.
+    pub fn add_address(&mut self, address: SocketAddr) -> &mut Self {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Also listen on a Unix socket bound to the given path, for local clients. The connections
+    /// accepted on it are handled by the same server, with the same options, except the socket
+    /// options which only apply to TCP connections. Building the server fails if the path
+    /// already exists.
+    #[cfg(unix)]
+    pub fn add_unix_socket<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.unix_sockets.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the accepted connections, so that small messages are sent
+    /// immediately instead of being delayed by Nagle's algorithm. It is not set by default.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.sockets.nodelay = nodelay;
+        self
+    }
+
+    /// Enable TCP keepalive on the accepted connections, with the given interval. It is
+    /// disabled by default.
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) -> &mut Self {
+        self.sockets.keepalive = keepalive;
+        self
+    }
+
+    /// Set the size of the queue of connections waiting to be accepted. The default is 1024.
+    pub fn set_backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set the maximum number of connections open at the same time, including the connections
+    /// that are still performing their TLS or WebSocket handshake. Once it is reached, the new
+    /// connections are closed as soon as they are accepted. By default, there is no limit.
+    pub fn set_max_connections(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Set the size of the receive buffer of the sockets of the accepted connections
+    /// (`SO_RCVBUF`). By default, the size chosen by the operating system is kept.
+    pub fn set_recv_buffer_size(&mut self, size: Option<usize>) -> &mut Self {
+        self.sockets.recv_buffer_size = size;
+        self
+    }
+
+    /// Set the size of the send buffer of the sockets of the accepted connections (`SO_SNDBUF`).
+    /// By default, the size chosen by the operating system is kept.
+    pub fn set_send_buffer_size(&mut self, size: Option<usize>) -> &mut Self {
+        self.sockets.send_buffer_size = size;
+        self
+    }
+
+    /// Set how long the TLS and WebSocket handshakes of a new connection can take. Connections
+    /// that do not complete them in time are closed. By default, there is no timeout. The timeout
+    /// of the requests is set with the [`Limits`](struct.Limits.html).
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// If `close` is `true`, the server closes a connection that has no request in flight each
+    /// time it fails to accept a connection because it ran out of file descriptors, to make room
+    /// for the new ones. Either way, the server stops accepting connections for a short while
+    /// when this happens, and reports it to the
+    /// [`Metrics::accept_failed`](trait.Metrics.html#method.accept_failed) hook. It is disabled by
+    /// default.
+    pub fn set_close_idle_on_exhaustion(&mut self, close: bool) -> &mut Self {
+        self.close_idle_on_exhaustion = close;
+        self
+    }
+
+    /// Reserve the methods that start with `prefix` for the framework, so that the services cannot
+    /// shadow them by accident: the server refuses to start if the service builder has a handler
+    /// for one of them (see
+    /// [`ServiceBuilder::registered_methods`](trait.ServiceBuilder.html#method.registered_methods)).
+    /// The `"$/"` prefix of the builtin methods, like `"$/ping"`, and the `"rpc."` prefix of the
+    /// protocol extensions are always reserved. Handlers registered while the server runs are not
+    /// checked.
+    pub fn reserve_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.reserved_prefixes.push(prefix.to_owned());
+        self
+    }
+
+    /// Use TLS on the accepted connections, with the given acceptor.
+    pub fn set_tls(&mut self, acceptor: TlsAcceptor) -> &mut Self {
+        self.tls = Some(TlsHook(Arc::new(acceptor)));
+        self
+    }
+
+    /// Expect the clients to connect with WebSockets instead of raw TCP: each connection starts
+    /// with an HTTP upgrade handshake, and each message is then carried by a binary WebSocket
+    /// message. This makes it possible to serve browsers, or to sit behind an HTTP reverse proxy.
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket(&mut self, websocket: bool) -> &mut Self {
+        self.websocket = websocket;
+        self
+    }
+
+    /// Set the number of worker threads of the servers built with
+    /// [`build_multi_threaded`](#method.build_multi_threaded). By default, or if `None` is given,
+    /// there is one per core.
+    pub fn set_worker_threads(&mut self, threads: Option<usize>) -> &mut Self {
+        self.worker_threads = threads;
+        self
+    }
+
+    /// Bind the listeners, and return a server that uses `service_builder` to handle the
+    /// connections it accepts. Connections are only accepted once the server is polled.
+    pub fn build<B: ServiceBuilder + 'static>(
+        &self,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Server<B>, ServerError> {
+        let listener = self.bind(&self.address, handle)?;
+        let address = listener.local_addr()?;
+        self.build_with(Listener::Tcp(listener), Some(address), service_builder, handle)
+    }
+
+    /// Like [`build`](#method.build), but serve the TCP connections on a pool of worker threads
+    /// (see [`set_worker_threads`](#method.set_worker_threads)), each running its own reactor.
+    /// The server still accepts the connections on the reactor of `handle`, and hands them to
+    /// the workers in turn, so that a busy server is not limited to a single core.
+    ///
+    /// The workers share `service_builder`, hence the `Send` and `Sync` bounds, but each
+    /// connection is served by a single thread, so the services it builds do not need to be
+    /// `Send`. The connections accepted on Unix sockets are served on the reactor of `handle`.
+    /// The workers stop once the server stopped accepting connections, and their connections are
+    /// closed.
+    pub fn build_multi_threaded<B: ServiceBuilder + Send + Sync + 'static>(
+        &self,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Server<B>, ServerError> {
+        let mut server = self.build(service_builder, handle)?;
+        for i in 0..self.worker_threads.unwrap_or_else(num_cpus::get) {
+            server.start_worker(i)?;
+        }
+        Ok(server)
+    }
+
+    /// Like [`build`](#method.build), but accept connections on the given listener instead of
+    /// binding the address given to [`new`](#method.new). This makes it possible to run a server
+    /// on a socket inherited from a supervisor, as with systemd socket activation. The other
+    /// addresses and Unix sockets are bound as usual.
+    pub fn build_from_listener<B: ServiceBuilder + 'static>(
+        &self,
+        listener: StdTcpListener,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Server<B>, ServerError> {
+        let address = listener.local_addr()?;
+        let listener = TcpListener::from_listener(listener, &address, handle)?;
+        self.build_with(Listener::Tcp(listener), Some(address), service_builder, handle)
+    }
+
+    /// Like [`build_from_listener`](#method.build_from_listener), for a Unix socket.
+    #[cfg(unix)]
+    pub fn build_from_unix_listener<B: ServiceBuilder + 'static>(
+        &self,
+        listener: net::UnixListener,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Server<B>, ServerError> {
+        let listener = UnixListener::from_std(listener, handle.new_tokio_handle())?;
+        self.build_with(Listener::Unix(listener.incoming()), None, service_builder, handle)
+    }
+
+    /// Build a server that accepts connections on `listener`, whose address is `address`, and
+    /// on the other addresses and Unix sockets.
+    fn build_with<B: ServiceBuilder + 'static>(
+        &self,
+        listener: Listener,
+        address: Option<SocketAddr>,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Server<B>, ServerError> {
+        self.check_reserved(&service_builder)?;
+        service_builder
+            .validate()
+            .map_err(ServerError::InvalidRouter)?;
+        self.check_protected(&service_builder)?;
+        let server_handle = ServerHandle::new(self.options.get_limits());
+        server_handle.state.lock().unwrap().local_addr = address;
+        let mut listeners = vec![listener];
+        for address in &self.addresses {
+            listeners.push(Listener::Tcp(self.bind(address, handle)?));
+        }
+        #[cfg(unix)]
+        for path in &self.unix_sockets {
+            let listener = net::UnixListener::bind(path)?;
+            let listener = UnixListener::from_std(listener, handle.new_tokio_handle())?;
+            listeners.push(Listener::Unix(listener.incoming()));
+        }
+        Ok(Server {
+            listeners: listeners,
+            accepting: false,
+            shared: Shared {
+                service_builder: Arc::new(service_builder),
+                server_handle: server_handle.clone(),
+                sockets: self.sockets,
+                tls: self.tls.clone(),
+                #[cfg(feature = "websocket")]
+                websocket: self.websocket,
+            },
+            handle: handle.clone(),
+            options: self.options.clone(),
+            server_handle: server_handle,
+            max_connections: self.max_connections,
+            handshakes: Arc::new(AtomicUsize::new(0)),
+            handshake_timeout: self.handshake_timeout,
+            close_idle_on_exhaustion: self.close_idle_on_exhaustion,
+            accept_delay: None,
+            accept_backoff: None,
+            workers: Vec::new(),
+            next_worker: 0,
+        })
+    }
+
+    /// Fail if `service_builder` has a handler for a method in a reserved namespace.
+    fn check_reserved<B: ServiceBuilder>(&self, service_builder: &B) -> Result<(), ServerError> {
+        for method in service_builder.registered_methods() {
+            let prefix = self.reserved_prefixes
+                .iter()
+                .map(|prefix| prefix.as_str())
+                .chain(Some(BUILTIN_PREFIX))
+                .chain(Some(EXTENSION_PREFIX))
+                .find(|prefix| method.starts_with(prefix));
+            if let Some(prefix) = prefix {
+                return Err(ServerError::ReservedMethod {
+                    method: method.to_string(),
+                    prefix: prefix.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if `service_builder` has protected namespaces, but connections are not authenticated.
+    fn check_protected<B: ServiceBuilder>(&self, service_builder: &B) -> Result<(), ServerError> {
+        if self.options.get_authenticator().is_some() {
+            return Ok(());
+        }
+        match service_builder.protected_prefixes().into_iter().next() {
+            Some(prefix) => Err(ServerError::InvalidRouter(RouterError::Unauthenticated {
+                prefix: prefix,
+            })),
+            None => Ok(()),
+        }
+    }
+
+    fn bind(&self, address: &SocketAddr, handle: &Handle) -> Result<TcpListener, ServerError> {
+        self.listen(address, handle)
+            .map_err(|e| ServerError::Bind(*address, e))
+    }
+
+    fn listen(&self, address: &SocketAddr, handle: &Handle) -> io::Result<TcpListener> {
+        let builder = match *address {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+        };
+        // Like `TcpListener::bind`, so that the server can be restarted right away.
+        #[cfg(unix)]
+        let _ = builder.reuse_address(true)?;
+        let listener = builder.bind(address)?.listen(self.backlog)?;
+        TcpListener::from_listener(listener, address, handle)
+    }
+
+    /// Build the server and spawn it on the reactor. Return a handle to control it, which also
+    /// gives the address the server listens on: bind the server to port 0 to let the operating
+    /// system pick a free port, as in tests.
+    pub fn spawn<B: ServiceBuilder + 'static>(
+        &self,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<ServerHandle, ServerError> {
+        let server = self.build(service_builder, handle)?;
+        let server_handle = server.handle();
+        handle.spawn(server.map_err(|e| error!("The server failed: {}", e)));
+        Ok(server_handle)
+    }
+
+    /// Like [`spawn`](#method.spawn), for a server built with
+    /// [`build_multi_threaded`](#method.build_multi_threaded).
+    pub fn spawn_multi_threaded<B: ServiceBuilder + Send + Sync + 'static>(
+        &self,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<ServerHandle, ServerError> {
+        let server = self.build_multi_threaded(service_builder, handle)?;
+        let server_handle = server.handle();
+        handle.spawn(server.map_err(|e| error!("The server failed: {}", e)));
+        Ok(server_handle)
+    }
+}
+
+/// Usage of the quotas of a principal, across all its connections.
+struct Usage {
+    in_flight: usize,
+    // Start of the current one-second window, and number of requests accepted during it.
+    window_start: Instant,
+    window_requests: u32,
+    // The bytes of the requests, if the bandwidth is limited.
+    bandwidth: Option<Bucket>,
+}
+
+impl Usage {
+    fn new() -> Self {
+        Usage {
+            in_flight: 0,
+            window_start: Instant::now(),
+            window_requests: 0,
+            bandwidth: None,
+        }
+    }
+
+    fn is_idle(&mut self) -> bool {
+        let bandwidth_idle = match self.bandwidth {
+            Some(ref mut bucket) => bucket.is_full(),
+            None => true,
+        };
+        self.in_flight == 0 && self.window_start.elapsed() >= Duration::from_secs(1)
+            && bandwidth_idle
+    }
+}
+
+/// Return the number of bytes of `request` accounted in the bandwidth quota of its principal:
+/// its method and its encoded parameters.
+fn request_size(request: &Request) -> usize {
+    let mut size = ByteCount(request.method.len());
+    for param in &request.params {
+        // Counting the bytes cannot fail.
+        match *param {
+            Param::Value(ref value) => {
+                let _ = encode::write_value(&mut size, value);
+            }
+            Param::Binary(ref bytes) => {
+                let _ = rmp_encode::write_bin(&mut size, bytes);
+            }
+        }
+    }
+    size.0
+}
+
+/// A writer that only counts the bytes written into it.
+struct ByteCount(usize);
+
+impl io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A request to close a connection, made with `ServerHandle::disconnect`.
+pub(crate) struct Disconnect {
+    pub(crate) grace: Duration,
+    pub(crate) notification: Option<Notification>,
+}
+
+/// A graceful shutdown of the server, made with `ServerHandle::shutdown`.
+struct Shutdown {
+    // When the connections are closed, even if they still have requests in flight.
+    deadline: Instant,
+    notification: Option<Notification>,
+}
+
+impl Shutdown {
+    fn disconnect(&self) -> Disconnect {
+        let now = Instant::now();
+        Disconnect {
+            grace: if self.deadline > now {
+                self.deadline - now
+            } else {
+                Duration::from_secs(0)
+            },
+            notification: self.notification.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Connection {
+    // The task running the endpoint of the connection, to wake it up when it must be closed.
+    task: Option<Task>,
+    disconnect: Option<Disconnect>,
+    // `true` once the connection has been asked to close: it does not get broadcasts anymore.
+    closing: bool,
+    // `true` if the connection has no request in flight.
+    idle: bool,
+    // The notifications broadcast to the connection, that it has not sent yet.
+    broadcasts: Vec<Notification>,
+}
+
+/// Whether a server accepts connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Readiness {
+    // The server has not been polled yet.
+    Starting,
+    Accepting,
+    Stopped,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness::Starting
+    }
+}
+
+#[derive(Default)]
+struct State {
+    readiness: Readiness,
+    local_addr: Option<SocketAddr>,
+    // The tasks waiting for the server to accept connections.
+    ready_tasks: Vec<Task>,
+    draining: bool,
+    limits: Limits,
+    usage: HashMap<String, Usage>,
+    // When the idle principals were last forgotten.
+    usage_swept: Option<Instant>,
+    // The rate limits of the IP addresses the requests come from.
+    peers: HashMap<IpAddr, Bucket>,
+    // When the full buckets of the IP addresses were last forgotten.
+    peers_swept: Option<Instant>,
+    connections: HashMap<usize, Connection>,
+    // The task running the server, to wake it up when it must stop accepting connections.
+    task: Option<Task>,
+    shutdown: Option<Shutdown>,
+    // The tasks waiting for all the connections to be closed.
+    shutdown_tasks: Vec<Task>,
+    event_subscribers: Vec<mpsc::UnboundedSender<ConnectionEvent>>,
+}
+
+impl State {
+    /// Send an event to the subscribers, and forget about those who are gone.
+    fn emit(&mut self, event: ConnectionEvent) {
+        self.event_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// A handle to control a running [`Server`](struct.Server.html). It can be obtained with
+/// [`Server::handle`](struct.Server.html#method.handle).
+///
+/// `ServerHandle` is cheap to clone: all the clones control the same server.
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    state: Arc<Mutex<State>>,
+}
+
+impl ServerHandle {
+    fn new(limits: Limits) -> Self {
+        let handle = ServerHandle::default();
+        handle.state.lock().unwrap().limits = limits;
+        handle
+    }
+
+    /// Return the limits currently applied to the connections of the server.
+    pub fn limits(&self) -> Limits {
+        self.state.lock().unwrap().limits
+    }
+
+    /// Change the limits applied to the connections of the server, including the connections
+    /// that are already established.
+    pub fn set_limits(&self, limits: Limits) {
+        trace!("New limits: {:?}", limits);
+        self.state.lock().unwrap().limits = limits;
+    }
+
+    /// Account for a new request from the given principal. Return `None` if it exceeds the
+    /// quotas of the principal. Otherwise, the returned permit must be kept as long as the request
+    /// is in flight.
+    pub(crate) fn acquire_quota(
+        &self,
+        principal: &str,
+        request: &Request,
+    ) -> Option<QuotaPermit> {
+        let mut state = self.state.lock().unwrap();
+        let limits = state.limits;
+        // The idle principals are forgotten at most once a second, rather than on every request,
+        // since this goes through all of them. The usage of the others is reset when they send
+        // a request after their window.
+        let sweep = match state.usage_swept {
+            Some(swept) => swept.elapsed() >= Duration::from_secs(1),
+            None => true,
+        };
+        if sweep {
+            state.usage.retain(|_, usage| !usage.is_idle());
+            state.usage_swept = Some(Instant::now());
+        }
+        let usage = state
+            .usage
+            .entry(principal.to_owned())
+            .or_insert_with(Usage::new);
+        if usage.window_start.elapsed() >= Duration::from_secs(1) {
+            usage.window_start = Instant::now();
+            usage.window_requests = 0;
+        }
+        if let Some(max) = limits.get_principal_max_in_flight() {
+            if usage.in_flight >= max {
+                return None;
+            }
+        }
+        if let Some(rate) = limits.get_principal_rate() {
+            if usage.window_requests >= rate {
+                return None;
+            }
+        }
+        match limits.get_principal_bandwidth() {
+            Some(bandwidth) => {
+                let bucket = usage
+                    .bandwidth
+                    .get_or_insert_with(|| Bucket::new(bandwidth));
+                bucket.set_rate(bandwidth);
+                if !bucket.try_take_many(request_size(request)) {
+                    return None;
+                }
+            }
+            None => usage.bandwidth = None,
+        }
+        usage.in_flight += 1;
+        usage.window_requests += 1;
+        Some(QuotaPermit {
+            state: Arc::clone(&self.state),
+            principal: principal.to_owned(),
+        })
+    }
+
+    /// Account for a new request from the given IP address. Return `false` if it exceeds the rate
+    /// limit of the address.
+    pub(crate) fn acquire_peer_rate(&self, address: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let rate = match state.limits.get_peer_rate() {
+            Some(rate) => rate,
+            None => return true,
+        };
+        // A full bucket is no different from a new one. They are forgotten at most once a
+        // second, like the idle principals.
+        let sweep = match state.peers_swept {
+            Some(swept) => swept.elapsed() >= Duration::from_secs(1),
+            None => true,
+        };
+        if sweep {
+            state.peers.retain(|_, bucket| !bucket.is_full());
+            state.peers_swept = Some(Instant::now());
+        }
+        let bucket = state
+            .peers
+            .entry(address)
+            .or_insert_with(|| Bucket::new(rate));
+        bucket.set_rate(rate);
+        bucket.try_take()
+    }
+
+    /// Start draining the server: it stops accepting new connections, and the `Server` future
+    /// completes. The connections that are already established are kept open, so that the
+    /// requests in flight can complete, but the new requests they receive are answered with the
+    /// [`shutdown_error`](struct.ProtocolOptions.html#method.shutdown_error) instead of being
+    /// handled.
+    pub fn drain(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.draining {
+            return;
+        }
+        trace!("Draining the server");
+        state.draining = true;
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Return `true` if the server is draining.
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().draining
+    }
+
+    /// Shut the server down gracefully: it is drained (see [`drain`](#method.drain)), and all
+    /// its connections are closed as with [`disconnect`](#method.disconnect), including those
+    /// that are still performing their handshakes. The requests in flight are answered, and
+    /// the connections that still have requests in flight after `grace` are closed anyway.
+    ///
+    /// Return a future that resolves once all the connections are closed.
+    pub fn shutdown(&self, grace: Duration) -> ServerShutdown {
+        self.start_shutdown(grace, None)
+    }
+
+    /// Like [`shutdown`](#method.shutdown), but first send the given notification to all the
+    /// connections, to tell the clients to reconnect elsewhere for instance.
+    pub fn shutdown_with_notification(
+        &self,
+        grace: Duration,
+        method: &str,
+        params: &[Value],
+    ) -> ServerShutdown {
+        let notification = Notification {
+            method: method.to_owned(),
+            params: params.to_vec(),
+        };
+        self.start_shutdown(grace, Some(notification))
+    }
+
+    fn start_shutdown(
+        &self,
+        grace: Duration,
+        notification: Option<Notification>,
+    ) -> ServerShutdown {
+        self.drain();
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown.is_none() {
+            trace!("Shutting down the server, with a grace period of {:?}", grace);
+            let shutdown = Shutdown {
+                deadline: Instant::now() + grace,
+                notification: notification,
+            };
+            for connection in state.connections.values_mut() {
+                if connection.closing {
+                    continue;
+                }
+                connection.disconnect = Some(shutdown.disconnect());
+                connection.closing = true;
+                if let Some(task) = connection.task.take() {
+                    task.notify();
+                }
+            }
+            state.shutdown = Some(shutdown);
+        }
+        ServerShutdown {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Return the address the server listens on (see
+    /// [`Server::local_addr`](struct.Server.html#method.local_addr)). Return `None` if the handle
+    /// was not obtained from a `Server`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().local_addr
+    }
+
+    /// Return a future that resolves with the address the server listens on (see
+    /// [`Server::local_addr`](struct.Server.html#method.local_addr)), once it accepts
+    /// connections, i.e. once the `Server` future is running. The future fails if the server is
+    /// not running anymore.
+    pub fn ready(&self) -> ServerReady {
+        ServerReady {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    fn set_readiness(&self, readiness: Readiness) {
+        let mut state = self.state.lock().unwrap();
+        if state.readiness == readiness {
+            return;
+        }
+        trace!("Server readiness: {:?}", readiness);
+        state.readiness = readiness;
+        for task in state.ready_tasks.drain(..) {
+            task.notify();
+        }
+    }
+
+    /// Return a stream of the connections being opened and closed, starting from now, so that
+    /// applications can keep track of who is connected. Each call returns a new stream that
+    /// receives all the events.
+    pub fn events(&self) -> ConnectionEvents {
+        let (tx, rx) = mpsc::unbounded();
+        self.state.lock().unwrap().event_subscribers.push(tx);
+        ConnectionEvents::new(rx)
+    }
+
+    /// Report a connection that failed before it was served.
+    fn connection_failed(&self, peer_addr: Option<SocketAddr>, error: &io::Error) {
+        self.state.lock().unwrap().emit(ConnectionEvent::Failed {
+            peer_addr: peer_addr,
+            error: CallError::from(error),
+        });
+    }
+
+    /// Return the ids of the connections currently open (see
+    /// [`Context::connection_id`](struct.Context.html#method.connection_id)).
+    pub fn connections(&self) -> Vec<usize> {
+        self.state.lock().unwrap().connections.keys().cloned().collect()
+    }
+
+    /// Close the given connection, without affecting the others: no more messages are read from
+    /// it, the requests in flight are answered, and the connection is closed as soon as they are.
+    /// If they take more than `grace`, they are abandoned and the connection is closed anyway.
+    ///
+    /// Return `false` if the connection is not open.
+    pub fn disconnect(&self, connection: usize, grace: Duration) -> bool {
+        self.request_disconnect(connection, grace, None)
+    }
+
+    /// Like [`disconnect`](#method.disconnect), but first send the given notification to the
+    /// remote endpoint, to tell it why it is being disconnected for instance.
+    pub fn disconnect_with_notification(
+        &self,
+        connection: usize,
+        grace: Duration,
+        method: &str,
+        params: &[Value],
+    ) -> bool {
+        let notification = Notification {
+            method: method.to_owned(),
+            params: params.to_vec(),
+        };
+        self.request_disconnect(connection, grace, Some(notification))
+    }
+
+    fn request_disconnect(
+        &self,
+        connection: usize,
+        grace: Duration,
+        notification: Option<Notification>,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let connection = match state.connections.get_mut(&connection) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        connection.disconnect = Some(Disconnect {
+            grace: grace,
+            notification: notification,
+        });
+        connection.closing = true;
+        if let Some(task) = connection.task.take() {
+            task.notify();
+        }
+        true
+    }
+
+    /// Close one of the connections that have no request in flight, the oldest one. Return
+    /// `false` if there is none.
+    fn close_idle_connection(&self) -> bool {
+        let idle = {
+            let state = self.state.lock().unwrap();
+            state
+                .connections
+                .iter()
+                .filter(|&(_, connection)| connection.idle && !connection.closing)
+                .map(|(id, _)| *id)
+                .min()
+        };
+        match idle {
+            Some(id) => {
+                warn!("Closing idle connection {} to free a file descriptor", id);
+                self.disconnect(id, Duration::from_secs(0))
+            }
+            None => false,
+        }
+    }
+
+    /// Send a notification to all the connections currently open, except those that are being
+    /// closed. Return the number of connections the notification is sent to.
+    ///
+    /// The notification is queued, and each connection sends it the next time it runs, after the
+    /// messages it already queued.
+    pub fn broadcast(&self, method: &str, params: &[Value]) -> usize {
+        self.broadcast_filtered(method, params, |_| true)
+    }
+
+    /// Like [`broadcast`](#method.broadcast), but only send the notification to the connections
+    /// for which `filter` returns `true`. `filter` is given the id of each connection (see
+    /// [`Context::connection_id`](struct.Context.html#method.connection_id)).
+    pub fn broadcast_filtered<F>(&self, method: &str, params: &[Value], mut filter: F) -> usize
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut state = self.state.lock().unwrap();
+        let mut count = 0;
+        for (id, connection) in &mut state.connections {
+            if connection.closing || !filter(*id) {
+                continue;
+            }
+            connection.broadcasts.push(Notification {
+                method: method.to_owned(),
+                params: params.to_vec(),
+            });
+            if let Some(task) = connection.task.take() {
+                task.notify();
+            }
+            count += 1;
+        }
+        trace!("Broadcasting {} to {} connections", method, count);
+        count
+    }
+
+    /// Return the number of connections currently open.
+    pub(crate) fn connection_count(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+
+    /// Account for a new connection from `peer_addr`. It is open until the returned registration
+    /// is dropped.
+    pub(crate) fn register(&self, connection: usize, peer_addr: Option<SocketAddr>) -> Registration {
+        let mut state = self.state.lock().unwrap();
+        let mut new_connection = Connection::default();
+        // Connections that complete their handshakes during a shutdown are closed right away.
+        if let Some(ref shutdown) = state.shutdown {
+            new_connection.disconnect = Some(shutdown.disconnect());
+            new_connection.closing = true;
+        }
+        let _ = state.connections.insert(connection, new_connection);
+        state.emit(ConnectionEvent::Connected {
+            connection: connection,
+            peer_addr: peer_addr,
+        });
+        Registration {
+            state: Arc::clone(&self.state),
+            connection: connection,
+            peer_addr: peer_addr,
+            reason: None,
+        }
+    }
+}
+
+/// A connection accounted in the connections of a server.
+pub(crate) struct Registration {
+    state: Arc<Mutex<State>>,
+    connection: usize,
+    peer_addr: Option<SocketAddr>,
+    // Why the connection was closed, once it is.
+    reason: Option<CallError>,
+}
+
+impl Registration {
+    /// Record why the connection was closed.
+    pub(crate) fn set_close_reason(&mut self, reason: CallError) {
+        self.reason = Some(reason);
+    }
+
+    /// Return the pending request to close the connection, if any. Otherwise, the current task is
+    /// notified when such a request is made.
+    pub(crate) fn poll_disconnect(&self) -> Option<Disconnect> {
+        let mut state = self.state.lock().unwrap();
+        let connection = match state.connections.get_mut(&self.connection) {
+            Some(connection) => connection,
+            None => return None,
+        };
+        let disconnect = connection.disconnect.take();
+        if disconnect.is_none() {
+            connection.task = Some(task::current());
+        }
+        disconnect
+    }
+
+    /// Tell whether the connection has requests in flight.
+    pub(crate) fn set_idle(&self, idle: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(connection) = state.connections.get_mut(&self.connection) {
+            connection.idle = idle;
+        }
+    }
+
+    /// Return the notifications broadcast to the connection since the last call. The current task
+    /// is notified by the next broadcast, as long as `poll_disconnect` is called in the same poll.
+    pub(crate) fn take_broadcasts(&self) -> Vec<Notification> {
+        let mut state = self.state.lock().unwrap();
+        match state.connections.get_mut(&self.connection) {
+            Some(connection) => connection.broadcasts.split_off(0),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let _ = state.connections.remove(&self.connection);
+        state.emit(ConnectionEvent::Disconnected {
+            connection: self.connection,
+            peer_addr: self.peer_addr,
+            reason: self.reason.unwrap_or(CallError::ConnectionClosed),
+        });
+        if state.connections.is_empty() {
+            for task in state.shutdown_tasks.drain(..) {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// A future that resolves once all the connections of a server that is shutting down are closed
+/// (see [`ServerHandle::shutdown`](struct.ServerHandle.html#method.shutdown)). It never fails.
+pub struct ServerShutdown {
+    state: Arc<Mutex<State>>,
+}
+
+impl Future for ServerShutdown {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.connections.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        state.shutdown_tasks.push(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+/// A future that resolves once a server accepts connections (see
+/// [`ServerHandle::ready`](struct.ServerHandle.html#method.ready)).
+pub struct ServerReady {
+    state: Arc<Mutex<State>>,
+}
+
+impl Future for ServerReady {
+    type Item = Option<SocketAddr>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.readiness {
+            Readiness::Accepting => Ok(Async::Ready(state.local_addr)),
+            Readiness::Stopped => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the server is not running",
+            )),
+            Readiness::Starting => {
+                state.ready_tasks.push(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// A request accounted in the quotas of a principal. The request is not in flight anymore once
+/// the permit is dropped.
+pub(crate) struct QuotaPermit {
+    state: Arc<Mutex<State>>,
+    principal: String,
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(usage) = state.usage.get_mut(&self.principal) {
+            usage.in_flight -= 1;
+        }
+    }
+}
+
+/// A `MessagePack-RPC` server. It is a future that accepts connections and spawns an endpoint on
+/// the reactor for each of them, until it is drained (see
+/// [`ServerHandle::drain`](struct.ServerHandle.html#method.drain)).
+pub struct Server<B> {
+    listeners: Vec<Listener>,
+    // `true` once the server has been polled.
+    accepting: bool,
+    shared: Shared<B>,
+    handle: Handle,
+    options: ProtocolOptions,
+    server_handle: ServerHandle,
+    max_connections: Option<usize>,
+    // Number of connections still performing their handshakes, on any thread.
+    handshakes: Arc<AtomicUsize>,
+    handshake_timeout: Option<Duration>,
+    close_idle_on_exhaustion: bool,
+    // The current delay before accepting connections again after running out of file
+    // descriptors, reset once a connection is accepted.
+    accept_delay: Option<Duration>,
+    // Set while the server does not accept connections because it ran out of file descriptors.
+    accept_backoff: Option<Timeout>,
+    // The workers the TCP connections are handed to, if the server is multi-threaded.
+    workers: Vec<mpsc::UnboundedSender<Job<StdTcpStream>>>,
+    next_worker: usize,
+}
+
+impl<B: ServiceBuilder + 'static> Server<B> {
+    /// Create a server listening on the given address, with the default options. Connections
+    /// are only accepted once the server is polled. See also
+    /// [`ServerBuilder`](struct.ServerBuilder.html).
+    pub fn bind(
+        address: &SocketAddr,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Self, ServerError> {
+        ServerBuilder::new(*address).build(service_builder, handle)
+    }
+
+    /// Create a server that accepts connections on the given listener, with the default options,
+    /// for instance a socket inherited from a supervisor. See also
+    /// [`ServerBuilder::build_from_listener`](struct.ServerBuilder.html#method.build_from_listener).
+    pub fn from_listener(
+        listener: StdTcpListener,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Self, ServerError> {
+        let address = listener.local_addr()?;
+        ServerBuilder::new(address).build_from_listener(listener, service_builder, handle)
+    }
+
+    /// Like [`from_listener`](#method.from_listener), for a Unix socket.
+    #[cfg(unix)]
+    pub fn from_unix_listener(
+        listener: net::UnixListener,
+        service_builder: B,
+        handle: &Handle,
+    ) -> Result<Self, ServerError> {
+        // The address is not used: the server does not bind any TCP listener.
+        ServerBuilder::new(SocketAddr::from(([0, 0, 0, 0], 0)))
+            .build_from_unix_listener(listener, service_builder, handle)
+    }
+
+    /// Set the options used for each connection the server accepts.
+    pub fn set_protocol_options(&mut self, options: ProtocolOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Return a handle to control the server.
+    pub fn handle(&self) -> ServerHandle {
+        self.server_handle.clone()
+    }
+
+    /// Shut the server down gracefully (see
+    /// [`ServerHandle::shutdown`](struct.ServerHandle.html#method.shutdown)).
+    pub fn shutdown(&self, grace: Duration) -> ServerShutdown {
+        self.server_handle.shutdown(grace)
+    }
+
+    /// Return the address the server listens on, i.e. the address given to
+    /// [`ServerBuilder::new`](struct.ServerBuilder.html#method.new), or the address of the
+    /// listener the server was built from. If the server was bound to port 0, it holds the port
+    /// assigned by the operating system. Return `None` if the server was built from a Unix
+    /// socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server_handle.local_addr()
+    }
+
+    fn accept(&mut self) -> Poll<(), io::Error> {
+        if let Some(mut backoff) = self.accept_backoff.take() {
+            if self.is_draining() {
+                trace!("The server is draining, not accepting connections anymore");
+                return Ok(Async::Ready(()));
+            }
+            if backoff.poll()?.is_not_ready() {
+                self.accept_backoff = Some(backoff);
+                return Ok(Async::NotReady);
+            }
+            trace!("Accepting connections again");
+        }
+        let mut i = 0;
+        while i < self.listeners.len() {
+            if self.is_draining() {
+                trace!("The server is draining, not accepting connections anymore");
+                return Ok(Async::Ready(()));
+            }
+            let accepted = match self.listeners[i].poll_accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if is_fd_exhaustion(e) => {
+                    self.back_off(e)?;
+                    // Poll the timer, so that the task is notified when it fires.
+                    return self.accept();
+                }
+                Err(e) => return Err(e),
+            };
+            match accepted {
+                Async::Ready(Some(Accepted::Tcp(stream, address))) => {
+                    trace!("Accepted connection from {}", address);
+                    self.accept_delay = None;
+                    if self.is_full() {
+                        warn!("Too many connections, closing the connection from {}", address);
+                        continue;
+                    }
+                    let job = self.job(stream, Some(address));
+                    self.dispatch(job);
+                }
+                #[cfg(unix)]
+                Async::Ready(Some(Accepted::Unix(stream))) => {
+                    trace!("Accepted connection on a Unix socket");
+                    self.accept_delay = None;
+                    if self.is_full() {
+                        warn!("Too many connections, closing a connection on a Unix socket");
+                        continue;
+                    }
+                    let job = self.job(stream, None);
+                    self.handle.spawn(self.shared.serve(job, &self.handle));
+                }
+                Async::Ready(None) => {
+                    let _ = self.listeners.remove(i);
+                }
+                Async::NotReady => i += 1,
+            }
+        }
+        if self.listeners.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+
+    /// Stop accepting connections for a while, after running out of file descriptors.
+    fn back_off(&mut self, error: &io::Error) -> io::Result<()> {
+        let delay = match self.accept_delay {
+            Some(delay) => cmp::min(delay * 2, Duration::from_millis(MAX_ACCEPT_DELAY_MS)),
+            None => Duration::from_millis(INITIAL_ACCEPT_DELAY_MS),
+        };
+        error!("Failed to accept a connection: {}. Retrying in {:?}", error, delay);
+        if let Some(metrics) = self.options.get_metrics() {
+            metrics.accept_failed(error);
+        }
+        if self.close_idle_on_exhaustion && !self.server_handle.close_idle_connection() {
+            warn!("No idle connection to close");
+        }
+        self.accept_delay = Some(delay);
+        self.accept_backoff = Some(Timeout::new(delay, &self.handle)?);
+        Ok(())
+    }
+
+    fn is_draining(&self) -> bool {
+        let mut state = self.server_handle.state.lock().unwrap();
+        if !state.draining {
+            state.task = Some(task::current());
+        }
+        state.draining
+    }
+
+    fn is_full(&self) -> bool {
+        match self.max_connections {
+            Some(max) => {
+                let handshakes = self.handshakes.load(Ordering::SeqCst);
+                self.server_handle.connection_count() + handshakes >= max
+            }
+            None => false,
+        }
+    }
+
+    /// Return the job of serving a newly accepted connection. `address` is `None` for the
+    /// connections accepted on a Unix socket.
+    fn job<T>(&self, stream: T, address: Option<SocketAddr>) -> Job<T> {
+        let _ = self.handshakes.fetch_add(1, Ordering::SeqCst);
+        Job {
+            stream: stream,
+            address: address,
+            options: self.options.clone(),
+            deadline: self.handshake_timeout
+                .map(|timeout| Instant::now() + timeout),
+            handshake: Handshake(Arc::clone(&self.handshakes)),
+        }
+    }
+
+    /// Hand a TCP connection to the next worker, or serve it on the reactor of the server if it
+    /// has none.
+    fn dispatch(&mut self, mut job: Job<StdTcpStream>) {
+        if !self.workers.is_empty() {
+            let i = self.next_worker % self.workers.len();
+            self.next_worker = i + 1;
+            match self.workers[i].unbounded_send(job) {
+                Ok(()) => return,
+                Err(e) => {
+                    error!("A worker of the server is gone, serving its connections here");
+                    let _ = self.workers.remove(i);
+                    job = e.into_inner();
+                }
+            }
+        }
+        self.handle.spawn(self.shared.serve_tcp(job, &self.handle));
+    }
+}
+
+impl<B: ServiceBuilder + Send + Sync + 'static> Server<B> {
+    /// Start a worker thread, that serves the TCP connections it is handed on its own reactor.
+    fn start_worker(&mut self, index: usize) -> io::Result<()> {
+        let (sender, jobs) = mpsc::unbounded();
+        let shared = self.shared.clone();
+        let _ = thread::Builder::new()
+            .name(format!("rmp-rpc-worker-{}", index))
+            .spawn(move || {
+                let mut core = match Core::new() {
+                    Ok(core) => core,
+                    Err(e) => return error!("Failed to start a worker of the server: {}", e),
+                };
+                let worker = Worker {
+                    shared: shared,
+                    handle: core.handle(),
+                    jobs: Some(jobs),
+                    connections: FuturesUnordered::new(),
+                };
+                let _ = core.run(worker);
+            })?;
+        self.workers.push(sender);
+        Ok(())
+    }
+}
+
+/// What the connections accepted by a server have in common. It is cloned by the workers of a
+/// multi-threaded server.
+struct Shared<B> {
+    service_builder: Arc<B>,
+    server_handle: ServerHandle,
+    sockets: SocketOptions,
+    tls: Option<TlsHook>,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
+}
+
+impl<B> Clone for Shared<B> {
+    fn clone(&self) -> Self {
+        Shared {
+            service_builder: Arc::clone(&self.service_builder),
+            server_handle: self.server_handle.clone(),
+            sockets: self.sockets,
+            tls: self.tls.clone(),
+            #[cfg(feature = "websocket")]
+            websocket: self.websocket,
+        }
+    }
+}
+
+impl<B: ServiceBuilder + 'static> Shared<B> {
+    /// Register a TCP connection on the reactor of `handle`, and serve it.
+    fn serve_tcp(
+        &self,
+        job: Job<StdTcpStream>,
+        handle: &Handle,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let address = job.address;
+        let stream = match TcpStream::from_stream(job.stream, handle) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to register the connection from {:?}: {}", address, e);
+                self.server_handle.connection_failed(address, &e);
+                return Box::new(future::ok(()));
+            }
+        };
+        if let Err(e) = self.sockets.apply(&stream) {
+            warn!("Failed to set the socket options of {:?}: {}", address, e);
+        }
+        self.serve(
+            Job {
+                stream: stream,
+                address: address,
+                options: job.options,
+                deadline: job.deadline,
+                handshake: job.handshake,
+            },
+            handle,
+        )
+    }
+
+    /// Return a future that performs the handshakes of a connection, and then runs its endpoint
+    /// on the reactor of `handle`.
+    fn serve<T>(&self, job: Job<T>, handle: &Handle) -> Box<Future<Item = (), Error = ()>>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let address = job.address;
+        let served = Rc::new(Cell::new(false));
+        let accept = Accept {
+            service_builder: Arc::clone(&self.service_builder),
+            options: job.options,
+            server_handle: self.server_handle.clone(),
+            handle: handle.clone(),
+            address: address,
+            deadline: job.deadline,
+            handshake: job.handshake,
+            served: Rc::clone(&served),
+            #[cfg(feature = "websocket")]
+            websocket: self.websocket,
+        };
+        let connection = match self.tls {
+            Some(TlsHook(ref acceptor)) => {
+                let handshake = acceptor
+                    .accept_async(job.stream)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+                let handshake = accept.with_deadline(handshake);
+                Box::new(handshake.and_then(move |stream| accept.start(stream)))
+            }
+            None => accept.start(job.stream),
+        };
+        let server_handle = self.server_handle.clone();
+        Box::new(connection.map_err(move |e| {
+            match address {
+                Some(address) => warn!("Connection from {} failed: {}", address, e),
+                None => warn!("Connection on a Unix socket failed: {}", e),
+            }
+            // The connections that are served report their own failures.
+            if !served.get() {
+                server_handle.connection_failed(address, &e);
+            }
+        }))
+    }
+}
+
+/// A connection accepted by a server, that remains to be served. A TCP connection is handed to a
+/// worker before its stream is registered on a reactor.
+struct Job<T> {
+    stream: T,
+    address: Option<SocketAddr>,
+    options: ProtocolOptions,
+    // When the handshakes must be done.
+    deadline: Option<Instant>,
+    handshake: Handshake,
+}
+
+/// A worker of a multi-threaded server, serving the TCP connections it is handed on its own
+/// reactor. It is done once the server stopped accepting connections, and all the connections
+/// it served are closed.
+struct Worker<B> {
+    shared: Shared<B>,
+    handle: Handle,
+    // `None` once the server is gone.
+    jobs: Option<mpsc::UnboundedReceiver<Job<StdTcpStream>>>,
+    connections: FuturesUnordered<Box<Future<Item = (), Error = ()>>>,
+}
+
+impl<B: ServiceBuilder + 'static> Future for Worker<B> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let job = match self.jobs {
+                Some(ref mut jobs) => match jobs.poll()? {
+                    Async::Ready(job) => job,
+                    Async::NotReady => break,
+                },
+                None => break,
+            };
+            match job {
+                Some(job) => {
+                    let connection = self.shared.serve_tcp(job, &self.handle);
+                    self.connections.push(connection);
+                }
+                None => {
+                    trace!("The server stopped accepting connections");
+                    self.jobs = None;
+                }
+            }
+        }
+        loop {
+            match self.connections.poll() {
+                // The connections report their own failures.
+                Ok(Async::Ready(Some(()))) | Err(()) => {}
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+            }
+        }
+        if self.jobs.is_none() && self.connections.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A connection performing its handshakes. It is not counted anymore once this is dropped.
+struct Handshake(Arc<AtomicUsize>);
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        let _ = self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What is needed to start the endpoint of an accepted connection, once the handshakes are done.
+struct Accept<B> {
+    service_builder: Arc<B>,
+    options: ProtocolOptions,
+    server_handle: ServerHandle,
+    handle: Handle,
+    address: Option<SocketAddr>,
+    // When the handshakes must be done.
+    deadline: Option<Instant>,
+    handshake: Handshake,
+    // Set once the handshakes are done, and the connection is served.
+    served: Rc<Cell<bool>>,
+    #[cfg(feature = "websocket")]
+    websocket: bool,
+}
+
+impl<B: ServiceBuilder + 'static> Accept<B> {
+    /// Make the given handshake fail if it is not done before the deadline.
+    fn with_deadline<F>(&self, handshake: F) -> Box<Future<Item = F::Item, Error = io::Error>>
+    where
+        F: Future<Error = io::Error> + 'static,
+    {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Box::new(handshake),
+        };
+        let timeout = match Timeout::new_at(deadline, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let timeout = timeout.and_then(|()| {
+            Err::<F::Item, _>(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))
+        });
+        Box::new(
+            handshake
+                .select(timeout)
+                .map(|(stream, _)| stream)
+                .map_err(|(e, _)| e),
+        )
+    }
+
+    /// Return a future that runs the endpoint on the given stream, after the WebSocket handshake
+    /// if needed.
+    fn start<T>(self, stream: T) -> Box<Future<Item = (), Error = io::Error>>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        #[cfg(feature = "websocket")]
+        {
+            if self.websocket {
+                let mut handshake = websocket::accept(stream);
+                let _ = handshake.set_max_frame_size(self.options.get_websocket_max_frame_size());
+                let handshake = self.with_deadline(handshake);
+                let endpoint = handshake.and_then(move |stream| {
+                    let mut endpoint = self.endpoint(stream);
+                    endpoint.set_message_aligned_writes();
+                    endpoint
+                });
+                return Box::new(endpoint);
+            }
+        }
+        Box::new(self.endpoint(stream))
+    }
+
+    fn endpoint<T: AsyncRead + AsyncWrite>(self, stream: T) -> Endpoint<B::Service, T> {
+        let mut endpoint = Endpoint::new(stream, self.options);
+        if let Some(address) = self.address {
+            endpoint.set_peer_addr(address);
+        }
+        endpoint.set_server_handle(self.server_handle);
+        endpoint.set_reactor(self.handle);
+        self.served.set(true);
+        let client_proxy = endpoint.set_client();
+        endpoint.set_server(self.service_builder.build(client_proxy));
+        // The connection is now accounted by the server handle.
+        drop(self.handshake);
+        endpoint
+    }
+}
+
+impl<B: ServiceBuilder + 'static> Future for Server<B> {
+    type Item = ();
+    type Error = ServerError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.accepting {
+            self.accepting = true;
+            self.server_handle.set_readiness(Readiness::Accepting);
+        }
+        let result = self.accept().map_err(ServerError::from);
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        self.server_handle.set_readiness(Readiness::Stopped);
+        result
+    }
+}
+
+impl<B> Drop for Server<B> {
+    fn drop(&mut self) {
+        self.server_handle.set_readiness(Readiness::Stopped);
+    }
+}
+
+#[test]
+fn principal_quotas() {
+    use message::Id;
+
+    let mut limits = Limits::new();
+    let _ = limits.principal_max_in_flight(Some(1)).principal_rate(Some(2));
+    let handle = ServerHandle::new(limits);
+    let request = Request {
+        id: Id::from(0_u32),
+        method: "upload".to_owned(),
+        params: vec![Param::Value(Value::from(vec![0_u8; 1000]))],
+    };
+
+    let permit = handle.acquire_quota("alice", &request).unwrap();
+    assert!(handle.acquire_quota("alice", &request).is_none());
+    assert!(handle.acquire_quota("bob", &request).is_some());
+    drop(permit);
+    let permit = handle.acquire_quota("alice", &request).unwrap();
+    drop(permit);
+    // Two requests have been accepted for alice during this second.
+    assert!(handle.acquire_quota("alice", &request).is_none());
+
+    // Bob is idle, but is only forgotten once a second has passed since the last sweep.
+    let past = Instant::now() - Duration::from_secs(2);
+    handle.state.lock().unwrap().usage.get_mut("bob").unwrap().window_start = past;
+    assert!(handle.acquire_quota("carol", &request).is_some());
+    assert!(handle.state.lock().unwrap().usage.contains_key("bob"));
+    handle.state.lock().unwrap().usage_swept = Some(past);
+    assert!(handle.acquire_quota("carol", &request).is_some());
+    assert!(!handle.state.lock().unwrap().usage.contains_key("bob"));
+
+    // The bandwidth quota allows a request as long as some bytes are left.
+    let mut limits = Limits::new();
+    let _ = limits.principal_bandwidth(Some(1500));
+    handle.set_limits(limits);
+    assert_eq!(request_size(&request), 1009);
+    assert!(handle.acquire_quota("dave", &request).is_some());
+    assert!(handle.acquire_quota("dave", &request).is_some());
+    assert!(handle.acquire_quota("dave", &request).is_none());
+    assert!(handle.acquire_quota("erin", &request).is_some());
+}
+
+#[test]
+fn rate_limits() {
+    use futures::future;
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+    use net::ClientOnlyConnector;
+    use rpc_error::RpcError;
+
+    let mut limits = Limits::new();
+    let _ = limits.connection_rate(Some(2)).peer_rate(Some(3));
+    let mut options = ProtocolOptions::default();
+    let _ = options.limits(limits);
+    let mut router = MethodRouter::new();
+    let _ = router.request("ping", |_| Box::new(future::ok(Ok(Value::Nil))));
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+        .set_protocol_options(options)
+        .spawn(router, &handle)
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    let rate_limited = Err(Value::from(RpcError::rate_limited()));
+
+    // The third request exceeds the rate of the connection.
+    let client = core.run(ClientOnlyConnector::new(&address, &handle).connect()).unwrap();
+    let requests = (0..3).map(|_| client.request("ping", &[])).collect::<Vec<_>>();
+    let results = core.run(future::join_all(requests)).unwrap();
+    assert_eq!(results, vec![Ok(Value::Nil), Ok(Value::Nil), rate_limited.clone()]);
+
+    // The second request exceeds the rate of the address, shared with the first connection.
+    let client = core.run(ClientOnlyConnector::new(&address, &handle).connect()).unwrap();
+    let requests = (0..2).map(|_| client.request("ping", &[])).collect::<Vec<_>>();
+    let results = core.run(future::join_all(requests)).unwrap();
+    assert_eq!(results, vec![Ok(Value::Nil), rate_limited]);
+}
+
+#[test]
+fn peer_rate_sweep() {
+    let mut limits = Limits::new();
+    let _ = limits.peer_rate(Some(2));
+    let handle = ServerHandle::new(limits);
+    let first: IpAddr = "10.0.0.1".parse().unwrap();
+    let second: IpAddr = "10.0.0.2".parse().unwrap();
+    assert!(handle.acquire_peer_rate(first));
+
+    // The first address has a full bucket again, but is only forgotten once a second has passed
+    // since the last sweep.
+    let _ = handle.state.lock().unwrap().peers.insert(first, Bucket::new(2));
+    assert!(handle.acquire_peer_rate(second));
+    assert!(handle.state.lock().unwrap().peers.contains_key(&first));
+    handle.state.lock().unwrap().peers_swept = Some(Instant::now() - Duration::from_secs(2));
+    assert!(handle.acquire_peer_rate(second));
+    assert!(!handle.state.lock().unwrap().peers.contains_key(&first));
+}
+
+#[test]
+fn ephemeral_port() {
+    use net::NoService;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+    let first = builder.spawn(NoService, &core.handle()).unwrap();
+    let second = builder.spawn(NoService, &core.handle()).unwrap();
+    let address = first.local_addr().unwrap();
+    assert_ne!(address.port(), 0);
+    assert_ne!(address, second.local_addr().unwrap());
+    assert_eq!(core.run(first.ready()).unwrap(), Some(address));
+    assert_eq!(ServerHandle::default().local_addr(), None);
+}
+
+#[test]
+fn disconnect() {
+    use futures::future;
+
+    let handle = ServerHandle::new(Limits::new());
+    let registration = handle.register(7, None);
+    assert_eq!(handle.connections(), vec![7]);
+    assert!(!handle.disconnect(8, Duration::from_secs(1)));
+
+    let polled = future::lazy(|| {
+        assert!(registration.poll_disconnect().is_none());
+        assert!(handle.disconnect_with_notification(7, Duration::from_secs(1), "bye", &[]));
+        let disconnect = registration.poll_disconnect().unwrap();
+        assert_eq!(disconnect.notification.unwrap().method, "bye");
+        Ok::<_, ()>(registration.poll_disconnect().is_none())
+    }).wait();
+    assert_eq!(polled, Ok(true));
+
+    drop(registration);
+    assert!(handle.connections().is_empty());
+}
+
+#[test]
+fn broadcast() {
+    let handle = ServerHandle::new(Limits::new());
+    let first = handle.register(1, None);
+    let second = handle.register(2, None);
+    assert_eq!(handle.broadcast("event", &[Value::from(1)]), 2);
+    assert_eq!(handle.broadcast_filtered("event", &[Value::from(2)], |id| id == 2), 1);
+    assert_eq!(first.take_broadcasts().len(), 1);
+    let broadcasts = second.take_broadcasts();
+    assert_eq!(broadcasts.len(), 2);
+    assert_eq!(broadcasts[1].params, vec![Value::from(2)]);
+    assert!(second.take_broadcasts().is_empty());
+
+    // Connections that are being closed do not get broadcasts anymore.
+    assert!(handle.disconnect(1, Duration::from_secs(1)));
+    assert_eq!(handle.broadcast("event", &[]), 1);
+    assert!(first.take_broadcasts().is_empty());
+}
+
+#[test]
+fn shutdown() {
+    use futures::future;
+
+    let handle = ServerHandle::new(Limits::new());
+    let first = handle.register(1, None);
+    let polled = future::lazy(|| {
+        assert!(first.poll_disconnect().is_none());
+        let mut shutdown =
+            handle.shutdown_with_notification(Duration::from_secs(1), "shutting_down", &[]);
+        assert!(handle.is_draining());
+        let disconnect = first.poll_disconnect().unwrap();
+        assert_eq!(disconnect.notification.unwrap().method, "shutting_down");
+
+        // A connection registered during the shutdown is closed right away.
+        let second = handle.register(2, None);
+        assert!(second.poll_disconnect().is_some());
+        assert_eq!(handle.broadcast("event", &[]), 0);
+
+        assert_eq!(shutdown.poll().unwrap(), Async::NotReady);
+        drop(first);
+        assert_eq!(shutdown.poll().unwrap(), Async::NotReady);
+        drop(second);
+        shutdown.poll()
+    }).wait();
+    assert_eq!(polled.unwrap(), Async::Ready(()));
+}
+
+#[test]
+fn close_idle_connection() {
+    let handle = ServerHandle::new(Limits::new());
+    let first = handle.register(1, None);
+    let second = handle.register(2, None);
+    let third = handle.register(3, None);
+    assert!(!handle.close_idle_connection());
+
+    second.set_idle(true);
+    third.set_idle(true);
+    assert!(handle.close_idle_connection());
+    assert!(handle.state.lock().unwrap().connections[&2].closing);
+    assert!(handle.close_idle_connection());
+    assert!(!handle.close_idle_connection());
+    drop((first, second, third));
+
+    #[cfg(unix)]
+    assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(EMFILE)));
+    #[cfg(windows)]
+    assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(WSAEMFILE)));
+    assert!(!is_fd_exhaustion(&io::Error::new(io::ErrorKind::Other, "other")));
+}
+
+#[test]
+fn reserved_prefixes() {
+    use futures::future;
+    use auth::Admission;
+    use methods::MethodRouter;
+    use tokio_core::reactor::Core;
+
+    let core = Core::new().unwrap();
+    let mut router = MethodRouter::new();
+    let _ = router.request("admin.stats", |_| Box::new(future::ok(Ok(Value::Nil))));
+    let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+    assert!(builder.spawn(router.clone(), &core.handle()).is_ok());
+
+    let _ = builder.reserve_prefix("admin.");
+    match builder.spawn(router.clone(), &core.handle()) {
+        Err(ServerError::ReservedMethod { method, prefix }) => {
+            assert_eq!((method.as_str(), prefix.as_str()), ("admin.stats", "admin."))
+        }
+        _ => panic!("a reserved method has a handler"),
+    }
+
+    let mut router = MethodRouter::new();
+    let _ = router.notification("$/ping", |_| Box::new(future::ok(())));
+    let builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+    assert!(builder.spawn(router, &core.handle()).is_err());
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("rpc.discover", |_| Box::new(future::ok(Ok(Value::Nil))));
+    assert!(builder.spawn(router, &core.handle()).is_err());
+
+    // The router is validated as well. An empty router is valid, unless handlers are required.
+    let mut router = MethodRouter::new();
+    assert!(builder.spawn(router.clone(), &core.handle()).is_ok());
+    let _ = router.require_handlers(true);
+    match builder.spawn(router, &core.handle()) {
+        Err(ServerError::InvalidRouter(RouterError::Empty)) => {}
+        _ => panic!("an empty router was accepted"),
+    }
+
+    // Protected namespaces need an authenticator.
+    let mut router = MethodRouter::new();
+    let _ = router
+        .request("admin.stats", |_| Box::new(future::ok(Ok(Value::Nil))))
+        .protect("admin.");
+    match builder.spawn(router.clone(), &core.handle()) {
+        Err(ServerError::InvalidRouter(RouterError::Unauthenticated { prefix })) => {
+            assert_eq!(prefix, "admin.")
+        }
+        _ => panic!("a protected namespace is served without authentication"),
+    }
+    let authenticator = |_: Option<SocketAddr>, _: &Request| Admission::Accept(None);
+    let mut options = ProtocolOptions::default();
+    let _ = options.authenticator(Some(Arc::new(authenticator)));
+    let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+    let _ = builder.set_protocol_options(options);
+    assert!(builder.spawn(router, &core.handle()).is_ok());
+}
+
+#[test]
+fn connection_events() {
+    use futures::future;
+    use methods::MethodRouter;
+    use net::ClientOnlyConnector;
+    use tokio_core::reactor::Core;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request("echo", |params| Box::new(future::ok(Ok(params[0].clone()))));
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+        .spawn(router, &handle)
+        .unwrap();
+    let events = server.events();
+
+    let address = server.local_addr().unwrap();
+    let client = core.run(ClientOnlyConnector::new(&address, &handle).connect()).unwrap();
+    let response = client.request("echo", &[Value::from(1)]);
+    assert_eq!(core.run(response).unwrap(), Ok(Value::from(1)));
+    let connection = server.connections()[0];
+    assert!(server.disconnect(connection, Duration::from_secs(0)));
+
+    let events = core.run(events.take(2).collect()).unwrap();
+    let peer_addr = match events[0] {
+        ConnectionEvent::Connected {
+            connection: id,
+            peer_addr,
+        } if id == connection => peer_addr,
+        ref event => panic!("unexpected event {:?}", event),
+    };
+    assert!(peer_addr.is_some());
+    let expected = ConnectionEvent::Disconnected {
+        connection: connection,
+        peer_addr: peer_addr,
+        reason: CallError::ConnectionClosed,
+    };
+    assert_eq!(events[1], expected);
+}
+
+#[test]
+fn multi_threaded() {
+    use futures::future;
+    use methods::MethodRouter;
+    use net::ClientOnlyConnector;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut router = MethodRouter::new();
+    let _ = router.request("thread", |_params| {
+        let name = thread::current().name().map(String::from);
+        Box::new(future::ok(Ok(Value::from(name.unwrap_or_default()))))
+    });
+    let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+        .set_worker_threads(Some(2))
+        .spawn_multi_threaded(router, &handle)
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    let mut clients = Vec::new();
+    let mut threads = Vec::new();
+    for _ in 0..3 {
+        let client = core.run(ClientOnlyConnector::new(&address, &handle).connect())
+            .unwrap();
+        let thread = core.run(client.call("thread", &[])).unwrap();
+        threads.push(thread.as_str().unwrap().to_string());
+        clients.push(client);
+    }
+    // The connections are handed to the workers in turn.
+    assert_eq!(threads, ["rmp-rpc-worker-0", "rmp-rpc-worker-1", "rmp-rpc-worker-0"]);
+    assert_eq!(server.connections().len(), 3);
+    core.run(server.shutdown(Duration::from_secs(1))).unwrap();
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use context::Context;
+use endpoint::{Client, Service, ServiceBuilder};
+
+/// A [`ServiceBuilder`](trait.ServiceBuilder.html) that builds the service of each connection
+/// from a state shared by all the connections, and from the metadata of the connection.
+///
+/// The service built for a connection is its session: it owns the state of the connection, with
+/// whatever type the application needs, and it is dropped when the connection is closed. The
+/// shared state is created once, and each session is given an `Arc` to it, so that it does not
+/// have to be smuggled through a hand-written builder. The metadata of the connection (its id,
+/// its peer address, the features of the remote endpoint, etc.) is in its
+/// [`Context`](struct.Context.html).
+///
+/// ```rust,ignore
+/// struct Session {
+///     global: Arc<Stats>,
+///     peer: Option<SocketAddr>,
+///     requests: u64,
+/// }
+///
+/// let builder = SessionBuilder::new(Stats::default(), |global, context, _client| Session {
+///     global: Arc::clone(global),
+///     peer: context.peer_addr(),
+///     requests: 0,
+/// });
+/// let stats = Arc::clone(builder.state());
+/// let server = ServerBuilder::new(address).spawn(builder, &handle)?;
+/// ```
+pub struct SessionBuilder<G, F> {
+    state: Arc<G>,
+    build: F,
+}
+
+impl<G, F, S> SessionBuilder<G, F>
+where
+    F: Fn(&Arc<G>, &Context, Client) -> S,
+    S: Service + 'static,
+{
+    /// Create a builder that calls `build` with the shared `state`, the context of the
+    /// connection, and the client to the remote endpoint, to build the service of each connection.
+    pub fn new(state: G, build: F) -> Self {
+        SessionBuilder::with_shared_state(Arc::new(state), build)
+    }
+
+    /// Like [`new`](#method.new), with a shared state that is also used elsewhere.
+    pub fn with_shared_state(state: Arc<G>, build: F) -> Self {
+        SessionBuilder {
+            state: state,
+            build: build,
+        }
+    }
+
+    /// Return the state shared by all the connections.
+    pub fn state(&self) -> &Arc<G> {
+        &self.state
+    }
+}
+
+impl<G, F, S> ServiceBuilder for SessionBuilder<G, F>
+where
+    F: Fn(&Arc<G>, &Context, Client) -> S,
+    S: Service + 'static,
+{
+    type Service = S;
+
+    fn build(&self, client: Client) -> S {
+        let context = client.context();
+        (self.build)(&self.state, &context, client)
+    }
+}
+
+#[test]
+fn sessions() {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::future::{self, FutureResult};
+    use rmpv::Value;
+    use mock::test_runtime;
+    use options::ProtocolOptions;
+
+    struct Session {
+        global: Arc<AtomicUsize>,
+        requests: u64,
+    }
+
+    impl Service for Session {
+        type Error = io::Error;
+        type T = Value;
+        type E = Value;
+        type RequestFuture = FutureResult<Result<Value, Value>, io::Error>;
+        type NotificationFuture = FutureResult<(), io::Error>;
+
+        fn handle_request(&mut self, _method: &str, _params: &[Value]) -> Self::RequestFuture {
+            self.requests += 1;
+            let total = self.global.fetch_add(1, Ordering::SeqCst) + 1;
+            future::ok(Ok(Value::from(vec![
+                Value::from(self.requests),
+                Value::from(total),
+            ])))
+        }
+
+        fn handle_notification(&mut self, _: &str, _: &[Value]) -> Self::NotificationFuture {
+            future::ok(())
+        }
+    }
+
+    let builder = SessionBuilder::new(AtomicUsize::new(0), |global, _context, _client| Session {
+        global: Arc::clone(global),
+        requests: 0,
+    });
+    let mut runtime = test_runtime(&builder, 2, &ProtocolOptions::default(), 1);
+    let counts = |requests: u64, total: u64| -> Result<Value, Value> {
+        Ok(Value::from(vec![Value::from(requests), Value::from(total)]))
+    };
+    let first = runtime.client(0).request("count", &[]);
+    assert_eq!(runtime.run(first).unwrap(), counts(1, 1));
+    let second = runtime.client(1).request("count", &[]);
+    assert_eq!(runtime.run(second).unwrap(), counts(1, 2));
+    let third = runtime.client(0).request("count", &[]);
+    assert_eq!(runtime.run(third).unwrap(), counts(2, 3));
+    assert_eq!(builder.state().load(Ordering::SeqCst), 3);
+}
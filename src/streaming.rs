@@ -0,0 +1,117 @@
+//! Streamed results.
+//!
+//! A result too large for a single response can be sent in chunks: the handler sends each chunk
+//! in a `$stream/<id>/chunk` notification whose only parameter is the chunk, `<id>` being the id
+//! of the request, and then answers the request as usual. The client receives the chunks as a
+//! [`ResponseStream`](struct.ResponseStream.html), created with
+//! [`Client::request_stream`](struct.Client.html#method.request_stream).
+//!
+//! Handlers send the chunks with [`Pusher::send_chunk`](struct.Pusher.html#method.send_chunk).
+//! Since the notifications a handler sends before it completes are written before its response,
+//! the client receives all the chunks before the response. Servers that do not stream their
+//! results just answer the request, and the stream then yields the result as its only item.
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::mpsc;
+use rmpv::Value;
+
+use endpoint::Response;
+use errors::StreamError;
+use message::Id;
+
+/// Prefix of the notifications that carry the chunks of streamed results.
+pub const STREAM_PREFIX: &str = "$stream/";
+
+/// Return the method of the notifications that carry the chunks of the result of request `id`.
+pub fn chunk_method(id: Id) -> String {
+    format!("{}{}/chunk", STREAM_PREFIX, id)
+}
+
+/// A result received in chunks, returned by
+/// [`Client::request_stream`](struct.Client.html#method.request_stream).
+///
+/// It yields the chunks in the order they were sent, and then the result of the response unless
+/// it is `nil`. It fails if the response is an error, or if the connection is closed before the
+/// response is received.
+pub struct ResponseStream {
+    chunks: mpsc::UnboundedReceiver<Value>,
+    response: Option<Response>,
+    // The result of the response, once it is received, until it is yielded.
+    result: Option<Result<Value, Value>>,
+}
+
+impl ResponseStream {
+    pub(crate) fn new(chunks: mpsc::UnboundedReceiver<Value>, response: Response) -> Self {
+        ResponseStream {
+            chunks: chunks,
+            response: Some(response),
+            result: None,
+        }
+    }
+}
+
+impl Stream for ResponseStream {
+    type Item = Value;
+    type Error = StreamError;
+
+    fn poll(&mut self) -> Poll<Option<Value>, StreamError> {
+        loop {
+            if let Ok(Async::Ready(Some(chunk))) = self.chunks.poll() {
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            let result = match self.response {
+                Some(ref mut response) => match response.poll() {
+                    Ok(Async::Ready(result)) => result,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => return Err(StreamError::Call(e)),
+                },
+                None => break,
+            };
+            // The chunks are forwarded before the response, so those that have not been yielded
+            // yet are all in the channel.
+            self.response = None;
+            self.result = Some(result);
+        }
+        match self.result.take() {
+            Some(Ok(Value::Nil)) | None => Ok(Async::Ready(None)),
+            Some(Ok(value)) => Ok(Async::Ready(Some(value))),
+            Some(Err(e)) => Err(StreamError::Response(e)),
+        }
+    }
+}
+
+#[test]
+fn streamed_results() {
+    use futures::future;
+    use methods::MethodRouter;
+    use mock::{test_runtime, RunError};
+    use options::ProtocolOptions;
+
+    let mut router = MethodRouter::new();
+    let _ = router.request_with_pusher("count", |params, pusher| {
+        let count = params[0].as_u64().unwrap();
+        for i in 0..count {
+            let _ = pusher.send_chunk(Value::from(i));
+        }
+        Box::new(future::ok(Ok(Value::Nil)))
+    });
+    let _ = router.request("answer", |_| Box::new(future::ok(Ok(Value::from(42)))));
+    let _ = router.request_with_pusher("fail", |_, pusher| {
+        let _ = pusher.send_chunk(Value::from(0));
+        Box::new(future::ok(Err(Value::from("failed"))))
+    });
+    let mut options = ProtocolOptions::default();
+    let _ = options.poll_budget(1);
+    let mut runtime = test_runtime(&router, 1, &options, 3);
+
+    let chunks = runtime.client(0).request_stream("count", &[Value::from(3)]).collect();
+    let expected = vec![Value::from(0), Value::from(1), Value::from(2)];
+    assert_eq!(runtime.run(chunks).unwrap(), expected);
+
+    // A result that is not streamed is the only item.
+    let chunks = runtime.client(0).request_stream("answer", &[]).collect();
+    assert_eq!(runtime.run(chunks).unwrap(), vec![Value::from(42)]);
+
+    let chunks = runtime.client(0).request_stream("fail", &[]).collect();
+    let error = StreamError::Response(Value::from("failed"));
+    assert_eq!(runtime.run(chunks), Err(RunError::Failed(error)));
+}
@@ -0,0 +1,136 @@
+use std::cmp;
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::task;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// A token bucket, that holds up to one second worth of tokens.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bucket {
+    // In tokens per second.
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn new(rate: u32) -> Self {
+        let rate = f64::from(cmp::max(rate, 1));
+        Bucket {
+            rate: rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last_refill;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// Change the rate, keeping the tokens already in the bucket.
+    pub(crate) fn set_rate(&mut self, rate: u32) {
+        self.refill();
+        self.rate = f64::from(cmp::max(rate, 1));
+        self.tokens = self.tokens.min(self.rate);
+    }
+
+    /// Take a token if one is available. Return `false` otherwise.
+    pub(crate) fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Take `tokens` tokens if the bucket is not empty, possibly going into debt, so that a take
+    /// larger than the bucket is not refused forever. Return `false` if the bucket is empty.
+    pub(crate) fn try_take_many(&mut self, tokens: usize) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= tokens as f64;
+        true
+    }
+
+    /// Return `true` if the bucket is full, in which case it is no different from a new one.
+    pub(crate) fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate
+    }
+}
+
+/// A token bucket that limits the number of bytes read from, or written to, a connection (see
+/// [`ProtocolOptions::read_rate`](struct.ProtocolOptions.html#method.read_rate)).
+///
+/// The bucket holds up to one second worth of bytes. A read or a write may take more bytes than
+/// the bucket holds, in which case the next ones wait until the debt is paid back.
+pub(crate) struct Throttle {
+    bucket: Bucket,
+    reactor: Handle,
+    // Fires once some bytes can be transferred again.
+    timer: Option<Timeout>,
+}
+
+impl Throttle {
+    pub(crate) fn new(rate: u32, reactor: &Handle) -> Self {
+        Throttle {
+            bucket: Bucket::new(rate),
+            reactor: reactor.clone(),
+            timer: None,
+        }
+    }
+
+    /// Return how many bytes can be transferred now. If none can, the current task is notified
+    /// once some can.
+    pub(crate) fn poll_allowance(&mut self) -> Poll<usize, io::Error> {
+        let bucket = &mut self.bucket;
+        bucket.refill();
+        if bucket.tokens >= 1.0 {
+            return Ok(Async::Ready(bucket.tokens as usize));
+        }
+        let nanos = ((1.0 - bucket.tokens) / bucket.rate * 1e9) as u64;
+        let deadline = bucket.last_refill + Duration::new(nanos / 1_000_000_000, nanos as u32);
+        match self.timer {
+            Some(ref mut timer) => timer.reset(deadline),
+            None => self.timer = Some(Timeout::new_at(deadline, &self.reactor)?),
+        }
+        if let Some(ref mut timer) = self.timer {
+            if let Async::Ready(()) = timer.poll()? {
+                task::current().notify();
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    /// Record that `bytes` bytes have been transferred.
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.bucket.tokens -= bytes as f64;
+    }
+}
+
+#[test]
+fn throttle() {
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut throttle = Throttle::new(1000, &core.handle());
+    let allowance = future::poll_fn(|| throttle.poll_allowance());
+    assert_eq!(core.run(allowance).unwrap(), 1000);
+
+    throttle.consume(1100);
+    let start = Instant::now();
+    let allowance = future::poll_fn(|| throttle.poll_allowance());
+    assert!(core.run(allowance).unwrap() >= 1);
+    // The debt of 100 bytes takes 100ms to pay back.
+    assert!(start.elapsed() >= Duration::from_millis(90));
+}
@@ -0,0 +1,168 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rmpv::Value;
+
+use ext::{from_ext, to_ext, ExtType};
+
+/// A duration, sent as an integer number of milliseconds so that peers written in other
+/// languages do not have to guess the unit. Durations longer than `u64::MAX` milliseconds are
+/// sent as `u64::MAX`, and the sub-millisecond part is truncated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RpcDuration(pub Duration);
+
+impl RpcDuration {
+    /// Decode a duration received as a parameter. Return `None` if the value is not a
+    /// non-negative integer.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value.as_u64() {
+            Some(millis) => Some(RpcDuration(Duration::from_millis(millis))),
+            None => None,
+        }
+    }
+}
+
+impl From<Duration> for RpcDuration {
+    fn from(duration: Duration) -> Self {
+        RpcDuration(duration)
+    }
+}
+
+impl From<RpcDuration> for Value {
+    fn from(duration: RpcDuration) -> Value {
+        let millis = duration
+            .0
+            .as_secs()
+            .checked_mul(1000)
+            .and_then(|millis| millis.checked_add(u64::from(duration.0.subsec_nanos() / 1_000_000)))
+            .unwrap_or(u64::max_value());
+        Value::from(millis)
+    }
+}
+
+/// A point in time, sent with the timestamp extension type of the MessagePack specification,
+/// which most implementations decode as a native date. The most compact of the 32, 64 and 96
+/// bits formats is used, and all three are decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RpcTimestamp(pub SystemTime);
+
+impl RpcTimestamp {
+    /// Return the current time.
+    pub fn now() -> Self {
+        RpcTimestamp(SystemTime::now())
+    }
+
+    /// Decode a timestamp received as a parameter. Return `None` if the value is not a timestamp
+    /// extension.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        from_ext(value)
+    }
+}
+
+impl ExtType for RpcTimestamp {
+    /// Type of the MessagePack timestamp extension.
+    const EXT_TYPE: i8 = -1;
+
+    fn decode_ext(data: &[u8]) -> Option<Self> {
+        let (secs, nanos) = match data.len() {
+            4 => (i64::from(read_u32(&data[0..4])), 0),
+            8 => {
+                let high = u64::from(read_u32(&data[0..4]));
+                let value = high << 32 | u64::from(read_u32(&data[4..8]));
+                ((value & 0x3_ffff_ffff) as i64, (value >> 34) as u32)
+            }
+            12 => {
+                let high = u64::from(read_u32(&data[4..8]));
+                let low = u64::from(read_u32(&data[8..12]));
+                ((high << 32 | low) as i64, read_u32(&data[0..4]))
+            }
+            _ => return None,
+        };
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        let time = if secs >= 0 {
+            UNIX_EPOCH + Duration::new(secs as u64, nanos)
+        } else {
+            UNIX_EPOCH - Duration::new(secs.wrapping_neg() as u64, 0) + Duration::new(0, nanos)
+        };
+        Some(RpcTimestamp(time))
+    }
+
+    fn encode_ext(&self) -> Vec<u8> {
+        // The nanoseconds are always positive, even before the epoch.
+        let (secs, nanos) = match self.0.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => (elapsed.as_secs() as i64, elapsed.subsec_nanos()),
+            Err(e) => {
+                let before = e.duration();
+                match before.subsec_nanos() {
+                    0 => (-(before.as_secs() as i64), 0),
+                    nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                }
+            }
+        };
+        let mut data = Vec::with_capacity(12);
+        if secs >= 0 && secs >> 34 == 0 {
+            let value = u64::from(nanos) << 34 | secs as u64;
+            if value >> 32 == 0 {
+                write_u32(&mut data, value as u32);
+            } else {
+                write_u32(&mut data, (value >> 32) as u32);
+                write_u32(&mut data, value as u32);
+            }
+        } else {
+            write_u32(&mut data, nanos);
+            write_u32(&mut data, (secs as u64 >> 32) as u32);
+            write_u32(&mut data, secs as u32);
+        }
+        data
+    }
+}
+
+impl From<SystemTime> for RpcTimestamp {
+    fn from(time: SystemTime) -> Self {
+        RpcTimestamp(time)
+    }
+}
+
+impl From<RpcTimestamp> for Value {
+    fn from(timestamp: RpcTimestamp) -> Value {
+        to_ext(&timestamp)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| value << 8 | u32::from(*byte))
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+    let bytes = [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8];
+    data.extend_from_slice(&bytes);
+}
+
+#[test]
+fn time_round_trip() {
+    let duration = RpcDuration(Duration::new(3, 250_000_000));
+    assert_eq!(Value::from(duration), Value::from(3250));
+    assert_eq!(RpcDuration::from_value(&Value::from(3250)), Some(duration));
+    assert_eq!(RpcDuration::from_value(&Value::from(-1)), None);
+
+    let whole = RpcTimestamp(UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+    assert_eq!(Value::from(whole), Value::Ext(-1, vec![0x59, 0x68, 0x2f, 0x00]));
+    let times = [
+        whole,
+        RpcTimestamp(UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_789)),
+        RpcTimestamp(UNIX_EPOCH + Duration::new(1 << 35, 1)),
+        RpcTimestamp(UNIX_EPOCH - Duration::new(10, 250_000_000)),
+    ];
+    let sizes = [4, 8, 12, 12];
+    for (time, size) in times.iter().zip(sizes.iter()) {
+        let value = Value::from(*time);
+        match value {
+            Value::Ext(-1, ref data) => assert_eq!(data.len(), *size),
+            _ => panic!("not a timestamp: {:?}", value),
+        }
+        assert_eq!(RpcTimestamp::from_value(&value), Some(*time));
+    }
+}
@@ -0,0 +1,658 @@
+//! An experimental bridge to `std::future::Future` and tokio 1.x, with the `tokio1` feature.
+//!
+//! This module does not depend on the futures 0.1 runtime of the rest of the crate: it only
+//! shares the message types and the framing, so that its endpoints talk to the futures 0.1 ones,
+//! and the handlers can be `async`. A [`Service`](trait.Service.html) returns boxed `std`
+//! futures, which are usually `async` blocks or the futures of `async fn`s. An
+//! [`Endpoint`](struct.Endpoint.html) drives a connection and is itself a future to spawn on a
+//! tokio 1.x runtime, and the [`Client`](struct.Client.html)s it hands out send requests that can
+//! be awaited from any task.
+//!
+//! It is deliberately minimal, and its API may change. Only the encoding options and limits of
+//! [`Options`](struct.Options.html) apply: the builtin methods, authentication, timeouts, rate
+//! limits, the policies for duplicate ids and invalid messages (which are always logged and
+//! skipped), compression and the other features of the futures 0.1 endpoints are not available.
+//!
+//! ```rust,ignore
+//! struct Echo;
+//!
+//! impl Service for Echo {
+//!     fn handle_request(&self, _: &str, params: Vec<Value>) -> BoxFuture<Result<Value, Value>> {
+//!         Box::pin(async move { Ok(params.into_iter().next().unwrap_or(Value::Nil)) })
+//!     }
+//!
+//!     fn handle_notification(&self, _method: &str, _params: Vec<Value>) -> BoxFuture<()> {
+//!         Box::pin(async {})
+//!     }
+//! }
+//!
+//! let options = Options::default();
+//! let listener = TcpListener::bind("127.0.0.1:54321").await?;
+//! tokio::spawn(serve(listener, Echo, &options));
+//!
+//! let endpoint = Endpoint::new(TcpStream::connect("127.0.0.1:54321").await?, &options);
+//! let client = endpoint.client();
+//! tokio::spawn(endpoint);
+//! let echoed = client.request("echo", &[Value::from(3)]).await?;
+//! ```
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use rmpv::Value;
+use tokio_1::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_1::net::{TcpListener, TcpStream};
+use tokio_1::sync::{mpsc, oneshot};
+
+use errors::CallError;
+use message::{read_value, DecodeLimits, EmptyParams, Id, Message, Notification, Param, Request,
+              Response, DEFAULT_MAX_DEPTH};
+use scan::Scan;
+
+/// Maximum number of messages an endpoint reads each time it is polled, by default.
+const DEFAULT_POLL_BUDGET: usize = 128;
+
+/// Error sent back to a client when all the request ids are used by requests in flight.
+const REQUEST_IDS_EXHAUSTED_ERROR: &str = "too many requests in flight";
+
+/// The encoding options and limits of an [`Endpoint`](struct.Endpoint.html). These are the
+/// counterparts of the options of the same name of
+/// [`ProtocolOptions`](../struct.ProtocolOptions.html), which is not used by this module.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    named_params: bool,
+    empty_params: EmptyParams,
+    max_message_size: Option<usize>,
+    max_nesting_depth: usize,
+    max_elements: Option<usize>,
+    poll_budget: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            named_params: false,
+            empty_params: EmptyParams::Array,
+            max_message_size: None,
+            max_nesting_depth: DEFAULT_MAX_DEPTH,
+            max_elements: None,
+            poll_budget: DEFAULT_POLL_BUDGET,
+        }
+    }
+}
+
+impl Options {
+    /// Create a new set of options, with the default values.
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// If `enabled` is `true`, parameters are sent as a map of named arguments when they are a
+    /// single map, and incoming maps of named arguments are accepted. It is disabled by default.
+    pub fn named_params(&mut self, enabled: bool) -> &mut Self {
+        self.named_params = enabled;
+        self
+    }
+
+    /// Return `true` if the parameters can be sent and received as maps of named arguments.
+    pub fn has_named_params(&self) -> bool {
+        self.named_params
+    }
+
+    /// Set how the requests and notifications without parameters are sent. By default, they are
+    /// sent with an empty array.
+    pub fn empty_params(&mut self, encoding: EmptyParams) -> &mut Self {
+        self.empty_params = encoding;
+        self
+    }
+
+    /// Return how the requests and notifications without parameters are sent.
+    pub fn get_empty_params(&self) -> EmptyParams {
+        self.empty_params
+    }
+
+    /// If `max` is not `None`, the connection is closed with an `InvalidData` error as soon as an
+    /// incoming message is known to be larger than `max` bytes. By default, messages of any size
+    /// are accepted.
+    pub fn max_message_size(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_message_size = max;
+        self
+    }
+
+    /// Return the size above which incoming messages are rejected.
+    pub fn get_max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Set the maximum number of arrays and maps a value of an incoming message can be nested
+    /// in: messages nested more deeply are skipped. The default depth is 64.
+    pub fn max_nesting_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_nesting_depth = depth;
+        self
+    }
+
+    /// Return the maximum number of arrays and maps a value can be nested in.
+    pub fn get_max_nesting_depth(&self) -> usize {
+        self.max_nesting_depth
+    }
+
+    /// If `max` is not `None`, incoming messages that hold more than `max` values are skipped.
+    /// By default, messages can hold any number of values.
+    pub fn max_elements(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_elements = max;
+        self
+    }
+
+    /// Return the maximum number of values an incoming message can hold.
+    pub fn get_max_elements(&self) -> Option<usize> {
+        self.max_elements
+    }
+
+    /// Set the maximum number of incoming messages an endpoint reads each time it is polled,
+    /// after which it yields to the other tasks of the runtime. The default budget is 128.
+    pub fn poll_budget(&mut self, budget: usize) -> &mut Self {
+        self.poll_budget = budget;
+        self
+    }
+
+    /// Return the maximum number of incoming messages read each time an endpoint is polled.
+    pub fn get_poll_budget(&self) -> usize {
+        self.poll_budget
+    }
+}
+
+/// A boxed future, as returned by the handlers of a [`Service`](trait.Service.html).
+pub type BoxFuture<T> = Pin<Box<Future<Output = T> + Send + 'static>>;
+
+/// The requests and notifications a server handles. The handlers run concurrently, on tasks of
+/// their own, so that a slow request does not hold the others back.
+pub trait Service: Send + Sync + 'static {
+    /// Handle a request, and return its result, or the error to send back.
+    fn handle_request(&self, method: &str, params: Vec<Value>) -> BoxFuture<Result<Value, Value>>;
+
+    /// Handle a notification.
+    fn handle_notification(&self, method: &str, params: Vec<Value>) -> BoxFuture<()>;
+}
+
+/// A message sent by a [`Client`](struct.Client.html), waiting for its endpoint.
+enum Outgoing {
+    Request(String, Vec<Value>, oneshot::Sender<Result<Value, Value>>),
+    Notification(String, Vec<Value>),
+}
+
+/// Sends requests and notifications through an [`Endpoint`](struct.Endpoint.html). Clients are
+/// cheap to clone, and can be used from any task.
+#[derive(Clone)]
+pub struct Client(mpsc::UnboundedSender<Outgoing>);
+
+impl Client {
+    /// Send a request, and return a future of its response. The future fails with
+    /// `CallError::ConnectionClosed` if the endpoint stops before the response arrives.
+    pub fn request(&self, method: &str, params: &[Value]) -> ResponseFuture {
+        let (response_tx, response_rx) = oneshot::channel();
+        // If the endpoint is gone, the sender is dropped with the request, and the future fails.
+        let _ = self.0
+            .send(Outgoing::Request(method.to_owned(), params.to_vec(), response_tx));
+        ResponseFuture(response_rx)
+    }
+
+    /// Send a notification. This fails with `CallError::ConnectionClosed` if the endpoint
+    /// stopped.
+    pub fn notify(&self, method: &str, params: &[Value]) -> Result<(), CallError> {
+        self.0
+            .send(Outgoing::Notification(method.to_owned(), params.to_vec()))
+            .map_err(|_| CallError::ConnectionClosed)
+    }
+}
+
+/// The response to a request sent with [`Client::request`](struct.Client.html#method.request).
+pub struct ResponseFuture(oneshot::Receiver<Result<Value, Value>>);
+
+impl Future for ResponseFuture {
+    type Output = Result<Result<Value, Value>, CallError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match Pin::new(&mut self.0).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(Ok(result)),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(CallError::ConnectionClosed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Sends the result of a request handler to its endpoint, once it is ready.
+struct Reply {
+    id: Id,
+    result: BoxFuture<Result<Value, Value>>,
+    responses: mpsc::UnboundedSender<Response>,
+}
+
+impl Future for Reply {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.result.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                let response = Response {
+                    id: self.id,
+                    result: result,
+                };
+                let _ = self.responses.send(response);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// One end of a connection, that sends the requests of its clients and, if it has a service,
+/// handles the requests of the remote endpoint. It is a future that runs until the connection is
+/// closed, and must be spawned on a tokio 1.x runtime, since the handlers are spawned on it.
+pub struct Endpoint<T> {
+    stream: T,
+    options: Options,
+    // Scan of the message at the start of the receive buffer.
+    scan: Scan,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    service: Option<Arc<Service>>,
+    // The messages of the clients, and a sender to hand out to new clients.
+    outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    outgoing_tx: mpsc::UnboundedSender<Outgoing>,
+    // The results of the handlers of the service.
+    responses: mpsc::UnboundedReceiver<Response>,
+    responses_tx: mpsc::UnboundedSender<Response>,
+    in_flight: HashMap<Id, oneshot::Sender<Result<Value, Value>>>,
+    next_id: u32,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Endpoint<T> {
+    /// Return an endpoint that only acts as a client: the requests of the remote endpoint are
+    /// answered with an error.
+    pub fn new(stream: T, options: &Options) -> Self {
+        let (outgoing_tx, outgoing) = mpsc::unbounded_channel();
+        let (responses_tx, responses) = mpsc::unbounded_channel();
+        Endpoint {
+            stream: stream,
+            options: *options,
+            scan: Scan::default(),
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            service: None,
+            outgoing: outgoing,
+            outgoing_tx: outgoing_tx,
+            responses: responses,
+            responses_tx: responses_tx,
+            in_flight: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Return an endpoint that handles the requests and notifications of the remote endpoint
+    /// with `service`.
+    pub fn with_service(stream: T, service: Arc<Service>, options: &Options) -> Self {
+        let mut endpoint = Endpoint::new(stream, options);
+        endpoint.service = Some(service);
+        endpoint
+    }
+
+    /// Return a client that sends its requests and notifications through this endpoint.
+    pub fn client(&self) -> Client {
+        Client(self.outgoing_tx.clone())
+    }
+
+    /// Return an id that is not used by a request in flight, or `None` if they all are.
+    fn next_request_id(&mut self) -> Option<Id> {
+        if self.in_flight.len() as u64 > u64::from(u32::max_value()) {
+            return None;
+        }
+        loop {
+            let id = Id::from(self.next_id);
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.in_flight.contains_key(&id) {
+                return Some(id);
+            }
+        }
+    }
+
+    /// Decode the message at the start of the receive buffer, if it has been completely
+    /// received. The messages that cannot be decoded are logged and skipped.
+    fn decode(&mut self) -> io::Result<Option<Message>> {
+        let max = self.options.max_message_size.unwrap_or_else(usize::max_value);
+        let limits = DecodeLimits {
+            max_depth: self.options.max_nesting_depth,
+            max_elements: self.options.max_elements,
+        };
+        loop {
+            let size = match self.scan.advance(&self.read_buf, max)? {
+                Some(size) => size,
+                None => return Ok(None),
+            };
+            self.scan = Scan::default();
+            let frame = self.read_buf.split_to(size);
+            let decoded = Message::decode_with(
+                &mut io::Cursor::new(&frame[..]),
+                false,
+                false,
+                self.options.named_params,
+                limits,
+                &mut |rd, _, budget| Ok(Param::Value(read_value(rd, budget)?)),
+            );
+            match decoded {
+                Ok(message) => return Ok(Some(message)),
+                Err(e) => warn!("Skipping invalid message: {}", e),
+            }
+        }
+    }
+
+    /// Encode `message` at the end of the send buffer.
+    fn encode(&mut self, message: &Message) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        message.encode_with(&mut bytes, self.options.empty_params, self.options.named_params)?;
+        self.write_buf.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Handle a message from the remote endpoint.
+    fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Request(request) => {
+                let params = request.params.into_iter().map(Param::into_value).collect();
+                let result = match self.service {
+                    Some(ref service) => service.handle_request(&request.method, params),
+                    None => {
+                        let error = Value::from("this endpoint does not handle requests");
+                        Box::pin(::std::future::ready(Err(error)))
+                    }
+                };
+                tokio_1::spawn(Reply {
+                    id: request.id,
+                    result: result,
+                    responses: self.responses_tx.clone(),
+                });
+            }
+            Message::Notification(notification) => {
+                if let Some(ref service) = self.service {
+                    let handler =
+                        service.handle_notification(&notification.method, notification.params);
+                    tokio_1::spawn(handler);
+                }
+            }
+            Message::Response(response) => match self.in_flight.remove(&response.id) {
+                Some(response_tx) => {
+                    let _ = response_tx.send(response.result);
+                }
+                None => warn!("Dropping a response to an unknown request: {}", response.id),
+            },
+        }
+    }
+
+    /// Read and handle the messages of the remote endpoint. Return `true` once the connection
+    /// is closed. At most `poll_budget` messages are handled: the endpoint then asks to be polled
+    /// again, and yields to the other tasks.
+    fn poll_read(&mut self, cx: &mut Context) -> io::Result<bool> {
+        let mut chunk = [0; 8192];
+        let mut budget = self.options.poll_budget;
+        loop {
+            while budget > 0 {
+                match self.decode()? {
+                    Some(message) => self.handle_message(message),
+                    None => break,
+                }
+                budget -= 1;
+            }
+            if budget == 0 {
+                cx.waker().wake_by_ref();
+                return Ok(false);
+            }
+            let read = {
+                let mut buf = ReadBuf::new(&mut chunk);
+                match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) => buf.filled().len(),
+                    Poll::Ready(Err(e)) => return Err(e),
+                    Poll::Pending => return Ok(false),
+                }
+            };
+            if read == 0 {
+                return Ok(true);
+            }
+            self.read_buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Encode the messages of the clients and the responses of the service.
+    fn poll_outgoing(&mut self, cx: &mut Context) -> io::Result<()> {
+        while let Poll::Ready(Some(outgoing)) = self.outgoing.poll_recv(cx) {
+            let message = match outgoing {
+                Outgoing::Request(method, params, response_tx) => {
+                    let id = match self.next_request_id() {
+                        Some(id) => id,
+                        None => {
+                            warn!("All the request ids are in use. Failing request to {}.", method);
+                            let _ = response_tx.send(Err(Value::from(REQUEST_IDS_EXHAUSTED_ERROR)));
+                            continue;
+                        }
+                    };
+                    let _ = self.in_flight.insert(id, response_tx);
+                    Message::Request(Request {
+                        id: id,
+                        method: method,
+                        params: params.into_iter().map(Param::Value).collect(),
+                    })
+                }
+                Outgoing::Notification(method, params) => Message::Notification(Notification {
+                    method: method,
+                    params: params,
+                }),
+            };
+            self.encode(&message)?;
+        }
+        while let Poll::Ready(Some(response)) = self.responses.poll_recv(cx) {
+            self.encode(&Message::Response(response))?;
+        }
+        Ok(())
+    }
+
+    /// Write the encoded messages to the connection.
+    fn poll_write(&mut self, cx: &mut Context) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+                Poll::Ready(Ok(written)) => {
+                    let _ = self.write_buf.split_to(written);
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => return Ok(()),
+            }
+        }
+        match Pin::new(&mut self.stream).poll_flush(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Ok(()),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Future for Endpoint<T> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if this.poll_read(cx)? {
+            // The requests still in flight fail when the endpoint is dropped.
+            return Poll::Ready(Ok(()));
+        }
+        this.poll_outgoing(cx)?;
+        this.poll_write(cx)?;
+        Poll::Pending
+    }
+}
+
+/// Accept connections on `listener`, and handle their requests and notifications with `service`.
+/// The returned future must be spawned on a tokio 1.x runtime, and runs until accepting a
+/// connection fails.
+pub fn serve<S: Service>(listener: TcpListener, service: S, options: &Options) -> Serve {
+    Serve {
+        listener: listener,
+        service: Arc::new(service),
+        options: *options,
+    }
+}
+
+/// A server started with [`serve`](fn.serve.html).
+pub struct Serve {
+    listener: TcpListener,
+    service: Arc<Service>,
+    options: Options,
+}
+
+impl Future for Serve {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            match self.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, peer))) => {
+                    let endpoint =
+                        Endpoint::with_service(stream, Arc::clone(&self.service), &self.options);
+                    tokio_1::spawn(Connection {
+                        endpoint: endpoint,
+                        peer: peer,
+                    });
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// A connection accepted by a server, that logs how it ended.
+struct Connection {
+    endpoint: Endpoint<TcpStream>,
+    peer: ::std::net::SocketAddr,
+}
+
+impl Future for Connection {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match Pin::new(&mut self.endpoint).poll(cx) {
+            Poll::Ready(Ok(())) => {
+                debug!("Connection from {} closed", self.peer);
+                Poll::Ready(())
+            }
+            Poll::Ready(Err(e)) => {
+                warn!("Connection from {} failed: {}", self.peer, e);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[test]
+fn tokio1_endpoints() {
+    use std::sync::mpsc as std_mpsc;
+
+    struct Adder(std_mpsc::Sender<Vec<Value>>);
+
+    impl Service for Adder {
+        fn handle_request(
+            &self,
+            method: &str,
+            params: Vec<Value>,
+        ) -> BoxFuture<Result<Value, Value>> {
+            let sum = match (params.first(), params.get(1)) {
+                (Some(a), Some(b)) if method == "add" => {
+                    a.as_u64().and_then(|a| b.as_u64().map(|b| a + b))
+                }
+                _ => None,
+            };
+            let result = sum.map(Value::from)
+                .ok_or_else(|| Value::from("invalid request"));
+            Box::pin(::std::future::ready(result))
+        }
+
+        fn handle_notification(&self, _method: &str, params: Vec<Value>) -> BoxFuture<()> {
+            let _ = self.0.send(params);
+            Box::pin(::std::future::ready(()))
+        }
+    }
+
+    let runtime = tokio_1::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    let mut options = Options::default();
+    // A tiny budget, so that the endpoints yield between the messages.
+    let _ = options.poll_budget(1);
+    let (notifications_tx, notifications_rx) = std_mpsc::channel();
+    let listener = runtime
+        .block_on(TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let address = listener.local_addr().unwrap();
+    runtime.spawn(serve(listener, Adder(notifications_tx), &options));
+
+    let stream = runtime.block_on(TcpStream::connect(address)).unwrap();
+    let endpoint = Endpoint::new(stream, &options);
+    let client = endpoint.client();
+    runtime.spawn(endpoint);
+    let sum = client.request("add", &[Value::from(1), Value::from(2)]);
+    let invalid = client.request("add", &[]);
+    assert_eq!(runtime.block_on(sum), Ok(Ok(Value::from(3))));
+    assert_eq!(runtime.block_on(invalid), Ok(Err(Value::from("invalid request"))));
+    client.notify("event", &[Value::from("hello")]).unwrap();
+    let ping = client.request("add", &[Value::from(0), Value::from(0)]);
+    let _ = runtime.block_on(ping);
+    assert_eq!(notifications_rx.try_recv(), Ok(vec![Value::from("hello")]));
+
+    // Ids wrap around, skipping those still in flight.
+    let stream = runtime.block_on(TcpStream::connect(address)).unwrap();
+    let mut endpoint = Endpoint::new(stream, &options);
+    endpoint.next_id = u32::max_value();
+    for &id in &[u32::max_value(), 0, 2] {
+        let _ = endpoint.in_flight.insert(Id::from(id), oneshot::channel().0);
+    }
+    assert_eq!(endpoint.next_request_id(), Some(Id::from(1_u32)));
+    assert_eq!(endpoint.next_request_id(), Some(Id::from(3_u32)));
+}
+
+#[cfg(feature = "runtime")]
+#[test]
+fn tokio1_legacy_endpoints() {
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use futures::future as future01;
+    use tokio_core::reactor::Core;
+    use methods::MethodRouter;
+    use server::ServerBuilder;
+
+    // A server of the futures 0.1 side of the crate, to check that both speak the same protocol.
+    let (address_tx, address_rx) = std_mpsc::channel();
+    let _ = thread::spawn(move || {
+        let mut router = MethodRouter::new();
+        let _ = router.request("echo", |params| Box::new(future01::ok(Ok(params[0].clone()))));
+        let mut core = Core::new().unwrap();
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .spawn(router, &core.handle())
+            .unwrap();
+        address_tx.send(server.local_addr().unwrap()).unwrap();
+        core.run(future01::empty::<(), ()>()).unwrap();
+    });
+    let legacy = address_rx.recv().unwrap();
+
+    let runtime = tokio_1::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    let stream = runtime.block_on(TcpStream::connect(legacy)).unwrap();
+    let endpoint = Endpoint::new(stream, &Options::default());
+    let client = endpoint.client();
+    runtime.spawn(endpoint);
+    let echo = client.request("echo", &[Value::from("legacy")]);
+    assert_eq!(runtime.block_on(echo), Ok(Ok(Value::from("legacy"))));
+}
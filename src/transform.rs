@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rmpv::Value;
+
+use endpoint::{Client, Service, ServiceBuilder};
+use errors::RouterError;
+use message::Param;
+use priority::Priority;
+
+type Transform = Fn(Vec<Value>) -> Vec<Value> + Send + Sync;
+
+/// Per-method functions that rewrite the parameters of requests and notifications before they are
+/// handled, so that a server can keep accepting the parameters sent by older clients without
+/// handling them in every handler: missing parameters can be given a default value, parameters
+/// can be reordered, etc.
+///
+/// The functions are applied by the services wrapped with [`wrap`](#method.wrap). `ParamTransforms`
+/// is cheap to clone.
+#[derive(Clone, Default)]
+pub struct ParamTransforms {
+    methods: Arc<HashMap<String, Arc<Transform>>>,
+}
+
+impl ParamTransforms {
+    /// Create an empty set of transforms, that leaves all the parameters unchanged.
+    pub fn new() -> Self {
+        ParamTransforms::default()
+    }
+
+    /// Register the function that rewrites the parameters of the given method. If a function was
+    /// already registered for this method, `transform` is applied after it.
+    pub fn add<F>(&mut self, method: &str, transform: F) -> &mut Self
+    where
+        F: Fn(Vec<Value>) -> Vec<Value> + Send + Sync + 'static,
+    {
+        let transform: Arc<Transform> = match self.methods.get(method) {
+            Some(previous) => {
+                let previous = Arc::clone(previous);
+                Arc::new(move |params| transform(previous(params)))
+            }
+            None => Arc::new(transform),
+        };
+        let _ = Arc::make_mut(&mut self.methods).insert(method.to_owned(), transform);
+        self
+    }
+
+    /// Append the missing trailing parameters of the given method: if fewer parameters than
+    /// `defaults` are received, the last ones are taken from `defaults`.
+    pub fn defaults(&mut self, method: &str, defaults: &[Value]) -> &mut Self {
+        let defaults = defaults.to_vec();
+        self.add(method, move |mut params| {
+            if params.len() < defaults.len() {
+                let missing = defaults[params.len()..].iter().cloned();
+                params.extend(missing);
+            }
+            params
+        })
+    }
+
+    /// Return `true` if no function is registered.
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+
+    /// Return the parameters of the given method, once rewritten.
+    pub fn apply(&self, method: &str, params: Vec<Value>) -> Vec<Value> {
+        match self.methods.get(method) {
+            Some(transform) => transform(params),
+            None => params,
+        }
+    }
+
+    /// Wrap a [`Service`](trait.Service.html) or a [`ServiceBuilder`](trait.ServiceBuilder.html)
+    /// so that the parameters it receives are rewritten first.
+    pub fn wrap<T>(&self, inner: T) -> Transformed<T> {
+        Transformed {
+            inner: inner,
+            transforms: self.clone(),
+        }
+    }
+}
+
+/// A service, or a service builder, whose parameters are rewritten by
+/// [`ParamTransforms`](struct.ParamTransforms.html).
+pub struct Transformed<T> {
+    inner: T,
+    transforms: ParamTransforms,
+}
+
+impl<B: ServiceBuilder> ServiceBuilder for Transformed<B> {
+    type Service = Transformed<B::Service>;
+
+    fn build(&self, client: Client) -> Self::Service {
+        self.transforms.wrap(self.inner.build(client))
+    }
+
+    fn registered_methods(&self) -> Vec<String> {
+        self.inner.registered_methods()
+    }
+
+    fn validate(&self) -> Result<(), RouterError> {
+        self.inner.validate()
+    }
+
+    fn protected_prefixes(&self) -> Vec<String> {
+        self.inner.protected_prefixes()
+    }
+}
+
+impl<S: Service> Service for Transformed<S> {
+    type Error = S::Error;
+    type T = S::T;
+    type E = S::E;
+    type RequestFuture = S::RequestFuture;
+    type NotificationFuture = S::NotificationFuture;
+
+    fn handle_request(&mut self, method: &str, params: &[Value]) -> Self::RequestFuture {
+        if !self.transforms.methods.contains_key(method) {
+            return self.inner.handle_request(method, params);
+        }
+        let params = self.transforms.apply(method, params.to_vec());
+        self.inner.handle_request(method, &params)
+    }
+
+    fn handle_request_zero_copy(&mut self, method: &str, params: Vec<Param>) -> Self::RequestFuture {
+        if !self.transforms.methods.contains_key(method) {
+            return self.inner.handle_request_zero_copy(method, params);
+        }
+        let params = params
+            .into_iter()
+            .map(Param::into_value)
+            .collect::<Vec<Value>>();
+        let params = self.transforms.apply(method, params);
+        self.inner.handle_request(method, &params)
+    }
+
+    fn handle_request_with_deadline(
+        &mut self,
+        method: &str,
+        params: Vec<Param>,
+        deadline: Option<Instant>,
+    ) -> Self::RequestFuture {
+        if !self.transforms.methods.contains_key(method) {
+            return self.inner
+                .handle_request_with_deadline(method, params, deadline);
+        }
+        let params = params
+            .into_iter()
+            .map(Param::into_value)
+            .collect::<Vec<Value>>();
+        let params = self.transforms
+            .apply(method, params)
+            .into_iter()
+            .map(Param::Value)
+            .collect();
+        self.inner
+            .handle_request_with_deadline(method, params, deadline)
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &[Value]) -> Self::NotificationFuture {
+        if !self.transforms.methods.contains_key(method) {
+            return self.inner.handle_notification(method, params);
+        }
+        let params = self.transforms.apply(method, params.to_vec());
+        self.inner.handle_notification(method, &params)
+    }
+
+    fn map_error(&mut self, error: Self::Error) -> Value {
+        self.inner.map_error(error)
+    }
+
+    fn methods(&self) -> Option<Vec<String>> {
+        self.inner.methods()
+    }
+
+    fn priority(&self, method: &str) -> Priority {
+        self.inner.priority(method)
+    }
+}
+
+#[test]
+fn param_transforms() {
+    let mut transforms = ParamTransforms::new();
+    let _ = transforms
+        .defaults("search", &[Value::from(""), Value::from(10)])
+        .add("search", |mut params| {
+            // Old clients send the limit as a string
+            if let Some(limit) = params[1].as_str().and_then(|limit| limit.parse::<i64>().ok()) {
+                params[1] = Value::from(limit);
+            }
+            params
+        });
+    assert_eq!(
+        transforms.apply("search", vec![Value::from("rust")]),
+        vec![Value::from("rust"), Value::from(10)]
+    );
+    assert_eq!(
+        transforms.apply("search", vec![Value::from("rust"), Value::from("5")]),
+        vec![Value::from("rust"), Value::from(5)]
+    );
+    assert_eq!(transforms.apply("other", vec![]), vec![]);
+}
@@ -0,0 +1,665 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::collections::vec_deque;
+use std::io;
+use std::iter::Chain;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use iovec::IoVec;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Decoder, Encoder};
+
+use codec::Codec;
+use dump::{Direction, ProtocolDump};
+#[cfg(feature = "compression")]
+use compression;
+use message::{Message, MessageWriter};
+use metrics::ConnectionMetrics;
+use options::ProtocolOptions;
+use redact::Redactions;
+use throttle::Throttle;
+
+/// Capacity reserved in the read buffer before each read.
+const READ_CAPACITY: usize = 8 * 1024;
+
+/// When the encode buffer allocated ahead has less than this left, another one is reserved before
+/// encoding a message, rather than growing the buffer in the middle of the message.
+const MIN_ENCODE_SPACE: usize = 1024;
+
+/// When more than this many bytes are queued, `start_send` tries to write some of them out before
+/// queueing more.
+const WRITE_HIGH_WATER_MARK: usize = 64 * 1024;
+
+/// Binary parameters smaller than this are copied into the frame of their message rather than
+/// being queued as a frame on their own.
+const MIN_ZERO_COPY_LEN: usize = 4 * 1024;
+
+/// A queue of encoded frames waiting to be written.
+///
+/// It implements `Buf` so that all the queued frames can be handed to the socket at once: for
+/// sockets that support it, `write_buf` then performs a single vectored write (`writev`) instead
+/// of one write per frame.
+///
+/// Frames are queued in one of two lanes. Control frames are written before data frames, so that
+/// messages generated by the protocol itself (errors, shutdown notices, etc.) are not stuck
+/// behind large responses. A message of the data lane may span several frames (its large binary
+/// parameters are frames of their own): once its first frame is written, all its frames are
+/// written before anything else, so that control frames only go between messages.
+type Frames<'a> = Chain<
+    Chain<vec_deque::Iter<'a, Bytes>, vec_deque::Iter<'a, Bytes>>,
+    vec_deque::Iter<'a, Bytes>,
+>;
+
+struct FrameQueue {
+    // The frames left of the message being written, the first of which may be partially written.
+    current: VecDeque<Bytes>,
+    control: VecDeque<Bytes>,
+    data: VecDeque<Bytes>,
+    // The number of frames of each message of the data lane.
+    data_messages: VecDeque<usize>,
+    // The number of frames of the message being queued in the data lane.
+    pending_frames: usize,
+    remaining: usize,
+}
+
+impl FrameQueue {
+    fn new() -> Self {
+        FrameQueue {
+            current: VecDeque::new(),
+            control: VecDeque::new(),
+            data: VecDeque::new(),
+            data_messages: VecDeque::new(),
+            pending_frames: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Queue a frame of a message in the data lane. The message ends with
+    /// [`end_message`](#method.end_message).
+    fn push(&mut self, frame: Bytes) {
+        if frame.is_empty() {
+            return;
+        }
+        self.remaining += frame.len();
+        self.data.push_back(frame);
+        self.pending_frames += 1;
+    }
+
+    /// Mark the end of the message whose frames have been pushed to the data lane.
+    fn end_message(&mut self) {
+        if self.pending_frames > 0 {
+            self.data_messages.push_back(self.pending_frames);
+            self.pending_frames = 0;
+        }
+    }
+
+    fn push_control(&mut self, frame: Bytes) {
+        if frame.is_empty() {
+            return;
+        }
+        self.remaining += frame.len();
+        self.control.push_back(frame);
+    }
+
+    /// Iterate over the queued frames, in the order they will be written.
+    fn frames(&self) -> Frames {
+        self.current
+            .iter()
+            .chain(self.control.iter())
+            .chain(self.data.iter())
+    }
+
+    /// Remove the next frame to be written from the queue.
+    fn pop_front(&mut self) -> Option<Bytes> {
+        if let Some(frame) = self.current.pop_front() {
+            return Some(frame);
+        }
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+        // The whole message is taken, so that it is not interrupted by control frames.
+        let frames = self.data_messages.pop_front().unwrap_or(self.data.len());
+        self.current.extend(self.data.drain(..frames));
+        self.current.pop_front()
+    }
+}
+
+impl Buf for FrameQueue {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.frames().next() {
+            Some(frame) => &frame[..],
+            None => &[],
+        }
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.remaining, "cannot advance past the end of the queue");
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let mut frame = self.pop_front().unwrap();
+            if cnt < frame.len() {
+                frame.advance(cnt);
+                self.current.push_front(frame);
+                return;
+            }
+            cnt -= frame.len();
+        }
+    }
+
+    fn bytes_vec<'a>(&'a self, dst: &mut [&'a IoVec]) -> usize {
+        let mut n = 0;
+        for (frame, slot) in self.frames().zip(dst.iter_mut()) {
+            *slot = frame[..].into();
+            n += 1;
+        }
+        n
+    }
+}
+
+/// Encodes messages into the data lane of a `FrameQueue`. Large binary parameters are queued as
+/// they are, instead of being copied into the frame of the message.
+struct FrameWriter<'a> {
+    buf: &'a mut BytesMut,
+    queue: &'a mut FrameQueue,
+    zero_copy: bool,
+}
+
+impl<'a> FrameWriter<'a> {
+    fn finish(self) {
+        self.queue.push(self.buf.take().freeze());
+        self.queue.end_message();
+    }
+}
+
+impl<'a> io::Write for FrameWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MessageWriter for FrameWriter<'a> {
+    fn write_binary(&mut self, bytes: &Bytes) -> io::Result<()> {
+        if !self.zero_copy || bytes.len() < MIN_ZERO_COPY_LEN {
+            self.buf.extend_from_slice(bytes);
+        } else {
+            self.queue.push(self.buf.take().freeze());
+            self.queue.push(bytes.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A `Stream` of incoming messages and a `Sink` of outgoing messages, built on top of an
+/// `AsyncRead + AsyncWrite` byte stream.
+///
+/// It is the framing layer the endpoints are built on. It only encodes and decodes messages:
+/// matching responses with requests, handling requests, etc. is left to the user, which makes it
+/// possible to implement custom protocol state machines on top of it.
+///
+/// Messages passed to `start_send` are queued, and written out when the sink is polled with
+/// `poll_complete`.
+pub struct Transport<T: AsyncRead + AsyncWrite> {
+    io: T,
+    codec: Codec,
+    redactions: Redactions,
+    read_buf: BytesMut,
+    // Maximum space reserved in the read buffer from the observed message sizes.
+    adaptive_read_buffer: Option<usize>,
+    // Moving average of the size of the incoming messages.
+    average_size: usize,
+    encode_buf: BytesMut,
+    // Size of the encode buffers allocated ahead.
+    encode_buffer: Option<usize>,
+    write_queue: FrameQueue,
+    // If `false`, each queued frame holds exactly one message.
+    zero_copy_writes: bool,
+    metrics: Option<ConnectionMetrics>,
+    dump: Option<ProtocolDump>,
+    read_throttle: Option<Throttle>,
+    write_throttle: Option<Throttle>,
+    eof: bool,
+}
+
+impl<T> Transport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    /// Create a transport with the default options.
+    pub fn new(io: T) -> Self {
+        Transport::with_options(io, &ProtocolOptions::default())
+    }
+
+    /// Create a transport. Only the options related to encoding and decoding are used (see
+    /// [`ProtocolOptions::zero_copy_binary`](struct.ProtocolOptions.html#method.zero_copy_binary),
+    /// [`ProtocolOptions::lenient_decoding`](struct.ProtocolOptions.html#method.lenient_decoding),
+    /// [`ProtocolOptions::empty_params`](struct.ProtocolOptions.html#method.empty_params),
+    /// [`ProtocolOptions::encode_buffer`](struct.ProtocolOptions.html#method.encode_buffer),
+    /// [`ProtocolOptions::adaptive_read_buffer`](struct.ProtocolOptions.html#method.adaptive_read_buffer),
+    /// [`ProtocolOptions::redactions`](struct.ProtocolOptions.html#method.redactions)
+    /// and [`ProtocolOptions::dump`](struct.ProtocolOptions.html#method.dump)).
+    pub fn with_options(io: T, options: &ProtocolOptions) -> Self {
+        Transport {
+            io: io,
+            codec: Codec::new(options),
+            redactions: options.get_redactions().clone(),
+            read_buf: BytesMut::with_capacity(READ_CAPACITY),
+            adaptive_read_buffer: options.get_adaptive_read_buffer(),
+            average_size: 0,
+            encode_buf: BytesMut::new(),
+            encode_buffer: options.get_encode_buffer(),
+            write_queue: FrameQueue::new(),
+            zero_copy_writes: true,
+            metrics: None,
+            dump: options.get_dump().cloned(),
+            read_throttle: None,
+            write_throttle: None,
+            eof: false,
+        }
+    }
+
+    /// Return the functions that hide sensitive parameters from the logs.
+    pub(crate) fn redactions(&self) -> &Redactions {
+        &self.redactions
+    }
+
+    /// Copy the large binary parameters into the frame of their message, so that each write to
+    /// the underlying stream starts with a new message. This is needed for message-oriented
+    /// streams such as WebSockets.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn set_message_aligned_writes(&mut self) {
+        self.zero_copy_writes = false;
+    }
+
+    /// Report the bytes read and written to the metrics of the connection.
+    pub(crate) fn set_metrics(&mut self, metrics: Option<ConnectionMetrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Limit the bytes read and written per second, as set by the options (see
+    /// [`ProtocolOptions::read_rate`](struct.ProtocolOptions.html#method.read_rate)). The
+    /// reactor is used to wait until bytes can be transferred again.
+    pub(crate) fn set_throttles(&mut self, options: &ProtocolOptions, reactor: &Handle) {
+        self.read_throttle = options
+            .get_read_rate()
+            .map(|rate| Throttle::new(rate, reactor));
+        self.write_throttle = options
+            .get_write_rate()
+            .map(|rate| Throttle::new(rate, reactor));
+    }
+
+    /// Compress the messages sent from now on, if compression is enabled (see
+    /// `ProtocolOptions::compression`).
+    #[cfg(feature = "compression")]
+    pub(crate) fn enable_compression(&mut self) {
+        self.codec.enable_compression();
+    }
+
+    #[cfg(feature = "compression")]
+    fn compression_threshold(&self) -> Option<usize> {
+        self.codec.compression_threshold()
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn compression_threshold(&self) -> Option<usize> {
+        None
+    }
+
+    /// Return `true` if so many bytes are queued that no more messages should be queued until
+    /// some of them are written out.
+    pub(crate) fn is_congested(&self) -> bool {
+        self.write_queue.remaining() >= WRITE_HIGH_WATER_MARK
+    }
+
+    /// Queue a message. It is written out the next time the transport is flushed.
+    pub(crate) fn send(&mut self, message: Message) {
+        trace!("Sending {:?}", self.redactions.message(&message));
+        match self.start_send(message) {
+            Ok(AsyncSink::Ready) => return,
+            // FIXME: there should probably be a retry mechanism.
+            Ok(AsyncSink::NotReady(_message)) => panic!("The sink is full."),
+            Err(e) => panic!("An error occured while trying to send message: {:?}", e),
+        }
+    }
+
+    /// Queue a message that has been generated by the protocol itself rather than by the
+    /// application. It is written before the other queued messages.
+    pub(crate) fn send_control(&mut self, message: Message) {
+        trace!("Sending control message {:?}", self.redactions.message(&message));
+        // Control messages are small, and they are only copied if they are dumped.
+        let dumped = self.dump.as_ref().map(|_| message.clone());
+        self.reserve_encode_buffer();
+        if let Err(e) = self.codec.encode(message, &mut self.encode_buf) {
+            panic!("An error occured while trying to send message: {:?}", e);
+        }
+        let frame = self.encode_buf.take().freeze();
+        if let Some(message) = dumped {
+            self.dump(Direction::Outgoing, frame.len(), &message);
+        }
+        self.write_queue.push_control(frame);
+    }
+
+    /// Make room for the next message in the encode buffer, if it is allocated ahead.
+    fn reserve_encode_buffer(&mut self) {
+        if let Some(size) = self.encode_buffer {
+            // Once all the frames of the previous buffer have been written out, it is reused.
+            if self.encode_buf.capacity() < MIN_ENCODE_SPACE {
+                self.encode_buf.reserve(size);
+            }
+        }
+    }
+
+    /// Return the space to reserve in the read buffer before the next read.
+    fn read_capacity(&self) -> usize {
+        match self.adaptive_read_buffer {
+            Some(max) => {
+                let missing = self.codec
+                    .pending_size()
+                    .saturating_sub(self.read_buf.len());
+                cmp::max(READ_CAPACITY, cmp::min(max, cmp::max(missing, self.average_size)))
+            }
+            None => READ_CAPACITY,
+        }
+    }
+
+    /// Report a message to the dump, if any.
+    fn dump(&self, direction: Direction, size: usize, message: &Message) {
+        if let Some(ref dump) = self.dump {
+            dump.record(direction, size, message, &self.redactions);
+        }
+    }
+
+    fn write_queued(&mut self) -> Poll<(), io::Error> {
+        while self.write_queue.has_remaining() {
+            let written = match self.write_throttle {
+                Some(ref mut throttle) => {
+                    let allowance = match throttle.poll_allowance()? {
+                        Async::Ready(allowance) => allowance,
+                        Async::NotReady => return Ok(Async::NotReady),
+                    };
+                    self.io.write_buf(&mut (&mut self.write_queue).take(allowance))?
+                }
+                None => self.io.write_buf(&mut self.write_queue)?,
+            };
+            match written {
+                Async::Ready(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write frames to the transport",
+                    ))
+                }
+                Async::Ready(n) => {
+                    trace!("Wrote {} bytes", n);
+                    if let Some(ref mut throttle) = self.write_throttle {
+                        throttle.consume(n);
+                    }
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.bytes_written(n);
+                    }
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> Stream for Transport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Item = Message;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let buffered = self.read_buf.len();
+            if let Some(message) = self.codec.decode(&mut self.read_buf)? {
+                let size = buffered - self.read_buf.len();
+                if self.adaptive_read_buffer.is_some() {
+                    self.average_size = (self.average_size * 7 + size) / 8;
+                }
+                self.dump(Direction::Incoming, size, &message);
+                return Ok(Async::Ready(Some(message)));
+            }
+            if self.eof {
+                return Ok(Async::Ready(None));
+            }
+            if let Some(ref mut throttle) = self.read_throttle {
+                if let Async::NotReady = throttle.poll_allowance()? {
+                    return Ok(Async::NotReady);
+                }
+            }
+            let capacity = self.read_capacity();
+            self.read_buf.reserve(capacity);
+            match self.io.read_buf(&mut self.read_buf)? {
+                Async::Ready(0) => self.eof = true,
+                Async::Ready(n) => {
+                    if let Some(ref mut throttle) = self.read_throttle {
+                        throttle.consume(n);
+                    }
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.bytes_read(n);
+                    }
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl<T> Sink for Transport<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type SinkItem = Message;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.write_queue.remaining() >= WRITE_HIGH_WATER_MARK {
+            // Try to make some room, but queue the message anyway: the queue is flushed on each
+            // poll of the endpoint.
+            let _ = self.write_queued()?;
+        }
+        self.reserve_encode_buffer();
+        let compression = self.compression_threshold();
+        let queued = self.write_queue.remaining();
+        {
+            // A message that may be compressed must be encoded in one piece.
+            let mut writer = FrameWriter {
+                buf: &mut self.encode_buf,
+                queue: &mut self.write_queue,
+                zero_copy: self.zero_copy_writes && compression.is_none(),
+            };
+            self.codec.encode_message(&item, &mut writer)?;
+            #[cfg(feature = "compression")]
+            {
+                if let Some(threshold) = compression {
+                    if writer.buf.len() >= threshold {
+                        compression::compress(writer.buf, 0)?;
+                    }
+                }
+            }
+            writer.finish();
+        }
+        let size = self.write_queue.remaining() - queued;
+        self.dump(Direction::Outgoing, size, &item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if let Async::NotReady = self.write_queued()? {
+            return Ok(Async::NotReady);
+        }
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[test]
+fn frame_queue() {
+    let mut queue = FrameQueue::new();
+    queue.push(Bytes::from(&b"abc"[..]));
+    queue.end_message();
+    queue.push(Bytes::new());
+    queue.push(Bytes::from(&b"de"[..]));
+    queue.end_message();
+    assert_eq!(queue.remaining(), 5);
+
+    // Each non-empty frame gets its own slice
+    {
+        static DUMMY: &[u8] = &[0];
+        let mut slices = [<&IoVec>::from(DUMMY); 4];
+        assert_eq!(queue.bytes_vec(&mut slices), 2);
+        assert_eq!(&slices[0][..], b"abc");
+        assert_eq!(&slices[1][..], b"de");
+    }
+
+    // A partial write leaves the end of the first frame in the queue
+    queue.advance(2);
+    assert_eq!(queue.bytes(), b"c");
+    assert_eq!(queue.remaining(), 3);
+
+    // Control frames go before the data frames, but after the frame being written
+    queue.push_control(Bytes::from(&b"xy"[..]));
+    assert_eq!(queue.bytes(), b"c");
+    queue.advance(1);
+    assert_eq!(queue.bytes(), b"xy");
+
+    // Advancing across frame boundaries drops the frames that have been fully written
+    queue.advance(3);
+    assert_eq!(queue.bytes(), b"e");
+    queue.advance(1);
+    assert!(!queue.has_remaining());
+
+    // A message split in several frames is not interrupted by control frames.
+    queue.push(Bytes::from(&b"head"[..]));
+    queue.push(Bytes::from(&b"body"[..]));
+    queue.end_message();
+    queue.advance(4);
+    queue.push_control(Bytes::from(&b"xy"[..]));
+    assert_eq!(queue.bytes(), b"body");
+    queue.advance(4);
+    assert_eq!(queue.bytes(), b"xy");
+}
+
+#[test]
+fn transport_round_trip() {
+    use futures::Future;
+    use message::{Id, Notification, Request};
+    use mock::duplex;
+    use rmpv::Value;
+
+    let (left, right) = duplex();
+    let messages = vec![
+        Message::Request(Request {
+            id: Id::from(1_u32),
+            method: "add".to_owned(),
+            params: vec![Value::from(1).into(), Value::from(2).into()],
+        }),
+        Message::Notification(Notification {
+            method: "ping".to_owned(),
+            params: vec![],
+        }),
+    ];
+    let outgoing = ::futures::stream::iter_ok::<_, io::Error>(messages.clone());
+    let _ = Sink::send_all(Transport::new(left), outgoing).wait().unwrap();
+    let received = Transport::new(right).take(2).collect().wait().unwrap();
+    assert_eq!(received, messages);
+}
+
+#[test]
+fn zero_copy_with_control_messages() {
+    use message::{Id, Notification, Param, Request};
+    use mock::duplex;
+    use rmpv::Value;
+
+    let (left, _) = duplex();
+    let mut sender = Transport::new(left);
+    let payload = vec![7_u8; 2 * MIN_ZERO_COPY_LEN];
+    let request = Message::Request(Request {
+        id: Id::from(1_u32),
+        method: "upload".to_owned(),
+        params: vec![Param::Binary(Bytes::from(payload.clone()))],
+    });
+    let control = Message::Notification(Notification {
+        method: "tick".to_owned(),
+        params: vec![Value::from(1)],
+    });
+    let _ = sender.start_send(request).unwrap();
+
+    // Write the first frame of the request, queue a control message, then write the rest.
+    let mut written = BytesMut::new();
+    let first = sender.write_queue.bytes().len();
+    assert!(first < sender.write_queue.remaining());
+    written.extend_from_slice(sender.write_queue.bytes());
+    sender.write_queue.advance(first);
+    sender.send_control(control.clone());
+    while sender.write_queue.has_remaining() {
+        let frame = sender.write_queue.bytes().len();
+        written.extend_from_slice(sender.write_queue.bytes());
+        sender.write_queue.advance(frame);
+    }
+
+    let mut codec = Codec::new(&ProtocolOptions::default());
+    match codec.decode(&mut written).unwrap() {
+        Some(Message::Request(decoded)) => {
+            let params = decoded.params.into_iter().map(Param::into_value);
+            assert_eq!(params.collect::<Vec<_>>(), vec![Value::from(payload)]);
+        }
+        message => panic!("unexpected message: {:?}", message),
+    }
+    assert_eq!(codec.decode(&mut written).unwrap(), Some(control));
+}
+
+#[test]
+fn buffer_strategy() {
+    use futures::Future;
+    use message::Notification;
+    use mock::duplex;
+    use rmpv::Value;
+
+    let mut options = ProtocolOptions::new();
+    let _ = options
+        .encode_buffer(Some(64 * 1024))
+        .adaptive_read_buffer(Some(1024 * 1024));
+    let (left, right) = duplex();
+    let mut sender = Transport::with_options(left, &options);
+    let receiver = Transport::with_options(right, &options);
+
+    // Small messages are encoded one after the other in the buffer allocated ahead.
+    let small = Message::Notification(Notification {
+        method: "tick".to_owned(),
+        params: vec![],
+    });
+    let size = small.pack().unwrap().len();
+    let _ = sender.start_send(small.clone()).unwrap();
+    assert_eq!(sender.encode_buf.capacity(), 64 * 1024 - size);
+    let _ = sender.flush().wait().unwrap();
+    let (received, mut receiver) = receiver.into_future().map_err(|(e, _)| e).wait().unwrap();
+    assert_eq!(received, Some(small));
+    assert_eq!(receiver.average_size, size / 8);
+
+    // Once the first bytes of a large message are received, room is made for all of it.
+    let large = Message::Notification(Notification {
+        method: "bulk".to_owned(),
+        params: vec![Value::from(vec![0_u8; 200 * 1024]).into()],
+    });
+    let bytes = large.pack().unwrap();
+    receiver.read_buf.extend_from_slice(&bytes[..100]);
+    assert_eq!(receiver.codec.decode(&mut receiver.read_buf).unwrap(), None);
+    assert_eq!(receiver.read_capacity(), bytes.len() - 100);
+}
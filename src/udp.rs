@@ -0,0 +1,157 @@
+//! Notifications over UDP.
+//!
+//! A [`UdpTransport`](struct.UdpTransport.html) sends and receives notifications, one per
+//! datagram, without any connection. It suits telemetry and events, where losing a notification
+//! now and then is better than maintaining connections. Requests and responses cannot be sent
+//! this way, since datagrams may be lost, duplicated or reordered.
+//!
+//! ```rust,ignore
+//! let transport = UdpTransport::bind(&"0.0.0.0:0".parse().unwrap(), &handle)?;
+//! let event = Notification {
+//!     method: "cpu_load".to_owned(),
+//!     params: vec![Value::from(0.42)],
+//! };
+//! let transport = core.run(transport.send((collector, event)))?;
+//! ```
+use std::io;
+use std::net::SocketAddr;
+
+use futures::{Async, Poll, Sink, StartSend, Stream};
+use tokio_core::net::{UdpCodec, UdpFramed, UdpSocket};
+use tokio_core::reactor::Handle;
+
+use message::{Message, Notification};
+
+/// Decodes each datagram into a notification. Datagrams that do not hold exactly one
+/// notification are decoded as `None`, so that they do not end the stream.
+struct NotificationCodec;
+
+impl UdpCodec for NotificationCodec {
+    type In = Option<(SocketAddr, Notification)>;
+    type Out = (SocketAddr, Notification);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        let mut rd = io::Cursor::new(buf);
+        match Message::decode(&mut rd) {
+            Ok(Message::Notification(_)) if rd.position() as usize != buf.len() => {
+                warn!("Ignoring datagram from {}: trailing bytes after the notification", src);
+                Ok(None)
+            }
+            Ok(Message::Notification(notification)) => Ok(Some((*src, notification))),
+            Ok(_) => {
+                warn!("Ignoring datagram from {}: not a notification", src);
+                Ok(None)
+            }
+            Err(e) => {
+                warn!("Ignoring datagram from {}: {}", src, e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn encode(&mut self, item: Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
+        let (target, notification) = item;
+        if let Err(e) = Message::Notification(notification).encode(buf) {
+            // Writing to a `Vec` does not fail, so this is not supposed to happen.
+            warn!("Failed to encode notification for {}: {}", target, e);
+            buf.clear();
+        }
+        target
+    }
+}
+
+/// A `Stream` of the notifications received on a UDP socket, with the address they come from,
+/// and a `Sink` of notifications to send, with the address they are sent to.
+///
+/// Each datagram carries exactly one notification. The datagrams that do not are ignored, and
+/// logged. Notifications larger than a datagram (about 64KB, often less in practice because of
+/// fragmentation) cannot be sent: the sink fails with the error of the socket.
+pub struct UdpTransport {
+    framed: UdpFramed<NotificationCodec>,
+}
+
+impl UdpTransport {
+    /// Bind a UDP socket to `addr`, to send and receive notifications.
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<Self> {
+        Ok(UdpTransport::new(UdpSocket::bind(addr, handle)?))
+    }
+
+    /// Send and receive notifications on an existing socket, for instance one that has joined a
+    /// multicast group, or that is allowed to broadcast.
+    pub fn new(socket: UdpSocket) -> Self {
+        UdpTransport {
+            framed: socket.framed(NotificationCodec),
+        }
+    }
+
+    /// Return the address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.framed.get_ref().local_addr()
+    }
+
+    /// Return the underlying socket.
+    pub fn into_inner(self) -> UdpSocket {
+        self.framed.into_inner()
+    }
+}
+
+impl Stream for UdpTransport {
+    type Item = (SocketAddr, Notification);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.framed.poll()? {
+                Async::Ready(Some(Some(item))) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(Some(None)) => continue,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl Sink for UdpTransport {
+    type SinkItem = (SocketAddr, Notification);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.framed.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.framed.poll_complete()
+    }
+}
+
+#[test]
+fn udp_notifications() {
+    use message::{Id, Request};
+    use rmpv::Value;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let any = "127.0.0.1:0".parse().unwrap();
+    let receiver = UdpTransport::bind(&any, &handle).unwrap();
+    let target = receiver.local_addr().unwrap();
+    let sender = UdpTransport::bind(&any, &handle).unwrap();
+    let source = sender.local_addr().unwrap();
+
+    // A request is not a notification: it is ignored.
+    let request = Message::Request(Request {
+        id: Id::from(1_u32),
+        method: "add".to_owned(),
+        params: vec![],
+    });
+    let socket = ::std::net::UdpSocket::bind(any).unwrap();
+    let _ = socket.send_to(&request.pack().unwrap(), target).unwrap();
+
+    let notification = Notification {
+        method: "event".to_owned(),
+        params: vec![Value::from(42)],
+    };
+    let _ = core.run(sender.send((target, notification.clone()))).unwrap();
+    let (received, _) = core.run(receiver.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(received, Some((source, notification)));
+}
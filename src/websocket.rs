@@ -0,0 +1,605 @@
+//! A minimal WebSocket (RFC 6455) transport. Each `MessagePack-RPC` message is carried by a
+//! binary WebSocket message, so that services can sit behind HTTP reverse proxies and be reached
+//! from browsers.
+//!
+//! This module is only available with the `websocket` feature. Servers and clients use it when
+//! [`ServerBuilder::set_websocket`](../struct.ServerBuilder.html#method.set_websocket) or
+//! [`Connector::set_websocket`](../struct.Connector.html#method.set_websocket) is called, but the
+//! streams can also be used on their own.
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use bytes::{BufMut, BytesMut};
+use futures::{Async, Future, Poll};
+use httparse;
+use sha1::Sha1;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// GUID used to compute the `Sec-WebSocket-Accept` header.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of the HTTP request or response of the handshake.
+const MAX_HANDSHAKE_LEN: usize = 8 * 1024;
+
+/// Maximum number of headers in the HTTP request or response of the handshake.
+const MAX_HEADERS: usize = 32;
+
+/// When more than this many bytes are waiting to be written, writes are refused until some of them
+/// are written out.
+const WRITE_HIGH_WATER_MARK: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Maximum payload length of the control frames (close, ping and pong).
+const MAX_CONTROL_PAYLOAD_LEN: usize = 125;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Compute the `Sec-WebSocket-Accept` header corresponding to a `Sec-WebSocket-Key`.
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
+
+/// A xorshift generator for the masking keys and the handshake key. The masking keys only need
+/// to be unpredictable enough to prevent cache poisoning by intermediaries.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_secs() ^ u64::from(now.subsec_nanos()) << 32,
+            Err(_) => 0,
+        };
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn mask(&mut self) -> [u8; 4] {
+        let n = self.next();
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+}
+
+fn apply_mask(mask: [u8; 4], data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Append a complete frame to `buf`. Clients must mask the frames they send, servers must not.
+fn write_frame(buf: &mut BytesMut, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) {
+    buf.reserve(payload.len() + 14);
+    buf.put_u8(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    if payload.len() < 126 {
+        buf.put_u8(mask_bit | payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        buf.put_u8(mask_bit | 126);
+        buf.put_u16::<::bytes::BigEndian>(payload.len() as u16);
+    } else {
+        buf.put_u8(mask_bit | 127);
+        buf.put_u64::<::bytes::BigEndian>(payload.len() as u64);
+    }
+    match mask {
+        Some(mask) => {
+            buf.put_slice(&mask);
+            let start = buf.len();
+            buf.put_slice(payload);
+            apply_mask(mask, &mut buf[start..]);
+        }
+        None => buf.put_slice(payload),
+    }
+}
+
+/// A frame header.
+struct Header {
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+/// Parse the header of the frame at the beginning of `buf`. Return `None` if `buf` does not
+/// contain a complete header yet.
+fn parse_header(buf: &[u8]) -> io::Result<Option<Header>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    if buf[0] & 0x70 != 0 {
+        return Err(invalid_data("unsupported WebSocket extension"));
+    }
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut header_len) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            ((buf[2] as usize) << 8 | buf[3] as usize, 4)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut len: u64 = 0;
+            for byte in &buf[2..10] {
+                len = len << 8 | u64::from(*byte);
+            }
+            if len > usize::max_value() as u64 {
+                return Err(invalid_data("WebSocket frame too large"));
+            }
+            (len as usize, 10)
+        }
+        len => (len as usize, 2),
+    };
+    if opcode & 0x08 != 0 && payload_len > MAX_CONTROL_PAYLOAD_LEN {
+        return Err(invalid_data("WebSocket control frame too large"));
+    }
+    let mask = if masked {
+        if buf.len() < header_len + 4 {
+            return Ok(None);
+        }
+        let mask = [
+            buf[header_len],
+            buf[header_len + 1],
+            buf[header_len + 2],
+            buf[header_len + 3],
+        ];
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    Ok(Some(Header {
+        opcode: opcode,
+        mask: mask,
+        header_len: header_len,
+        payload_len: payload_len,
+    }))
+}
+
+/// A byte stream carried by the binary messages of a WebSocket connection.
+///
+/// Each `write` is sent as one binary message, and the payloads of the messages received are
+/// read as a continuous byte stream. Pings are answered automatically.
+pub struct WebSocketStream<T> {
+    io: T,
+    // `Some` for clients, which must mask the frames they send.
+    rng: Option<Rng>,
+    // Bytes read from `io` that have not been parsed yet.
+    read_buf: BytesMut,
+    // Payload of the data frames, waiting to be read.
+    payload: BytesMut,
+    // Frames waiting to be written to `io`.
+    write_buf: BytesMut,
+    max_frame_size: Option<usize>,
+    close_received: bool,
+    close_sent: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite> WebSocketStream<T> {
+    fn new(io: T, client: bool, read_buf: BytesMut, max_frame_size: Option<usize>) -> Self {
+        WebSocketStream {
+            io: io,
+            rng: if client { Some(Rng::new()) } else { None },
+            read_buf: read_buf,
+            payload: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            max_frame_size: max_frame_size,
+            close_received: false,
+            close_sent: false,
+        }
+    }
+
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask = self.rng.as_mut().map(|rng| rng.mask());
+        write_frame(&mut self.write_buf, opcode, payload, mask);
+    }
+
+    /// Write the queued frames to `io`. Return `WouldBlock` if they cannot all be written.
+    fn write_queued(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.io.write(&self.write_buf)? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write WebSocket frames",
+                    ))
+                }
+                n => {
+                    let _ = self.write_buf.split_to(n);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the frames in `read_buf`. Return `false` if more bytes must be read.
+    fn parse_frame(&mut self) -> io::Result<bool> {
+        let header = match parse_header(&self.read_buf)? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        // Clients must mask the frames they send, and servers must not.
+        if header.mask.is_some() == self.rng.is_some() {
+            return Err(invalid_data("invalid WebSocket frame masking"));
+        }
+        if let Some(max) = self.max_frame_size {
+            if header.payload_len > max {
+                return Err(invalid_data("WebSocket frame too large"));
+            }
+        }
+        let frame_len = match header.header_len.checked_add(header.payload_len) {
+            Some(len) => len,
+            None => return Err(invalid_data("WebSocket frame too large")),
+        };
+        if self.read_buf.len() < frame_len {
+            return Ok(false);
+        }
+        let _ = self.read_buf.split_to(header.header_len);
+        let mut payload = self.read_buf.split_to(header.payload_len);
+        if let Some(mask) = header.mask {
+            apply_mask(mask, &mut payload);
+        }
+        match header.opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => self.payload.extend_from_slice(&payload),
+            OPCODE_TEXT => return Err(invalid_data("unexpected WebSocket text message")),
+            OPCODE_PING => {
+                trace!("WebSocket ping");
+                self.queue_frame(OPCODE_PONG, &payload);
+            }
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                trace!("WebSocket close");
+                self.close_received = true;
+                if !self.close_sent {
+                    self.close_sent = true;
+                    self.queue_frame(OPCODE_CLOSE, &[]);
+                }
+            }
+            _ => return Err(invalid_data("unknown WebSocket opcode")),
+        }
+        Ok(true)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Read for WebSocketStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.payload.is_empty() {
+                let n = cmp::min(buf.len(), self.payload.len());
+                buf[..n].copy_from_slice(&self.payload.split_to(n));
+                return Ok(n);
+            }
+            if self.close_received {
+                // Send the answer to the close frame, if it is still queued.
+                match self.write_queued() {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                    Ok(()) => {}
+                }
+                return Ok(0);
+            }
+            if self.parse_frame()? {
+                continue;
+            }
+            // Answer the pings before blocking.
+            match self.write_queued() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+            self.read_buf.reserve(4096);
+            let n = self.io.read_buf(&mut self.read_buf);
+            match n? {
+                Async::Ready(0) => return Ok(0),
+                Async::Ready(_) => {}
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncRead for WebSocketStream<T> {}
+
+impl<T: AsyncRead + AsyncWrite> Write for WebSocketStream<T> {
+    /// Send `buf` as a single binary message. Either all of `buf` is accepted, or none of it.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_buf.len() >= WRITE_HIGH_WATER_MARK {
+            self.write_queued()?;
+        }
+        self.queue_frame(OPCODE_BINARY, buf);
+        match self.write_queued() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_queued()?;
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncWrite for WebSocketStream<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.close_sent {
+            self.close_sent = true;
+            self.queue_frame(OPCODE_CLOSE, &[]);
+        }
+        match self.write_queued() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+        self.io.shutdown()
+    }
+}
+
+/// The side of the handshake being performed.
+enum Side {
+    Server,
+    Client { key: String },
+}
+
+/// A future that performs the HTTP upgrade handshake, and resolves to a `WebSocketStream`.
+pub struct Handshake<T> {
+    io: Option<T>,
+    side: Side,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    max_frame_size: Option<usize>,
+    // Whether the request or response has been received.
+    parsed: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite> Handshake<T> {
+    /// If `max` is not `None`, the connection is closed with an `InvalidData` error as soon as the
+    /// peer announces a frame larger than `max` bytes, before the frame is buffered. By default,
+    /// frames of any size are accepted.
+    pub fn set_max_frame_size(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_frame_size = max;
+        self
+    }
+
+    /// Parse the request or response in `read_buf`. Return `None` if it is not complete yet.
+    fn parse(&mut self) -> io::Result<Option<usize>> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let (status, expected_key, len) = match self.side {
+            Side::Server => {
+                let mut request = httparse::Request::new(&mut headers);
+                let len = match request.parse(&self.read_buf) {
+                    Ok(httparse::Status::Complete(len)) => len,
+                    Ok(httparse::Status::Partial) => return Ok(None),
+                    Err(_) => return Err(invalid_data("invalid WebSocket handshake request")),
+                };
+                (None, None, len)
+            }
+            Side::Client { ref key } => {
+                let mut response = httparse::Response::new(&mut headers);
+                let len = match response.parse(&self.read_buf) {
+                    Ok(httparse::Status::Complete(len)) => len,
+                    Ok(httparse::Status::Partial) => return Ok(None),
+                    Err(_) => return Err(invalid_data("invalid WebSocket handshake response")),
+                };
+                (response.code, Some(accept_key(key.as_bytes())), len)
+            }
+        };
+
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .map(|header| header.value)
+        };
+        let upgrade = header("Upgrade")
+            .map(|value| value.eq_ignore_ascii_case(b"websocket"))
+            .unwrap_or(false);
+        if !upgrade {
+            return Err(invalid_data("not a WebSocket handshake"));
+        }
+
+        match expected_key {
+            None => {
+                let key = match header("Sec-WebSocket-Key") {
+                    Some(key) => key,
+                    None => return Err(invalid_data("missing Sec-WebSocket-Key header")),
+                };
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(key)
+                );
+                self.write_buf.extend_from_slice(response.as_bytes());
+            }
+            Some(expected) => {
+                if status != Some(101) {
+                    return Err(invalid_data("the server refused the WebSocket upgrade"));
+                }
+                if header("Sec-WebSocket-Accept") != Some(expected.as_bytes()) {
+                    return Err(invalid_data("invalid Sec-WebSocket-Accept header"));
+                }
+            }
+        }
+        Ok(Some(len))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for Handshake<T> {
+    type Item = WebSocketStream<T>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            {
+                let io = self.io.as_mut().unwrap();
+                while !self.write_buf.is_empty() {
+                    match io.write(&self.write_buf) {
+                        Ok(0) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::WriteZero,
+                                "failed to write the WebSocket handshake",
+                            ))
+                        }
+                        Ok(n) => {
+                            let _ = self.write_buf.split_to(n);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(Async::NotReady)
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            if self.parsed {
+                trace!("WebSocket handshake done");
+                let io = self.io.take().unwrap();
+                let client = match self.side {
+                    Side::Client { .. } => true,
+                    Side::Server => false,
+                };
+                let read_buf = self.read_buf.take();
+                let stream = WebSocketStream::new(io, client, read_buf, self.max_frame_size);
+                return Ok(Async::Ready(stream));
+            }
+            if let Some(len) = self.parse()? {
+                // The bytes that follow are WebSocket frames. The server still has to send its
+                // response before the handshake is done.
+                let _ = self.read_buf.split_to(len);
+                self.parsed = true;
+                continue;
+            }
+            if self.read_buf.len() >= MAX_HANDSHAKE_LEN {
+                return Err(invalid_data("WebSocket handshake too large"));
+            }
+            self.read_buf.reserve(1024);
+            match self.io.as_mut().unwrap().read_buf(&mut self.read_buf)? {
+                Async::Ready(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed during the WebSocket handshake",
+                    ))
+                }
+                Async::Ready(_) => {}
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Perform the server side of the WebSocket handshake on a newly accepted connection.
+pub fn accept<T: AsyncRead + AsyncWrite>(io: T) -> Handshake<T> {
+    Handshake {
+        io: Some(io),
+        side: Side::Server,
+        read_buf: BytesMut::new(),
+        write_buf: BytesMut::new(),
+        max_frame_size: None,
+        parsed: false,
+    }
+}
+
+/// Perform the client side of the WebSocket handshake, requesting `path` on `host`.
+pub fn connect<T: AsyncRead + AsyncWrite>(io: T, host: &str, path: &str) -> Handshake<T> {
+    let mut rng = Rng::new();
+    let mut nonce = [0; 16];
+    for chunk in nonce.chunks_mut(4) {
+        chunk.copy_from_slice(&rng.mask());
+    }
+    let key = base64::encode(&nonce);
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+    Handshake {
+        io: Some(io),
+        side: Side::Client { key: key },
+        read_buf: BytesMut::new(),
+        write_buf: BytesMut::from(request.as_bytes()),
+        max_frame_size: None,
+        parsed: false,
+    }
+}
+
+#[test]
+fn accept_key_example() {
+    // Example from RFC 6455, section 1.3
+    assert_eq!(
+        accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+}
+
+#[test]
+fn frame_round_trip() {
+    let mut buf = BytesMut::new();
+    let payload = vec![7; 300];
+    write_frame(&mut buf, OPCODE_BINARY, &payload, Some([1, 2, 3, 4]));
+    let header = parse_header(&buf).unwrap().unwrap();
+    assert_eq!(header.opcode, OPCODE_BINARY);
+    assert_eq!(header.header_len, 8);
+    assert_eq!(header.payload_len, 300);
+    let mut received = buf[header.header_len..].to_vec();
+    apply_mask(header.mask.unwrap(), &mut received);
+    assert_eq!(received, payload);
+}
+
+#[cfg(test)]
+fn read_frames(frames: &[u8], max_frame_size: Option<usize>) -> io::Result<Vec<u8>> {
+    let io = io::Cursor::new(frames.to_vec());
+    let mut stream = WebSocketStream::new(io, false, BytesMut::new(), max_frame_size);
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received)?;
+    Ok(received)
+}
+
+#[test]
+fn oversized_frames() {
+    let mut buf = BytesMut::new();
+    write_frame(&mut buf, OPCODE_BINARY, &[7; 300], Some([1, 2, 3, 4]));
+    assert_eq!(read_frames(&buf, Some(300)).unwrap(), vec![7; 300]);
+    let e = read_frames(&buf[..10], Some(299)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    // A 127-length header announcing the largest possible payload, without the payload.
+    let header = [0x82, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1, 2, 3, 4];
+    let e = read_frames(&header, None).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let e = read_frames(&header, Some(1024)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    // Control frames are limited to 125 bytes, and servers only accept masked frames.
+    let mut buf = BytesMut::new();
+    write_frame(&mut buf, OPCODE_PING, &[0; 126], Some([1, 2, 3, 4]));
+    assert!(read_frames(&buf, None).is_err());
+    let mut buf = BytesMut::new();
+    write_frame(&mut buf, OPCODE_BINARY, &[7; 10], None);
+    assert!(read_frames(&buf, None).is_err());
+}